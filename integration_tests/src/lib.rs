@@ -2,18 +2,47 @@
 mod tests {
     use anyhow::Result;
     use reqwest::Client;
+    use sha2::{Digest, Sha512};
     use signingcommon::{
-        ErrorResponse, ForgetRequest, ForgetResponse, RegisterRequest, RegisterResponse,
-        SignRequest, SignResponse,
+        ApprovalPolicy, ApproveRequest, ErrorResponse, ForgetRequest, ForgetResponse,
+        IdentityResponse, KeysResponse, PendingQuery, PendingSignature, RegisterRequest,
+        RegisterResponse, RequestEnvelope, RevocationList, RevocationStatement, RevokeRequest,
+        RotateRequest, RotateResponse, SignRequest, SignResponse, StreamSignParams, request_key,
+        request_mac, request_mac_message,
     };
+    use std::collections::HashMap;
+    use std::path::PathBuf;
     use std::process::{Child, Command, Stdio};
+    use std::sync::Mutex;
     use std::time::Duration;
     use tokio::time::sleep;
 
     struct TestServer {
         process: Child,
         port: u16,
+        secret_file: PathBuf,
         client: Client,
+        // Seeds of the users registered through `register`, by user ID
+        seeds: Mutex<HashMap<String, String>>,
+    }
+
+    // A fresh replay protection envelope, without its MAC
+    fn envelope() -> RequestEnvelope {
+        RequestEnvelope {
+            nonce: hex::encode(rand::random::<[u8; 16]>()),
+            expires_at: signingcommon::unix_time() + 60,
+            mac: String::new(),
+        }
+    }
+
+    // Put a request to `/sign` in a fresh envelope authenticated with `seed`
+    fn seal(request: &mut SignRequest, seed: &str) {
+        request.envelope = Some(envelope());
+        let mac = request_mac(
+            &request_key(seed.as_bytes()),
+            &request_mac_message("/sign", request),
+        );
+        request.envelope.as_mut().unwrap().mac = hex::encode(mac);
     }
 
     impl TestServer {
@@ -21,9 +50,14 @@ mod tests {
             // Use a random port to avoid conflicts
             let port = 3443; // For now, using the default port
 
-            // Start the server process
+            // Start the server process, with a secret of its own
+            let secret_file = std::env::temp_dir().join(format!(
+                "signingserver-test-{}.secret",
+                hex::encode(rand::random::<[u8; 8]>())
+            ));
             let mut process = Command::new("cargo")
-                .args(&["run", "--bin", "signingserver"])
+                .args(["run", "--bin", "signingserver", "--", "--secret-file"])
+                .arg(&secret_file)
                 .current_dir("..")
                 .stdout(Stdio::null())
                 .stderr(Stdio::null())
@@ -38,18 +72,26 @@ mod tests {
             // Wait for the server to be ready
             let server_url = format!("https://127.0.0.1:{}", port);
             for _ in 0..30 {
-                if let Ok(_) = client.get(format!("{}/health", server_url)).send().await {
+                if client
+                    .get(format!("{}/health", server_url))
+                    .send()
+                    .await
+                    .is_ok()
+                {
                     println!("Server is ready on port {}", port);
                     return Ok(TestServer {
                         process,
                         port,
+                        secret_file,
                         client,
+                        seeds: Mutex::default(),
                     });
                 }
                 sleep(Duration::from_millis(100)).await;
             }
             // If we get here, server didn't start
             process.kill()?;
+            let _ = std::fs::remove_file(&secret_file);
             anyhow::bail!("Server failed to start within 3 seconds")
         }
 
@@ -63,26 +105,83 @@ mod tests {
                 .post(format!("{}/register", self.url()))
                 .json(&RegisterRequest {
                     seed: seed.as_bytes().to_vec(),
+                    ..Default::default()
                 })
                 .send()
                 .await?;
 
             if response.status().is_success() {
-                Ok(response.json().await?)
+                let reg: RegisterResponse = response.json().await?;
+                self.seeds
+                    .lock()
+                    .unwrap()
+                    .insert(reg.user_id.clone(), seed.to_string());
+                Ok(reg)
             } else {
                 let err: ErrorResponse = response.json().await?;
                 anyhow::bail!("Registration failed: {}", err.error)
             }
         }
 
+        // The seed `user_id` was registered with, if it was registered through `register`
+        fn seed(&self, user_id: &str) -> Option<String> {
+            self.seeds.lock().unwrap().get(user_id).cloned()
+        }
+
+        // Sign in an envelope if the user's seed is known, and without one otherwise
         async fn sign(&self, user_id: &str, message: &str) -> Result<SignResponse> {
+            let mut request = SignRequest {
+                user_id: user_id.to_string(),
+                message: message.to_string(),
+                context: None,
+                envelope: None,
+                format: signingcommon::SignFormat::Raw,
+                trusted_comment: None,
+            };
+            if let Some(seed) = self.seed(user_id) {
+                seal(&mut request, &seed);
+            }
             let response = self
                 .client
                 .post(format!("{}/sign", self.url()))
-                .json(&SignRequest {
-                    user_id: user_id.to_string(),
-                    message: message.to_string(),
-                })
+                .json(&request)
+                .send()
+                .await?;
+
+            if response.status().is_success() {
+                Ok(response.json().await?)
+            } else {
+                let err: ErrorResponse = response.json().await?;
+                anyhow::bail!("Signing failed: {}", err.error)
+            }
+        }
+
+        async fn sign_stream(
+            &self,
+            user_id: &str,
+            message: Vec<u8>,
+            context: Option<&str>,
+        ) -> Result<SignResponse> {
+            let mut params = StreamSignParams {
+                user_id: user_id.to_string(),
+                context: context.map(str::to_string),
+                ..Default::default()
+            };
+            if let Some(seed) = self.seed(user_id) {
+                let envelope = envelope();
+                params.nonce = Some(envelope.nonce);
+                params.expires_at = Some(envelope.expires_at);
+                let mac = request_mac(
+                    &request_key(seed.as_bytes()),
+                    &params.mac_message(&Sha512::digest(&message)),
+                );
+                params.mac = Some(hex::encode(mac));
+            }
+            let response = self
+                .client
+                .post(format!("{}/sign/stream", self.url()))
+                .query(&params)
+                .body(message)
                 .send()
                 .await?;
 
@@ -127,6 +226,7 @@ mod tests {
         fn drop(&mut self) {
             // Kill the server process when the test ends
             let _ = self.process.kill();
+            let _ = std::fs::remove_file(&self.secret_file);
         }
     }
 
@@ -267,6 +367,401 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_idempotent_registration() -> Result<()> {
+        let server = TestServer::start().await?;
+
+        let request = RegisterRequest {
+            seed: b"idempotent-seed".to_vec(),
+            ..Default::default()
+        };
+        let key = hex::encode(rand::random::<[u8; 16]>());
+        let mut user_ids = Vec::new();
+        for _ in 0..2 {
+            let response = server
+                .client
+                .post(format!("{}/register", server.url()))
+                .header(signingcommon::IDEMPOTENCY_KEY_HEADER, &key)
+                .json(&request)
+                .send()
+                .await?;
+            assert_eq!(response.status(), reqwest::StatusCode::CREATED);
+            let reg: RegisterResponse = response.json().await?;
+            user_ids.push(reg.user_id);
+        }
+
+        // The retry returned the original registration
+        assert_eq!(user_ids[0], user_ids[1]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_deterministic_registration() -> Result<()> {
+        let server = TestServer::start().await?;
+
+        let request = RegisterRequest {
+            seed: b"deterministic-seed".to_vec(),
+            deterministic_id: true,
+            ..Default::default()
+        };
+        let url = format!("{}/register", server.url());
+        let first = server.client.post(&url).json(&request).send().await?;
+        let first: RegisterResponse = first.json().await?;
+        let second = server.client.post(&url).json(&request).send().await?;
+        assert_eq!(second.status(), reqwest::StatusCode::OK);
+        let second: RegisterResponse = second.json().await?;
+
+        assert_eq!(first.user_id, second.user_id);
+        assert_eq!(first.verifying_key, second.verifying_key);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_key_rotation() -> Result<()> {
+        use ed25519_dalek::{Signature, VerifyingKey};
+
+        let server = TestServer::start().await?;
+        let reg = server.register("rotation-test").await?;
+        let parse_key = |key: &str| -> Result<VerifyingKey> {
+            Ok(VerifyingKey::from_bytes(
+                &hex::decode(key)?
+                    .try_into()
+                    .map_err(|_| anyhow::anyhow!("bad key length"))?,
+            )?)
+        };
+
+        let response = server
+            .client
+            .post(format!("{}/rotate", server.url()))
+            .json(&RotateRequest {
+                user_id: reg.user_id.clone(),
+                seed: b"rotation-test".to_vec(),
+            })
+            .send()
+            .await?;
+        assert!(response.status().is_success());
+        let rotation: RotateResponse = response.json().await?;
+        assert_eq!(
+            rotation.certificate.previous_verifying_key,
+            reg.verifying_key
+        );
+
+        // The certificate is signed by the old key...
+        let old_key = parse_key(&reg.verifying_key)?;
+        let signature = Signature::from_slice(&hex::decode(&rotation.certificate.signature)?)?;
+        old_key.verify_strict(&rotation.certificate.statement(), &signature)?;
+
+        // ...and new signatures are made by the new key.
+        let new_key = parse_key(&rotation.verifying_key)?;
+        let sig = server.sign(&reg.user_id, "after rotation").await?;
+        let signature = Signature::from_slice(&hex::decode(&sig.signature)?)?;
+        new_key.verify_strict(b"after rotation", &signature)?;
+
+        let keys: KeysResponse = server
+            .client
+            .get(format!("{}/users/{}/keys", server.url(), reg.user_id))
+            .send()
+            .await?
+            .json()
+            .await?;
+        assert_eq!(keys.keys.len(), 2);
+        assert_eq!(keys.keys[0].verifying_key, rotation.verifying_key);
+        assert_eq!(keys.keys[1].verifying_key, reg.verifying_key);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_approval_quorum() -> Result<()> {
+        use ed25519_dalek::{Signature, VerifyingKey};
+
+        let server = TestServer::start().await?;
+        let first = server.register("approver-1").await?;
+        let second = server.register("approver-2").await?;
+        let treasury: RegisterResponse = server
+            .client
+            .post(format!("{}/register", server.url()))
+            .json(&RegisterRequest {
+                seed: b"treasury".to_vec(),
+                approval: Some(ApprovalPolicy {
+                    threshold: 2,
+                    approvers: vec![first.user_id.clone(), second.user_id.clone()],
+                }),
+                ..Default::default()
+            })
+            .send()
+            .await?
+            .json()
+            .await?;
+
+        // Signing only records the request.
+        let mut sign_request = SignRequest {
+            user_id: treasury.user_id.clone(),
+            message: "pay 100".to_string(),
+            context: None,
+            envelope: None,
+            format: signingcommon::SignFormat::Raw,
+            trusted_comment: None,
+        };
+        seal(&mut sign_request, "treasury");
+        let response = server
+            .client
+            .post(format!("{}/sign", server.url()))
+            .json(&sign_request)
+            .send()
+            .await?;
+        assert_eq!(response.status(), reqwest::StatusCode::ACCEPTED);
+        let request: PendingSignature = response.json().await?;
+        assert!(request.signature.is_none());
+
+        // Approvals cannot be signed through `/sign`, so knowing the approvers' IDs does not let
+        // the requester approve on their behalf.
+        assert!(
+            server
+                .sign(&first.user_id, std::str::from_utf8(&request.statement())?)
+                .await
+                .is_err()
+        );
+        let approve = |approver: &RegisterResponse, seed: &str| {
+            server
+                .client
+                .post(format!("{}/approve/{}", server.url(), request.request_id))
+                .json(&ApproveRequest {
+                    approver_id: approver.user_id.clone(),
+                    seed: seed.as_bytes().to_vec(),
+                })
+                .send()
+        };
+        let response = approve(&first, "treasury").await?;
+        assert_eq!(response.status(), reqwest::StatusCode::FORBIDDEN);
+
+        let mut approved = None;
+        for (approver, seed) in [(&first, "approver-1"), (&second, "approver-2")] {
+            let response = approve(approver, seed).await?;
+            assert!(response.status().is_success());
+            approved = Some(response.json::<PendingSignature>().await?);
+        }
+
+        let signature = approved
+            .and_then(|request| request.signature)
+            .ok_or_else(|| anyhow::anyhow!("no signature after quorum"))?;
+        let treasury_key = VerifyingKey::from_bytes(
+            &hex::decode(&treasury.verifying_key)?
+                .try_into()
+                .map_err(|_| anyhow::anyhow!("bad key length"))?,
+        )?;
+        treasury_key.verify_strict(
+            b"pay 100",
+            &Signature::from_slice(&hex::decode(&signature)?)?,
+        )?;
+
+        // The requester can fetch the signature, but knowing the request ID is not enough.
+        let path = format!("/pending/{}", request.request_id);
+        let mut query = PendingQuery {
+            user_id: treasury.user_id.clone(),
+            nonce: envelope().nonce,
+            expires_at: signingcommon::unix_time() + 60,
+            mac: String::new(),
+        };
+        let response = server
+            .client
+            .get(format!("{}{}", server.url(), path))
+            .query(&query)
+            .send()
+            .await?;
+        assert_eq!(response.status(), reqwest::StatusCode::FORBIDDEN);
+
+        query.mac = hex::encode(request_mac(
+            &request_key(b"treasury"),
+            &query.mac_message(&path),
+        ));
+        let fetched: PendingSignature = server
+            .client
+            .get(format!("{}{}", server.url(), path))
+            .query(&query)
+            .send()
+            .await?
+            .json()
+            .await?;
+        assert_eq!(fetched.signature, Some(signature));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_revocation() -> Result<()> {
+        use ed25519_dalek::{Signature, VerifyingKey};
+
+        let server = TestServer::start().await?;
+        let reg = server.register("revocation-test").await?;
+
+        let identity: IdentityResponse = server
+            .client
+            .get(format!("{}/identity", server.url()))
+            .send()
+            .await?
+            .json()
+            .await?;
+        let server_key = VerifyingKey::from_bytes(
+            &hex::decode(&identity.verifying_key)?
+                .try_into()
+                .map_err(|_| anyhow::anyhow!("bad key length"))?,
+        )?;
+
+        let response = server
+            .client
+            .post(format!("{}/revoke", server.url()))
+            .json(&RevokeRequest {
+                user_id: reg.user_id.clone(),
+                seed: b"revocation-test".to_vec(),
+            })
+            .send()
+            .await?;
+        assert!(response.status().is_success());
+        let statement: RevocationStatement = response.json().await?;
+        assert_eq!(statement.verifying_keys, vec![reg.verifying_key.clone()]);
+        let signature = Signature::from_slice(&hex::decode(&statement.signature)?)?;
+        server_key.verify_strict(&statement.statement(), &signature)?;
+
+        let list: RevocationList = server
+            .client
+            .get(format!("{}/revocations", server.url()))
+            .send()
+            .await?
+            .json()
+            .await?;
+        let signature = Signature::from_slice(&hex::decode(&list.signature)?)?;
+        server_key.verify_strict(&list.statement(), &signature)?;
+        assert!(list.find(&reg.verifying_key).is_some());
+
+        // The revoked user cannot sign, and the seed cannot be registered again.
+        assert!(server.sign(&reg.user_id, "hello").await.is_err());
+        assert!(server.register("revocation-test").await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_responses_are_signed() -> Result<()> {
+        use ed25519_dalek::{Signature, VerifyingKey};
+        use signingcommon::{
+            REQUEST_NONCE_HEADER, RESPONSE_SIGNATURE_HEADER, response_signature_message,
+        };
+
+        let server = TestServer::start().await?;
+        let identity: IdentityResponse = server
+            .client
+            .get(format!("{}/identity", server.url()))
+            .send()
+            .await?
+            .json()
+            .await?;
+        let server_key = VerifyingKey::from_bytes(
+            &hex::decode(&identity.verifying_key)?
+                .try_into()
+                .map_err(|_| anyhow::anyhow!("bad key length"))?,
+        )?;
+
+        let response = server
+            .client
+            .post(format!("{}/register", server.url()))
+            .header(REQUEST_NONCE_HEADER, "register-nonce")
+            .json(&RegisterRequest {
+                seed: b"signed-response-test".to_vec(),
+                ..Default::default()
+            })
+            .send()
+            .await?;
+        let status = response.status().as_u16();
+        let signature = response.headers()[RESPONSE_SIGNATURE_HEADER].to_str()?;
+        let signature = Signature::from_slice(&hex::decode(signature)?)?;
+        let body = response.bytes().await?;
+        server_key.verify_strict(
+            &response_signature_message("/register", "register-nonce", status, &body),
+            &signature,
+        )?;
+        assert!(
+            server_key
+                .verify_strict(
+                    &response_signature_message("/register", "other-nonce", status, &body),
+                    &signature,
+                )
+                .is_err()
+        );
+        let reg: RegisterResponse = serde_json::from_slice(&body)?;
+        assert!(!reg.user_id.is_empty());
+
+        let response = server
+            .client
+            .delete(format!("{}/forget", server.url()))
+            .json(&ForgetRequest {
+                user_id: reg.user_id,
+            })
+            .send()
+            .await?;
+        let status = response.status().as_u16();
+        let signature = response.headers()[RESPONSE_SIGNATURE_HEADER].to_str()?;
+        let signature = Signature::from_slice(&hex::decode(signature)?)?;
+        let body = response.bytes().await?;
+        server_key.verify_strict(
+            &response_signature_message("/forget", "", status, &body),
+            &signature,
+        )?;
+        let forgotten: ForgetResponse = serde_json::from_slice(&body)?;
+        assert_eq!(forgotten.message, "User successfully forgotten");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_attestation() -> Result<()> {
+        use ed25519_dalek::{Signature, VerifyingKey};
+        use signingcommon::{AttestationResponse, attestation_report_data};
+
+        let server = TestServer::start().await?;
+        let nonce = [42u8; 32];
+        let attestation: AttestationResponse = server
+            .client
+            .get(format!(
+                "{}/attestation?nonce={}",
+                server.url(),
+                hex::encode(nonce)
+            ))
+            .send()
+            .await?
+            .json()
+            .await?;
+
+        let quote = &attestation.quote;
+        assert_eq!(quote.provider, "mock");
+        let attestation_key = VerifyingKey::from_bytes(
+            &hex::decode(&quote.attestation_key)?
+                .try_into()
+                .map_err(|_| anyhow::anyhow!("bad key length"))?,
+        )?;
+        let signature = Signature::from_slice(&hex::decode(&quote.signature)?)?;
+        attestation_key.verify_strict(&quote.statement(), &signature)?;
+
+        let report_data = attestation_report_data(
+            &hex::decode(&attestation.tls_certificate_sha256)?,
+            &hex::decode(&attestation.server_key)?,
+            &nonce,
+        );
+        assert_eq!(quote.report_data, hex::encode(report_data));
+
+        // A nonce of the wrong length is rejected.
+        let response = server
+            .client
+            .get(format!("{}/attestation?nonce=abcd", server.url()))
+            .send()
+            .await?;
+        assert_eq!(response.status(), reqwest::StatusCode::BAD_REQUEST);
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_empty_message_signing() -> Result<()> {
         let server = TestServer::start().await?;
@@ -298,6 +793,104 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_streaming_large_message_signing() -> Result<()> {
+        use ed25519_dalek::{Signature, VerifyingKey};
+        use sha2::{Digest, Sha512};
+
+        let server = TestServer::start().await?;
+
+        let reg = server.register("stream-msg-test").await?;
+        let verifying_key = VerifyingKey::from_bytes(
+            &hex::decode(&reg.verifying_key)?
+                .try_into()
+                .map_err(|_| anyhow::anyhow!("bad key length"))?,
+        )?;
+
+        // Larger than any default body limit.
+        let large_message = vec![b'A'; 16_000_000];
+        let sig = server
+            .sign_stream(&reg.user_id, large_message.clone(), Some("test-context"))
+            .await?;
+        let signature = Signature::from_slice(&hex::decode(&sig.signature)?)?;
+
+        let prehashed = Sha512::new().chain_update(&large_message);
+        verifying_key.verify_prehashed(prehashed, Some(b"test-context"), &signature)?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_replayed_request_is_rejected() -> Result<()> {
+        let server = TestServer::start().await?;
+
+        let reg = server.register("replay-test").await?;
+        let mut request = SignRequest {
+            user_id: reg.user_id.clone(),
+            message: "pay alice 10".to_string(),
+            context: None,
+            envelope: None,
+            format: signingcommon::SignFormat::Raw,
+            trusted_comment: None,
+        };
+        seal(&mut request, "replay-test");
+
+        let url = format!("{}/sign", server.url());
+        let first = server.client.post(&url).json(&request).send().await?;
+        assert!(first.status().is_success());
+
+        let replayed = server.client.post(&url).json(&request).send().await?;
+        assert_eq!(replayed.status(), reqwest::StatusCode::CONFLICT);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_unauthenticated_request_is_rejected() -> Result<()> {
+        let server = TestServer::start().await?;
+
+        let reg = server.register("unauthenticated-test").await?;
+        let url = format!("{}/sign", server.url());
+        let mut request = SignRequest {
+            user_id: reg.user_id.clone(),
+            message: "pay alice 10".to_string(),
+            context: None,
+            envelope: None,
+            format: signingcommon::SignFormat::Raw,
+            trusted_comment: None,
+        };
+
+        // Knowing the user ID is not enough: the envelope cannot be dropped...
+        let response = server.client.post(&url).json(&request).send().await?;
+        assert_eq!(response.status(), reqwest::StatusCode::BAD_REQUEST);
+
+        // ...nor made up without the seed...
+        request.envelope = Some(envelope());
+        let response = server.client.post(&url).json(&request).send().await?;
+        assert_eq!(response.status(), reqwest::StatusCode::FORBIDDEN);
+
+        // ...nor taken from a captured request to sign another message.
+        seal(&mut request, "unauthenticated-test");
+        request.message = "pay mallory 10".to_string();
+        let response = server.client.post(&url).json(&request).send().await?;
+        assert_eq!(response.status(), reqwest::StatusCode::FORBIDDEN);
+
+        // The other sign endpoints need an envelope too.
+        let response = server
+            .client
+            .post(format!("{}/sign/stream", server.url()))
+            .query(&StreamSignParams {
+                user_id: reg.user_id.clone(),
+                ..Default::default()
+            })
+            .body("pay mallory 10")
+            .send()
+            .await?;
+        assert_eq!(response.status(), reqwest::StatusCode::BAD_REQUEST);
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_forget_nonexistent_user() -> Result<()> {
         let server = TestServer::start().await?;
@@ -1,11 +1,42 @@
 use anyhow::Result;
+use base64::prelude::{BASE64_STANDARD, Engine};
+use blake2::Blake2b512;
 use clap::{Parser, Subcommand};
+use ed25519_dalek::{Signature, VerifyingKey};
+use rand::RngCore;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use sha2::{Digest, Sha256, Sha512};
 use signingcommon::{
-    ErrorResponse, ForgetRequest, ForgetResponse, RegisterRequest, RegisterResponse, SignRequest,
-    SignResponse,
+    ATTESTATION_NONCE_LEN, AdminResponse, AdminStatsResponse, AdminUsersResponse, ApprovalPolicy,
+    ApproveRequest, AttestationParams, AttestationPolicy, AttestationResponse, CoseSign1,
+    CosmosSignMode, CosmosSignRequest, CosmosSignResponse, CredentialSignRequest,
+    DEFAULT_STREAM_CONTEXT, DidDocument, ErrorResponse, ForgetRequest, ForgetResponse,
+    IDEMPOTENCY_KEY_HEADER, IdentityResponse, IdentityRotation, JwtSignRequest, JwtSignResponse,
+    NostrEvent, NostrSignRequest, PendingQuery, PendingResponse, PendingSignature, PsbtSignRequest,
+    PsbtSignResponse, REQUEST_NONCE_HEADER, RESPONSE_SIGNATURE_HEADER, ReadOnlyRequest,
+    RegisterRequest, RegisterResponse, RenewRequest, RenewResponse, RequestEnvelope,
+    RevocationList, RevocationStatement, RevokeRequest, RotateRequest, RotateResponse,
+    RotationCertificate, SignFormat, SignRequest, SignResponse, SolanaSignRequest,
+    SolanaSignResponse, SshSignRequest, SshSignResponse, SshSignature, StreamSignParams,
+    attestation_report_data, context_envelope, cose_key_thumbprint, parse_allowed_signers,
+    parse_cose_keys, parse_cwt_claims, request_key, request_mac, request_mac_message,
+    response_signature_message, ssh_public_key, unix_time,
 };
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio_util::io::ReaderStream;
 use tracing::{error, info};
 
+/// How long a sign request stays valid, in seconds
+const REQUEST_VALIDITY: u64 = 60;
+/// How long to wait for a registration before retrying
+const REGISTER_TIMEOUT: Duration = Duration::from_secs(10);
+/// How often to try registering before giving up
+const REGISTER_ATTEMPTS: u32 = 3;
+/// The magic bytes binary PSBTs start with
+const PSBT_MAGIC: &[u8] = b"psbt\xff";
+
 #[derive(Parser, Debug)]
 #[command(name = "sign")]
 #[command(about = "Sign messages using the remote signing service")]
@@ -14,17 +45,73 @@ struct Args {
     command: Option<Commands>,
 
     /// The message to sign (used when no subcommand is given)
-    #[arg(short, long, requires = "user_id")]
+    #[arg(short, long, group = "input", requires = "user_id")]
     message: Option<String>,
 
+    /// A file to sign instead of a message. The file is streamed to the server and signed with
+    /// Ed25519ph, so it can be arbitrarily large
+    #[arg(short, long, group = "input", requires = "user_id")]
+    file: Option<PathBuf>,
+
+    /// Sign the inputs of this Bitcoin PSBT file (binary or base64) that spend the user's keys. The
+    /// updated PSBT is printed in base64
+    #[arg(long, group = "input", requires = "user_id")]
+    psbt: Option<PathBuf>,
+
+    /// Sign under this context. Messages are wrapped in a domain separation envelope, files are
+    /// signed with Ed25519ph under this context (defaults to "wallet-poc/stream" for files)
+    #[arg(short, long, requires = "input")]
+    context: Option<String>,
+
+    /// Print a COSE_Sign1 (base64) with the message as its payload instead of the bare signature.
+    /// The context, if any, is its external AAD
+    #[arg(long, requires = "message", conflicts_with = "cwt")]
+    cose: bool,
+
+    /// Issue a CWT (base64) with the claims in the message, a JSON object. Registered claims
+    /// (iss, sub, aud, exp, nbf, iat, cti) get their integer keys
+    #[arg(long, requires = "message")]
+    cwt: bool,
+
+    /// Make a minisign signature. A file is hashed with BLAKE2b-512 and signed to
+    /// `<file>.minisig`; for a message the signature file is printed
+    #[arg(long, requires = "input", conflicts_with_all = ["cose", "cwt", "signify", "psbt", "context"])]
+    minisign: bool,
+
+    /// Trusted comment of the minisign signature (defaults to the time and the file name)
+    #[arg(long, requires = "minisign")]
+    trusted_comment: Option<String>,
+
+    /// Make an OpenBSD signify signature. A file is sent to the server whole and signed to
+    /// `<file>.sig`; for a message the signature file is printed
+    #[arg(long, requires = "input", conflicts_with_all = ["cose", "cwt", "psbt", "context"])]
+    signify: bool,
+
     /// The user ID for signing (used when no subcommand is given)
-    #[arg(short, long, requires = "message")]
+    #[arg(short, long, requires = "input")]
     user_id: Option<String>,
 
+    /// Seed string the user registered with. The request is then sent in a replay protection
+    /// envelope authenticated with it, which the server requires unless started with
+    /// --allow-requests-without-envelope
+    #[arg(long, requires = "input")]
+    seed: Option<String>,
+
     /// The server URL
     #[arg(short, long, default_value = "https://127.0.0.1:3443", global = true)]
     server: String,
 
+    /// Hex encoded identity key of the server. When given, every response must be signed by this
+    /// key. The server logs its identity key on startup
+    #[arg(long, global = true)]
+    server_key: Option<String>,
+
+    /// Refuse to talk to the server unless it attests to running a binary allowed by this policy
+    /// file (JSON, see `sign attest`). The attested identity key is then pinned as if given with
+    /// --server-key
+    #[arg(long, global = true)]
+    attestation_policy: Option<PathBuf>,
+
     /// Accept self-signed certificates (for development)
     #[arg(long, default_value_t = true, global = true)]
     danger_accept_invalid_certs: bool,
@@ -36,6 +123,257 @@ enum Commands {
     Register {
         /// Seed string for key generation
         seed: String,
+
+        /// Only allow the key to sign under this context (may be repeated)
+        #[arg(long = "allow-context")]
+        allowed_contexts: Vec<String>,
+
+        /// Derive the user ID from the key, so that registering the same seed again returns the
+        /// same ID
+        #[arg(long)]
+        deterministic: bool,
+
+        /// Have the server forget the key after this many seconds, unless renewed
+        #[arg(long)]
+        ttl: Option<u64>,
+
+        /// Require approval by this user for signatures (may be repeated)
+        #[arg(long = "approver")]
+        approvers: Vec<String>,
+
+        /// How many approvers must approve each signature (defaults to all of them)
+        #[arg(long, requires = "approvers")]
+        threshold: Option<usize>,
+    },
+    /// Approve a pending signature as one of its approvers
+    Approve {
+        /// ID of the pending request
+        request_id: String,
+
+        /// User ID of the approver, whose key signs the approval
+        #[arg(short, long)]
+        user_id: String,
+
+        /// Seed string the approver registered with, which proves they are the approver
+        #[arg(long)]
+        seed: String,
+    },
+    /// List pending signatures for a user, or awaiting their approval
+    Pending {
+        #[arg(short, long)]
+        user_id: String,
+
+        /// Seed string the user registered with, which proves they may read the requests
+        #[arg(long)]
+        seed: String,
+    },
+    /// Extend the lifetime of a user registered with a TTL
+    Renew {
+        /// Seed string the key was registered with
+        seed: String,
+
+        /// User ID to renew
+        #[arg(short, long)]
+        user_id: String,
+
+        /// New lifetime in seconds, counted from now
+        #[arg(long)]
+        ttl: u64,
+    },
+    /// Rotate to a new signing key under the same user ID. The old key stops signing and the
+    /// server returns a certificate, signed by the old key, binding the new one
+    Rotate {
+        /// Seed string the key was registered with
+        seed: String,
+
+        /// User ID to rotate
+        #[arg(short, long)]
+        user_id: String,
+    },
+    /// Revoke all keys of a user whose seed was compromised. The server forgets the user and
+    /// publishes the keys in its signed revocation list
+    Revoke {
+        /// Seed string the key was registered with
+        seed: String,
+
+        /// User ID to revoke
+        #[arg(short, long)]
+        user_id: String,
+    },
+    /// Sign a serialized Solana transaction message (legacy or v0). The server shows what it
+    /// signed and returns a base58 signature
+    Solana {
+        #[arg(short, long)]
+        user_id: String,
+
+        /// Seed string the user registered with, which authenticates the request
+        #[arg(long)]
+        seed: Option<String>,
+
+        /// The message, base64 encoded as produced by Solana tooling
+        #[arg(required_unless_present = "file", conflicts_with = "file")]
+        message: Option<String>,
+
+        /// A file holding the raw serialized message
+        #[arg(short, long)]
+        file: Option<PathBuf>,
+    },
+    /// Make an SSH signature, as `ssh-keygen -Y sign` does. A file is signed to `<file>.sig`,
+    /// standard input to standard output
+    Ssh {
+        #[arg(short, long)]
+        user_id: String,
+
+        /// Seed string the user registered with, which authenticates the request
+        #[arg(long)]
+        seed: Option<String>,
+
+        /// Namespace of the signature, such as "git" or "file"
+        #[arg(short, long)]
+        namespace: String,
+
+        /// The file to sign
+        file: Option<PathBuf>,
+    },
+    /// Issue a JWT signed with EdDSA, with the claims in a JSON file or standard input
+    Jwt {
+        #[arg(short, long)]
+        user_id: String,
+
+        /// Seed string the user registered with, which authenticates the request
+        #[arg(long)]
+        seed: Option<String>,
+
+        /// JSON object of the claims
+        claims: Option<PathBuf>,
+    },
+    /// Sign a Nostr event (NIP-01) with the user's secp256k1 key and print the signed event. The
+    /// unsigned event JSON is read from a file or standard input
+    Nostr {
+        #[arg(short, long)]
+        user_id: String,
+
+        /// Seed string the user registered with, which authenticates the request
+        #[arg(long)]
+        seed: Option<String>,
+
+        /// The unsigned event: created_at, kind, tags and content
+        event: Option<PathBuf>,
+    },
+    /// Add a Data Integrity proof (eddsa-jcs-2022) to a verifiable credential and print the
+    /// secured credential. The credential JSON is read from a file or standard input
+    Credential {
+        #[arg(short, long)]
+        user_id: String,
+
+        /// Seed string the user registered with, which authenticates the request
+        #[arg(long)]
+        seed: Option<String>,
+
+        /// The unsecured credential, whose issuer should be the user's DID
+        credential: Option<PathBuf>,
+    },
+    /// Sign a Cosmos SDK transaction with the user's secp256k1 key and print the base64 signature
+    Cosmos {
+        #[arg(short, long)]
+        user_id: String,
+
+        /// Seed string the user registered with, which authenticates the request
+        #[arg(long)]
+        seed: Option<String>,
+
+        /// The sign doc: an Amino JSON `StdSignDoc`, or with --direct a serialized Protobuf
+        /// `SignDoc`
+        file: PathBuf,
+
+        /// Sign in direct mode (SIGN_MODE_DIRECT) rather than Amino JSON
+        #[arg(long)]
+        direct: bool,
+    },
+    /// Verify a signature, checking the key against the server's revocation list
+    Verify {
+        /// Hex encoded verifying key of the signer
+        #[arg(short, long, required_unless_present_any = ["allowed_signers", "cose_key"])]
+        key: Option<String>,
+
+        /// Hex encoded signature, or with --allowed-signers the file holding the SSH signature
+        #[arg(long, required_unless_present = "cose")]
+        signature: Option<String>,
+
+        /// The signed message
+        #[arg(short, long, required_unless_present_any = ["file", "cose"], conflicts_with = "file")]
+        message: Option<String>,
+
+        /// The signed file
+        #[arg(short, long)]
+        file: Option<PathBuf>,
+
+        /// The context the message or file was signed under
+        #[arg(short, long, conflicts_with = "allowed_signers")]
+        context: Option<String>,
+
+        /// Verify an SSH signature against an OpenSSH allowed signers file instead of a key
+        #[arg(long, conflicts_with = "key", requires = "namespace")]
+        allowed_signers: Option<PathBuf>,
+
+        /// The principal the SSH signature must be from. Without it, the principals whose keys
+        /// made the signature are printed
+        #[arg(short = 'I', long, requires = "allowed_signers")]
+        identity: Option<String>,
+
+        /// The namespace the SSH signature must be in
+        #[arg(short, long, requires = "allowed_signers")]
+        namespace: Option<String>,
+
+        /// Verify this COSE_Sign1 or CWT file (binary or base64) instead of a signature. With a
+        /// message, the payload must be the message. The claims of CWTs are printed, and they
+        /// must be valid now
+        #[arg(long, conflicts_with_all = ["signature", "file", "allowed_signers"])]
+        cose: Option<PathBuf>,
+
+        /// COSE_Key or COSE_KeySet file (see `sign cose-key`) holding the signer's key, which is
+        /// picked by the `kid` of the COSE_Sign1
+        #[arg(long, requires = "cose", conflicts_with = "key")]
+        cose_key: Option<PathBuf>,
+    },
+    /// Export the verifying keys of a user as a COSE_KeySet, for verifiers of COSE signatures and
+    /// CWTs. During a rotation's grace period it also has the previous key
+    CoseKey {
+        #[arg(short, long)]
+        user_id: String,
+
+        /// File to write the COSE_KeySet (CBOR) to
+        #[arg(short, long)]
+        output: PathBuf,
+    },
+    /// Export a user's current key as a minisign public key file
+    PublicKey {
+        #[arg(short, long)]
+        user_id: String,
+
+        /// Export a signify public key file instead
+        #[arg(long)]
+        signify: bool,
+
+        /// File to write the key to, rather than standard output
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Print the DID document of a user's `did:key`
+    Did {
+        #[arg(short, long)]
+        user_id: String,
+    },
+    /// Fetch an attestation from the server and print a policy trusting the binary it runs
+    Attest,
+    /// Operate the server through its admin API
+    Admin {
+        /// File holding the admin token
+        #[arg(long)]
+        token_file: PathBuf,
+
+        #[command(subcommand)]
+        command: AdminCommands,
     },
     /// Forget a user (delete their signing key)
     Forget {
@@ -45,6 +383,43 @@ enum Commands {
     },
 }
 
+#[derive(Subcommand, Debug)]
+enum AdminCommands {
+    /// List users: their IDs, key types and creation times
+    Users,
+    /// Show capacity statistics
+    Stats,
+    /// Forget a user, even in read-only mode
+    Forget {
+        #[arg(short, long)]
+        user_id: String,
+    },
+    /// Suspend signing for a user, keeping their key
+    Freeze {
+        #[arg(short, long)]
+        user_id: String,
+    },
+    /// Resume signing for a frozen user
+    Unfreeze {
+        #[arg(short, long)]
+        user_id: String,
+    },
+    /// Let a user pending approval sign
+    Approve {
+        #[arg(short, long)]
+        user_id: String,
+    },
+    /// Stop users from being registered, renewed, rotated or forgotten. Signing is unaffected
+    ReadOnly {
+        /// Leave read-only mode instead
+        #[arg(long)]
+        off: bool,
+    },
+    /// Rotate the server identity key. The server must be restarted with the new generation to
+    /// keep using the new key
+    RotateIdentity,
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     tracing_subscriber::fmt()
@@ -52,62 +427,586 @@ async fn main() -> Result<()> {
         .init();
 
     let args = Args::parse();
+    let mut server_key = args
+        .server_key
+        .as_deref()
+        .map(parse_verifying_key)
+        .transpose()?;
 
-    // Build client with TLS configuration
+    // Build client with TLS configuration. The server's certificate is needed to check
+    // attestations.
     let client = if args.danger_accept_invalid_certs {
         info!("Warning: Accepting self-signed certificates");
         reqwest::Client::builder()
             .danger_accept_invalid_certs(true)
+            .tls_info(true)
             .build()?
     } else {
-        reqwest::Client::new()
+        reqwest::Client::builder().tls_info(true).build()?
     };
 
+    if let Some(path) = &args.attestation_policy {
+        let policy: AttestationPolicy = serde_json::from_slice(&std::fs::read(path)?)?;
+        let attested_key = attest(&client, &args.server, &policy, server_key.as_ref()).await?;
+        server_key = Some(attested_key);
+    }
+
     match args.command {
-        Some(Commands::Register { seed }) => {
-            register_user(&client, &args.server, &seed).await?;
+        Some(Commands::Register {
+            seed,
+            allowed_contexts,
+            deterministic,
+            ttl,
+            approvers,
+            threshold,
+        }) => {
+            let approval = (!approvers.is_empty()).then(|| ApprovalPolicy {
+                threshold: threshold.unwrap_or(approvers.len()),
+                approvers,
+            });
+            let req = RegisterRequest {
+                seed: seed.into_bytes(),
+                allowed_contexts,
+                deterministic_id: deterministic,
+                ttl_secs: ttl,
+                approval,
+            };
+            register_user(&client, &args.server, server_key.as_ref(), &req).await?;
+        }
+        Some(Commands::Approve {
+            request_id,
+            user_id,
+            seed,
+        }) => {
+            approve(
+                &client,
+                &args.server,
+                server_key.as_ref(),
+                &request_id,
+                &ApproveRequest {
+                    approver_id: user_id,
+                    seed: seed.into_bytes(),
+                },
+            )
+            .await?;
+        }
+        Some(Commands::Pending { user_id, seed }) => {
+            list_pending(
+                &client,
+                &args.server,
+                server_key.as_ref(),
+                &user_id,
+                seed.as_bytes(),
+            )
+            .await?;
+        }
+        Some(Commands::Renew { seed, user_id, ttl }) => {
+            let req = RenewRequest {
+                user_id,
+                seed: seed.into_bytes(),
+                ttl_secs: ttl,
+            };
+            renew_user(&client, &args.server, server_key.as_ref(), &req).await?;
+        }
+        Some(Commands::Rotate { seed, user_id }) => {
+            let req = RotateRequest {
+                user_id,
+                seed: seed.into_bytes(),
+            };
+            rotate_user(&client, &args.server, server_key.as_ref(), &req).await?;
+        }
+        Some(Commands::Revoke { seed, user_id }) => {
+            let req = RevokeRequest {
+                user_id,
+                seed: seed.into_bytes(),
+            };
+            revoke_user(&client, &args.server, server_key.as_ref(), &req).await?;
+        }
+        Some(Commands::Verify {
+            key,
+            signature,
+            message,
+            file,
+            context,
+            allowed_signers,
+            identity,
+            namespace,
+            cose,
+            cose_key,
+        }) => {
+            if let Some(cose) = cose {
+                let key = match (key, cose_key) {
+                    (Some(key), _) => CoseVerifyingKey::Hex(key),
+                    (None, Some(path)) => CoseVerifyingKey::File(path),
+                    (None, None) => anyhow::bail!("Key (-k) or COSE key file required"),
+                };
+                verify_cose(
+                    &client,
+                    &args.server,
+                    server_key.as_ref(),
+                    &key,
+                    &cose,
+                    message.as_deref(),
+                    context.as_deref(),
+                )
+                .await?;
+                return Ok(());
+            }
+            let signature =
+                signature.ok_or_else(|| anyhow::anyhow!("Signature required (--signature)"))?;
+            let signed = match (message, file) {
+                (Some(message), _) => Signed::Message(message),
+                (None, Some(file)) => Signed::File(file),
+                (None, None) => anyhow::bail!("Message required (-m or -f flag)"),
+            };
+            match (key, allowed_signers, namespace) {
+                (_, Some(allowed_signers), Some(namespace)) => {
+                    verify_ssh(
+                        &client,
+                        &args.server,
+                        server_key.as_ref(),
+                        &allowed_signers,
+                        identity.as_deref(),
+                        &namespace,
+                        Path::new(&signature),
+                        &signed,
+                    )
+                    .await?
+                }
+                (Some(key), _, _) => {
+                    verify(
+                        &client,
+                        &args.server,
+                        server_key.as_ref(),
+                        &key,
+                        &signature,
+                        &signed,
+                        context.as_deref(),
+                    )
+                    .await?
+                }
+                _ => anyhow::bail!("Key (-k) or allowed signers file required"),
+            }
+        }
+        Some(Commands::CoseKey { user_id, output }) => {
+            let response = client
+                .get(format!("{}/users/{}/cose_key", args.server, user_id))
+                .send_with_nonce()
+                .await?;
+            if !response.status().is_success() {
+                let err: ErrorResponse = read_json(response, server_key.as_ref()).await?;
+                anyhow::bail!("Fetching the keys failed: {}", err.error);
+            }
+            let keys = read_body(response, server_key.as_ref()).await?;
+            let count = parse_cose_keys(&keys)?.len();
+            std::fs::write(&output, keys)?;
+            info!("{} key(s) written to {}", count, output.display());
+        }
+        Some(Commands::PublicKey {
+            user_id,
+            signify,
+            output,
+        }) => {
+            let file = if signify {
+                "signify.pub"
+            } else {
+                "minisign.pub"
+            };
+            let response = client
+                .get(format!("{}/users/{}/{}", args.server, user_id, file))
+                .send_with_nonce()
+                .await?;
+            if !response.status().is_success() {
+                let err: ErrorResponse = read_json(response, server_key.as_ref()).await?;
+                anyhow::bail!("Fetching the key failed: {}", err.error);
+            }
+            let key = String::from_utf8(read_body(response, server_key.as_ref()).await?)?;
+            match output {
+                Some(output) => {
+                    std::fs::write(&output, key)?;
+                    info!("Key written to {}", output.display());
+                }
+                None => print!("{key}"),
+            }
+        }
+        Some(Commands::Did { user_id }) => {
+            let response = client
+                .get(format!("{}/users/{}/did.json", args.server, user_id))
+                .send_with_nonce()
+                .await?;
+            if !response.status().is_success() {
+                let err: ErrorResponse = read_json(response, server_key.as_ref()).await?;
+                anyhow::bail!("Fetching the DID document failed: {}", err.error);
+            }
+            let document: DidDocument = read_json(response, server_key.as_ref()).await?;
+            info!("DID: {}", document.id);
+            println!("{}", serde_json::to_string_pretty(&document)?);
+        }
+        Some(Commands::Credential {
+            user_id,
+            seed,
+            credential,
+        }) => {
+            let credential = match credential {
+                Some(path) => std::fs::read_to_string(path)?,
+                None => std::io::read_to_string(std::io::stdin())?,
+            };
+            let mut req = CredentialSignRequest {
+                user_id,
+                credential: serde_json::from_str(&credential)
+                    .map_err(|e| anyhow::anyhow!("Credential must be a JSON object: {e}"))?,
+                envelope: None,
+            };
+            seal(
+                &mut req,
+                |req| &mut req.envelope,
+                "/sign/credential",
+                seed.as_deref(),
+            );
+            let credential =
+                sign_credential(&client, &args.server, server_key.as_ref(), &req).await?;
+            println!("{}", serde_json::to_string(&credential)?);
+        }
+        Some(Commands::Nostr {
+            user_id,
+            seed,
+            event,
+        }) => {
+            let event = match event {
+                Some(path) => std::fs::read_to_string(path)?,
+                None => std::io::read_to_string(std::io::stdin())?,
+            };
+            let mut req = NostrSignRequest {
+                user_id,
+                event: serde_json::from_str(&event)
+                    .map_err(|e| anyhow::anyhow!("Invalid event: {e}"))?,
+                envelope: None,
+            };
+            seal(
+                &mut req,
+                |req| &mut req.envelope,
+                "/sign/nostr",
+                seed.as_deref(),
+            );
+            let event = sign_nostr(&client, &args.server, server_key.as_ref(), &req).await?;
+            info!("Signed event {} by {}", event.id, event.pubkey);
+            println!("{}", serde_json::to_string(&event)?);
+        }
+        Some(Commands::Jwt {
+            user_id,
+            seed,
+            claims,
+        }) => {
+            let claims = match claims {
+                Some(path) => std::fs::read_to_string(path)?,
+                None => std::io::read_to_string(std::io::stdin())?,
+            };
+            let mut req = JwtSignRequest {
+                user_id,
+                claims: serde_json::from_str(&claims)
+                    .map_err(|e| anyhow::anyhow!("Claims must be a JSON object: {e}"))?,
+                envelope: None,
+            };
+            seal(
+                &mut req,
+                |req| &mut req.envelope,
+                "/sign/jwt",
+                seed.as_deref(),
+            );
+            let result = sign_jwt(&client, &args.server, server_key.as_ref(), &req).await?;
+            info!("Key ID: {}", result.kid);
+            println!("{}", result.token);
+        }
+        Some(Commands::Ssh {
+            user_id,
+            seed,
+            namespace,
+            file,
+        }) => {
+            let hash = match &file {
+                Some(file) => Sha512::digest(std::fs::read(file)?),
+                None => {
+                    let mut hash = Sha512::new();
+                    std::io::copy(&mut std::io::stdin().lock(), &mut hash)?;
+                    hash.finalize()
+                }
+            };
+            let mut req = SshSignRequest {
+                user_id,
+                namespace,
+                hash: hex::encode(hash),
+                envelope: None,
+            };
+            seal(
+                &mut req,
+                |req| &mut req.envelope,
+                "/sign/ssh",
+                seed.as_deref(),
+            );
+            let signature = sign_ssh(&client, &args.server, server_key.as_ref(), &req).await?;
+            match file {
+                Some(file) => {
+                    let mut path = file.into_os_string();
+                    path.push(".sig");
+                    std::fs::write(&path, signature)?;
+                    info!("Signature written to {}", Path::new(&path).display());
+                }
+                None => print!("{signature}"),
+            }
+        }
+        Some(Commands::Solana {
+            user_id,
+            seed,
+            message,
+            file,
+        }) => {
+            let message = match (message, file) {
+                (Some(message), _) => message,
+                (None, Some(file)) => BASE64_STANDARD.encode(std::fs::read(file)?),
+                (None, None) => anyhow::bail!("Message required (base64 or -f flag)"),
+            };
+            let mut req = SolanaSignRequest {
+                user_id,
+                message,
+                envelope: None,
+            };
+            seal(
+                &mut req,
+                |req| &mut req.envelope,
+                "/sign/solana",
+                seed.as_deref(),
+            );
+            sign_solana(&client, &args.server, server_key.as_ref(), &req).await?;
+        }
+        Some(Commands::Cosmos {
+            user_id,
+            seed,
+            file,
+            direct,
+        }) => {
+            let sign_doc = std::fs::read(file)?;
+            let mut req = if direct {
+                CosmosSignRequest {
+                    user_id,
+                    mode: CosmosSignMode::Direct,
+                    sign_doc: BASE64_STANDARD.encode(sign_doc),
+                    envelope: None,
+                }
+            } else {
+                CosmosSignRequest {
+                    user_id,
+                    mode: CosmosSignMode::AminoJson,
+                    sign_doc: String::from_utf8(sign_doc)?,
+                    envelope: None,
+                }
+            };
+            seal(
+                &mut req,
+                |req| &mut req.envelope,
+                "/sign/cosmos",
+                seed.as_deref(),
+            );
+            sign_cosmos(&client, &args.server, server_key.as_ref(), &req).await?;
+        }
+        Some(Commands::Attest) => {
+            print_attestation_policy(&client, &args.server, server_key.as_ref()).await?;
+        }
+        Some(Commands::Admin {
+            token_file,
+            command,
+        }) => {
+            let token = std::fs::read_to_string(token_file)?;
+            admin(
+                &client,
+                &args.server,
+                server_key.as_ref(),
+                token.trim(),
+                command,
+            )
+            .await?;
         }
         Some(Commands::Forget { user_id }) => {
-            forget_user(&client, &args.server, &user_id).await?;
+            forget_user(&client, &args.server, server_key.as_ref(), &user_id).await?;
         }
         None => {
             // Handle the default sign operation when no subcommand is given
             let user_id = args
                 .user_id
                 .ok_or_else(|| anyhow::anyhow!("User ID required (-u flag)"))?;
-            let message = args
-                .message
-                .ok_or_else(|| anyhow::anyhow!("Message required (-m flag)"))?;
-
-            sign_message(&client, &args.server, &user_id, &message).await?;
+            if let Some(path) = args.psbt {
+                anyhow::ensure!(
+                    args.context.is_none(),
+                    "PSBTs are not signed under a context"
+                );
+                let psbt = std::fs::read(path)?;
+                let psbt = if psbt.starts_with(PSBT_MAGIC) {
+                    BASE64_STANDARD.encode(psbt)
+                } else {
+                    String::from_utf8(psbt)?.trim().to_string()
+                };
+                let mut req = PsbtSignRequest {
+                    user_id,
+                    psbt,
+                    envelope: None,
+                };
+                seal(
+                    &mut req,
+                    |req| &mut req.envelope,
+                    "/sign/psbt",
+                    args.seed.as_deref(),
+                );
+                sign_psbt(&client, &args.server, server_key.as_ref(), &req).await?;
+            } else if args.minisign || args.signify {
+                let format = if args.minisign {
+                    SignFormat::Minisign
+                } else {
+                    SignFormat::Signify
+                };
+                let (message, trusted_comment) = match (&args.file, args.message) {
+                    (Some(file), _) if args.minisign => {
+                        let mut hash = Blake2b512::new();
+                        std::io::copy(&mut std::fs::File::open(file)?, &mut hash)?;
+                        let name = file.file_name().unwrap_or_default().to_string_lossy();
+                        let trusted_comment = args.trusted_comment.unwrap_or_else(|| {
+                            format!("timestamp:{}\tfile:{}\thashed", unix_time(), name)
+                        });
+                        (hex::encode(hash.finalize()), Some(trusted_comment))
+                    }
+                    (Some(file), _) => (BASE64_STANDARD.encode(std::fs::read(file)?), None),
+                    (None, Some(message)) if args.minisign => (
+                        hex::encode(Blake2b512::digest(message)),
+                        args.trusted_comment,
+                    ),
+                    (None, Some(message)) => (BASE64_STANDARD.encode(message), None),
+                    (None, None) => anyhow::bail!("Message required (-m or -f flag)"),
+                };
+                let mut req = SignRequest {
+                    user_id,
+                    message,
+                    context: None,
+                    envelope: None,
+                    format,
+                    trusted_comment,
+                };
+                seal(
+                    &mut req,
+                    |req| &mut req.envelope,
+                    "/sign",
+                    args.seed.as_deref(),
+                );
+                let signature =
+                    sign_detached(&client, &args.server, server_key.as_ref(), &req).await?;
+                match args.file {
+                    Some(file) => {
+                        let mut path = file.into_os_string();
+                        path.push(if args.minisign { ".minisig" } else { ".sig" });
+                        std::fs::write(&path, signature)?;
+                        info!("Signature written to {}", Path::new(&path).display());
+                    }
+                    None => print!("{signature}"),
+                }
+            } else if let Some(file) = args.file {
+                sign_file(
+                    &client,
+                    &args.server,
+                    server_key.as_ref(),
+                    &user_id,
+                    &file,
+                    args.context.as_deref(),
+                    args.seed.as_deref(),
+                )
+                .await?;
+            } else {
+                let message = args
+                    .message
+                    .ok_or_else(|| anyhow::anyhow!("Message required (-m or -f flag)"))?;
+                let format = match (args.cose, args.cwt) {
+                    (true, _) => SignFormat::CoseSign1,
+                    (_, true) => SignFormat::Cwt,
+                    _ => SignFormat::Raw,
+                };
+                let mut req = SignRequest {
+                    user_id,
+                    message,
+                    context: args.context,
+                    envelope: None,
+                    format,
+                    trusted_comment: None,
+                };
+                seal(
+                    &mut req,
+                    |req| &mut req.envelope,
+                    "/sign",
+                    args.seed.as_deref(),
+                );
+                sign_message(&client, &args.server, server_key.as_ref(), &req).await?;
+            }
         }
     }
 
     Ok(())
 }
 
-async fn register_user(client: &reqwest::Client, server_url: &str, seed: &str) -> Result<()> {
+async fn register_user(
+    client: &reqwest::Client,
+    server_url: &str,
+    server_key: Option<&VerifyingKey>,
+    req: &RegisterRequest,
+) -> Result<()> {
     info!("Registering new user...");
 
-    // Convert seed string to bytes
-    let seed = seed.as_bytes().to_vec();
-
-    let response = client
-        .post(format!("{}/register", server_url))
-        .json(&RegisterRequest { seed })
-        .send()
-        .await?;
+    // Retries reuse the idempotency key, so a request that timed out after the server registered
+    // the user does not register a second one.
+    let idempotency_key = hex::encode(rand::random::<[u8; 16]>());
+    let mut attempt = 1;
+    let response = loop {
+        let result = client
+            .post(format!("{}/register", server_url))
+            .header(IDEMPOTENCY_KEY_HEADER, &idempotency_key)
+            .timeout(REGISTER_TIMEOUT)
+            .json(req)
+            .send_with_nonce()
+            .await;
+        match result {
+            Err(e) if (e.is_timeout() || e.is_connect()) && attempt < REGISTER_ATTEMPTS => {
+                info!("Registration attempt {} failed ({}), retrying", attempt, e);
+                attempt += 1;
+            }
+            result => break result?,
+        }
+    };
 
     if response.status().is_success() {
-        let result: RegisterResponse = response.json().await?;
+        let result: RegisterResponse = read_json(response, server_key).await?;
         println!("{}", result.user_id);
         println!("{}", result.verifying_key);
         info!(
             "User registered successfully.\n UUID:\t{}\n Verifying key:\t{}",
             result.user_id, result.verifying_key
         );
+        if let Some(expires_at) = result.expires_at {
+            info!("User expires at {} (unix time)", expires_at);
+        }
+        if let Some(ssh_public_key) = &result.ssh_public_key {
+            info!("SSH public key: {}", ssh_public_key);
+        }
+        if let Some(minisign_public_key) = &result.minisign_public_key {
+            info!("Minisign public key: {}", minisign_public_key);
+        }
+        if let Some(did) = &result.did {
+            info!("DID: {}", did);
+        }
+        if let Some(npub) = &result.nostr_public_key {
+            info!("Nostr public key: {}", npub);
+        }
+        if let Some(address) = &result.cosmos_address {
+            info!("Cosmos address: {}", address);
+        }
+        if !result.status.is_active() {
+            info!("User is {}: it cannot sign yet", result.status);
+        }
     } else {
-        let err: ErrorResponse = response.json().await?;
+        let err: ErrorResponse = read_json(response, server_key).await?;
         error!("Registration failed: {}", err.error);
         anyhow::bail!("Registration failed: {}", err.error);
     }
@@ -115,56 +1014,1095 @@ async fn register_user(client: &reqwest::Client, server_url: &str, seed: &str) -
     Ok(())
 }
 
-async fn sign_message(
+async fn renew_user(
     client: &reqwest::Client,
     server_url: &str,
-    user_id: &str,
-    message: &str,
+    server_key: Option<&VerifyingKey>,
+    req: &RenewRequest,
 ) -> Result<()> {
-    info!("Signing message...");
+    info!("Renewing user {}...", req.user_id);
 
     let response = client
-        .post(format!("{}/sign", server_url))
-        .json(&SignRequest {
-            user_id: user_id.to_string(),
-            message: message.to_string(),
-        })
-        .send()
+        .post(format!("{}/renew", server_url))
+        .json(req)
+        .send_with_nonce()
         .await?;
 
     if response.status().is_success() {
-        let result: SignResponse = response.json().await?;
-        println!("{}", result.signature);
-        info!("Message signed successfully");
-    } else if response.status() == 404 {
-        error!("User not found. Please register first using 'sign register'");
-        anyhow::bail!("User not found");
+        let result: RenewResponse = read_json(response, server_key).await?;
+        println!("{}", result.expires_at);
+        info!(
+            "User {} renewed until {} (unix time)",
+            result.user_id, result.expires_at
+        );
     } else {
-        let err: ErrorResponse = response.json().await?;
-        error!("Signing failed: {}", err.error);
-        anyhow::bail!("Signing failed: {}", err.error);
+        let err: ErrorResponse = read_json(response, server_key).await?;
+        error!("Renewal failed: {}", err.error);
+        anyhow::bail!("Renewal failed: {}", err.error);
     }
 
     Ok(())
 }
 
-async fn forget_user(client: &reqwest::Client, server_url: &str, user_id: &str) -> Result<()> {
-    info!("Forgetting user {}...", user_id);
+async fn rotate_user(
+    client: &reqwest::Client,
+    server_url: &str,
+    server_key: Option<&VerifyingKey>,
+    req: &RotateRequest,
+) -> Result<()> {
+    info!("Rotating key of user {}...", req.user_id);
 
     let response = client
-        .delete(format!("{}/forget", server_url))
-        .json(&ForgetRequest {
-            user_id: user_id.to_string(),
+        .post(format!("{}/rotate", server_url))
+        .json(req)
+        .send_with_nonce()
+        .await?;
+
+    if response.status().is_success() {
+        let result: RotateResponse = read_json(response, server_key).await?;
+        verify_rotation_certificate(&result.certificate)?;
+        println!("{}", result.verifying_key);
+        println!("{}", serde_json::to_string(&result.certificate)?);
+        info!(
+            "Key of user {} rotated to generation {}. The previous key remains valid for \
+             verification until {} (unix time)",
+            result.user_id, result.generation, result.previous_valid_until
+        );
+    } else {
+        let err: ErrorResponse = read_json(response, server_key).await?;
+        error!("Rotation failed: {}", err.error);
+        anyhow::bail!("Rotation failed: {}", err.error);
+    }
+
+    Ok(())
+}
+
+/// Check that a rotation certificate is signed by the previous key it names
+fn verify_rotation_certificate(certificate: &RotationCertificate) -> Result<()> {
+    let previous_key = parse_verifying_key(&certificate.previous_verifying_key)?;
+    let signature = parse_signature(&certificate.signature)?;
+    previous_key
+        .verify_strict(&certificate.statement(), &signature)
+        .map_err(|_| anyhow::anyhow!("Rotation certificate signature is invalid"))
+}
+
+/// Fetch an attestation for a fresh nonce. Returns it along with the SHA-256 of the TLS
+/// certificate the server presented and the nonce.
+async fn fetch_attestation(
+    client: &reqwest::Client,
+    server_url: &str,
+    server_key: Option<&VerifyingKey>,
+) -> Result<(AttestationResponse, [u8; 32], [u8; ATTESTATION_NONCE_LEN])> {
+    let nonce = rand::random::<[u8; ATTESTATION_NONCE_LEN]>();
+    let response = client
+        .get(format!("{}/attestation", server_url))
+        .query(&AttestationParams {
+            nonce: hex::encode(nonce),
         })
+        .send_with_nonce()
+        .await?
+        .error_for_status()?;
+    let certificate = response
+        .extensions()
+        .get::<reqwest::tls::TlsInfo>()
+        .and_then(|info| info.peer_certificate())
+        .ok_or_else(|| anyhow::anyhow!("Server did not present a TLS certificate"))?;
+    let certificate_sha256 = Sha256::digest(certificate).into();
+    let attestation = read_json(response, server_key).await?;
+    Ok((attestation, certificate_sha256, nonce))
+}
+
+/// Check that the server runs a binary allowed by `policy`, and that this binary holds the TLS
+/// certificate the server presented. Returns the attested identity key of the server.
+async fn attest(
+    client: &reqwest::Client,
+    server_url: &str,
+    policy: &AttestationPolicy,
+    server_key: Option<&VerifyingKey>,
+) -> Result<VerifyingKey> {
+    info!("Checking server attestation...");
+
+    let (attestation, certificate_sha256, nonce) =
+        fetch_attestation(client, server_url, server_key).await?;
+    let quote = &attestation.quote;
+
+    if quote.provider != policy.provider {
+        anyhow::bail!(
+            "Attestation is from provider {:?}, policy requires {:?}",
+            quote.provider,
+            policy.provider
+        );
+    }
+    if !quote
+        .attestation_key
+        .eq_ignore_ascii_case(&policy.attestation_key)
+    {
+        anyhow::bail!("Attestation is not signed by the key the policy requires");
+    }
+    parse_verifying_key(&quote.attestation_key)?
+        .verify_strict(&quote.statement(), &parse_signature(&quote.signature)?)
+        .map_err(|_| anyhow::anyhow!("Attestation signature is invalid"))?;
+    if !policy
+        .measurements
+        .iter()
+        .any(|measurement| measurement.eq_ignore_ascii_case(&quote.measurement))
+    {
+        error!("Server measurement {} is not allowed", quote.measurement);
+        anyhow::bail!("Server runs a binary not allowed by the attestation policy");
+    }
+
+    // The quote must be fresh and cover the TLS certificate we are talking to.
+    if hex::decode(&attestation.tls_certificate_sha256)? != certificate_sha256 {
+        anyhow::bail!("Attested TLS certificate differs from the one the server presented");
+    }
+    let attested_key = parse_verifying_key(&attestation.server_key)?;
+    if server_key.is_some_and(|server_key| *server_key != attested_key) {
+        anyhow::bail!("Attested server key differs from the pinned server key");
+    }
+    let report_data = attestation_report_data(&certificate_sha256, attested_key.as_bytes(), &nonce);
+    if hex::decode(&quote.report_data)? != report_data {
+        anyhow::bail!("Attestation report data does not match");
+    }
+
+    info!(
+        "Server attested by {} provider, measurement {}",
+        quote.provider, quote.measurement
+    );
+    Ok(attested_key)
+}
+
+/// Print a policy trusting whatever the server currently attests to
+async fn print_attestation_policy(
+    client: &reqwest::Client,
+    server_url: &str,
+    server_key: Option<&VerifyingKey>,
+) -> Result<()> {
+    let (attestation, _, _) = fetch_attestation(client, server_url, server_key).await?;
+    let policy = AttestationPolicy {
+        provider: attestation.quote.provider,
+        attestation_key: attestation.quote.attestation_key,
+        measurements: vec![attestation.quote.measurement],
+    };
+    println!("{}", serde_json::to_string_pretty(&policy)?);
+    Ok(())
+}
+
+async fn admin(
+    client: &reqwest::Client,
+    server_url: &str,
+    server_key: Option<&VerifyingKey>,
+    token: &str,
+    command: AdminCommands,
+) -> Result<()> {
+    let url = format!("{}/admin", server_url);
+    let request = match &command {
+        AdminCommands::Users => client.get(format!("{url}/users")),
+        AdminCommands::Stats => client.get(format!("{url}/stats")),
+        AdminCommands::Forget { user_id } => client.delete(format!("{url}/users/{user_id}")),
+        AdminCommands::Freeze { user_id } => client.post(format!("{url}/users/{user_id}/freeze")),
+        AdminCommands::Unfreeze { user_id } => {
+            client.post(format!("{url}/users/{user_id}/unfreeze"))
+        }
+        AdminCommands::ReadOnly { off } => client
+            .post(format!("{url}/read-only"))
+            .json(&ReadOnlyRequest { read_only: !off }),
+        AdminCommands::Approve { user_id } => client.post(format!("{url}/users/{user_id}/approve")),
+        AdminCommands::RotateIdentity => client.post(format!("{url}/rotate-identity")),
+    };
+    let response = request.bearer_auth(token).send_with_nonce().await?;
+
+    if !response.status().is_success() {
+        if response.status() == 404 && !matches!(command, AdminCommands::Forget { .. }) {
+            anyhow::bail!("Admin request failed: not found (is the admin API enabled?)");
+        }
+        let err: ErrorResponse = read_json(response, server_key).await?;
+        error!("Admin request failed: {}", err.error);
+        anyhow::bail!("Admin request failed: {}", err.error);
+    }
+
+    match command {
+        AdminCommands::Users => {
+            let result: AdminUsersResponse = read_json(response, server_key).await?;
+            for user in result.users {
+                println!(
+                    "{}\t{}\t{}\t{}",
+                    user.user_id,
+                    user.key_types.join(","),
+                    user.created_at,
+                    user.status
+                );
+            }
+        }
+        AdminCommands::Stats => {
+            let result: AdminStatsResponse = read_json(response, server_key).await?;
+            println!("{}", serde_json::to_string_pretty(&result)?);
+        }
+        AdminCommands::RotateIdentity => {
+            let rotation: IdentityRotation = read_json(response, server_key).await?;
+            let previous_key = parse_verifying_key(&rotation.previous_verifying_key)?;
+            if server_key.is_some_and(|server_key| *server_key != previous_key) {
+                anyhow::bail!("Identity rotation is not from the pinned server key");
+            }
+            previous_key
+                .verify_strict(
+                    &rotation.statement(),
+                    &parse_signature(&rotation.signature)?,
+                )
+                .map_err(|_| anyhow::anyhow!("Identity rotation signature is invalid"))?;
+            println!("{}", rotation.verifying_key);
+            info!(
+                "Server identity key rotated to generation {}",
+                rotation.generation
+            );
+        }
+        _ => {
+            let result: AdminResponse = read_json(response, server_key).await?;
+            println!("{}", result.message);
+        }
+    }
+
+    Ok(())
+}
+
+/// What a signature is over
+enum Signed {
+    Message(String),
+    /// Signed with Ed25519ph, see `sign --file`
+    File(PathBuf),
+}
+
+/// The server's verifying key: the pinned key if there is one, otherwise whatever the server
+/// claims its key is
+async fn server_identity(
+    client: &reqwest::Client,
+    server_url: &str,
+    server_key: Option<&VerifyingKey>,
+) -> Result<VerifyingKey> {
+    if let Some(server_key) = server_key {
+        return Ok(*server_key);
+    }
+    let identity: IdentityResponse = client
+        .get(format!("{}/identity", server_url))
         .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    parse_verifying_key(&identity.verifying_key)
+}
+
+/// Parse a JSON response. If the server key is pinned, the response must carry a valid signature
+/// by it.
+async fn read_json<T: DeserializeOwned>(
+    response: Reply,
+    server_key: Option<&VerifyingKey>,
+) -> Result<T> {
+    Ok(serde_json::from_slice(
+        &read_body(response, server_key).await?,
+    )?)
+}
+
+/// The body of a response, checked against the server's signature when a server key is pinned.
+/// The signature must cover the nonce the request was sent with, so an old response from the
+/// server cannot be played back in its place.
+async fn read_body(response: Reply, server_key: Option<&VerifyingKey>) -> Result<Vec<u8>> {
+    let Reply { response, nonce } = response;
+    let path = response.url().path().to_string();
+    let status = response.status().as_u16();
+    let signature = response.headers().get(RESPONSE_SIGNATURE_HEADER).cloned();
+    let body = response.bytes().await?;
+
+    if let Some(server_key) = server_key {
+        let signature =
+            signature.ok_or_else(|| anyhow::anyhow!("Response is not signed by the server"))?;
+        let signature = parse_signature(signature.to_str()?)?;
+        server_key
+            .verify_strict(
+                &response_signature_message(&path, &nonce, status, &body),
+                &signature,
+            )
+            .map_err(|_| anyhow::anyhow!("Response is not signed by the pinned server key"))?;
+    }
+    Ok(body.to_vec())
+}
+
+/// A response, together with the nonce its request was sent with
+struct Reply {
+    response: reqwest::Response,
+    nonce: String,
+}
+
+impl Reply {
+    fn error_for_status(self) -> reqwest::Result<Self> {
+        Ok(Reply {
+            response: self.response.error_for_status()?,
+            nonce: self.nonce,
+        })
+    }
+}
+
+impl std::ops::Deref for Reply {
+    type Target = reqwest::Response;
+
+    fn deref(&self) -> &reqwest::Response {
+        &self.response
+    }
+}
+
+/// Sending requests with a fresh nonce in the [`REQUEST_NONCE_HEADER`], for the server to sign
+/// along with its response
+trait SendWithNonce {
+    async fn send_with_nonce(self) -> reqwest::Result<Reply>;
+}
+
+impl SendWithNonce for reqwest::RequestBuilder {
+    async fn send_with_nonce(self) -> reqwest::Result<Reply> {
+        let nonce = request_nonce();
+        let response = self.header(REQUEST_NONCE_HEADER, &nonce).send().await?;
+        Ok(Reply { response, nonce })
+    }
+}
+
+fn parse_verifying_key(key: &str) -> Result<VerifyingKey> {
+    let key: [u8; 32] = hex::decode(key)?
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("Verifying key has the wrong length"))?;
+    Ok(VerifyingKey::from_bytes(&key)?)
+}
+
+fn parse_signature(signature: &str) -> Result<Signature> {
+    Ok(Signature::from_slice(&hex::decode(signature)?)?)
+}
+
+async fn revoke_user(
+    client: &reqwest::Client,
+    server_url: &str,
+    server_key: Option<&VerifyingKey>,
+    req: &RevokeRequest,
+) -> Result<()> {
+    info!("Revoking keys of user {}...", req.user_id);
+
+    let identity = server_identity(client, server_url, server_key).await?;
+    let response = client
+        .post(format!("{}/revoke", server_url))
+        .json(req)
+        .send_with_nonce()
+        .await?;
+
+    if response.status().is_success() {
+        let statement: RevocationStatement = read_json(response, Some(&identity)).await?;
+        identity
+            .verify_strict(
+                &statement.statement(),
+                &parse_signature(&statement.signature)?,
+            )
+            .map_err(|_| anyhow::anyhow!("Revocation statement signature is invalid"))?;
+        println!("{}", serde_json::to_string(&statement)?);
+        info!(
+            "Revoked {} keys of user {}",
+            statement.verifying_keys.len(),
+            statement.user_id
+        );
+    } else {
+        let err: ErrorResponse = read_json(response, Some(&identity)).await?;
+        error!("Revocation failed: {}", err.error);
+        anyhow::bail!("Revocation failed: {}", err.error);
+    }
+
+    Ok(())
+}
+
+async fn verify(
+    client: &reqwest::Client,
+    server_url: &str,
+    server_key: Option<&VerifyingKey>,
+    key: &str,
+    signature: &str,
+    signed: &Signed,
+    context: Option<&str>,
+) -> Result<()> {
+    check_not_revoked(client, server_url, server_key, key).await?;
+
+    let verifying_key = parse_verifying_key(key)?;
+    let signature = parse_signature(signature)?;
+    let result = match signed {
+        Signed::Message(message) => match context {
+            Some(context) => verifying_key
+                .verify_strict(&context_envelope(context, message.as_bytes()), &signature),
+            None => verifying_key.verify_strict(message.as_bytes(), &signature),
+        },
+        Signed::File(path) => {
+            let mut prehashed = Sha512::new();
+            std::io::copy(&mut std::fs::File::open(path)?, &mut prehashed)?;
+            let context = context.unwrap_or(DEFAULT_STREAM_CONTEXT);
+            verifying_key.verify_prehashed_strict(prehashed, Some(context.as_bytes()), &signature)
+        }
+    };
+    if result.is_err() {
+        error!("Signature is invalid");
+        anyhow::bail!("Signature is invalid");
+    }
+    println!("valid");
+    info!("Signature is valid and the key is not revoked");
+
+    Ok(())
+}
+
+/// Where `sign verify --cose` gets the signer's key from
+enum CoseVerifyingKey {
+    Hex(String),
+    /// A COSE_Key or COSE_KeySet file
+    File(PathBuf),
+}
+
+/// Verify a COSE_Sign1 or CWT made by `/sign`: its signature, with the context as external AAD,
+/// that its key is not revoked, that its payload is `message` if given, and for CWTs that the
+/// token is valid now
+async fn verify_cose(
+    client: &reqwest::Client,
+    server_url: &str,
+    server_key: Option<&VerifyingKey>,
+    key: &CoseVerifyingKey,
+    path: &Path,
+    message: Option<&str>,
+    context: Option<&str>,
+) -> Result<()> {
+    let bytes = std::fs::read(path)?;
+    let cose = match CoseSign1::parse(&bytes) {
+        Ok(cose) => cose,
+        Err(e) => {
+            let decoded = std::str::from_utf8(&bytes)
+                .ok()
+                .and_then(|text| BASE64_STANDARD.decode(text.trim()).ok())
+                .ok_or(e)?;
+            CoseSign1::parse(&decoded)?
+        }
+    };
+
+    let candidates = match key {
+        CoseVerifyingKey::Hex(key) => vec![parse_verifying_key(key)?.to_bytes()],
+        CoseVerifyingKey::File(path) => parse_cose_keys(&std::fs::read(path)?)?,
+    };
+    let verifying_key = candidates
+        .iter()
+        .find(|key| cose_key_thumbprint(key) == cose.key_id())
+        .ok_or_else(|| anyhow::anyhow!("No key with the kid of the COSE_Sign1"))?;
+    let signature = cose.signature().expect("parsed COSE_Sign1s are signed");
+    let data = cose.to_be_signed(context.unwrap_or_default().as_bytes());
+    if VerifyingKey::from_bytes(verifying_key)?
+        .verify_strict(&data, &Signature::from_bytes(&signature))
+        .is_err()
+    {
+        error!("Signature is invalid");
+        anyhow::bail!("Signature is invalid");
+    }
+    if message.is_some_and(|message| message.as_bytes() != cose.payload()) {
+        anyhow::bail!("The payload is not the message");
+    }
+
+    check_not_revoked(client, server_url, server_key, &hex::encode(verifying_key)).await?;
+
+    if cose.cwt {
+        let claims = parse_cwt_claims(cose.payload())?;
+        let now = unix_time() as f64;
+        if claims
+            .get("exp")
+            .and_then(serde_json::Value::as_f64)
+            .is_some_and(|exp| exp <= now)
+        {
+            anyhow::bail!("Token has expired");
+        }
+        if claims
+            .get("nbf")
+            .and_then(serde_json::Value::as_f64)
+            .is_some_and(|nbf| nbf > now)
+        {
+            anyhow::bail!("Token is not valid yet");
+        }
+        println!("valid");
+        println!("{}", serde_json::to_string_pretty(&claims)?);
+    } else {
+        println!("valid");
+        info!("Payload: {}", String::from_utf8_lossy(cose.payload()));
+    }
+    info!("Signature is valid and the key is not revoked");
+
+    Ok(())
+}
+
+/// Fail if the server's signed revocation list has the hex encoded `key`
+async fn check_not_revoked(
+    client: &reqwest::Client,
+    server_url: &str,
+    server_key: Option<&VerifyingKey>,
+    key: &str,
+) -> Result<()> {
+    info!("Checking revocation list...");
+
+    let identity = server_identity(client, server_url, server_key).await?;
+    let response = client
+        .get(format!("{}/revocations", server_url))
+        .send_with_nonce()
+        .await?
+        .error_for_status()?;
+    let revocations: RevocationList = read_json(response, Some(&identity)).await?;
+    identity
+        .verify_strict(
+            &revocations.statement(),
+            &parse_signature(&revocations.signature)?,
+        )
+        .map_err(|_| anyhow::anyhow!("Revocation list signature is invalid"))?;
+    if let Some(revocation) = revocations.find(key) {
+        error!("Key was revoked at {} (unix time)", revocation.revoked_at);
+        anyhow::bail!("Key is revoked");
+    }
+    Ok(())
+}
+
+/// Verify an SSH signature, as `ssh-keygen -Y verify` does: the signature must be valid, in
+/// `namespace`, and by a key the allowed signers file allows for `identity`, or for anyone when no
+/// identity is given. Its key must not be revoked either.
+#[allow(clippy::too_many_arguments)]
+async fn verify_ssh(
+    client: &reqwest::Client,
+    server_url: &str,
+    server_key: Option<&VerifyingKey>,
+    allowed_signers: &Path,
+    identity: Option<&str>,
+    namespace: &str,
+    signature: &Path,
+    signed: &Signed,
+) -> Result<()> {
+    let signature = SshSignature::parse(&std::fs::read_to_string(signature)?)?;
+    if signature.namespace != namespace {
+        anyhow::bail!(
+            "Signature is in namespace {:?}, not {:?}",
+            signature.namespace,
+            namespace
+        );
+    }
+    let hash = match signed {
+        Signed::Message(message) => signature.hash(message.as_bytes())?,
+        Signed::File(path) => signature.hash(std::fs::File::open(path)?)?,
+    };
+    let verifying_key = VerifyingKey::from_bytes(&signature.verifying_key)?;
+    let data = SshSignature::signed_data(namespace, &signature.hash_algorithm, &hash);
+    if verifying_key
+        .verify_strict(&data, &Signature::from_bytes(&signature.signature))
+        .is_err()
+    {
+        error!("Signature is invalid");
+        anyhow::bail!("Signature is invalid");
+    }
+
+    let now = unix_time();
+    let signers = parse_allowed_signers(&std::fs::read_to_string(allowed_signers)?)?;
+    let principals: Vec<_> = signers
+        .iter()
+        .filter(|signer| {
+            signer.verifying_key == signature.verifying_key
+                && signer.allows(namespace, now)
+                && identity.is_none_or(|identity| signer.matches_principal(identity))
+        })
+        .map(|signer| signer.principals.as_str())
+        .collect();
+    if principals.is_empty() {
+        error!(
+            "Key {} is not an allowed signer",
+            ssh_public_key(&signature.verifying_key)
+        );
+        anyhow::bail!("Key is not an allowed signer");
+    }
+
+    check_not_revoked(
+        client,
+        server_url,
+        server_key,
+        &hex::encode(signature.verifying_key),
+    )
+    .await?;
+
+    println!("valid");
+    match identity {
+        Some(identity) => info!("Good {:?} signature for {}", namespace, identity),
+        None => info!(
+            "Good {:?} signature by {}",
+            namespace,
+            principals.join(", ")
+        ),
+    }
+
+    Ok(())
+}
+
+async fn sign_nostr(
+    client: &reqwest::Client,
+    server_url: &str,
+    server_key: Option<&VerifyingKey>,
+    req: &NostrSignRequest,
+) -> Result<NostrEvent> {
+    info!("Signing Nostr event...");
+
+    let response = client
+        .post(format!("{}/sign/nostr", server_url))
+        .json(req)
+        .send_with_nonce()
+        .await?;
+    if !response.status().is_success() {
+        let err: ErrorResponse = read_json(response, server_key).await?;
+        error!("Signing failed: {}", err.error);
+        anyhow::bail!("Signing failed: {}", err.error);
+    }
+
+    read_json(response, server_key).await
+}
+
+async fn sign_credential(
+    client: &reqwest::Client,
+    server_url: &str,
+    server_key: Option<&VerifyingKey>,
+    req: &CredentialSignRequest,
+) -> Result<serde_json::Map<String, serde_json::Value>> {
+    info!("Signing credential...");
+
+    let response = client
+        .post(format!("{}/sign/credential", server_url))
+        .json(req)
+        .send_with_nonce()
+        .await?;
+    if !response.status().is_success() {
+        let err: ErrorResponse = read_json(response, server_key).await?;
+        error!("Signing failed: {}", err.error);
+        anyhow::bail!("Signing failed: {}", err.error);
+    }
+
+    read_json(response, server_key).await
+}
+
+async fn sign_jwt(
+    client: &reqwest::Client,
+    server_url: &str,
+    server_key: Option<&VerifyingKey>,
+    req: &JwtSignRequest,
+) -> Result<JwtSignResponse> {
+    info!("Issuing JWT...");
+
+    let response = client
+        .post(format!("{}/sign/jwt", server_url))
+        .json(req)
+        .send_with_nonce()
+        .await?;
+    if !response.status().is_success() {
+        let err: ErrorResponse = read_json(response, server_key).await?;
+        error!("Signing failed: {}", err.error);
+        anyhow::bail!("Signing failed: {}", err.error);
+    }
+
+    read_json(response, server_key).await
+}
+
+async fn sign_ssh(
+    client: &reqwest::Client,
+    server_url: &str,
+    server_key: Option<&VerifyingKey>,
+    req: &SshSignRequest,
+) -> Result<String> {
+    info!("Making SSH signature...");
+
+    let response = client
+        .post(format!("{}/sign/ssh", server_url))
+        .json(req)
+        .send_with_nonce()
+        .await?;
+    if !response.status().is_success() {
+        let err: ErrorResponse = read_json(response, server_key).await?;
+        error!("Signing failed: {}", err.error);
+        anyhow::bail!("Signing failed: {}", err.error);
+    }
+
+    let result: SshSignResponse = read_json(response, server_key).await?;
+    Ok(result.signature)
+}
+
+async fn sign_message(
+    client: &reqwest::Client,
+    server_url: &str,
+    server_key: Option<&VerifyingKey>,
+    req: &SignRequest,
+) -> Result<()> {
+    info!("Signing message...");
+
+    let response = client
+        .post(format!("{}/sign", server_url))
+        .json(req)
+        .send_with_nonce()
+        .await?;
+
+    if response.status() == reqwest::StatusCode::ACCEPTED {
+        let request: PendingSignature = read_json(response, server_key).await?;
+        println!("{}", request.request_id);
+        info!(
+            "Signature awaits {} of {} approvals until {} (unix time). Approvers run `sign approve {}`",
+            request.threshold,
+            request.approvers.len(),
+            request.expires_at,
+            request.request_id
+        );
+        return Ok(());
+    }
+    print_signature(response, server_key).await
+}
+
+/// Make a minisign or signify signature and return the signature file
+async fn sign_detached(
+    client: &reqwest::Client,
+    server_url: &str,
+    server_key: Option<&VerifyingKey>,
+    req: &SignRequest,
+) -> Result<String> {
+    info!("Making {:?} signature...", req.format);
+
+    let response = client
+        .post(format!("{}/sign", server_url))
+        .json(req)
+        .send_with_nonce()
+        .await?;
+    if !response.status().is_success() {
+        let err: ErrorResponse = read_json(response, server_key).await?;
+        error!("Signing failed: {}", err.error);
+        anyhow::bail!("Signing failed: {}", err.error);
+    }
+
+    let result: SignResponse = read_json(response, server_key).await?;
+    result
+        .signature_file
+        .ok_or_else(|| anyhow::anyhow!("Server returned no signature file"))
+}
+
+/// Approve a pending signature: fetch it, check the approver is one of its approvers and submit
+/// the approval, which the server signs with the approver's key
+async fn approve(
+    client: &reqwest::Client,
+    server_url: &str,
+    server_key: Option<&VerifyingKey>,
+    request_id: &str,
+    req: &ApproveRequest,
+) -> Result<()> {
+    let path = format!("/pending/{}", request_id);
+    let response = client
+        .get(format!("{}{}", server_url, path))
+        .query(&pending_query(&path, &req.approver_id, &req.seed))
+        .send_with_nonce()
+        .await?;
+    if !response.status().is_success() {
+        let err: ErrorResponse = read_json(response, server_key).await?;
+        anyhow::bail!("Fetching the request failed: {}", err.error);
+    }
+    let request: PendingSignature = read_json(response, server_key).await?;
+    if !request.approvers.contains(&req.approver_id) {
+        anyhow::bail!(
+            "User {} is not an approver of this request",
+            req.approver_id
+        );
+    }
+    info!(
+        "Approving signature of {:?} (context {:?}) for user {}",
+        request.message, request.context, request.user_id
+    );
+
+    let response = client
+        .post(format!("{}/approve/{}", server_url, request_id))
+        .json(req)
+        .send_with_nonce()
+        .await?;
+    if !response.status().is_success() {
+        let err: ErrorResponse = read_json(response, server_key).await?;
+        error!("Approval failed: {}", err.error);
+        anyhow::bail!("Approval failed: {}", err.error);
+    }
+    let request: PendingSignature = read_json(response, server_key).await?;
+    match request.signature {
+        Some(signature) => {
+            println!("{}", signature);
+            info!("Quorum met, message signed for user {}", request.user_id);
+        }
+        None => info!(
+            "Approved, {} of {} approvals so far",
+            request.approved_by.len(),
+            request.threshold
+        ),
+    }
+
+    Ok(())
+}
+
+async fn list_pending(
+    client: &reqwest::Client,
+    server_url: &str,
+    server_key: Option<&VerifyingKey>,
+    user_id: &str,
+    seed: &[u8],
+) -> Result<()> {
+    let path = format!("/users/{}/pending", user_id);
+    let response = client
+        .get(format!("{}{}", server_url, path))
+        .query(&pending_query(&path, user_id, seed))
+        .send_with_nonce()
+        .await?;
+    if !response.status().is_success() {
+        let err: ErrorResponse = read_json(response, server_key).await?;
+        anyhow::bail!("Listing pending requests failed: {}", err.error);
+    }
+    let pending: PendingResponse = read_json(response, server_key).await?;
+    for request in pending.requests {
+        println!(
+            "{}\t{}\t{}/{}\t{}\t{:?}\t{}",
+            request.request_id,
+            request.user_id,
+            request.approved_by.len(),
+            request.threshold,
+            request.expires_at,
+            request.message,
+            request.signature.as_deref().unwrap_or("-")
+        );
+    }
+    Ok(())
+}
+
+async fn sign_solana(
+    client: &reqwest::Client,
+    server_url: &str,
+    server_key: Option<&VerifyingKey>,
+    req: &SolanaSignRequest,
+) -> Result<()> {
+    info!("Signing Solana transaction...");
+
+    let response = client
+        .post(format!("{}/sign/solana", server_url))
+        .json(req)
+        .send_with_nonce()
+        .await?;
+    if !response.status().is_success() {
+        let err: ErrorResponse = read_json(response, server_key).await?;
+        error!("Signing failed: {}", err.error);
+        anyhow::bail!("Signing failed: {}", err.error);
+    }
+
+    let result: SolanaSignResponse = read_json(response, server_key).await?;
+    let message = &result.message;
+    info!(
+        "Signed {} message as {}, recent blockhash {}",
+        message.version, result.signer, message.recent_blockhash
+    );
+    for (i, instruction) in message.instructions.iter().enumerate() {
+        info!(
+            " Instruction {}: program {}, accounts [{}], data {}",
+            i,
+            instruction.program_id,
+            instruction.accounts.join(", "),
+            instruction.data
+        );
+    }
+    println!("{}", result.signature);
+
+    Ok(())
+}
+
+async fn sign_cosmos(
+    client: &reqwest::Client,
+    server_url: &str,
+    server_key: Option<&VerifyingKey>,
+    req: &CosmosSignRequest,
+) -> Result<()> {
+    info!("Signing Cosmos transaction...");
+
+    let response = client
+        .post(format!("{}/sign/cosmos", server_url))
+        .json(req)
+        .send_with_nonce()
+        .await?;
+    if !response.status().is_success() {
+        let err: ErrorResponse = read_json(response, server_key).await?;
+        error!("Signing failed: {}", err.error);
+        anyhow::bail!("Signing failed: {}", err.error);
+    }
+
+    let result: CosmosSignResponse = read_json(response, server_key).await?;
+    let sign_doc = &result.sign_doc;
+    info!(
+        "Signed as {} on chain {}, account number {}{}",
+        result.address,
+        sign_doc.chain_id,
+        sign_doc.account_number,
+        sign_doc
+            .sequence
+            .map(|sequence| format!(", sequence {sequence}"))
+            .unwrap_or_default()
+    );
+    for (i, message) in sign_doc.messages.iter().enumerate() {
+        info!(" Message {}: {}", i, message);
+    }
+    if !sign_doc.memo.is_empty() {
+        info!(" Memo: {}", sign_doc.memo);
+    }
+    info!("Public key: {}", result.public_key);
+    println!("{}", result.signature);
+
+    Ok(())
+}
+
+async fn sign_psbt(
+    client: &reqwest::Client,
+    server_url: &str,
+    server_key: Option<&VerifyingKey>,
+    req: &PsbtSignRequest,
+) -> Result<()> {
+    info!("Signing PSBT...");
+
+    let response = client
+        .post(format!("{}/sign/psbt", server_url))
+        .json(req)
+        .send_with_nonce()
+        .await?;
+    if !response.status().is_success() {
+        let err: ErrorResponse = read_json(response, server_key).await?;
+        error!("Signing failed: {}", err.error);
+        anyhow::bail!("Signing failed: {}", err.error);
+    }
+
+    let result: PsbtSignResponse = read_json(response, server_key).await?;
+    info!(
+        "Signed inputs {:?} with key {} (Taproot internal key {})",
+        result.signed_inputs, result.keys.public_key, result.keys.x_only_public_key
+    );
+    println!("{}", result.psbt);
+
+    Ok(())
+}
+
+/// A fresh replay protection nonce
+fn request_nonce() -> String {
+    let mut nonce = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    hex::encode(nonce)
+}
+
+/// Query parameters reading the pending signatures at `path` as `user_id`, authenticated with the
+/// request key of the user's seed
+fn pending_query(path: &str, user_id: &str, seed: &[u8]) -> PendingQuery {
+    let mut query = PendingQuery {
+        user_id: user_id.to_string(),
+        nonce: request_nonce(),
+        expires_at: unix_time() + REQUEST_VALIDITY,
+        mac: String::new(),
+    };
+    query.mac = hex::encode(request_mac(&request_key(seed), &query.mac_message(path)));
+    query
+}
+
+/// Put a request to `path` in a fresh replay protection envelope, authenticated with the request
+/// key of `seed`. Without a seed the request is sent as is
+fn seal<T: Serialize>(
+    request: &mut T,
+    envelope: impl Fn(&mut T) -> &mut Option<RequestEnvelope>,
+    path: &str,
+    seed: Option<&str>,
+) {
+    let Some(seed) = seed else {
+        return;
+    };
+    *envelope(request) = Some(RequestEnvelope {
+        nonce: request_nonce(),
+        expires_at: unix_time() + REQUEST_VALIDITY,
+        mac: String::new(),
+    });
+    let mac = request_mac(
+        &request_key(seed.as_bytes()),
+        &request_mac_message(path, request),
+    );
+    if let Some(envelope) = envelope(request) {
+        envelope.mac = hex::encode(mac);
+    }
+}
+
+async fn sign_file(
+    client: &reqwest::Client,
+    server_url: &str,
+    server_key: Option<&VerifyingKey>,
+    user_id: &str,
+    path: &Path,
+    context: Option<&str>,
+    seed: Option<&str>,
+) -> Result<()> {
+    info!("Signing file {}...", path.display());
+
+    let mut params = StreamSignParams {
+        user_id: user_id.to_string(),
+        context: context.map(str::to_string),
+        ..Default::default()
+    };
+    if let Some(seed) = seed {
+        // The envelope's MAC covers the file, so it is hashed before streaming it
+        let mut hash = Sha512::new();
+        std::io::copy(&mut std::fs::File::open(path)?, &mut hash)?;
+        params.nonce = Some(request_nonce());
+        params.expires_at = Some(unix_time() + REQUEST_VALIDITY);
+        let mac = request_mac(
+            &request_key(seed.as_bytes()),
+            &params.mac_message(&hash.finalize()),
+        );
+        params.mac = Some(hex::encode(mac));
+    }
+    let file = tokio::fs::File::open(path).await?;
+    let response = client
+        .post(format!("{}/sign/stream", server_url))
+        .query(&params)
+        .body(reqwest::Body::wrap_stream(ReaderStream::new(file)))
+        .send_with_nonce()
+        .await?;
+
+    print_signature(response, server_key).await
+}
+
+async fn print_signature(response: Reply, server_key: Option<&VerifyingKey>) -> Result<()> {
+    if response.status().is_success() {
+        let result: SignResponse = read_json(response, server_key).await?;
+        println!("{}", result.cose.unwrap_or(result.signature));
+        info!("Message signed successfully");
+    } else if response.status() == 404 {
+        error!("User not found. Please register first using 'sign register'");
+        anyhow::bail!("User not found");
+    } else {
+        let err: ErrorResponse = read_json(response, server_key).await?;
+        error!("Signing failed: {}", err.error);
+        anyhow::bail!("Signing failed: {}", err.error);
+    }
+
+    Ok(())
+}
+
+async fn forget_user(
+    client: &reqwest::Client,
+    server_url: &str,
+    server_key: Option<&VerifyingKey>,
+    user_id: &str,
+) -> Result<()> {
+    info!("Forgetting user {}...", user_id);
+
+    let response = client
+        .delete(format!("{}/forget", server_url))
+        .json(&ForgetRequest {
+            user_id: user_id.to_string(),
+        })
+        .send_with_nonce()
         .await?;
 
     if response.status().is_success() {
-        let result: ForgetResponse = response.json().await?;
+        let result: ForgetResponse = read_json(response, server_key).await?;
         println!("{}", result.message);
         info!("User {} forgotten successfully", user_id);
     } else {
-        let err: ErrorResponse = response.json().await?;
+        let err: ErrorResponse = read_json(response, server_key).await?;
         error!("Forget failed: {}", err.error);
         anyhow::bail!("Forget failed: {}", err.error);
     }
@@ -0,0 +1,410 @@
+//! COSE_Sign1 (RFC 9052) signatures, COSE keys and CWTs (RFC 8392).
+
+use sha2::{Digest, Sha256};
+use std::fmt;
+
+/// CBOR tag of CWTs (RFC 8392), which the server puts around the COSE_Sign1 of a CWT
+pub const CWT_TAG: u64 = 61;
+
+/// An invalid COSE structure, COSE key or CWT
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CoseError(pub String);
+
+impl fmt::Display for CoseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for CoseError {}
+
+impl From<coset::CoseError> for CoseError {
+    fn from(e: coset::CoseError) -> Self {
+        CoseError(e.to_string())
+    }
+}
+
+/// A COSE_Sign1 signed with EdDSA by an Ed25519 key, whose protected header has the algorithm and
+/// the key's [`cose_key_thumbprint`] as `kid`
+#[derive(Debug, Clone)]
+pub struct CoseSign1 {
+    inner: coset::CoseSign1,
+    /// Whether the payload is a CWT claims set, tagged as a CWT
+    pub cwt: bool,
+}
+
+impl CoseSign1 {
+    /// An unsigned COSE_Sign1 of `payload` for `verifying_key` to sign
+    pub fn new(verifying_key: &[u8; 32], payload: Vec<u8>, cwt: bool) -> Self {
+        let protected = coset::HeaderBuilder::new()
+            .algorithm(coset::iana::Algorithm::EdDSA)
+            .key_id(cose_key_thumbprint(verifying_key).to_vec())
+            .build();
+        CoseSign1 {
+            inner: coset::CoseSign1Builder::new()
+                .protected(protected)
+                .payload(payload)
+                .build(),
+            cwt,
+        }
+    }
+
+    /// Parse a COSE_Sign1, tagged as one, as a CWT or not at all. It must be signed with EdDSA and
+    /// carry its payload.
+    pub fn parse(bytes: &[u8]) -> Result<Self, CoseError> {
+        let value: coset::cbor::Value = coset::cbor::de::from_reader(bytes)
+            .map_err(|e| CoseError(format!("invalid CBOR: {e}")))?;
+        let (value, cwt) = match value {
+            coset::cbor::Value::Tag(CWT_TAG, value) => (*value, true),
+            value => (value, false),
+        };
+        let value = match value {
+            coset::cbor::Value::Tag(tag, value)
+                if tag == coset::iana::CborTag::CoseSign1 as u64 =>
+            {
+                *value
+            }
+            coset::cbor::Value::Tag(tag, _) => {
+                return Err(CoseError(format!("unexpected CBOR tag {tag}")));
+            }
+            value => value,
+        };
+        let inner = <coset::CoseSign1 as coset::AsCborValue>::from_cbor_value(value)?;
+        if inner.protected.header.alg
+            != Some(coset::RegisteredLabelWithPrivate::Assigned(
+                coset::iana::Algorithm::EdDSA,
+            ))
+        {
+            return Err(CoseError("not signed with EdDSA".into()));
+        }
+        if inner.payload.is_none() {
+            return Err(CoseError("detached payloads are not supported".into()));
+        }
+        if inner.signature.len() != 64 {
+            return Err(CoseError("not an Ed25519 signature".into()));
+        }
+        Ok(CoseSign1 { inner, cwt })
+    }
+
+    /// The Sig_structure the signature is over, with `external_aad` (the context the message was
+    /// signed under, or nothing)
+    pub fn to_be_signed(&self, external_aad: &[u8]) -> Vec<u8> {
+        self.inner.tbs_data(external_aad)
+    }
+
+    pub fn set_signature(&mut self, signature: &[u8; 64]) {
+        self.inner.signature = signature.to_vec();
+    }
+
+    /// The signature, once signed
+    pub fn signature(&self) -> Option<[u8; 64]> {
+        self.inner.signature.as_slice().try_into().ok()
+    }
+
+    /// The `kid` of the protected header
+    pub fn key_id(&self) -> &[u8] {
+        &self.inner.protected.header.key_id
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        self.inner.payload.as_deref().unwrap_or_default()
+    }
+
+    /// The tagged encoding
+    pub fn to_vec(&self) -> Vec<u8> {
+        let value =
+            coset::AsCborValue::to_cbor_value(self.inner.clone()).expect("COSE_Sign1 encodes");
+        let mut value =
+            coset::cbor::Value::Tag(coset::iana::CborTag::CoseSign1 as u64, Box::new(value));
+        if self.cwt {
+            value = coset::cbor::Value::Tag(CWT_TAG, Box::new(value));
+        }
+        let mut bytes = Vec::new();
+        coset::cbor::ser::into_writer(&value, &mut bytes).expect("writing to a Vec succeeds");
+        bytes
+    }
+}
+
+/// The COSE key thumbprint (RFC 9679) of an Ed25519 `verifying_key`: the SHA-256 of the
+/// deterministic encoding of its required COSE_Key parameters, kty, crv and x
+pub fn cose_key_thumbprint(verifying_key: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    // {1: 1 (OKP), -1: 6 (Ed25519), -2: h'...'}
+    hasher.update([0xa3, 0x01, 0x01, 0x20, 0x06, 0x21, 0x58, 0x20]);
+    hasher.update(verifying_key);
+    hasher.finalize().into()
+}
+
+/// A COSE_KeySet of Ed25519 `verifying_keys`, each a COSE_Key for verifying EdDSA signatures with
+/// its [`cose_key_thumbprint`] as `kid`
+pub fn cose_key_set(verifying_keys: &[[u8; 32]]) -> Vec<u8> {
+    let keys = verifying_keys
+        .iter()
+        .map(|verifying_key| {
+            coset::CoseKeyBuilder::new_okp_key()
+                .key_id(cose_key_thumbprint(verifying_key).to_vec())
+                .algorithm(coset::iana::Algorithm::EdDSA)
+                .add_key_op(coset::iana::KeyOperation::Verify)
+                .param(
+                    coset::iana::OkpKeyParameter::Crv as i64,
+                    (coset::iana::EllipticCurve::Ed25519 as i64).into(),
+                )
+                .param(
+                    coset::iana::OkpKeyParameter::X as i64,
+                    coset::cbor::Value::Bytes(verifying_key.to_vec()),
+                )
+                .build()
+        })
+        .collect();
+    coset::CborSerializable::to_vec(coset::CoseKeySet(keys)).expect("COSE keys encode")
+}
+
+/// The Ed25519 verifying keys of a COSE_Key or COSE_KeySet. Keys of other types are skipped.
+pub fn parse_cose_keys(bytes: &[u8]) -> Result<Vec<[u8; 32]>, CoseError> {
+    let keys = match <coset::CoseKeySet as coset::CborSerializable>::from_slice(bytes) {
+        Ok(keys) => keys.0,
+        Err(_) => vec![<coset::CoseKey as coset::CborSerializable>::from_slice(
+            bytes,
+        )?],
+    };
+    let crv = coset::Label::Int(coset::iana::OkpKeyParameter::Crv as i64);
+    let x = coset::Label::Int(coset::iana::OkpKeyParameter::X as i64);
+    let ed25519 = coset::cbor::Value::from(coset::iana::EllipticCurve::Ed25519 as i64);
+    Ok(keys
+        .iter()
+        .filter(|key| key.kty == coset::KeyType::Assigned(coset::iana::KeyType::OKP))
+        .filter(|key| key.params.contains(&(crv.clone(), ed25519.clone())))
+        .filter_map(|key| {
+            key.params.iter().find_map(|(label, value)| match value {
+                coset::cbor::Value::Bytes(bytes) if *label == x => bytes.as_slice().try_into().ok(),
+                _ => None,
+            })
+        })
+        .collect())
+}
+
+/// The CWT claims set of `claims`, a JSON object. The registered claims `iss`, `sub` and `aud`
+/// must be strings, `exp`, `nbf` and `iat` numbers, and `cti` a hex string; they are encoded under
+/// their integer keys. Other claims keep their names, with their values converted to CBOR.
+pub fn cwt_claims(
+    claims: &serde_json::Map<String, serde_json::Value>,
+) -> Result<Vec<u8>, CoseError> {
+    let string = |name: &str, value: &serde_json::Value| {
+        value
+            .as_str()
+            .map(str::to_string)
+            .ok_or_else(|| CoseError(format!("claim {name} must be a string")))
+    };
+    let timestamp = |name: &str, value: &serde_json::Value| match value {
+        serde_json::Value::Number(n) => Ok(match n.as_i64() {
+            Some(seconds) => coset::cwt::Timestamp::WholeSeconds(seconds),
+            None => coset::cwt::Timestamp::FractionalSeconds(n.as_f64().unwrap_or_default()),
+        }),
+        _ => Err(CoseError(format!("claim {name} must be a number"))),
+    };
+
+    let mut builder = coset::cwt::ClaimsSetBuilder::new();
+    for (name, value) in claims {
+        builder = match name.as_str() {
+            "iss" => builder.issuer(string(name, value)?),
+            "sub" => builder.subject(string(name, value)?),
+            "aud" => builder.audience(string(name, value)?),
+            "exp" => builder.expiration_time(timestamp(name, value)?),
+            "nbf" => builder.not_before(timestamp(name, value)?),
+            "iat" => builder.issued_at(timestamp(name, value)?),
+            "cti" => builder.cwt_id(
+                hex::decode(string(name, value)?)
+                    .map_err(|_| CoseError("claim cti must be hex encoded".into()))?,
+            ),
+            _ => builder.text_claim(name.clone(), json_to_cbor(value)),
+        };
+    }
+    Ok(coset::CborSerializable::to_vec(builder.build())?)
+}
+
+/// The claims of a CWT claims set as a JSON object, the inverse of [`cwt_claims`]. Byte strings
+/// are hex encoded.
+pub fn parse_cwt_claims(
+    bytes: &[u8],
+) -> Result<serde_json::Map<String, serde_json::Value>, CoseError> {
+    let claims = <coset::cwt::ClaimsSet as coset::CborSerializable>::from_slice(bytes)?;
+    let timestamp = |timestamp: coset::cwt::Timestamp| match timestamp {
+        coset::cwt::Timestamp::WholeSeconds(seconds) => serde_json::Value::from(seconds),
+        coset::cwt::Timestamp::FractionalSeconds(seconds) => serde_json::Value::from(seconds),
+    };
+
+    let mut json = serde_json::Map::new();
+    let registered = [
+        ("iss", claims.issuer.map(serde_json::Value::from)),
+        ("sub", claims.subject.map(serde_json::Value::from)),
+        ("aud", claims.audience.map(serde_json::Value::from)),
+        ("exp", claims.expiration_time.map(timestamp)),
+        ("nbf", claims.not_before.map(timestamp)),
+        ("iat", claims.issued_at.map(timestamp)),
+        ("cti", claims.cwt_id.map(|cti| hex::encode(cti).into())),
+    ];
+    for (name, value) in registered {
+        if let Some(value) = value {
+            json.insert(name.to_string(), value);
+        }
+    }
+    for (name, value) in claims.rest {
+        let name = match name {
+            coset::RegisteredLabelWithPrivate::Text(name) => name,
+            coset::RegisteredLabelWithPrivate::PrivateUse(id) => id.to_string(),
+            coset::RegisteredLabelWithPrivate::Assigned(name) => (name as i64).to_string(),
+        };
+        json.insert(name, cbor_to_json(value)?);
+    }
+    Ok(json)
+}
+
+fn json_to_cbor(value: &serde_json::Value) -> coset::cbor::Value {
+    use coset::cbor::Value;
+    match value {
+        serde_json::Value::Null => Value::Null,
+        serde_json::Value::Bool(b) => Value::Bool(*b),
+        serde_json::Value::Number(n) => match (n.as_i64(), n.as_u64()) {
+            (Some(i), _) => i.into(),
+            (None, Some(u)) => u.into(),
+            (None, None) => Value::Float(n.as_f64().unwrap_or_default()),
+        },
+        serde_json::Value::String(s) => Value::Text(s.clone()),
+        serde_json::Value::Array(values) => Value::Array(values.iter().map(json_to_cbor).collect()),
+        serde_json::Value::Object(map) => Value::Map(
+            map.iter()
+                .map(|(key, value)| (Value::Text(key.clone()), json_to_cbor(value)))
+                .collect(),
+        ),
+    }
+}
+
+fn cbor_to_json(value: coset::cbor::Value) -> Result<serde_json::Value, CoseError> {
+    use coset::cbor::Value;
+    Ok(match value {
+        Value::Null => serde_json::Value::Null,
+        Value::Bool(b) => b.into(),
+        Value::Integer(i) => match i64::try_from(i) {
+            Ok(i) => i.into(),
+            Err(_) => u64::try_from(i)
+                .map_err(|_| CoseError("integer out of range".into()))?
+                .into(),
+        },
+        Value::Float(f) => f.into(),
+        Value::Text(s) => s.into(),
+        Value::Bytes(bytes) => hex::encode(bytes).into(),
+        Value::Tag(_, value) => cbor_to_json(*value)?,
+        Value::Array(values) => values
+            .into_iter()
+            .map(cbor_to_json)
+            .collect::<Result<Vec<_>, _>>()?
+            .into(),
+        Value::Map(entries) => entries
+            .into_iter()
+            .map(|(key, value)| {
+                let key = match key {
+                    Value::Text(key) => key,
+                    Value::Integer(i) => i128::from(i).to_string(),
+                    _ => return Err(CoseError("map keys must be text or integers".into())),
+                };
+                Ok((key, cbor_to_json(value)?))
+            })
+            .collect::<Result<serde_json::Map<_, _>, _>>()?
+            .into(),
+        _ => return Err(CoseError("unsupported CBOR value".into())),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cose_sign1() {
+        use ed25519_dalek::{Signer, SigningKey, Verifier};
+
+        let signing_key = SigningKey::from_bytes(&[5; 32]);
+        let verifying_key = signing_key.verifying_key().to_bytes();
+        let mut cose = CoseSign1::new(&verifying_key, b"reading 21.5C".to_vec(), false);
+        let signature = signing_key.sign(&cose.to_be_signed(b"sensor"));
+        cose.set_signature(&signature.to_bytes());
+
+        let bytes = cose.to_vec();
+        // Tag 18, then a 4 element array whose protected header is {1: -8, 4: kid}
+        assert_eq!(&bytes[..2], [0xd2, 0x84]);
+        let parsed = CoseSign1::parse(&bytes).unwrap();
+        assert_eq!(parsed.to_vec(), bytes);
+        assert!(!parsed.cwt);
+        assert_eq!(parsed.payload(), b"reading 21.5C");
+        assert_eq!(parsed.key_id(), cose_key_thumbprint(&verifying_key));
+        let signature = ed25519_dalek::Signature::from_bytes(&parsed.signature().unwrap());
+        let key = signing_key.verifying_key();
+        assert!(
+            key.verify(&parsed.to_be_signed(b"sensor"), &signature)
+                .is_ok()
+        );
+        assert!(key.verify(&parsed.to_be_signed(b""), &signature).is_err());
+
+        let mut cwt = CoseSign1::new(&verifying_key, vec![0xa0], true);
+        cwt.set_signature(&[0; 64]);
+        let bytes = cwt.to_vec();
+        assert_eq!(&bytes[..3], [0xd8, 0x3d, 0xd2]);
+        assert!(CoseSign1::parse(&bytes).unwrap().cwt);
+
+        // Unsigned and truncated structures are refused.
+        let unsigned = CoseSign1::new(&verifying_key, vec![], false).to_vec();
+        assert!(CoseSign1::parse(&unsigned).is_err());
+        assert!(CoseSign1::parse(&cose.to_vec()[..20]).is_err());
+    }
+
+    #[test]
+    fn test_cose_keys() {
+        let key = [9; 32];
+        // The thumbprint is over the canonical encoding of the required parameters.
+        let mut required = coset::CoseKeyBuilder::new_okp_key()
+            .param(
+                coset::iana::OkpKeyParameter::X as i64,
+                coset::cbor::Value::Bytes(key.to_vec()),
+            )
+            .param(coset::iana::OkpKeyParameter::Crv as i64, 6.into())
+            .build();
+        required.canonicalize(coset::CborOrdering::Lexicographic);
+        let encoded = coset::CborSerializable::to_vec(required).unwrap();
+        assert_eq!(
+            cose_key_thumbprint(&key),
+            <[u8; 32]>::from(Sha256::digest(encoded))
+        );
+
+        let set = cose_key_set(&[key, [10; 32]]);
+        assert_eq!(parse_cose_keys(&set).unwrap(), vec![key, [10; 32]]);
+        let keys = <coset::CoseKeySet as coset::CborSerializable>::from_slice(&set).unwrap();
+        assert_eq!(keys.0[0].key_id, cose_key_thumbprint(&key));
+        let single = coset::CborSerializable::to_vec(keys.0[1].clone()).unwrap();
+        assert_eq!(parse_cose_keys(&single).unwrap(), vec![[10; 32]]);
+        assert!(parse_cose_keys(b"not cbor").is_err());
+    }
+
+    #[test]
+    fn test_cwt_claims() {
+        let claims = serde_json::json!({
+            "iss": "coap://as.example.com",
+            "sub": "erikw",
+            "aud": "coap://light.example.com",
+            "exp": 1444064944,
+            "nbf": 1443944944,
+            "iat": 1443944944,
+            "cti": "0b71",
+            "scope": ["read", "write"],
+        });
+        let claims = claims.as_object().unwrap();
+        let bytes = cwt_claims(claims).unwrap();
+        // RFC 8392, appendix A.1: registered claims use their integer keys.
+        assert!(bytes.starts_with(&[0xa8, 0x01, 0x75]));
+        assert_eq!(&parse_cwt_claims(&bytes).unwrap(), claims);
+
+        let bad = serde_json::json!({"exp": "tomorrow"});
+        assert!(cwt_claims(bad.as_object().unwrap()).is_err());
+        let bad = serde_json::json!({"cti": "not hex"});
+        assert!(cwt_claims(bad.as_object().unwrap()).is_err());
+    }
+}
@@ -0,0 +1,154 @@
+//! `did:key` identifiers, DID documents and Data Integrity proofs for verifiable credentials.
+
+use serde::{Deserialize, Serialize};
+
+use crate::RequestEnvelope;
+
+/// The cryptosuite of Data Integrity proofs made by the server
+pub const DATA_INTEGRITY_CRYPTOSUITE: &str = "eddsa-jcs-2022";
+
+/// The `did:key` identifier of `verifying_key`: the base58btc multibase encoding of the key with
+/// its `ed25519-pub` multicodec prefix
+pub fn did_key(verifying_key: &[u8; 32]) -> String {
+    format!("did:key:{}", ed25519_multibase(verifying_key))
+}
+
+// A key with its ed25519-pub multicodec prefix, base58btc multibase encoded
+fn ed25519_multibase(verifying_key: &[u8; 32]) -> String {
+    let mut bytes = vec![0xed, 0x01];
+    bytes.extend_from_slice(verifying_key);
+    format!("z{}", bs58::encode(bytes).into_string())
+}
+
+/// A DID document
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct DidDocument {
+    #[serde(rename = "@context")]
+    pub context: Vec<String>,
+    pub id: String,
+    pub verification_method: Vec<VerificationMethod>,
+    pub authentication: Vec<String>,
+    pub assertion_method: Vec<String>,
+    pub capability_invocation: Vec<String>,
+    pub capability_delegation: Vec<String>,
+}
+
+impl DidDocument {
+    /// The document a `did:key` resolver makes for `verifying_key`, whose single verification
+    /// method may be used for every relationship
+    pub fn new(verifying_key: &[u8; 32]) -> Self {
+        let method = VerificationMethod::new(verifying_key);
+        let methods = vec![method.id.clone()];
+        DidDocument {
+            context: vec![
+                "https://www.w3.org/ns/did/v1".to_string(),
+                "https://w3id.org/security/multikey/v1".to_string(),
+            ],
+            id: method.controller.clone(),
+            verification_method: vec![method],
+            authentication: methods.clone(),
+            assertion_method: methods.clone(),
+            capability_invocation: methods.clone(),
+            capability_delegation: methods,
+        }
+    }
+}
+
+/// An Ed25519 verification method of a DID document, as a Multikey
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct VerificationMethod {
+    /// The DID with the multibase key as fragment
+    pub id: String,
+    #[serde(rename = "type")]
+    pub method_type: String,
+    pub controller: String,
+    pub public_key_multibase: String,
+}
+
+impl VerificationMethod {
+    /// The verification method of the `did:key` of `verifying_key`
+    pub fn new(verifying_key: &[u8; 32]) -> Self {
+        let multibase = ed25519_multibase(verifying_key);
+        let did = format!("did:key:{multibase}");
+        VerificationMethod {
+            id: format!("{did}#{multibase}"),
+            method_type: "Multikey".to_string(),
+            controller: did,
+            public_key_multibase: multibase,
+        }
+    }
+}
+
+/// A Data Integrity proof (W3C VC Data Integrity) of a document
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct DataIntegrityProof {
+    /// The `@context` of the document, if it has one
+    #[serde(rename = "@context", default, skip_serializing_if = "Option::is_none")]
+    pub context: Option<serde_json::Value>,
+    /// Always `DataIntegrityProof`
+    #[serde(rename = "type")]
+    pub proof_type: String,
+    pub cryptosuite: String,
+    /// XML Schema `dateTime` in UTC
+    pub created: String,
+    /// Id of the signing key's verification method
+    pub verification_method: String,
+    pub proof_purpose: String,
+    /// Multibase encoded signature. Absent from the proof configuration that is signed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proof_value: Option<String>,
+}
+
+/// Request to add a Data Integrity proof by the user's key to a verifiable credential
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CredentialSignRequest {
+    pub user_id: String,
+    /// The unsecured credential. Its `issuer` should be the user's DID.
+    pub credential: serde_json::Map<String, serde_json::Value>,
+    /// Replay protection
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub envelope: Option<RequestEnvelope>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_did_key() {
+        // VC Data Integrity EdDSA Cryptosuites, test vector key
+        let key: [u8; 32] =
+            hex::decode("b00d8d938e7f773d51565aad36a623f5344f7f5d1960f9cf3e8e12620ea2810f")
+                .unwrap()
+                .try_into()
+                .unwrap();
+        let multibase = "z6MkrJVnaZkeFzdQyMZu1cgjg7k1pZZ6pvBQ7XJPt4swbTQ2";
+        assert_eq!(did_key(&key), format!("did:key:{multibase}"));
+
+        let document = serde_json::to_value(DidDocument::new(&key)).unwrap();
+        let method = format!("did:key:{multibase}#{multibase}");
+        assert_eq!(
+            document,
+            serde_json::json!({
+                "@context": [
+                    "https://www.w3.org/ns/did/v1",
+                    "https://w3id.org/security/multikey/v1",
+                ],
+                "id": format!("did:key:{multibase}"),
+                "verificationMethod": [{
+                    "id": method,
+                    "type": "Multikey",
+                    "controller": format!("did:key:{multibase}"),
+                    "publicKeyMultibase": multibase,
+                }],
+                "authentication": [method],
+                "assertionMethod": [method],
+                "capabilityInvocation": [method],
+                "capabilityDelegation": [method],
+            })
+        );
+    }
+}
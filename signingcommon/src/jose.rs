@@ -0,0 +1,158 @@
+//! JWS (RFC 7515) signing inputs and JSON Web Keys (RFC 7517, RFC 8037) for the JWTs the server
+//! issues.
+
+use base64::prelude::{BASE64_URL_SAFE_NO_PAD, Engine};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::RequestEnvelope;
+
+/// The JWS algorithm of Ed25519 signatures (RFC 8037)
+pub const JWS_ALGORITHM: &str = "EdDSA";
+
+/// Request to issue a JWT signed with the user's key
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct JwtSignRequest {
+    pub user_id: String,
+    /// The claims of the token, signed as given
+    pub claims: serde_json::Map<String, serde_json::Value>,
+    /// Replay protection
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub envelope: Option<RequestEnvelope>,
+}
+
+/// A JWT issued by a user
+#[derive(Debug, Serialize, Deserialize)]
+pub struct JwtSignResponse {
+    /// The token in the JWS compact serialization
+    pub token: String,
+    /// The `kid` in the token's header: the [`jwk_thumbprint`] of the key that signed it
+    pub kid: String,
+}
+
+/// The protected header of the JWTs the server issues
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct JwsHeader {
+    pub alg: String,
+    pub typ: String,
+    pub kid: String,
+}
+
+impl JwsHeader {
+    /// The header of a JWT signed by `verifying_key`
+    pub fn new(verifying_key: &[u8; 32]) -> Self {
+        JwsHeader {
+            alg: JWS_ALGORITHM.to_string(),
+            typ: "JWT".to_string(),
+            kid: jwk_thumbprint(verifying_key),
+        }
+    }
+}
+
+/// The JWS signing input of a token with `header` and `claims`: their base64url encoded JSON,
+/// joined by a dot. The signature over it is the third part of the compact serialization.
+pub fn jws_signing_input(
+    header: &JwsHeader,
+    claims: &serde_json::Map<String, serde_json::Value>,
+) -> String {
+    let header = serde_json::to_vec(header).expect("header serializes");
+    let claims = serde_json::to_vec(claims).expect("claims serialize");
+    format!(
+        "{}.{}",
+        BASE64_URL_SAFE_NO_PAD.encode(header),
+        BASE64_URL_SAFE_NO_PAD.encode(claims)
+    )
+}
+
+/// An Ed25519 public key as an OKP JSON Web Key (RFC 8037)
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Jwk {
+    pub kty: String,
+    pub crv: String,
+    /// Base64url encoded public key
+    pub x: String,
+    pub kid: String,
+    #[serde(rename = "use")]
+    pub key_use: String,
+    pub alg: String,
+}
+
+impl Jwk {
+    /// The signing JWK of `verifying_key`, identified by its thumbprint
+    pub fn new(verifying_key: &[u8; 32]) -> Self {
+        Jwk {
+            kty: "OKP".to_string(),
+            crv: "Ed25519".to_string(),
+            x: BASE64_URL_SAFE_NO_PAD.encode(verifying_key),
+            kid: jwk_thumbprint(verifying_key),
+            key_use: "sig".to_string(),
+            alg: JWS_ALGORITHM.to_string(),
+        }
+    }
+}
+
+/// The JWK thumbprint (RFC 7638) of `verifying_key`: the base64url encoded SHA-256 of its required
+/// JWK members, in lexicographic order and without whitespace
+pub fn jwk_thumbprint(verifying_key: &[u8; 32]) -> String {
+    let members = format!(
+        r#"{{"crv":"Ed25519","kty":"OKP","x":"{}"}}"#,
+        BASE64_URL_SAFE_NO_PAD.encode(verifying_key)
+    );
+    BASE64_URL_SAFE_NO_PAD.encode(Sha256::digest(members))
+}
+
+/// A JWK set, as served at a `jwks.json` URL
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct JwkSet {
+    pub keys: Vec<Jwk>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_jwk() {
+        // RFC 8037, appendix A.2 and A.3
+        let key: [u8; 32] = BASE64_URL_SAFE_NO_PAD
+            .decode("11qYAYKxCrfVS_7TyWQHOg7hcvPapiMlrwIaaPcHURo")
+            .unwrap()
+            .try_into()
+            .unwrap();
+        assert_eq!(
+            jwk_thumbprint(&key),
+            "kPrK_qmxVWaYVA9wwBF6Iuo3vVzz7TxHCTwXBygrS4k"
+        );
+
+        let jwk = serde_json::to_value(Jwk::new(&key)).unwrap();
+        assert_eq!(
+            jwk,
+            serde_json::json!({
+                "kty": "OKP",
+                "crv": "Ed25519",
+                "x": "11qYAYKxCrfVS_7TyWQHOg7hcvPapiMlrwIaaPcHURo",
+                "kid": "kPrK_qmxVWaYVA9wwBF6Iuo3vVzz7TxHCTwXBygrS4k",
+                "use": "sig",
+                "alg": "EdDSA",
+            })
+        );
+
+        let claims = serde_json::json!({"sub": "alice", "iat": 1700000000});
+        let input = jws_signing_input(&JwsHeader::new(&key), claims.as_object().unwrap());
+        let (header, payload) = input.split_once('.').unwrap();
+        let header: serde_json::Value =
+            serde_json::from_slice(&BASE64_URL_SAFE_NO_PAD.decode(header).unwrap()).unwrap();
+        assert_eq!(
+            header,
+            serde_json::json!({
+                "alg": "EdDSA",
+                "typ": "JWT",
+                "kid": "kPrK_qmxVWaYVA9wwBF6Iuo3vVzz7TxHCTwXBygrS4k",
+            })
+        );
+        assert_eq!(
+            BASE64_URL_SAFE_NO_PAD.decode(payload).unwrap(),
+            br#"{"iat":1700000000,"sub":"alice"}"#
+        );
+    }
+}
@@ -1,9 +1,146 @@
+use hmac::{Hmac, Mac};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256, Sha512};
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+mod cose;
+mod did;
+mod jose;
+mod minisign;
+mod ssh;
+
+pub use cose::*;
+pub use did::*;
+pub use jose::*;
+pub use minisign::*;
+pub use ssh::*;
+
+/// Seconds since the Unix epoch
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system clock is after 1970")
+        .as_secs()
+}
+
+/// Prefix of the domain separation envelope messages signed under a context are wrapped in
+pub const CONTEXT_ENVELOPE_PREFIX: &[u8] = b"wallet-poc/ctx\0";
+
+/// The bytes actually signed when `message` is signed under `context`: the
+/// [`CONTEXT_ENVELOPE_PREFIX`], the length of the context as a single byte, the context and
+/// finally the message. Verifiers must rebuild the envelope to check a signature.
+///
+/// Panics if the context is longer than 255 bytes.
+pub fn context_envelope(context: &str, message: &[u8]) -> Vec<u8> {
+    let context_len = u8::try_from(context.len()).expect("context is at most 255 bytes");
+    let mut envelope =
+        Vec::with_capacity(CONTEXT_ENVELOPE_PREFIX.len() + 1 + context.len() + message.len());
+    envelope.extend_from_slice(CONTEXT_ENVELOPE_PREFIX);
+    envelope.push(context_len);
+    envelope.extend_from_slice(context.as_bytes());
+    envelope.extend_from_slice(message);
+    envelope
+}
+
+/// Header carrying the server's detached signature over a response, hex encoded
+pub const RESPONSE_SIGNATURE_HEADER: &str = "X-Response-Signature";
+
+/// Header carrying a nonce chosen by the client, which the server includes in the signature over
+/// its response
+pub const REQUEST_NONCE_HEADER: &str = "X-Request-Nonce";
+
+/// Longest request nonce the server accepts. Nonces consist of printable ASCII characters.
+pub const MAX_REQUEST_NONCE_LEN: usize = 64;
+
+/// Prefix of the bytes the server signs for a response
+pub const RESPONSE_SIGNATURE_PREFIX: &[u8] = b"wallet-poc/response\0";
+
+/// The bytes the server signs for a response: the [`RESPONSE_SIGNATURE_PREFIX`], the path of the
+/// request, the request's nonce (empty if it had none) and the status code as a decimal number,
+/// each terminated by a NUL byte, and finally the body. Binding the path and status means a
+/// signed response cannot be passed off as the answer to a different endpoint, and binding the
+/// nonce that it cannot be replayed as the answer to a later request.
+pub fn response_signature_message(path: &str, nonce: &str, status: u16, body: &[u8]) -> Vec<u8> {
+    let mut message = statement(
+        RESPONSE_SIGNATURE_PREFIX,
+        [path.to_string(), nonce.to_string(), status.to_string()],
+    );
+    message.extend_from_slice(body);
+    message
+}
+
+/// Label of the key that authenticates a user's request envelopes
+pub const REQUEST_KEY_LABEL: &[u8] = b"wallet-poc/request-key";
+
+/// Prefix of the bytes a request envelope's MAC covers
+pub const REQUEST_MAC_PREFIX: &[u8] = b"wallet-poc/request\0";
+
+/// The key that authenticates a user's request envelopes: the HMAC-SHA256 of
+/// [`REQUEST_KEY_LABEL`] under the seed. The server keeps it from registration, so only holders
+/// of the seed can make envelopes it accepts.
+pub fn request_key(seed: &[u8]) -> [u8; 32] {
+    hmac_sha256(seed, REQUEST_KEY_LABEL)
+}
+
+/// The bytes a request envelope's MAC covers: the [`REQUEST_MAC_PREFIX`], the path of the request
+/// terminated by a NUL byte, and the request as JSON without the envelope's MAC. Covering the
+/// whole request binds the nonce to it, so an envelope cannot be moved to another request.
+pub fn request_mac_message(path: &str, request: &impl Serialize) -> Vec<u8> {
+    let mut request = serde_json::to_value(request).expect("requests serialize to JSON");
+    if let Some(envelope) = request
+        .get_mut("envelope")
+        .and_then(serde_json::Value::as_object_mut)
+    {
+        envelope.remove("mac");
+    }
+    let mut message = statement(REQUEST_MAC_PREFIX, [path.to_string()]);
+    message.extend(serde_json::to_vec(&request).expect("JSON values serialize"));
+    message
+}
+
+/// The MAC of a request envelope: the HMAC-SHA256 of `message`, see [`request_mac_message`],
+/// under the user's [`request_key`]
+pub fn request_mac(request_key: &[u8; 32], message: &[u8]) -> [u8; 32] {
+    hmac_sha256(request_key, message)
+}
+
+fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(message);
+    mac.finalize().into_bytes().into()
+}
+
+/// Header carrying a client chosen key that makes retried `/register` requests idempotent
+pub const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
 
 /// Request to register a new user and generate a signing key
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct RegisterRequest {
     pub seed: Vec<u8>,
+    /// Contexts the key may sign under. When empty the key may sign anything, including messages
+    /// without a context.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allowed_contexts: Vec<String>,
+    /// Derive the user ID from the verifying key, keyed with a server secret, instead of picking a
+    /// random one, so that registering the same seed again returns the same ID
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub deterministic_id: bool,
+    /// Forget the user after this many seconds, unless renewed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl_secs: Option<u64>,
+    /// Require approvals from other users before each signature
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub approval: Option<ApprovalPolicy>,
+}
+
+/// An m-of-n approver set: a signature is only released once `threshold` of the `approvers`
+/// approved it
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct ApprovalPolicy {
+    pub threshold: usize,
+    /// User IDs of the approvers. Approvals are signed with their registered keys.
+    pub approvers: Vec<String>,
 }
 
 /// Response after successful registration
@@ -11,6 +148,722 @@ pub struct RegisterRequest {
 pub struct RegisterResponse {
     pub user_id: String,
     pub verifying_key: String,
+    /// Unix timestamp after which the user is forgotten
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+    /// Status of the new user. Users registered on servers requiring approval cannot sign until
+    /// an operator approves them.
+    #[serde(default, skip_serializing_if = "UserStatus::is_active")]
+    pub status: UserStatus,
+    /// The verifying key as an OpenSSH public key line, for `authorized_keys` and allowed
+    /// signers files
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ssh_public_key: Option<String>,
+    /// Bech32 Cosmos account address of the user's secp256k1 key, with the server's prefix
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cosmos_address: Option<String>,
+    /// Bech32 `npub` public key of the user's secp256k1 key, which signs Nostr events
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nostr_public_key: Option<String>,
+    /// The `did:key` identifier of the verifying key
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub did: Option<String>,
+    /// The verifying key as a minisign public key, which is also its signify public key
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub minisign_public_key: Option<String>,
+}
+
+/// Status of a user. Only active users can sign.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum UserStatus {
+    #[default]
+    Active,
+    /// Suspended by an operator. The user keeps their key.
+    Frozen,
+    /// Registered, waiting for an operator to approve the registration
+    PendingApproval,
+}
+
+impl UserStatus {
+    pub fn is_active(&self) -> bool {
+        *self == UserStatus::Active
+    }
+}
+
+impl fmt::Display for UserStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            UserStatus::Active => "active",
+            UserStatus::Frozen => "frozen",
+            UserStatus::PendingApproval => "pending approval",
+        })
+    }
+}
+
+/// Request to extend the lifetime of a user. The seed proves ownership of the user.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RenewRequest {
+    pub user_id: String,
+    pub seed: Vec<u8>,
+    /// New lifetime, counted from now
+    pub ttl_secs: u64,
+}
+
+/// Response after renewing a user
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RenewResponse {
+    pub user_id: String,
+    pub expires_at: u64,
+}
+
+/// Prefix of the statement a [`RotationCertificate`] signs. The server refuses to sign plain
+/// messages starting with it, so a certificate cannot be forged through `/sign`.
+pub const ROTATION_STATEMENT_PREFIX: &[u8] = b"wallet-poc/rotate\0";
+
+/// Request to rotate a user's key to its next generation. The seed proves ownership of the user.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RotateRequest {
+    pub user_id: String,
+    pub seed: Vec<u8>,
+}
+
+/// Binds a user's new verifying key to the previous one, signed by the previous key
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct RotationCertificate {
+    pub user_id: String,
+    pub previous_generation: u32,
+    /// Hex encoded
+    pub previous_verifying_key: String,
+    pub generation: u32,
+    /// Hex encoded
+    pub verifying_key: String,
+    /// Unix timestamp of the rotation
+    pub issued_at: u64,
+    /// Hex encoded Ed25519 signature over [`RotationCertificate::statement`] by the previous key
+    pub signature: String,
+}
+
+impl RotationCertificate {
+    /// The bytes signed by the previous key: the [`ROTATION_STATEMENT_PREFIX`] followed by every
+    /// other field in declaration order, each terminated by a NUL byte.
+    pub fn statement(&self) -> Vec<u8> {
+        statement(
+            ROTATION_STATEMENT_PREFIX,
+            [
+                self.user_id.clone(),
+                self.previous_generation.to_string(),
+                self.previous_verifying_key.clone(),
+                self.generation.to_string(),
+                self.verifying_key.clone(),
+                self.issued_at.to_string(),
+            ],
+        )
+    }
+}
+
+// `prefix` followed by each field terminated by a NUL byte. None of the fields signed this way
+// (UUIDs, hex strings and numbers) can contain a NUL byte, so the encoding is unambiguous.
+fn statement(prefix: &[u8], fields: impl IntoIterator<Item = String>) -> Vec<u8> {
+    let mut statement = prefix.to_vec();
+    for field in fields {
+        statement.extend_from_slice(field.as_bytes());
+        statement.push(0);
+    }
+    statement
+}
+
+/// Prefix of the statement a [`RevocationStatement`] signs
+pub const REVOCATION_STATEMENT_PREFIX: &[u8] = b"wallet-poc/revoke\0";
+/// Prefix of the statement a [`RevocationList`] signs
+pub const REVOCATION_LIST_PREFIX: &[u8] = b"wallet-poc/revocations\0";
+
+/// Request to revoke all keys of a user, e.g. because the seed was compromised. The seed proves
+/// ownership of the user.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RevokeRequest {
+    pub user_id: String,
+    pub seed: Vec<u8>,
+}
+
+/// Statement by the server that a user's keys are revoked
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct RevocationStatement {
+    pub user_id: String,
+    /// Hex encoded verifying keys that must no longer be trusted
+    pub verifying_keys: Vec<String>,
+    /// Unix timestamp of the revocation
+    pub revoked_at: u64,
+    /// Hex encoded Ed25519 signature over [`RevocationStatement::statement`] by the server key
+    pub signature: String,
+}
+
+impl RevocationStatement {
+    /// The bytes signed by the server: the [`REVOCATION_STATEMENT_PREFIX`], the user ID, the
+    /// revocation time and the verifying keys, each terminated by a NUL byte.
+    pub fn statement(&self) -> Vec<u8> {
+        statement(
+            REVOCATION_STATEMENT_PREFIX,
+            [self.user_id.clone(), self.revoked_at.to_string()]
+                .into_iter()
+                .chain(self.verifying_keys.iter().cloned()),
+        )
+    }
+}
+
+/// A revoked key
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Revocation {
+    pub user_id: String,
+    /// Hex encoded
+    pub verifying_key: String,
+    /// Unix timestamp of the revocation
+    pub revoked_at: u64,
+}
+
+/// Every key revoked on the server, signed by the server key
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct RevocationList {
+    /// Unix timestamp the list was signed at
+    pub issued_at: u64,
+    pub revocations: Vec<Revocation>,
+    /// Hex encoded Ed25519 signature over [`RevocationList::statement`] by the server key
+    pub signature: String,
+}
+
+impl RevocationList {
+    /// The bytes signed by the server: the [`REVOCATION_LIST_PREFIX`], the issue time and the
+    /// user ID, verifying key and revocation time of every revocation, each terminated by a NUL
+    /// byte.
+    pub fn statement(&self) -> Vec<u8> {
+        statement(
+            REVOCATION_LIST_PREFIX,
+            std::iter::once(self.issued_at.to_string()).chain(self.revocations.iter().flat_map(
+                |revocation| {
+                    [
+                        revocation.user_id.clone(),
+                        revocation.verifying_key.clone(),
+                        revocation.revoked_at.to_string(),
+                    ]
+                },
+            )),
+        )
+    }
+
+    /// The revocation of a hex encoded verifying key, if it is revoked
+    pub fn find(&self, verifying_key: &str) -> Option<&Revocation> {
+        self.revocations
+            .iter()
+            .find(|revocation| revocation.verifying_key.eq_ignore_ascii_case(verifying_key))
+    }
+}
+
+/// The server's own verifying key, which signs revocations
+#[derive(Debug, Serialize, Deserialize)]
+pub struct IdentityResponse {
+    /// Hex encoded
+    pub verifying_key: String,
+}
+
+/// Response after rotating a user's key
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RotateResponse {
+    pub user_id: String,
+    pub generation: u32,
+    /// Hex encoded verifying key of the new generation
+    pub verifying_key: String,
+    /// Unix timestamp until which the previous key remains listed for verification
+    pub previous_valid_until: u64,
+    pub certificate: RotationCertificate,
+}
+
+/// A verifying key of a user
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct KeyInfo {
+    pub generation: u32,
+    /// Hex encoded
+    pub verifying_key: String,
+    /// For retired keys, the Unix timestamp until which signatures by the key should still be
+    /// accepted. Absent for the current key.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub valid_until: Option<u64>,
+}
+
+/// The verifying keys of a user, current key first
+#[derive(Debug, Serialize, Deserialize)]
+pub struct KeysResponse {
+    pub user_id: String,
+    pub keys: Vec<KeyInfo>,
+}
+
+/// Prefix of the statement a mock [`Quote`] signs
+pub const QUOTE_STATEMENT_PREFIX: &[u8] = b"wallet-poc/quote\0";
+/// Prefix of the data bound into a quote's report data
+pub const ATTESTATION_REPORT_PREFIX: &[u8] = b"wallet-poc/attestation\0";
+/// Length of the client chosen nonce that makes a quote fresh, in bytes
+pub const ATTESTATION_NONCE_LEN: usize = 32;
+
+/// The 64 bytes of report data a quote binds: the SHA-512 of the [`ATTESTATION_REPORT_PREFIX`],
+/// the SHA-256 of the server's TLS certificate (DER), the server's identity key and the client's
+/// nonce. A quote over it shows the attested binary holds this TLS certificate and identity key.
+pub fn attestation_report_data(
+    tls_certificate_sha256: &[u8],
+    server_key: &[u8],
+    nonce: &[u8],
+) -> [u8; 64] {
+    Sha512::new()
+        .chain_update(ATTESTATION_REPORT_PREFIX)
+        .chain_update(tls_certificate_sha256)
+        .chain_update(server_key)
+        .chain_update(nonce)
+        .finalize()
+        .into()
+}
+
+/// Query parameters of `/attestation`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AttestationParams {
+    /// Hex encoded, [`ATTESTATION_NONCE_LEN`] bytes
+    pub nonce: String,
+}
+
+/// Evidence, signed by the attestation provider, of the binary a server runs
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Quote {
+    /// Name of the attestation provider that produced the quote
+    pub provider: String,
+    /// Hex encoded measurement of the server binary
+    pub measurement: String,
+    /// Hex encoded, see [`attestation_report_data`]
+    pub report_data: String,
+    /// Hex encoded key of the provider that signed the quote
+    pub attestation_key: String,
+    /// Hex encoded signature by the attestation key over [`Quote::statement`]
+    pub signature: String,
+}
+
+impl Quote {
+    /// The bytes signed by the attestation key: the [`QUOTE_STATEMENT_PREFIX`] followed by the
+    /// provider, measurement, report data and attestation key, each terminated by a NUL byte.
+    pub fn statement(&self) -> Vec<u8> {
+        statement(
+            QUOTE_STATEMENT_PREFIX,
+            [
+                self.provider.clone(),
+                self.measurement.clone(),
+                self.report_data.clone(),
+                self.attestation_key.clone(),
+            ],
+        )
+    }
+}
+
+/// Response of `/attestation`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AttestationResponse {
+    pub quote: Quote,
+    /// Hex encoded SHA-256 of the server's TLS certificate (DER)
+    pub tls_certificate_sha256: String,
+    /// Hex encoded identity key of the server
+    pub server_key: String,
+}
+
+/// What a client expects of an attested server
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct AttestationPolicy {
+    /// Name of the attestation provider quotes must come from
+    pub provider: String,
+    /// Hex encoded key quotes must be signed with
+    pub attestation_key: String,
+    /// Hex encoded measurements of trusted server binaries
+    pub measurements: Vec<String>,
+}
+
+/// Prefix of the statement an [`IdentityRotation`] signs
+pub const IDENTITY_ROTATION_PREFIX: &[u8] = b"wallet-poc/identity-rotate\0";
+
+/// Binds a new server identity key to the previous one, signed by the previous key
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct IdentityRotation {
+    pub previous_generation: u32,
+    /// Hex encoded
+    pub previous_verifying_key: String,
+    pub generation: u32,
+    /// Hex encoded
+    pub verifying_key: String,
+    /// Hex encoded Ed25519 signature over [`IdentityRotation::statement`] by the previous key
+    pub signature: String,
+}
+
+impl IdentityRotation {
+    /// The bytes signed by the previous key: the [`IDENTITY_ROTATION_PREFIX`] followed by every
+    /// other field in declaration order, each terminated by a NUL byte.
+    pub fn statement(&self) -> Vec<u8> {
+        statement(
+            IDENTITY_ROTATION_PREFIX,
+            [
+                self.previous_generation.to_string(),
+                self.previous_verifying_key.clone(),
+                self.generation.to_string(),
+                self.verifying_key.clone(),
+            ],
+        )
+    }
+}
+
+/// A user, as seen by operators
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct AdminUser {
+    pub user_id: String,
+    /// Types of the user's keys: the Ed25519 signing key and the secp256k1 key derived from it
+    pub key_types: Vec<String>,
+    /// Unix timestamp of the registration
+    pub created_at: u64,
+    #[serde(default)]
+    pub status: UserStatus,
+}
+
+/// All users on the server
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AdminUsersResponse {
+    pub users: Vec<AdminUser>,
+}
+
+/// Capacity statistics
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AdminStatsResponse {
+    pub users: usize,
+    pub capacity: usize,
+    /// Number of users in each shard
+    pub shard_usage: Vec<usize>,
+    pub shard_capacity: usize,
+    pub revoked_keys: usize,
+    pub read_only: bool,
+}
+
+/// Request to enter or leave read-only mode
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReadOnlyRequest {
+    pub read_only: bool,
+}
+
+/// Outcome of an admin operation
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AdminResponse {
+    pub message: String,
+}
+
+/// Prefix of the statements approvers sign to approve a pending signature. The server signs no
+/// other message starting with it, so approvals can only be made through `/approve`.
+pub const APPROVAL_STATEMENT_PREFIX: &[u8] = b"wallet-poc/approve\0";
+
+/// A signature waiting for approvals, returned by `/sign` with `202 Accepted` for users with an
+/// [`ApprovalPolicy`]
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PendingSignature {
+    pub request_id: String,
+    pub user_id: String,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub context: Option<String>,
+    /// Unix timestamp after which the request is dropped
+    pub expires_at: u64,
+    pub threshold: usize,
+    pub approvers: Vec<String>,
+    /// Approvers who approved so far
+    pub approved_by: Vec<String>,
+    /// Their approvals: signatures by their keys over [`statement`](Self::statement), hex
+    /// encoded, in the order of `approved_by`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub approvals: Vec<String>,
+    /// The signature, once the quorum is met
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+}
+
+impl PendingSignature {
+    /// The bytes an approval signs: the [`APPROVAL_STATEMENT_PREFIX`], the request ID, the user
+    /// ID, the context (empty if none) and the hex encoded SHA-256 of the message, each followed by
+    /// a NUL byte
+    pub fn statement(&self) -> Vec<u8> {
+        statement(
+            APPROVAL_STATEMENT_PREFIX,
+            [
+                self.request_id.clone(),
+                self.user_id.clone(),
+                self.context.clone().unwrap_or_default(),
+                hex::encode(Sha256::digest(self.message.as_bytes())),
+            ],
+        )
+    }
+}
+
+/// Pending signatures of, or awaiting approval by, a user
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PendingResponse {
+    pub requests: Vec<PendingSignature>,
+}
+
+/// Query parameters of `/pending/{request_id}` and `/users/{user_id}/pending`: a replay protection
+/// envelope of the reading user, who must be the requester or one of the approvers
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct PendingQuery {
+    /// The reading user
+    pub user_id: String,
+    /// Nonce of the envelope, which query parameters cannot nest
+    pub nonce: String,
+    /// Expiry time of the envelope
+    pub expires_at: u64,
+    /// MAC of the envelope, see [`PendingQuery::mac_message`]
+    pub mac: String,
+}
+
+impl PendingQuery {
+    /// The replay protection envelope given in the parameters
+    pub fn envelope(&self) -> RequestEnvelope {
+        RequestEnvelope {
+            nonce: self.nonce.clone(),
+            expires_at: self.expires_at,
+            mac: self.mac.clone(),
+        }
+    }
+
+    /// The bytes the envelope's MAC covers: the [`request_mac_message`] of the parameters without
+    /// the MAC, for the `path` read
+    pub fn mac_message(&self, path: &str) -> Vec<u8> {
+        let query = PendingQuery {
+            mac: String::new(),
+            ..self.clone()
+        };
+        request_mac_message(path, &query)
+    }
+}
+
+/// An approval of a pending signature. The seed proves ownership of the approver, whose key then
+/// signs the request's [`PendingSignature::statement`].
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ApproveRequest {
+    pub approver_id: String,
+    pub seed: Vec<u8>,
+}
+
+/// Request to sign a serialized Solana transaction message, legacy or v0
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SolanaSignRequest {
+    pub user_id: String,
+    /// The serialized message, base64 encoded
+    pub message: String,
+    /// Replay protection
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub envelope: Option<RequestEnvelope>,
+}
+
+/// A signed Solana transaction message, with what the server understood it to be
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SolanaSignResponse {
+    /// Base58 encoded signature
+    pub signature: String,
+    /// Base58 encoded public key of the signer
+    pub signer: String,
+    pub message: SolanaMessageInfo,
+}
+
+/// A parsed Solana transaction message. Public keys and instruction data are base58 encoded.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SolanaMessageInfo {
+    /// "legacy" or "0"
+    pub version: String,
+    pub recent_blockhash: String,
+    pub accounts: Vec<SolanaAccount>,
+    pub instructions: Vec<SolanaInstruction>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub address_table_lookups: Vec<SolanaAddressTableLookup>,
+}
+
+/// An account listed in a Solana message
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SolanaAccount {
+    pub pubkey: String,
+    pub signer: bool,
+    pub writable: bool,
+}
+
+/// An instruction of a Solana message. Accounts loaded from address lookup tables are shown as
+/// `<table>[<index>]`, as resolving them needs chain state.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SolanaInstruction {
+    pub program_id: String,
+    pub accounts: Vec<String>,
+    pub data: String,
+}
+
+/// Accounts a v0 message loads from an address lookup table
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SolanaAddressTableLookup {
+    pub account_key: String,
+    pub writable_indexes: Vec<u8>,
+    pub readonly_indexes: Vec<u8>,
+}
+
+/// Request to sign the inputs of a Bitcoin PSBT (BIP-174) that spend the user's keys
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PsbtSignRequest {
+    pub user_id: String,
+    /// The PSBT, base64 encoded
+    pub psbt: String,
+    /// Replay protection
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub envelope: Option<RequestEnvelope>,
+}
+
+/// A PSBT with the user's partial signatures added
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PsbtSignResponse {
+    /// The updated PSBT, base64 encoded
+    pub psbt: String,
+    /// Indexes of the inputs that were signed
+    pub signed_inputs: Vec<usize>,
+    /// The user's Bitcoin keys
+    #[serde(flatten)]
+    pub keys: BitcoinKeys,
+}
+
+/// How a Cosmos SDK transaction is serialized for signing
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CosmosSignMode {
+    /// A legacy Amino JSON `StdSignDoc`
+    AminoJson,
+    /// A Protobuf `SignDoc` (SIGN_MODE_DIRECT)
+    Direct,
+}
+
+/// Request to sign a Cosmos SDK transaction with the user's secp256k1 key
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CosmosSignRequest {
+    pub user_id: String,
+    pub mode: CosmosSignMode,
+    /// For Amino JSON, the sign doc as JSON text. The server signs its canonical form, so key
+    /// order and whitespace do not matter. For direct mode, the serialized `SignDoc`, base64
+    /// encoded.
+    pub sign_doc: String,
+    /// Replay protection
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub envelope: Option<RequestEnvelope>,
+}
+
+/// A signed Cosmos SDK transaction, with what the server understood it to be
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CosmosSignResponse {
+    /// Base64 encoded 64 byte signature (r || s, low S)
+    pub signature: String,
+    /// Base64 encoded compressed public key, as in a `secp256k1.PubKey`
+    pub public_key: String,
+    /// Bech32 account address of the key
+    pub address: String,
+    pub sign_doc: CosmosSignDocInfo,
+}
+
+/// A parsed Cosmos SDK sign doc
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct CosmosSignDocInfo {
+    pub mode: CosmosSignMode,
+    pub chain_id: String,
+    pub account_number: u64,
+    /// Sequence of the signer. Only Amino JSON sign docs carry it at the top level.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sequence: Option<u64>,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub memo: String,
+    /// Amino types or Protobuf type URLs of the messages
+    pub messages: Vec<String>,
+}
+
+/// The secp256k1 keys of a user, which sign Bitcoin transactions. Both are hex encoded.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct BitcoinKeys {
+    /// Compressed public key, for legacy and SegWit v0 outputs
+    pub public_key: String,
+    /// X-only internal key, for Taproot outputs. The output key is this key tweaked with the
+    /// output's script tree, or with none (BIP-86).
+    pub x_only_public_key: String,
+}
+
+/// An unsigned Nostr event (NIP-01)
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct UnsignedNostrEvent {
+    /// Hex encoded x-only public key of the author. The server fills in the user's key if absent,
+    /// and refuses events by other keys.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pubkey: Option<String>,
+    /// Unix timestamp in seconds
+    pub created_at: u64,
+    pub kind: u16,
+    pub tags: Vec<Vec<String>>,
+    pub content: String,
+}
+
+/// A signed Nostr event (NIP-01), ready to publish
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct NostrEvent {
+    /// Hex encoded [`nostr_event_id`]
+    pub id: String,
+    pub pubkey: String,
+    pub created_at: u64,
+    pub kind: u16,
+    pub tags: Vec<Vec<String>>,
+    pub content: String,
+    /// Hex encoded BIP-340 Schnorr signature of the id
+    pub sig: String,
+}
+
+/// The id of a Nostr event by `pubkey` (hex): the SHA-256 of the JSON array
+/// `[0, pubkey, created_at, kind, tags, content]` without whitespace
+pub fn nostr_event_id(
+    pubkey: &str,
+    created_at: u64,
+    kind: u16,
+    tags: &[Vec<String>],
+    content: &str,
+) -> [u8; 32] {
+    let serialized = serde_json::to_vec(&(0, pubkey, created_at, kind, tags, content))
+        .expect("event serializes");
+    Sha256::digest(serialized).into()
+}
+
+/// Request to sign a Nostr event with the user's secp256k1 key
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct NostrSignRequest {
+    pub user_id: String,
+    pub event: UnsignedNostrEvent,
+    /// Replay protection
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub envelope: Option<RequestEnvelope>,
+}
+
+/// What `/sign` returns
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SignFormat {
+    /// The bare signature
+    #[default]
+    Raw,
+    /// A COSE_Sign1 (RFC 9052) with the message as its payload
+    CoseSign1,
+    /// A CWT (RFC 8392) with the claims in the message, a JSON object (see [`cwt_claims`])
+    Cwt,
+    /// A minisign signature file (see [`minisig`]). The message is the hex encoded BLAKE2b-512
+    /// hash of the file.
+    Minisign,
+    /// An OpenBSD signify signature file (see [`signify_signature`]). The message is the file,
+    /// base64 encoded.
+    Signify,
+}
+
+impl SignFormat {
+    pub fn is_raw(&self) -> bool {
+        *self == SignFormat::Raw
+    }
 }
 
 /// Request to sign a message
@@ -18,12 +871,86 @@ pub struct RegisterResponse {
 pub struct SignRequest {
     pub user_id: String,
     pub message: String,
+    /// Sign the message wrapped in a [`context_envelope`] for this context
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub context: Option<String>,
+    /// Replay protection
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub envelope: Option<RequestEnvelope>,
+    /// What to return besides the bare signature: a COSE_Sign1 or a CWT, in `cose`, whose external
+    /// AAD is the context, if any, rather than an envelope; or a minisign or signify signature
+    /// file, in `signature_file`, which cannot have a context.
+    #[serde(default, skip_serializing_if = "SignFormat::is_raw")]
+    pub format: SignFormat,
+    /// Trusted comment of a minisign signature, a single line. Defaults to the time of signing.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trusted_comment: Option<String>,
+}
+
+/// Replay protection for a request: the server rejects requests whose nonce it has seen before,
+/// requests past their expiry time and envelopes not authenticated by the user's request key
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RequestEnvelope {
+    /// Random 16 byte nonce, hex encoded
+    pub nonce: String,
+    /// Unix timestamp (seconds) after which the request is no longer valid
+    pub expires_at: u64,
+    /// MAC of the request, hex encoded, see [`request_mac`]
+    pub mac: String,
 }
 
 /// Response with the signature
 #[derive(Debug, Serialize, Deserialize)]
 pub struct SignResponse {
     pub signature: String,
+    /// Base64 encoded COSE_Sign1 or CWT, for the COSE formats
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cose: Option<String>,
+    /// The signature file, for the minisign and signify formats
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature_file: Option<String>,
+}
+
+/// Ed25519ph context used by `/sign/stream` when the client does not supply one
+pub const DEFAULT_STREAM_CONTEXT: &str = "wallet-poc/stream";
+
+/// Query parameters for `/sign/stream`. The message itself is streamed as the request body and
+/// signed with Ed25519ph (SHA-512 prehash) under `context`, or [`DEFAULT_STREAM_CONTEXT`].
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct StreamSignParams {
+    pub user_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub context: Option<String>,
+    /// Nonce of the replay protection envelope, which query parameters cannot nest
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<String>,
+    /// Expiry time of the replay protection envelope
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+    /// MAC of the replay protection envelope, see [`StreamSignParams::mac_message`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mac: Option<String>,
+}
+
+impl StreamSignParams {
+    /// The replay protection envelope given in the parameters, if any
+    pub fn envelope(&self) -> Option<RequestEnvelope> {
+        Some(RequestEnvelope {
+            nonce: self.nonce.clone()?,
+            expires_at: self.expires_at?,
+            mac: self.mac.clone().unwrap_or_default(),
+        })
+    }
+
+    /// The bytes the envelope's MAC covers: the [`request_mac_message`] of the parameters without
+    /// the MAC and of the SHA-512 of the streamed message
+    pub fn mac_message(&self, sha512: &[u8]) -> Vec<u8> {
+        let params = StreamSignParams {
+            mac: None,
+            ..self.clone()
+        };
+        request_mac_message("/sign/stream", &(params, hex::encode(sha512)))
+    }
 }
 
 /// Request to forget a user
@@ -52,6 +979,7 @@ mod tests {
     fn test_register_request_serialization() {
         let req = RegisterRequest {
             seed: vec![1, 2, 3],
+            ..Default::default()
         };
         let json = serde_json::to_string(&req).unwrap();
         assert!(json.contains("\"seed\""));
@@ -64,15 +992,49 @@ mod tests {
         assert_eq!(req.seed, vec![1, 2, 3]);
     }
 
+    #[test]
+    fn test_register_request_defaults() {
+        let req = RegisterRequest {
+            seed: vec![1],
+            ..Default::default()
+        };
+        assert_eq!(serde_json::to_string(&req).unwrap(), r#"{"seed":[1]}"#);
+
+        let json = r#"{"seed":[1],"allowed_contexts":["app"],"deterministic_id":true}"#;
+        let req: RegisterRequest = serde_json::from_str(json).unwrap();
+        assert_eq!(req.allowed_contexts, vec!["app"]);
+        assert!(req.deterministic_id);
+    }
+
     #[test]
     fn test_register_response_serialization() {
         let resp = RegisterResponse {
             user_id: "123".to_string(),
             verifying_key: "abc".to_string(),
+            expires_at: None,
+            status: UserStatus::Active,
+            ssh_public_key: None,
+            cosmos_address: None,
+            nostr_public_key: None,
+            did: None,
+            minisign_public_key: None,
         };
         let json = serde_json::to_string(&resp).unwrap();
         assert!(json.contains("\"user_id\":\"123\""));
         assert!(json.contains("\"verifying_key\":\"abc\""));
+        assert!(!json.contains("status"));
+    }
+
+    #[test]
+    fn test_user_status_serialization() {
+        assert_eq!(
+            serde_json::to_string(&UserStatus::PendingApproval).unwrap(),
+            r#""pending_approval""#
+        );
+        let resp: RegisterResponse =
+            serde_json::from_str(r#"{"user_id":"1","verifying_key":"ab","status":"frozen"}"#)
+                .unwrap();
+        assert_eq!(resp.status, UserStatus::Frozen);
     }
 
     #[test]
@@ -83,11 +1045,159 @@ mod tests {
         assert_eq!(resp.verifying_key, "xyz");
     }
 
+    #[test]
+    fn test_renew_request_deserialization() {
+        let json = r#"{"user_id":"u","seed":[1,2],"ttl_secs":60}"#;
+        let req: RenewRequest = serde_json::from_str(json).unwrap();
+        assert_eq!(req.seed, vec![1, 2]);
+        assert_eq!(req.ttl_secs, 60);
+    }
+
+    #[test]
+    fn test_rotation_statement() {
+        let certificate = RotationCertificate {
+            user_id: "u".into(),
+            previous_generation: 0,
+            previous_verifying_key: "aa".into(),
+            generation: 1,
+            verifying_key: "bb".into(),
+            issued_at: 42,
+            signature: "cc".into(),
+        };
+        assert_eq!(
+            certificate.statement(),
+            b"wallet-poc/rotate\0u\x000\0aa\x001\0bb\x0042\0"
+        );
+    }
+
+    #[test]
+    fn test_response_signature_message() {
+        assert_eq!(
+            response_signature_message("/register", "n1", 201, b"{}"),
+            b"wallet-poc/response\0/register\0n1\x00201\0{}"
+        );
+    }
+
+    #[test]
+    fn test_request_mac_message() {
+        let mut req = PsbtSignRequest {
+            user_id: "u".into(),
+            psbt: "cHNidP8=".into(),
+            envelope: Some(RequestEnvelope {
+                nonce: "00".into(),
+                expires_at: 7,
+                mac: String::new(),
+            }),
+        };
+        let message = request_mac_message("/sign/psbt", &req);
+        let prefix = b"wallet-poc/request\0/sign/psbt\0";
+        assert_eq!(message[..prefix.len()], prefix[..]);
+        let json: serde_json::Value = serde_json::from_slice(&message[prefix.len()..]).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "user_id": "u",
+                "psbt": "cHNidP8=",
+                "envelope": {"nonce": "00", "expires_at": 7}
+            })
+        );
+
+        // The MAC itself is not covered, everything else is.
+        req.envelope.as_mut().unwrap().mac = "ff".into();
+        assert_eq!(request_mac_message("/sign/psbt", &req), message);
+        assert_ne!(request_mac_message("/sign/solana", &req), message);
+        req.envelope.as_mut().unwrap().nonce = "01".into();
+        assert_ne!(request_mac_message("/sign/psbt", &req), message);
+    }
+
+    #[test]
+    fn test_revocation_list_statement() {
+        let list = RevocationList {
+            issued_at: 7,
+            revocations: vec![Revocation {
+                user_id: "u".into(),
+                verifying_key: "AA".into(),
+                revoked_at: 5,
+            }],
+            signature: String::new(),
+        };
+        assert_eq!(
+            list.statement(),
+            b"wallet-poc/revocations\x007\0u\0AA\x005\0"
+        );
+        assert!(list.find("aa").is_some());
+        assert!(list.find("bb").is_none());
+    }
+
+    #[test]
+    fn test_attestation_report_data_binds_inputs() {
+        let report_data = attestation_report_data(&[1; 32], &[2; 32], &[3; 32]);
+        assert_ne!(
+            report_data,
+            attestation_report_data(&[1; 32], &[2; 32], &[4; 32])
+        );
+        assert_ne!(
+            report_data,
+            attestation_report_data(&[9; 32], &[2; 32], &[3; 32])
+        );
+    }
+
+    #[test]
+    fn test_admin_stats_serialization() {
+        let stats = AdminStatsResponse {
+            users: 1,
+            capacity: 2,
+            shard_usage: vec![1, 0],
+            shard_capacity: 1,
+            revoked_keys: 0,
+            read_only: true,
+        };
+        let json = serde_json::to_string(&stats).unwrap();
+        let parsed: AdminStatsResponse = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.shard_usage, vec![1, 0]);
+        assert!(parsed.read_only);
+    }
+
+    #[test]
+    fn test_psbt_sign_response_serialization() {
+        let response = PsbtSignResponse {
+            psbt: "cHNidP8=".into(),
+            signed_inputs: vec![0, 2],
+            keys: BitcoinKeys {
+                public_key: "02aa".into(),
+                x_only_public_key: "aa".into(),
+            },
+        };
+        let json = serde_json::to_value(&response).unwrap();
+        assert_eq!(json["public_key"], "02aa");
+        assert_eq!(json["x_only_public_key"], "aa");
+        let parsed: PsbtSignResponse = serde_json::from_value(json).unwrap();
+        assert_eq!(parsed.signed_inputs, vec![0, 2]);
+        assert_eq!(parsed.keys, response.keys);
+    }
+
+    #[test]
+    fn test_key_info_serialization() {
+        let current = KeyInfo {
+            generation: 1,
+            verifying_key: "bb".into(),
+            valid_until: None,
+        };
+        assert_eq!(
+            serde_json::to_string(&current).unwrap(),
+            r#"{"generation":1,"verifying_key":"bb"}"#
+        );
+    }
+
     #[test]
     fn test_sign_request_serialization() {
         let req = SignRequest {
             user_id: "user1".to_string(),
             message: "hello".to_string(),
+            context: None,
+            envelope: None,
+            format: SignFormat::Raw,
+            trusted_comment: None,
         };
         let json = serde_json::to_string(&req).unwrap();
         assert!(json.contains("\"user_id\":\"user1\""));
@@ -102,10 +1212,40 @@ mod tests {
         assert_eq!(req.message, "world");
     }
 
+    #[test]
+    fn test_sign_request_with_context() {
+        let json = r#"{"user_id":"user2","message":"world","context":"app"}"#;
+        let req: SignRequest = serde_json::from_str(json).unwrap();
+        assert_eq!(req.context.as_deref(), Some("app"));
+    }
+
+    #[test]
+    fn test_sign_request_with_envelope() {
+        let json = r#"{"user_id":"u","message":"m","envelope":{"nonce":"00ff","expires_at":42,"mac":"ab"}}"#;
+        let req: SignRequest = serde_json::from_str(json).unwrap();
+        let envelope = req.envelope.unwrap();
+        assert_eq!(envelope.nonce, "00ff");
+        assert_eq!(envelope.expires_at, 42);
+        assert_eq!(envelope.mac, "ab");
+    }
+
+    #[test]
+    fn test_context_envelope() {
+        let envelope = context_envelope("app", b"msg");
+        assert_eq!(envelope, b"wallet-poc/ctx\0\x03appmsg");
+        // The context length prevents ambiguity between context and message.
+        assert_ne!(
+            context_envelope("ap", b"pmsg"),
+            context_envelope("app", b"msg")
+        );
+    }
+
     #[test]
     fn test_sign_response_serialization() {
         let resp = SignResponse {
             signature: "sig123".to_string(),
+            cose: None,
+            signature_file: None,
         };
         let json = serde_json::to_string(&resp).unwrap();
         assert_eq!(json, r#"{"signature":"sig123"}"#);
@@ -118,6 +1258,35 @@ mod tests {
         assert_eq!(resp.signature, "sig456");
     }
 
+    #[test]
+    fn test_stream_sign_params_serialization() {
+        let params = StreamSignParams {
+            user_id: "user5".to_string(),
+            ..Default::default()
+        };
+        let json = serde_json::to_string(&params).unwrap();
+        assert_eq!(json, r#"{"user_id":"user5"}"#);
+        assert!(params.envelope().is_none());
+
+        let params: StreamSignParams = serde_json::from_str(
+            r#"{"user_id":"user6","context":"ctx","nonce":"00","expires_at":7,"mac":"ff"}"#,
+        )
+        .unwrap();
+        assert_eq!(params.context.as_deref(), Some("ctx"));
+        let envelope = params.envelope().unwrap();
+        assert_eq!((envelope.nonce.as_str(), envelope.expires_at), ("00", 7));
+        // The MAC covers the digest of the message, but not itself.
+        assert_eq!(
+            params.mac_message(&[1; 64]),
+            StreamSignParams {
+                mac: None,
+                ..params.clone()
+            }
+            .mac_message(&[1; 64])
+        );
+        assert_ne!(params.mac_message(&[1; 64]), params.mac_message(&[2; 64]));
+    }
+
     #[test]
     fn test_forget_request_serialization() {
         let req = ForgetRequest {
@@ -170,6 +1339,7 @@ mod tests {
     fn test_register_request_clone() {
         let req1 = RegisterRequest {
             seed: vec![1, 2, 3],
+            ..Default::default()
         };
         let req2 = req1.clone();
         assert_eq!(req1.seed, req2.seed);
@@ -180,6 +1350,10 @@ mod tests {
         let req1 = SignRequest {
             user_id: "id".to_string(),
             message: "msg".to_string(),
+            context: None,
+            envelope: None,
+            format: SignFormat::Raw,
+            trusted_comment: None,
         };
         let req2 = req1.clone();
         assert_eq!(req1.user_id, req2.user_id);
@@ -190,6 +1364,7 @@ mod tests {
     fn test_register_request_debug() {
         let req = RegisterRequest {
             seed: vec![1, 2, 3],
+            ..Default::default()
         };
         let debug_str = format!("{:?}", req);
         assert!(debug_str.contains("RegisterRequest"));
@@ -200,6 +1375,10 @@ mod tests {
         let req = SignRequest {
             user_id: "user".to_string(),
             message: "msg".to_string(),
+            context: None,
+            envelope: None,
+            format: SignFormat::Raw,
+            trusted_comment: None,
         };
         let debug_str = format!("{:?}", req);
         assert!(debug_str.contains("SignRequest"));
@@ -214,4 +1393,43 @@ mod tests {
         assert!(debug_str.contains("ErrorResponse"));
         assert!(debug_str.contains("test error"));
     }
+
+    #[test]
+    fn test_sign_request_format() {
+        let json = r#"{"user_id":"u","message":"m","format":"cose_sign1"}"#;
+        let req: SignRequest = serde_json::from_str(json).unwrap();
+        assert_eq!(req.format, SignFormat::CoseSign1);
+        let req: SignRequest = serde_json::from_str(r#"{"user_id":"u","message":"m"}"#).unwrap();
+        assert!(req.format.is_raw());
+        assert!(!serde_json::to_string(&req).unwrap().contains("format"));
+    }
+
+    #[test]
+    fn test_nostr_event_id() {
+        let pubkey = "3bf0c63fcb93463407af97a5e5ee64fa883d107ef9e558472c4eb9aaaefa459d";
+        let tags = vec![
+            vec![
+                "e".to_string(),
+                "5c83da77af1dec6d7289834998ad7aafbd9e2191396d75ec3cc27f5a77226f36".to_string(),
+            ],
+            vec!["p".to_string(), pubkey.to_string()],
+        ];
+        // Quotes, backslashes and newlines are escaped; other characters, even non-ASCII ones and
+        // slashes, are not.
+        let id = nostr_event_id(pubkey, 1700000000, 1, &tags, "gm \"nostr\"\nünïcödé ☕/\\");
+        assert_eq!(
+            hex::encode(id),
+            "04be0f714831f63841644690e15849474459a29aaf068346cac96ae794f40b08"
+        );
+
+        let event: UnsignedNostrEvent =
+            serde_json::from_str(r#"{"created_at":1,"kind":1,"tags":[],"content":"hi"}"#).unwrap();
+        assert_eq!(event.pubkey, None);
+        assert!(
+            serde_json::from_str::<UnsignedNostrEvent>(
+                r#"{"created_at":1,"kind":70000,"tags":[],"content":"hi"}"#
+            )
+            .is_err()
+        );
+    }
 }
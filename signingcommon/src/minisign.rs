@@ -0,0 +1,152 @@
+//! minisign and OpenBSD signify public key and signature files.
+
+use base64::prelude::{BASE64_STANDARD, Engine};
+use sha2::{Digest, Sha256};
+
+/// Untrusted comment of minisign public key files
+const MINISIGN_PUBLIC_KEY_COMMENT: &str = "minisign public key";
+/// Untrusted comment of signify public key files
+const SIGNIFY_PUBLIC_KEY_COMMENT: &str = "signify public key";
+
+/// The minisign and signify key id of `verifying_key`: the first 8 bytes of its SHA-256. The
+/// tools pick random key ids; deriving it from the key keeps it the same for the same key.
+pub fn minisign_key_id(verifying_key: &[u8; 32]) -> [u8; 8] {
+    Sha256::digest(verifying_key)[..8]
+        .try_into()
+        .expect("8 bytes")
+}
+
+/// A key id the way minisign shows it: the id as a little endian number, in upper case hex
+pub fn minisign_key_id_hex(key_id: &[u8; 8]) -> String {
+    format!("{:016X}", u64::from_le_bytes(*key_id))
+}
+
+/// `verifying_key` as a minisign public key: `Ed`, the key id and the key, base64 encoded.
+/// signify public keys are the same.
+pub fn minisign_public_key(verifying_key: &[u8; 32]) -> String {
+    let mut bytes = b"Ed".to_vec();
+    bytes.extend_from_slice(&minisign_key_id(verifying_key));
+    bytes.extend_from_slice(verifying_key);
+    BASE64_STANDARD.encode(bytes)
+}
+
+/// A minisign public key file (`minisign.pub`) for `verifying_key`
+pub fn minisign_public_key_file(verifying_key: &[u8; 32]) -> String {
+    let key_id = minisign_key_id_hex(&minisign_key_id(verifying_key));
+    format!(
+        "untrusted comment: {MINISIGN_PUBLIC_KEY_COMMENT} {key_id}\n{}\n",
+        minisign_public_key(verifying_key)
+    )
+}
+
+/// A signify public key file for `verifying_key`
+pub fn signify_public_key_file(verifying_key: &[u8; 32]) -> String {
+    format!(
+        "untrusted comment: {SIGNIFY_PUBLIC_KEY_COMMENT}\n{}\n",
+        minisign_public_key(verifying_key)
+    )
+}
+
+/// What the global signature of a minisign signature file signs: the signature followed by the
+/// trusted comment
+pub fn minisign_global_data(signature: &[u8; 64], trusted_comment: &str) -> Vec<u8> {
+    let mut data = signature.to_vec();
+    data.extend_from_slice(trusted_comment.as_bytes());
+    data
+}
+
+/// A minisign signature file (`.minisig`). `signature` signs the BLAKE2b-512 hash of the file
+/// (minisign's `ED` algorithm), `global_signature` its [`minisign_global_data`].
+pub fn minisig(
+    key_id: &[u8; 8],
+    signature: &[u8; 64],
+    trusted_comment: &str,
+    global_signature: &[u8; 64],
+) -> String {
+    let mut bytes = b"ED".to_vec();
+    bytes.extend_from_slice(key_id);
+    bytes.extend_from_slice(signature);
+    format!(
+        "untrusted comment: signature from minisign secret key {}\n{}\ntrusted comment: {}\n{}\n",
+        minisign_key_id_hex(key_id),
+        BASE64_STANDARD.encode(bytes),
+        trusted_comment,
+        BASE64_STANDARD.encode(global_signature)
+    )
+}
+
+/// A signify signature file (`.sig`). `signature` signs the file itself.
+pub fn signify_signature(key_id: &[u8; 8], signature: &[u8; 64]) -> String {
+    let mut bytes = b"Ed".to_vec();
+    bytes.extend_from_slice(key_id);
+    bytes.extend_from_slice(signature);
+    format!(
+        "untrusted comment: signature from signify secret key\n{}\n",
+        BASE64_STANDARD.encode(bytes)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_minisign() {
+        use ed25519_dalek::{Signer, SigningKey, Verifier};
+
+        let signing_key = SigningKey::from_bytes(&[7; 32]);
+        let verifying_key = signing_key.verifying_key();
+        let key = verifying_key.as_bytes();
+        let key_id = minisign_key_id(key);
+        assert_eq!(key_id[..], Sha256::digest(key)[..8]);
+        let mut reversed = key_id;
+        reversed.reverse();
+        assert_eq!(minisign_key_id_hex(&key_id), hex::encode_upper(reversed));
+
+        // Public keys are `Ed`, the key id and the key, in files that differ only in the comment.
+        let public_key = BASE64_STANDARD.decode(minisign_public_key(key)).unwrap();
+        assert_eq!(public_key, [&b"Ed"[..], &key_id, key].concat());
+        assert_eq!(
+            minisign_public_key_file(key),
+            format!(
+                "untrusted comment: minisign public key {}\n{}\n",
+                minisign_key_id_hex(&key_id),
+                minisign_public_key(key)
+            )
+        );
+        assert_eq!(
+            signify_public_key_file(key),
+            format!(
+                "untrusted comment: signify public key\n{}\n",
+                minisign_public_key(key)
+            )
+        );
+
+        let hash = [9; 64];
+        let signature = signing_key.sign(&hash).to_bytes();
+        let trusted_comment = "timestamp:1700000000\tfile:release.tar.gz\thashed";
+        let global_signature = signing_key
+            .sign(&minisign_global_data(&signature, trusted_comment))
+            .to_bytes();
+        let file = minisig(&key_id, &signature, trusted_comment, &global_signature);
+        let lines: Vec<_> = file.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].starts_with("untrusted comment: "));
+        let decoded = BASE64_STANDARD.decode(lines[1]).unwrap();
+        assert_eq!(decoded, [&b"ED"[..], &key_id, &signature].concat());
+        assert_eq!(lines[2], format!("trusted comment: {trusted_comment}"));
+        let global =
+            ed25519_dalek::Signature::from_slice(&BASE64_STANDARD.decode(lines[3]).unwrap())
+                .unwrap();
+        let mut global_data = signature.to_vec();
+        global_data.extend_from_slice(trusted_comment.as_bytes());
+        assert!(verifying_key.verify(&global_data, &global).is_ok());
+
+        let file = signify_signature(&key_id, &signature);
+        let lines: Vec<_> = file.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("untrusted comment: "));
+        let decoded = BASE64_STANDARD.decode(lines[1]).unwrap();
+        assert_eq!(decoded, [&b"Ed"[..], &key_id, &signature].concat());
+    }
+}
@@ -0,0 +1,533 @@
+//! SSH signatures as made by `ssh-keygen -Y sign` (OpenSSH's PROTOCOL.sshsig), OpenSSH public
+//! keys and allowed signers files.
+
+use base64::prelude::{BASE64_STANDARD, Engine};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256, Sha512};
+use std::fmt;
+
+use crate::RequestEnvelope;
+
+/// Key type of Ed25519 keys in SSH
+pub const SSH_ED25519: &str = "ssh-ed25519";
+/// Preamble of SSH signatures and of the data they sign
+pub const SSHSIG_MAGIC: &[u8] = b"SSHSIG";
+/// Hash algorithm of the SSH signatures the server makes
+pub const SSHSIG_HASH_ALGORITHM: &str = "sha512";
+
+const SSHSIG_VERSION: u32 = 1;
+const SSHSIG_BEGIN: &str = "-----BEGIN SSH SIGNATURE-----";
+const SSHSIG_END: &str = "-----END SSH SIGNATURE-----";
+// ssh-keygen wraps armored signatures at 70 columns.
+const SSHSIG_LINE_LEN: usize = 70;
+
+/// Request to make an SSH signature (as `ssh-keygen -Y sign` does) with the user's key
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SshSignRequest {
+    pub user_id: String,
+    /// Namespace of the signature, such as "git" or "file". Users restricted to contexts can only
+    /// sign under namespaces that are among their contexts.
+    pub namespace: String,
+    /// Hex encoded SHA-512 of the message
+    pub hash: String,
+    /// Replay protection
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub envelope: Option<RequestEnvelope>,
+}
+
+/// An armored SSH signature
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SshSignResponse {
+    pub signature: String,
+}
+
+/// Error parsing SSH keys, signatures or allowed signers
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SshError(String);
+
+impl fmt::Display for SshError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for SshError {}
+
+fn ssh_error(reason: impl ToString) -> SshError {
+    SshError(reason.to_string())
+}
+
+// Append an SSH wire format string: a big endian u32 length followed by the bytes.
+fn put_ssh_string(buf: &mut Vec<u8>, bytes: &[u8]) {
+    let len = u32::try_from(bytes.len()).expect("SSH strings are shorter than 4 GiB");
+    buf.extend_from_slice(&len.to_be_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+// Reads SSH wire format values off the front of a buffer.
+struct SshReader<'a>(&'a [u8]);
+
+impl<'a> SshReader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], SshError> {
+        if self.0.len() < len {
+            return Err(ssh_error("truncated"));
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn u32(&mut self) -> Result<u32, SshError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes(bytes.try_into().expect("took 4 bytes")))
+    }
+
+    fn string(&mut self) -> Result<&'a [u8], SshError> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    fn utf8(&mut self) -> Result<&'a str, SshError> {
+        std::str::from_utf8(self.string()?).map_err(ssh_error)
+    }
+
+    fn finish(&self) -> Result<(), SshError> {
+        if !self.0.is_empty() {
+            return Err(ssh_error("trailing data"));
+        }
+        Ok(())
+    }
+}
+
+// The wire format of an Ed25519 public key.
+fn ssh_public_key_blob(verifying_key: &[u8; 32]) -> Vec<u8> {
+    let mut blob = Vec::new();
+    put_ssh_string(&mut blob, SSH_ED25519.as_bytes());
+    put_ssh_string(&mut blob, verifying_key);
+    blob
+}
+
+fn parse_ssh_public_key_blob(blob: &[u8]) -> Result<[u8; 32], SshError> {
+    let mut reader = SshReader(blob);
+    if reader.string()? != SSH_ED25519.as_bytes() {
+        return Err(ssh_error(format!("not an {SSH_ED25519} key")));
+    }
+    let key = reader
+        .string()?
+        .try_into()
+        .map_err(|_| ssh_error("key has the wrong length"))?;
+    reader.finish()?;
+    Ok(key)
+}
+
+/// An Ed25519 verifying key as an OpenSSH public key line (`ssh-ed25519 AAAA...`)
+pub fn ssh_public_key(verifying_key: &[u8; 32]) -> String {
+    format!(
+        "{SSH_ED25519} {}",
+        BASE64_STANDARD.encode(ssh_public_key_blob(verifying_key))
+    )
+}
+
+/// The verifying key of an OpenSSH public key line. Any comment after the key is ignored.
+pub fn parse_ssh_public_key(line: &str) -> Result<[u8; 32], SshError> {
+    let mut fields = line.split_whitespace();
+    if fields.next() != Some(SSH_ED25519) {
+        return Err(ssh_error(format!("not an {SSH_ED25519} key")));
+    }
+    let blob = BASE64_STANDARD
+        .decode(fields.next().ok_or_else(|| ssh_error("missing key"))?)
+        .map_err(ssh_error)?;
+    parse_ssh_public_key_blob(&blob)
+}
+
+/// An SSH signature, as made by `ssh-keygen -Y sign` (see OpenSSH's PROTOCOL.sshsig)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SshSignature {
+    pub verifying_key: [u8; 32],
+    pub namespace: String,
+    /// "sha256" or "sha512"
+    pub hash_algorithm: String,
+    pub signature: [u8; 64],
+}
+
+impl SshSignature {
+    /// The bytes an SSH signature signs: the [`SSHSIG_MAGIC`], then the namespace, an empty
+    /// reserved field, the hash algorithm and the hash of the message as SSH strings
+    pub fn signed_data(namespace: &str, hash_algorithm: &str, hash: &[u8]) -> Vec<u8> {
+        let mut data = SSHSIG_MAGIC.to_vec();
+        put_ssh_string(&mut data, namespace.as_bytes());
+        put_ssh_string(&mut data, &[]);
+        put_ssh_string(&mut data, hash_algorithm.as_bytes());
+        put_ssh_string(&mut data, hash);
+        data
+    }
+
+    /// The armored signature, as written to `.sig` files
+    pub fn armor(&self) -> String {
+        let mut blob = SSHSIG_MAGIC.to_vec();
+        blob.extend_from_slice(&SSHSIG_VERSION.to_be_bytes());
+        put_ssh_string(&mut blob, &ssh_public_key_blob(&self.verifying_key));
+        put_ssh_string(&mut blob, self.namespace.as_bytes());
+        put_ssh_string(&mut blob, &[]);
+        put_ssh_string(&mut blob, self.hash_algorithm.as_bytes());
+        let mut signature = Vec::new();
+        put_ssh_string(&mut signature, SSH_ED25519.as_bytes());
+        put_ssh_string(&mut signature, &self.signature);
+        put_ssh_string(&mut blob, &signature);
+
+        let encoded = BASE64_STANDARD.encode(blob);
+        let mut armored = format!("{SSHSIG_BEGIN}\n");
+        for line in encoded.as_bytes().chunks(SSHSIG_LINE_LEN) {
+            armored.push_str(std::str::from_utf8(line).expect("base64 is ASCII"));
+            armored.push('\n');
+        }
+        armored.push_str(SSHSIG_END);
+        armored.push('\n');
+        armored
+    }
+
+    /// Parse an armored Ed25519 SSH signature
+    pub fn parse(armored: &str) -> Result<Self, SshError> {
+        let armored = armored.trim();
+        let encoded: String = armored
+            .strip_prefix(SSHSIG_BEGIN)
+            .and_then(|rest| rest.strip_suffix(SSHSIG_END))
+            .ok_or_else(|| ssh_error("not an armored SSH signature"))?
+            .split_whitespace()
+            .collect();
+        let blob = BASE64_STANDARD.decode(encoded).map_err(ssh_error)?;
+
+        let mut reader = SshReader(&blob);
+        if reader.take(SSHSIG_MAGIC.len())? != SSHSIG_MAGIC {
+            return Err(ssh_error("not an SSH signature"));
+        }
+        let version = reader.u32()?;
+        if version != SSHSIG_VERSION {
+            return Err(ssh_error(format!("unsupported version {version}")));
+        }
+        let verifying_key = parse_ssh_public_key_blob(reader.string()?)?;
+        let namespace = reader.utf8()?.to_string();
+        reader.string()?;
+        let hash_algorithm = reader.utf8()?.to_string();
+        if hash_algorithm != "sha256" && hash_algorithm != "sha512" {
+            return Err(ssh_error(format!(
+                "unsupported hash algorithm {hash_algorithm}"
+            )));
+        }
+        let mut signature_reader = SshReader(reader.string()?);
+        reader.finish()?;
+        if signature_reader.string()? != SSH_ED25519.as_bytes() {
+            return Err(ssh_error(format!("not an {SSH_ED25519} signature")));
+        }
+        let signature = signature_reader
+            .string()?
+            .try_into()
+            .map_err(|_| ssh_error("signature has the wrong length"))?;
+        signature_reader.finish()?;
+
+        Ok(SshSignature {
+            verifying_key,
+            namespace,
+            hash_algorithm,
+            signature,
+        })
+    }
+
+    /// The hash of `message` this signature's data includes
+    pub fn hash(&self, message: impl std::io::Read) -> std::io::Result<Vec<u8>> {
+        fn hash<D: Digest + std::io::Write>(
+            mut message: impl std::io::Read,
+        ) -> std::io::Result<Vec<u8>> {
+            let mut digest = D::new();
+            std::io::copy(&mut message, &mut digest)?;
+            Ok(digest.finalize().to_vec())
+        }
+        match self.hash_algorithm.as_str() {
+            "sha256" => hash::<Sha256>(message),
+            _ => hash::<Sha512>(message),
+        }
+    }
+}
+
+/// An entry of an OpenSSH allowed signers file (see ALLOWED SIGNERS in ssh-keygen(1))
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AllowedSigner {
+    /// Comma separated principal patterns, with `*` and `?` wildcards and `!` negation
+    pub principals: String,
+    pub cert_authority: bool,
+    /// Namespace patterns the key may sign under, if restricted
+    pub namespaces: Option<String>,
+    /// Unix time from which the key is valid
+    pub valid_after: Option<u64>,
+    /// Unix time until which the key is valid
+    pub valid_before: Option<u64>,
+    pub verifying_key: [u8; 32],
+}
+
+impl AllowedSigner {
+    /// Whether this entry allows its key to sign under `namespace` at `now`. Certificate
+    /// authorities never do, as the server does not issue certificates.
+    pub fn allows(&self, namespace: &str, now: u64) -> bool {
+        !self.cert_authority
+            && self
+                .namespaces
+                .as_ref()
+                .is_none_or(|namespaces| match_pattern_list(namespaces, namespace))
+            && self.valid_after.is_none_or(|after| now >= after)
+            && self.valid_before.is_none_or(|before| now <= before)
+    }
+
+    /// Whether `principal` matches this entry's principals
+    pub fn matches_principal(&self, principal: &str) -> bool {
+        match_pattern_list(&self.principals, principal)
+    }
+}
+
+/// Parse an allowed signers file. Entries for keys other than Ed25519 keys are skipped.
+pub fn parse_allowed_signers(text: &str) -> Result<Vec<AllowedSigner>, SshError> {
+    let mut signers = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let signer = parse_allowed_signer(line)
+            .map_err(|e| ssh_error(format!("line {}: {e}", number + 1)))?;
+        signers.extend(signer);
+    }
+    Ok(signers)
+}
+
+fn parse_allowed_signer(line: &str) -> Result<Option<AllowedSigner>, SshError> {
+    let mut fields = split_unquoted(line, char::is_whitespace).into_iter();
+    let principals = unquote(fields.next().expect("line is not empty"));
+    let mut field = fields.next().ok_or_else(|| ssh_error("missing key"))?;
+
+    let mut signer = AllowedSigner {
+        principals,
+        cert_authority: false,
+        namespaces: None,
+        valid_after: None,
+        valid_before: None,
+        verifying_key: [0; 32],
+    };
+    if !is_ssh_key_type(field) {
+        for option in split_unquoted(field, |c| c == ',') {
+            let (name, value) = match option.split_once('=') {
+                Some((name, value)) => (name, Some(unquote(value))),
+                None => (option, None),
+            };
+            match (name.to_ascii_lowercase().as_str(), value) {
+                ("cert-authority", None) => signer.cert_authority = true,
+                ("namespaces", Some(value)) => signer.namespaces = Some(value),
+                ("valid-after", Some(value)) => signer.valid_after = Some(parse_ssh_time(&value)?),
+                ("valid-before", Some(value)) => {
+                    signer.valid_before = Some(parse_ssh_time(&value)?)
+                }
+                _ => return Err(ssh_error(format!("unsupported option {option}"))),
+            }
+        }
+        field = fields.next().ok_or_else(|| ssh_error("missing key"))?;
+    }
+    if field != SSH_ED25519 {
+        return Ok(None);
+    }
+    let key = fields.next().ok_or_else(|| ssh_error("missing key"))?;
+    signer.verifying_key = parse_ssh_public_key(&format!("{field} {key}"))?;
+    Ok(Some(signer))
+}
+
+fn is_ssh_key_type(field: &str) -> bool {
+    ["ssh-", "ecdsa-sha2-", "sk-"]
+        .iter()
+        .any(|prefix| field.starts_with(prefix))
+}
+
+// Split `text` at the characters matching `split`, except inside double quotes.
+fn split_unquoted(text: &str, split: impl Fn(char) -> bool) -> Vec<&str> {
+    let mut fields = Vec::new();
+    let mut start = None;
+    let mut quoted = false;
+    for (i, c) in text.char_indices() {
+        if c == '"' {
+            quoted = !quoted;
+        }
+        if split(c) && !quoted {
+            if let Some(start) = start.take() {
+                fields.push(&text[start..i]);
+            }
+        } else if start.is_none() {
+            start = Some(i);
+        }
+    }
+    if let Some(start) = start {
+        fields.push(&text[start..]);
+    }
+    fields
+}
+
+fn unquote(text: &str) -> String {
+    text.strip_prefix('"')
+        .and_then(|text| text.strip_suffix('"'))
+        .unwrap_or(text)
+        .to_string()
+}
+
+// Parse a YYYYMMDD[HHMM[SS]][Z] time. Times are taken as UTC whether or not they end in Z.
+fn parse_ssh_time(text: &str) -> Result<u64, SshError> {
+    let digits = text.strip_suffix(['Z', 'z']).unwrap_or(text);
+    let invalid = || ssh_error(format!("invalid time {text}"));
+    if !digits.bytes().all(|b| b.is_ascii_digit()) || ![8, 12, 14].contains(&digits.len()) {
+        return Err(invalid());
+    }
+    let number = |range: std::ops::Range<usize>| digits.get(range).map_or(Ok(0), str::parse::<u64>);
+    let (year, month, day) = (number(0..4), number(4..6), number(6..8));
+    let (hour, minute, second) = (number(8..10), number(10..12), number(12..14));
+    let [year, month, day, hour, minute, second] =
+        [year, month, day, hour, minute, second].map(|n| n.expect("all digits"));
+    if year < 1970
+        || !(1..=12).contains(&month)
+        || !(1..=31).contains(&day)
+        || hour > 23
+        || minute > 59
+        || second > 59
+    {
+        return Err(invalid());
+    }
+    // Days since the epoch of a proleptic Gregorian date (Howard Hinnant's days_from_civil).
+    let (year, month) = if month <= 2 {
+        (year - 1, month + 9)
+    } else {
+        (year, month - 3)
+    };
+    let era = year / 400;
+    let day_of_era =
+        (year % 400) * 365 + (year % 400) / 4 - (year % 400) / 100 + (153 * month + 2) / 5 + day
+            - 1;
+    let days = era * 146097 + day_of_era - 719468;
+    Ok(days * 86400 + hour * 3600 + minute * 60 + second)
+}
+
+// Whether `text` matches a comma separated list of patterns, none of the negated (`!`) ones.
+fn match_pattern_list(patterns: &str, text: &str) -> bool {
+    let mut matched = false;
+    for pattern in patterns.split(',') {
+        match pattern.strip_prefix('!') {
+            Some(pattern) if match_pattern(pattern.as_bytes(), text.as_bytes()) => return false,
+            Some(_) => {}
+            None => matched |= match_pattern(pattern.as_bytes(), text.as_bytes()),
+        }
+    }
+    matched
+}
+
+// Whether `text` matches `pattern`, in which `*` matches any run of characters and `?` any one.
+fn match_pattern(pattern: &[u8], text: &[u8]) -> bool {
+    match (pattern.split_first(), text.split_first()) {
+        (None, None) => true,
+        (Some((b'*', rest)), _) => {
+            match_pattern(rest, text) || (!text.is_empty() && match_pattern(pattern, &text[1..]))
+        }
+        (Some((b'?', rest)), Some((_, text))) => match_pattern(rest, text),
+        (Some((p, rest)), Some((t, text))) if p == t => match_pattern(rest, text),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Made with `ssh-keygen -Y sign -n file` over "test message"
+    const SSH_KEY: &str =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIIm8DRt0BVflU/IJYx3TlDBs9GJX9bWkTJiCm0zpVRC8";
+    const SSH_SIGNATURE: &str = "-----BEGIN SSH SIGNATURE-----
+U1NIU0lHAAAAAQAAADMAAAALc3NoLWVkMjU1MTkAAAAgibwNG3QFV+VT8gljHdOUMGz0Yl
+f1taRMmIKbTOlVELwAAAAEZmlsZQAAAAAAAAAGc2hhNTEyAAAAUwAAAAtzc2gtZWQyNTUx
+OQAAAEBsiRvdXriWRGMIUlQLAbnjosawlxrZmwNiUBKCF/v10ioGHF0KZATlLIj8Ku05Y8
+nGM+Hk6X6y65UrZn0iMJ0L
+-----END SSH SIGNATURE-----
+";
+
+    #[test]
+    fn test_ssh_public_key() {
+        let key = parse_ssh_public_key(&format!("{SSH_KEY} comment")).unwrap();
+        assert_eq!(
+            hex::encode(key),
+            "89bc0d1b740557e553f209631dd394306cf46257f5b5a44c98829b4ce95510bc"
+        );
+        assert_eq!(ssh_public_key(&key), SSH_KEY);
+        assert!(parse_ssh_public_key("ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABAQ").is_err());
+    }
+
+    #[test]
+    fn test_ssh_signature() {
+        let signature = SshSignature::parse(SSH_SIGNATURE).unwrap();
+        assert_eq!(signature.namespace, "file");
+        assert_eq!(signature.hash_algorithm, SSHSIG_HASH_ALGORITHM);
+        assert_eq!(signature.armor(), SSH_SIGNATURE);
+
+        let hash = signature.hash(&b"test message"[..]).unwrap();
+        let data = SshSignature::signed_data("file", "sha512", &hash);
+        assert_eq!(&data[..22], b"SSHSIG\0\0\0\x04file\0\0\0\0\0\0\0\x06");
+        let key = ed25519_dalek::VerifyingKey::from_bytes(&signature.verifying_key).unwrap();
+        let ed25519_signature = ed25519_dalek::Signature::from_bytes(&signature.signature);
+        assert!(key.verify_strict(&data, &ed25519_signature).is_ok());
+        assert!(
+            key.verify_strict(
+                &SshSignature::signed_data("git", "sha512", &hash),
+                &ed25519_signature
+            )
+            .is_err()
+        );
+
+        assert!(SshSignature::parse(&SSH_SIGNATURE.replace("U1NIU0lH", "U1NIU0lI")).is_err());
+    }
+
+    #[test]
+    fn test_allowed_signers() {
+        let text = format!(
+            "# comment\n\
+             \n\
+             alice@example.com,bob@* {SSH_KEY} alice\n\
+             \"carol@example.com\" namespaces=\"git,file\",valid-before=\"20300101\" {SSH_KEY}\n\
+             *@example.com cert-authority {SSH_KEY}\n\
+             dave ecdsa-sha2-nistp256 AAAAE2VjZHNh\n"
+        );
+        let signers = parse_allowed_signers(&text).unwrap();
+        assert_eq!(signers.len(), 3);
+        let now = 1_800_000_000;
+
+        assert!(signers[0].allows("anything", now));
+        assert!(signers[0].matches_principal("alice@example.com"));
+        assert!(signers[0].matches_principal("bob@example.org"));
+        assert!(!signers[0].matches_principal("carol@example.com"));
+
+        assert_eq!(signers[1].principals, "carol@example.com");
+        assert_eq!(signers[1].valid_before, Some(1_893_456_000));
+        assert!(signers[1].allows("git", now));
+        assert!(!signers[1].allows("email", now));
+        assert!(!signers[1].allows("git", 1_900_000_000));
+
+        assert!(signers[2].cert_authority);
+        assert!(!signers[2].allows("git", now));
+
+        assert!(parse_allowed_signers(&format!("x unknown-option {SSH_KEY}")).is_err());
+        assert!(parse_allowed_signers("x").is_err());
+    }
+
+    #[test]
+    fn test_principal_patterns() {
+        assert!(match_pattern_list(
+            "*@example.com,!mallory@*",
+            "alice@example.com"
+        ));
+        assert!(!match_pattern_list(
+            "*@example.com,!mallory@*",
+            "mallory@example.com"
+        ));
+        assert!(match_pattern_list("b?b", "bob"));
+        assert!(!match_pattern_list("b?b", "boob"));
+        assert!(!match_pattern_list("!alice", "bob"));
+    }
+}
@@ -0,0 +1,86 @@
+//! Signing throughput while other threads keep registering and forgetting users.
+//!
+//! Run with `cargo bench -p signingserver`.
+
+use criterion::{Criterion, Throughput, criterion_group, criterion_main};
+use signingcommon::RegisterRequest;
+use signingserver::state::AppState;
+use std::hint::black_box;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const SIGNERS: usize = 4;
+const USERS: usize = 256;
+
+/// Sign `iters` messages spread over [`SIGNERS`] threads while `churn` threads loop over
+/// register/forget.
+fn mixed_load(state: &Arc<AppState>, users: &[String], churn: usize, iters: u64) -> Duration {
+    let stop = Arc::new(AtomicBool::new(false));
+    let churners: Vec<_> = (0..churn)
+        .map(|i| {
+            let state = state.clone();
+            let stop = stop.clone();
+            thread::spawn(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    let req = RegisterRequest {
+                        seed: vec![i as u8],
+                        ..Default::default()
+                    };
+                    if let Ok(registration) = state.register_user(&req) {
+                        state.forget(&registration.user_id.to_string()).unwrap();
+                    }
+                }
+            })
+        })
+        .collect();
+
+    let start = Instant::now();
+    thread::scope(|s| {
+        for t in 0..SIGNERS {
+            s.spawn(move || {
+                for i in (t as u64..iters).step_by(SIGNERS) {
+                    let user_id = &users[i as usize % users.len()];
+                    black_box(
+                        state
+                            .sign_message(user_id, "benchmark message", None)
+                            .unwrap(),
+                    );
+                }
+            });
+        }
+    });
+    let elapsed = start.elapsed();
+
+    stop.store(true, Ordering::Relaxed);
+    for churner in churners {
+        churner.join().unwrap();
+    }
+    elapsed
+}
+
+fn bench_sign(c: &mut Criterion) {
+    let state = Arc::new(AppState::new());
+    let users: Vec<String> = (0..USERS)
+        .map(|i| {
+            let req = RegisterRequest {
+                seed: i.to_le_bytes().to_vec(),
+                ..Default::default()
+            };
+            state.register_user(&req).unwrap().user_id.to_string()
+        })
+        .collect();
+
+    let mut group = c.benchmark_group("sign");
+    group.throughput(Throughput::Elements(1));
+    for churn in [0, 1, 4] {
+        group.bench_function(format!("{SIGNERS}_signers_{churn}_registering"), |b| {
+            b.iter_custom(|iters| mixed_load(&state, &users, churn, iters))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_sign);
+criterion_main!(benches);
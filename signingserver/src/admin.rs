@@ -0,0 +1,235 @@
+//! Admin API for operators.
+//!
+//! The routes under `/admin` are only mounted when the server is given an admin token, and every
+//! request to them must carry it as a bearer token. Operators can inspect users and capacity,
+//! forget, freeze, unfreeze and approve users, put the server in read-only mode and rotate the server
+//! identity key. None of the admin endpoints expose key material.
+
+use axum::{
+    Json,
+    extract::{Path, Request, State},
+    http::{HeaderMap, StatusCode, header::AUTHORIZATION},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use sha2::{Digest, Sha256};
+use signingcommon::{
+    AdminResponse, AdminStatsResponse, AdminUsersResponse, ErrorResponse, ReadOnlyRequest,
+    UserStatus,
+};
+use std::sync::Arc;
+use subtle::ConstantTimeEq;
+use tracing::{info, warn};
+
+use crate::error::Error;
+use crate::handlers::error_response;
+use crate::secret::IdentityGeneration;
+use crate::state::{AppState, MAX_KEYS, MAX_KEYS_PER_SHARD};
+
+/// Checks the credentials of admin requests
+#[derive(Debug)]
+pub struct AdminAuth {
+    // Only the hash of the token is kept, so that comparisons take the same time whatever the
+    // length of the presented token.
+    token_sha256: [u8; 32],
+}
+
+impl AdminAuth {
+    pub fn new(token: &str) -> Self {
+        AdminAuth {
+            token_sha256: Sha256::digest(token.as_bytes()).into(),
+        }
+    }
+
+    /// Whether `headers` carry the admin token as a bearer token
+    pub fn is_authorized(&self, headers: &HeaderMap) -> bool {
+        let Some(token) = headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+        else {
+            return false;
+        };
+        let presented: [u8; 32] = Sha256::digest(token.as_bytes()).into();
+        presented.ct_eq(&self.token_sha256).into()
+    }
+}
+
+/// Middleware rejecting admin requests without the admin token
+pub async fn require_admin(
+    State(auth): State<Arc<AdminAuth>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if !auth.is_authorized(request.headers()) {
+        warn!("Unauthorized admin request to {}", request.uri().path());
+        return (
+            StatusCode::UNAUTHORIZED,
+            Json(ErrorResponse {
+                error: "Invalid admin token".to_string(),
+            }),
+        )
+            .into_response();
+    }
+    next.run(request).await
+}
+
+/// List all users: their IDs, key types and creation times
+pub async fn users(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    Json(AdminUsersResponse {
+        users: state.users(),
+    })
+}
+
+/// Capacity statistics
+pub async fn stats(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let shard_usage = state.shard_usage();
+    Json(AdminStatsResponse {
+        users: shard_usage.iter().sum(),
+        capacity: MAX_KEYS,
+        shard_usage,
+        shard_capacity: MAX_KEYS_PER_SHARD,
+        revoked_keys: state.revoked_count(),
+        read_only: state.is_read_only(),
+    })
+}
+
+/// Forget a user, even in read-only mode
+pub async fn forget(
+    State(state): State<Arc<AppState>>,
+    Path(user_id): Path<String>,
+) -> impl IntoResponse {
+    info!("Admin: forgetting user {}", user_id);
+    outcome(
+        state.force_forget(&user_id),
+        format!("User {user_id} forgotten"),
+    )
+}
+
+/// Suspend signing for an active user
+pub async fn freeze(
+    State(state): State<Arc<AppState>>,
+    Path(user_id): Path<String>,
+) -> impl IntoResponse {
+    info!("Admin: freezing user {}", user_id);
+    outcome(
+        state.set_status(&user_id, UserStatus::Active, UserStatus::Frozen),
+        format!("User {user_id} frozen"),
+    )
+}
+
+/// Resume signing for a frozen user
+pub async fn unfreeze(
+    State(state): State<Arc<AppState>>,
+    Path(user_id): Path<String>,
+) -> impl IntoResponse {
+    info!("Admin: unfreezing user {}", user_id);
+    outcome(
+        state.set_status(&user_id, UserStatus::Frozen, UserStatus::Active),
+        format!("User {user_id} unfrozen"),
+    )
+}
+
+/// Let a user pending approval sign
+pub async fn approve(
+    State(state): State<Arc<AppState>>,
+    Path(user_id): Path<String>,
+) -> impl IntoResponse {
+    info!("Admin: approving user {}", user_id);
+    outcome(
+        state.set_status(&user_id, UserStatus::PendingApproval, UserStatus::Active),
+        format!("User {user_id} approved"),
+    )
+}
+
+/// Enter or leave read-only mode
+pub async fn read_only(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<ReadOnlyRequest>,
+) -> impl IntoResponse {
+    info!("Admin: setting read-only mode to {}", req.read_only);
+    state.set_read_only(req.read_only);
+    let mode = if req.read_only {
+        "read-only"
+    } else {
+        "writable"
+    };
+    outcome(Ok(()), format!("Server is {mode}"))
+}
+
+/// Rotate the server identity key
+///
+/// The new generation is stored next to the server secret before the server switches to it, so
+/// the new key survives restarts.
+pub async fn rotate_identity(
+    State(state): State<Arc<AppState>>,
+    State(generation): State<Arc<IdentityGeneration>>,
+) -> Response {
+    match generation.advance(|| state.rotate_identity()) {
+        Ok(rotation) => {
+            warn!(
+                "Admin: server identity key rotated to generation {}",
+                rotation.generation
+            );
+            Json(rotation).into_response()
+        }
+        Err(e) => error_response(Error::IdentityGeneration(e.to_string())),
+    }
+}
+
+fn outcome(result: Result<(), Error>, message: String) -> Response {
+    match result {
+        Ok(()) => (StatusCode::OK, Json(AdminResponse { message })).into_response(),
+        Err(e) => (
+            e.status_code(),
+            Json(ErrorResponse {
+                error: e.to_string(),
+            }),
+        )
+            .into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_admin_auth() {
+        let auth = AdminAuth::new("s3cret");
+        let mut headers = HeaderMap::new();
+        assert!(!auth.is_authorized(&headers));
+
+        headers.insert(AUTHORIZATION, "Bearer wrong".parse().unwrap());
+        assert!(!auth.is_authorized(&headers));
+        headers.insert(AUTHORIZATION, "s3cret".parse().unwrap());
+        assert!(!auth.is_authorized(&headers));
+        headers.insert(AUTHORIZATION, "Bearer s3cret".parse().unwrap());
+        assert!(auth.is_authorized(&headers));
+    }
+
+    #[tokio::test]
+    async fn test_freeze() {
+        let state = Arc::new(AppState::new());
+        let response = freeze(State(state.clone()), Path(uuid::Uuid::new_v4().to_string()))
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let registration = state
+            .register_user(&signingcommon::RegisterRequest {
+                seed: b"seed".to_vec(),
+                ..Default::default()
+            })
+            .unwrap();
+        let user_id = registration.user_id.to_string();
+        let response = approve(State(state.clone()), Path(user_id.clone()))
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let response = freeze(State(state.clone()), Path(user_id))
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
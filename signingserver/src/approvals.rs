@@ -0,0 +1,372 @@
+//! Multi-party approval of signatures.
+//!
+//! Users registered with an approver set cannot sign alone: `/sign` records the request here and
+//! returns its ID. Approvers approve at `/approve/{id}`, proving with their seed that they own
+//! their user; their keys then sign the request's statement (see
+//! [`PendingSignature::statement`]), which no other signing path signs. Once `threshold` distinct
+//! approvers have approved, the signature is made and kept with the request, so that both the
+//! last approver and the requester can fetch it. Requests, signed or not, are dropped when they
+//! expire. They live in a fixed-size map; when it is full new requests are refused.
+
+use ed25519_dalek::Signature;
+use heapless::index_map::FnvIndexMap;
+use signingcommon::{ApproveRequest, PendingSignature, unix_time};
+use std::sync::Mutex;
+use std::time::Duration;
+use uuid::Uuid;
+
+use crate::error::Error;
+use crate::state::{AppState, Approval, MAX_APPROVERS};
+
+/// Maximum number of pending requests at any time. Must be a power of two.
+pub const MAX_PENDING: usize = 256;
+
+#[derive(Debug, Clone)]
+struct Pending {
+    user_id: Uuid,
+    message: String,
+    context: Option<String>,
+    expires_at: u64,
+    approval: Approval,
+    approved_by: heapless::Vec<Uuid, MAX_APPROVERS>,
+    approvals: heapless::Vec<Signature, MAX_APPROVERS>,
+    signature: Option<Signature>,
+}
+
+impl Pending {
+    fn view(&self, request_id: &Uuid) -> PendingSignature {
+        PendingSignature {
+            request_id: request_id.to_string(),
+            user_id: self.user_id.to_string(),
+            message: self.message.clone(),
+            context: self.context.clone(),
+            expires_at: self.expires_at,
+            threshold: self.approval.threshold,
+            approvers: self
+                .approval
+                .approvers
+                .iter()
+                .map(Uuid::to_string)
+                .collect(),
+            approved_by: self.approved_by.iter().map(Uuid::to_string).collect(),
+            approvals: self
+                .approvals
+                .iter()
+                .map(|approval| hex::encode(approval.to_bytes()))
+                .collect(),
+            signature: self.signature.map(|s| hex::encode(s.to_bytes())),
+        }
+    }
+
+    fn involves(&self, user_id: &Uuid) -> bool {
+        self.user_id == *user_id || self.approval.approvers.contains(user_id)
+    }
+}
+
+/// Outcome of an approval
+#[derive(Debug)]
+pub struct Approved {
+    pub request: PendingSignature,
+    /// Whether the quorum is met and the signature still has to be made
+    pub ready: bool,
+}
+
+/// Signatures waiting for approvals, by request ID
+#[derive(Debug)]
+pub struct PendingSignatures {
+    ttl: u64,
+    entries: Mutex<Box<FnvIndexMap<Uuid, Pending, MAX_PENDING>>>,
+}
+
+impl PendingSignatures {
+    /// Create a store whose requests expire after `ttl`
+    pub fn new(ttl: Duration) -> Self {
+        PendingSignatures {
+            ttl: ttl.as_secs(),
+            entries: Mutex::new(Box::new(FnvIndexMap::new())),
+        }
+    }
+
+    /// Record a request to sign `message` for `user_id`, which requires `approval`
+    pub fn create(
+        &self,
+        user_id: &str,
+        message: String,
+        context: Option<String>,
+        approval: Approval,
+    ) -> Result<PendingSignature, Error> {
+        let user_id = Uuid::parse_str(user_id)?;
+        let now = unix_time();
+        let pending = Pending {
+            user_id,
+            message,
+            context,
+            expires_at: now.saturating_add(self.ttl),
+            approval,
+            approved_by: heapless::Vec::new(),
+            approvals: heapless::Vec::new(),
+            signature: None,
+        };
+
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, pending| pending.expires_at > now);
+        let request_id = Uuid::new_v4();
+        let view = pending.view(&request_id);
+        entries
+            .insert(request_id, pending)
+            .map_err(|_| Error::TooManyPending)?;
+        Ok(view)
+    }
+
+    /// A pending request, as seen by `reader`: requests the reader is neither the requester nor an
+    /// approver of do not exist for them
+    pub fn get(&self, request_id: &str, reader: &str) -> Result<PendingSignature, Error> {
+        let request_id = Uuid::parse_str(request_id).map_err(|_| Error::NoSuchRequest)?;
+        let reader = Uuid::parse_str(reader)?;
+        self.entries
+            .lock()
+            .unwrap()
+            .get(&request_id)
+            .filter(|pending| pending.expires_at > unix_time() && pending.involves(&reader))
+            .map(|pending| pending.view(&request_id))
+            .ok_or(Error::NoSuchRequest)
+    }
+
+    /// Pending requests to sign for `user_id`, or awaiting their approval
+    pub fn for_user(&self, user_id: &str) -> Result<Vec<PendingSignature>, Error> {
+        let user_id = Uuid::parse_str(user_id)?;
+        let now = unix_time();
+        let mut requests: Vec<_> = self
+            .entries
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, pending)| pending.expires_at > now && pending.involves(&user_id))
+            .map(|(request_id, pending)| pending.view(request_id))
+            .collect();
+        requests.sort_by_key(|request| request.expires_at);
+        Ok(requests)
+    }
+
+    /// Record an approval, signed by the approver's current key once the seed proves ownership
+    pub fn approve(
+        &self,
+        state: &AppState,
+        request_id: &str,
+        req: &ApproveRequest,
+    ) -> Result<Approved, Error> {
+        let request_id = Uuid::parse_str(request_id).map_err(|_| Error::NoSuchRequest)?;
+        let approver = Uuid::parse_str(&req.approver_id).map_err(|_| Error::NotAnApprover)?;
+
+        let mut entries = self.entries.lock().unwrap();
+        let pending = entries
+            .get_mut(&request_id)
+            .filter(|pending| pending.expires_at > unix_time())
+            .ok_or(Error::NoSuchRequest)?;
+        if !pending.approval.approvers.contains(&approver) {
+            return Err(Error::NotAnApprover);
+        }
+        let approval =
+            state.sign_approval(&approver, &req.seed, &pending.view(&request_id).statement())?;
+        if !pending.approved_by.contains(&approver) {
+            pending
+                .approved_by
+                .push(approver)
+                .expect("approvers are at most MAX_APPROVERS");
+            pending
+                .approvals
+                .push(approval)
+                .expect("approvers are at most MAX_APPROVERS");
+        }
+
+        Ok(Approved {
+            request: pending.view(&request_id),
+            ready: pending.signature.is_none()
+                && pending.approved_by.len() >= pending.approval.threshold,
+        })
+    }
+
+    /// Store the signature of an approved request
+    pub fn release(
+        &self,
+        request_id: &str,
+        signature: Signature,
+    ) -> Result<PendingSignature, Error> {
+        let request_id = Uuid::parse_str(request_id).map_err(|_| Error::NoSuchRequest)?;
+        let mut entries = self.entries.lock().unwrap();
+        let pending = entries.get_mut(&request_id).ok_or(Error::NoSuchRequest)?;
+        pending.signature = Some(signature);
+        Ok(pending.view(&request_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use signingcommon::{ApprovalPolicy, RegisterRequest};
+
+    fn register(state: &AppState, seed: &[u8], approval: Option<ApprovalPolicy>) -> String {
+        state
+            .register_user(&RegisterRequest {
+                seed: seed.to_vec(),
+                approval,
+                ..Default::default()
+            })
+            .unwrap()
+            .user_id
+            .to_string()
+    }
+
+    #[test]
+    fn test_two_of_three() {
+        let state = AppState::new();
+        let approvers: Vec<_> = [b"a1", b"a2", b"a3"]
+            .iter()
+            .map(|seed| register(&state, *seed, None))
+            .collect();
+        let treasury = register(
+            &state,
+            b"treasury",
+            Some(ApprovalPolicy {
+                threshold: 2,
+                approvers: approvers.clone(),
+            }),
+        );
+        assert!(matches!(
+            state.sign_message(&treasury, "pay", None),
+            Err(Error::ApprovalRequired)
+        ));
+
+        let pending = PendingSignatures::new(Duration::from_secs(60));
+        let approval = state.approval(&treasury, None).unwrap().unwrap();
+        let request = pending
+            .create(&treasury, "pay".into(), None, approval)
+            .unwrap();
+        let approve_as = |seed: &[u8], approver: &str| {
+            pending.approve(
+                &state,
+                &request.request_id,
+                &ApproveRequest {
+                    approver_id: approver.to_string(),
+                    seed: seed.to_vec(),
+                },
+            )
+        };
+
+        // Approving twice counts once.
+        assert!(!approve_as(b"a1", &approvers[0]).unwrap().ready);
+        assert!(!approve_as(b"a1", &approvers[0]).unwrap().ready);
+        assert!(matches!(
+            approve_as(b"treasury", &treasury),
+            Err(Error::NotAnApprover)
+        ));
+        let approved = approve_as(b"a3", &approvers[2]).unwrap();
+        assert!(approved.ready);
+        assert_eq!(
+            approved.request.approved_by,
+            [&*approvers[0], &*approvers[2]]
+        );
+        // The approvals are signatures by the approvers' keys.
+        for (approver, approval) in approved
+            .request
+            .approved_by
+            .iter()
+            .zip(&approved.request.approvals)
+        {
+            let verifying_key = &state.verifying_keys(approver).unwrap()[0].verifying_key;
+            let verifying_key = ed25519_dalek::VerifyingKey::from_bytes(
+                &hex::decode(verifying_key).unwrap().try_into().unwrap(),
+            )
+            .unwrap();
+            let approval = Signature::from_slice(&hex::decode(approval).unwrap()).unwrap();
+            assert!(
+                verifying_key
+                    .verify_strict(&request.statement(), &approval)
+                    .is_ok()
+            );
+        }
+
+        let signature = state.sign_approved(&treasury, "pay", None).unwrap();
+        let released = pending.release(&request.request_id, signature).unwrap();
+        assert_eq!(released.signature, Some(hex::encode(signature.to_bytes())));
+        assert!(!approve_as(b"a2", &approvers[1]).unwrap().ready);
+        assert_eq!(pending.for_user(&approvers[1]).unwrap().len(), 1);
+
+        // Only the requester and the approvers can see the request.
+        assert!(pending.get(&request.request_id, &approvers[1]).is_ok());
+        let outsider = register(&state, b"outsider", None);
+        assert!(matches!(
+            pending.get(&request.request_id, &outsider),
+            Err(Error::NoSuchRequest)
+        ));
+    }
+
+    #[test]
+    fn test_forged_approval() {
+        let state = AppState::new();
+        let approver = register(&state, b"approver", None);
+        let treasury = register(
+            &state,
+            b"treasury",
+            Some(ApprovalPolicy {
+                threshold: 1,
+                approvers: vec![approver.clone()],
+            }),
+        );
+        let pending = PendingSignatures::new(Duration::from_secs(60));
+        let approval = state.approval(&treasury, None).unwrap().unwrap();
+        let request = pending
+            .create(&treasury, "pay".into(), None, approval)
+            .unwrap();
+
+        // Knowing the approver's ID is not enough: the seed must be theirs.
+        assert!(matches!(
+            pending.approve(
+                &state,
+                &request.request_id,
+                &ApproveRequest {
+                    approver_id: approver.clone(),
+                    seed: b"treasury".to_vec(),
+                },
+            ),
+            Err(Error::WrongSeed)
+        ));
+
+        // Nor can the approver's key be made to sign the statement through `/sign`.
+        let statement = request.statement();
+        assert!(matches!(
+            state.sign_message(&approver, std::str::from_utf8(&statement).unwrap(), None),
+            Err(Error::ReservedPrefix)
+        ));
+        assert!(
+            pending
+                .get(&request.request_id, &approver)
+                .unwrap()
+                .approved_by
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_expired_requests_are_dropped() {
+        let state = AppState::new();
+        let approver = register(&state, b"approver", None);
+        let treasury = register(
+            &state,
+            b"treasury",
+            Some(ApprovalPolicy {
+                threshold: 1,
+                approvers: vec![approver],
+            }),
+        );
+        let pending = PendingSignatures::new(Duration::ZERO);
+        let approval = state.approval(&treasury, None).unwrap().unwrap();
+        let request = pending
+            .create(&treasury, "pay".into(), None, approval)
+            .unwrap();
+        assert!(matches!(
+            pending.get(&request.request_id, &treasury),
+            Err(Error::NoSuchRequest)
+        ));
+    }
+}
@@ -0,0 +1,191 @@
+//! Remote attestation.
+//!
+//! A client talking to a server running in a TEE wants evidence that it is talking to the
+//! expected binary, and that this binary holds the TLS certificate and identity key the client
+//! sees. `/attestation` returns a quote from an [`AttestationProvider`] over report data binding
+//! both (see [`attestation_report_data`]) and a client chosen nonce.
+//!
+//! The only provider so far is [`MockAttestation`], a software simulation for local testing: its
+//! quotes are signed with a well known key and prove nothing. A TEE backed provider (e.g. TDX)
+//! implements the same trait.
+
+use ed25519_dalek::{Signer, SigningKey, VerifyingKey};
+use sha2::{Digest, Sha256, Sha384};
+use signingcommon::{ATTESTATION_NONCE_LEN, AttestationResponse, Quote, attestation_report_data};
+use std::path::Path;
+
+use crate::error::Error;
+
+/// Produces quotes over report data
+pub trait AttestationProvider: Send + Sync {
+    /// Name of the provider, as reported in its quotes
+    fn name(&self) -> &'static str;
+
+    /// Measurement of the running server binary
+    fn measurement(&self) -> &[u8];
+
+    /// Produce a quote over `report_data`
+    fn quote(&self, report_data: &[u8; 64]) -> Result<Quote, Error>;
+}
+
+/// Seed of the key mock quotes are signed with. It is public, so mock quotes can be forged by
+/// anyone.
+const MOCK_ATTESTATION_SEED: [u8; 32] = *b"wallet-poc mock attestation key!";
+
+/// Software simulation of a TEE: measures the server executable and signs quotes with a well
+/// known key
+pub struct MockAttestation {
+    key: SigningKey,
+    measurement: [u8; 48],
+}
+
+impl MockAttestation {
+    /// Measure the running executable
+    pub fn new() -> anyhow::Result<Self> {
+        let executable = std::fs::read(std::env::current_exe()?)?;
+        Ok(MockAttestation {
+            key: SigningKey::from_bytes(&MOCK_ATTESTATION_SEED),
+            measurement: Sha384::digest(executable).into(),
+        })
+    }
+
+    /// The key mock quotes are signed with
+    pub fn attestation_key() -> VerifyingKey {
+        SigningKey::from_bytes(&MOCK_ATTESTATION_SEED).verifying_key()
+    }
+}
+
+impl AttestationProvider for MockAttestation {
+    fn name(&self) -> &'static str {
+        "mock"
+    }
+
+    fn measurement(&self) -> &[u8] {
+        &self.measurement
+    }
+
+    fn quote(&self, report_data: &[u8; 64]) -> Result<Quote, Error> {
+        let mut quote = Quote {
+            provider: self.name().to_string(),
+            measurement: hex::encode(self.measurement),
+            report_data: hex::encode(report_data),
+            attestation_key: hex::encode(self.key.verifying_key().as_bytes()),
+            signature: String::new(),
+        };
+        quote.signature = hex::encode(self.key.sign(&quote.statement()).to_bytes());
+        Ok(quote)
+    }
+}
+
+/// Attests the server: its binary, TLS certificate and identity key
+pub struct Attestation {
+    provider: Box<dyn AttestationProvider>,
+    tls_certificate_sha256: [u8; 32],
+}
+
+impl std::fmt::Debug for Attestation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Attestation")
+            .field("provider", &self.provider.name())
+            .field(
+                "tls_certificate_sha256",
+                &hex::encode(self.tls_certificate_sha256),
+            )
+            .finish_non_exhaustive()
+    }
+}
+
+impl Attestation {
+    pub fn new(provider: Box<dyn AttestationProvider>, tls_certificate_der: &[u8]) -> Self {
+        Attestation {
+            provider,
+            tls_certificate_sha256: Sha256::digest(tls_certificate_der).into(),
+        }
+    }
+
+    /// Name of the provider
+    pub fn provider(&self) -> &'static str {
+        self.provider.name()
+    }
+
+    /// Measurement of the running server binary
+    pub fn measurement(&self) -> &[u8] {
+        self.provider.measurement()
+    }
+
+    /// Quote the server's state, including its current identity key, for a client that sent the
+    /// hex encoded `nonce`
+    pub fn attest(
+        &self,
+        nonce: &str,
+        server_key: &VerifyingKey,
+    ) -> Result<AttestationResponse, Error> {
+        let nonce = hex::decode(nonce)
+            .ok()
+            .filter(|nonce| nonce.len() == ATTESTATION_NONCE_LEN)
+            .ok_or_else(|| {
+                Error::InvalidNonce(format!(
+                    "nonce must be {ATTESTATION_NONCE_LEN} hex encoded bytes"
+                ))
+            })?;
+        let report_data =
+            attestation_report_data(&self.tls_certificate_sha256, server_key.as_bytes(), &nonce);
+        Ok(AttestationResponse {
+            quote: self.provider.quote(&report_data)?,
+            tls_certificate_sha256: hex::encode(self.tls_certificate_sha256),
+            server_key: hex::encode(server_key.as_bytes()),
+        })
+    }
+}
+
+/// Read the DER encoding of the first certificate in a PEM file
+pub fn read_certificate_der(path: impl AsRef<Path>) -> anyhow::Result<Vec<u8>> {
+    let pem = std::fs::read(path)?;
+    let certificate = rustls_pemfile::certs(&mut pem.as_slice())
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("no certificate found"))??;
+    Ok(certificate.to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::Signature;
+
+    #[test]
+    fn test_mock_quote() {
+        let server_key = SigningKey::from_bytes(&[7; 32]).verifying_key();
+        let attestation =
+            Attestation::new(Box::new(MockAttestation::new().unwrap()), b"certificate");
+
+        let nonce = [1; ATTESTATION_NONCE_LEN];
+        let response = attestation
+            .attest(&hex::encode(nonce), &server_key)
+            .unwrap();
+        let quote = &response.quote;
+        assert_eq!(quote.provider, "mock");
+        assert_eq!(quote.measurement, hex::encode(attestation.measurement()));
+        let report_data = attestation_report_data(
+            &Sha256::digest(b"certificate"),
+            server_key.as_bytes(),
+            &nonce,
+        );
+        assert_eq!(quote.report_data, hex::encode(report_data));
+        assert_eq!(
+            quote.attestation_key,
+            hex::encode(MockAttestation::attestation_key().as_bytes())
+        );
+
+        let signature = Signature::from_slice(&hex::decode(&quote.signature).unwrap()).unwrap();
+        assert!(
+            MockAttestation::attestation_key()
+                .verify_strict(&quote.statement(), &signature)
+                .is_ok()
+        );
+
+        assert!(matches!(
+            attestation.attest("abcd", &server_key),
+            Err(Error::InvalidNonce(_))
+        ));
+    }
+}
@@ -0,0 +1,489 @@
+//! Cosmos SDK transaction signing.
+//!
+//! Cosmos chains sign with secp256k1 ECDSA over the SHA-256 of a sign doc, using the same
+//! secp256k1 key as Bitcoin (see [`AppState::sign_cosmos`](crate::state::AppState::sign_cosmos)).
+//! Two sign modes are supported:
+//!
+//! - Amino JSON: a legacy `StdSignDoc`. The server signs its canonical form as the SDK computes
+//!   it: keys sorted, no whitespace, and `<`, `>`, `&`, U+2028 and U+2029 escaped the way Go's
+//!   `encoding/json` does.
+//! - Direct: a Protobuf `SignDoc`, signed as given.
+//!
+//! Either way the sign doc is parsed first, so that the configured [`CosmosPolicy`] hooks can
+//! inspect it and the response can show what was signed.
+
+use bech32::{Bech32, Hrp};
+use bitcoin::hashes::{Hash, hash160};
+use bitcoin::secp256k1::PublicKey;
+use serde_json::Value;
+use signingcommon::{CosmosSignDocInfo, CosmosSignMode};
+use uuid::Uuid;
+
+use crate::error::Error;
+
+/// A parsed sign doc and the bytes to sign
+#[derive(Debug, Clone)]
+pub struct SignDoc {
+    bytes: Vec<u8>,
+    info: CosmosSignDocInfo,
+}
+
+impl SignDoc {
+    /// Parse a sign doc in the given mode, see [`SignDoc::amino_json`] and [`SignDoc::direct`]
+    pub fn parse(mode: CosmosSignMode, sign_doc: &[u8]) -> Result<Self, Error> {
+        match mode {
+            CosmosSignMode::AminoJson => Self::amino_json(sign_doc),
+            CosmosSignMode::Direct => Self::direct(sign_doc.to_vec()),
+        }
+    }
+
+    /// Parse an Amino JSON `StdSignDoc` and put it in canonical form
+    pub fn amino_json(json: &[u8]) -> Result<Self, Error> {
+        let value: Value = serde_json::from_slice(json).map_err(invalid)?;
+        let Value::Object(doc) = &value else {
+            return Err(invalid("not a JSON object"));
+        };
+        let field = |name: &str| {
+            doc.get(name)
+                .ok_or_else(|| invalid(format!("missing {name}")))
+        };
+        let string = |name: &str| {
+            field(name)?
+                .as_str()
+                .ok_or_else(|| invalid(format!("{name} is not a string")))
+        };
+        let number = |name: &str| {
+            string(name)?
+                .parse::<u64>()
+                .map_err(|_| invalid(format!("{name} is not an unsigned integer")))
+        };
+
+        let chain_id = string("chain_id")?;
+        if chain_id.is_empty() {
+            return Err(invalid("chain_id is empty"));
+        }
+        if !field("fee")?.is_object() {
+            return Err(invalid("fee is not an object"));
+        }
+        let messages = field("msgs")?
+            .as_array()
+            .ok_or_else(|| invalid("msgs is not an array"))?
+            .iter()
+            .map(|msg| {
+                msg.get("type")
+                    .and_then(Value::as_str)
+                    .map(str::to_string)
+                    .ok_or_else(|| invalid("message without a type"))
+            })
+            .collect::<Result<_, _>>()?;
+        let info = CosmosSignDocInfo {
+            mode: CosmosSignMode::AminoJson,
+            chain_id: chain_id.to_string(),
+            account_number: number("account_number")?,
+            sequence: Some(number("sequence")?),
+            memo: string("memo")?.to_string(),
+            messages,
+        };
+
+        let mut canonical = String::new();
+        write_canonical(&value, &mut canonical)?;
+        Ok(SignDoc {
+            bytes: canonical.into_bytes(),
+            info,
+        })
+    }
+
+    /// Parse a Protobuf `SignDoc`. Unknown or repeated fields are refused, as the signer could
+    /// not tell what they mean.
+    pub fn direct(bytes: Vec<u8>) -> Result<Self, Error> {
+        let mut body = None;
+        let mut auth_info = None;
+        let mut chain_id = None;
+        let mut account_number = None;
+        for field in fields(&bytes) {
+            let repeated = match field? {
+                (1, Field::Bytes(value)) => body.replace(value).is_some(),
+                (2, Field::Bytes(value)) => auth_info.replace(value).is_some(),
+                (3, Field::Bytes(value)) => chain_id.replace(value).is_some(),
+                (4, Field::Varint(value)) => account_number.replace(value).is_some(),
+                (number, _) => return Err(invalid(format!("unexpected field {number}"))),
+            };
+            if repeated {
+                return Err(invalid("repeated field"));
+            }
+        }
+
+        let body = body.ok_or_else(|| invalid("missing body_bytes"))?;
+        if auth_info.is_none() {
+            return Err(invalid("missing auth_info_bytes"));
+        }
+        let chain_id = std::str::from_utf8(chain_id.unwrap_or_default()).map_err(invalid)?;
+        if chain_id.is_empty() {
+            return Err(invalid("missing chain_id"));
+        }
+
+        let mut messages = Vec::new();
+        let mut memo = String::new();
+        for field in fields(body) {
+            match field? {
+                (1, Field::Bytes(any)) => messages.push(type_url(any)?),
+                (2, Field::Bytes(text)) => {
+                    memo = std::str::from_utf8(text).map_err(invalid)?.to_string()
+                }
+                _ => {}
+            }
+        }
+
+        let info = CosmosSignDocInfo {
+            mode: CosmosSignMode::Direct,
+            chain_id: chain_id.to_string(),
+            account_number: account_number.unwrap_or_default(),
+            sequence: None,
+            memo,
+            messages,
+        };
+        Ok(SignDoc { bytes, info })
+    }
+
+    /// The bytes to sign
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// What the sign doc contains
+    pub fn info(&self) -> &CosmosSignDocInfo {
+        &self.info
+    }
+}
+
+fn invalid(reason: impl ToString) -> Error {
+    Error::InvalidSignDoc(reason.to_string())
+}
+
+// Write `value` as Go's encoding/json writes it after the SDK sorted it: object keys in byte
+// order, no whitespace, and HTML characters and line separators escaped. Numbers other than
+// integers are refused, as they may not survive the round trip unchanged.
+fn write_canonical(value: &Value, out: &mut String) -> Result<(), Error> {
+    match value {
+        Value::Object(object) => {
+            let mut entries: Vec<_> = object.iter().collect();
+            entries.sort_by_key(|(key, _)| *key);
+            out.push('{');
+            for (i, (key, value)) in entries.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_string(key, out);
+                out.push(':');
+                write_canonical(value, out)?;
+            }
+            out.push('}');
+        }
+        Value::Array(array) => {
+            out.push('[');
+            for (i, value) in array.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_canonical(value, out)?;
+            }
+            out.push(']');
+        }
+        Value::String(string) => write_string(string, out),
+        Value::Number(number) if number.is_f64() => {
+            return Err(invalid(format!("{number} is not an integer")));
+        }
+        value => out.push_str(&value.to_string()),
+    }
+    Ok(())
+}
+
+fn write_string(string: &str, out: &mut String) {
+    let escaped = Value::from(string).to_string();
+    for c in escaped.chars() {
+        match c {
+            '<' | '>' | '&' | '\u{2028}' | '\u{2029}' => {
+                out.push_str(&format!("\\u{:04x}", c as u32))
+            }
+            c => out.push(c),
+        }
+    }
+}
+
+// A field of a Protobuf message
+#[derive(Debug, Clone, Copy)]
+enum Field<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+    Fixed,
+}
+
+// The fields of a Protobuf message, as (field number, value). Iteration stops after an error.
+fn fields(mut bytes: &[u8]) -> impl Iterator<Item = Result<(u64, Field<'_>), Error>> {
+    std::iter::from_fn(move || {
+        if bytes.is_empty() {
+            return None;
+        }
+        let field = next_field(&mut bytes);
+        if field.is_err() {
+            bytes = &[];
+        }
+        Some(field)
+    })
+}
+
+fn next_field<'a>(bytes: &mut &'a [u8]) -> Result<(u64, Field<'a>), Error> {
+    let key = varint(bytes)?;
+    let number = key >> 3;
+    if number == 0 {
+        return Err(invalid("field number 0"));
+    }
+    let field = match key & 7 {
+        0 => Field::Varint(varint(bytes)?),
+        1 => {
+            take(bytes, 8)?;
+            Field::Fixed
+        }
+        2 => {
+            let len = usize::try_from(varint(bytes)?).map_err(invalid)?;
+            Field::Bytes(take(bytes, len)?)
+        }
+        5 => {
+            take(bytes, 4)?;
+            Field::Fixed
+        }
+        wire_type => return Err(invalid(format!("unsupported wire type {wire_type}"))),
+    };
+    Ok((number, field))
+}
+
+fn varint(bytes: &mut &[u8]) -> Result<u64, Error> {
+    let mut value = 0u64;
+    for i in 0..10 {
+        let [byte, rest @ ..] = *bytes else {
+            return Err(invalid("message is truncated"));
+        };
+        *bytes = rest;
+        value |= u64::from(byte & 0x7f) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(invalid("varint is too long"))
+}
+
+fn take<'a>(bytes: &mut &'a [u8], len: usize) -> Result<&'a [u8], Error> {
+    if bytes.len() < len {
+        return Err(invalid("message is truncated"));
+    }
+    let (taken, rest) = bytes.split_at(len);
+    *bytes = rest;
+    Ok(taken)
+}
+
+// The type URL of a Protobuf `Any`.
+fn type_url(any: &[u8]) -> Result<String, Error> {
+    for field in fields(any) {
+        if let (1, Field::Bytes(url)) = field? {
+            return Ok(std::str::from_utf8(url).map_err(invalid)?.to_string());
+        }
+    }
+    Err(invalid("message without a type URL"))
+}
+
+/// A policy hook that can refuse to sign a sign doc
+pub trait CosmosPolicy: Send + Sync {
+    /// Name of the policy, reported when it refuses a sign doc
+    fn name(&self) -> &'static str;
+
+    /// Return the reason to refuse signing `sign_doc` for `user_id`, if any
+    fn check(&self, user_id: &Uuid, sign_doc: &SignDoc) -> Result<(), String>;
+}
+
+/// Only sign for the listed chains
+#[derive(Debug)]
+pub struct ChainAllowlist {
+    chain_ids: Vec<String>,
+}
+
+impl ChainAllowlist {
+    pub fn new(chain_ids: &[String]) -> Self {
+        ChainAllowlist {
+            chain_ids: chain_ids.to_vec(),
+        }
+    }
+}
+
+impl CosmosPolicy for ChainAllowlist {
+    fn name(&self) -> &'static str {
+        "chain allowlist"
+    }
+
+    fn check(&self, _user_id: &Uuid, sign_doc: &SignDoc) -> Result<(), String> {
+        let chain_id = &sign_doc.info().chain_id;
+        if !self.chain_ids.contains(chain_id) {
+            return Err(format!("chain {chain_id} is not allowed"));
+        }
+        Ok(())
+    }
+}
+
+/// The address prefix and the policies sign docs must pass
+pub struct Cosmos {
+    prefix: Hrp,
+    policies: Vec<Box<dyn CosmosPolicy>>,
+}
+
+impl std::fmt::Debug for Cosmos {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Cosmos")
+            .field("prefix", &self.prefix.as_str())
+            .field(
+                "policies",
+                &self.policies.iter().map(|p| p.name()).collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+impl Cosmos {
+    /// Addresses get the bech32 human readable part `prefix`, such as "cosmos" or "osmo"
+    pub fn new(prefix: &str, policies: Vec<Box<dyn CosmosPolicy>>) -> anyhow::Result<Self> {
+        let prefix = Hrp::parse(prefix)
+            .map_err(|e| anyhow::anyhow!("invalid address prefix {prefix}: {e}"))?;
+        Ok(Cosmos { prefix, policies })
+    }
+
+    /// The account address of a key: the bech32 encoded RIPEMD-160 of the SHA-256 of the
+    /// compressed key
+    pub fn address(&self, public_key: &PublicKey) -> String {
+        let hash = hash160::Hash::hash(&public_key.serialize());
+        bech32::encode::<Bech32>(self.prefix, hash.as_byte_array())
+            .expect("20 bytes fit in an address")
+    }
+
+    /// Run all policies on `sign_doc`
+    pub fn check(&self, user_id: &Uuid, sign_doc: &SignDoc) -> Result<(), Error> {
+        for policy in &self.policies {
+            policy
+                .check(user_id, sign_doc)
+                .map_err(|reason| Error::PolicyViolation(format!("{}: {reason}", policy.name())))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AMINO: &str = r#"{
+        "sequence": "1",
+        "msgs": [{"type": "cosmos-sdk/MsgSend", "value": {
+            "to_address": "b", "from_address": "a<b",
+            "amount": [{"denom": "uatom", "amount": "1"}]}}],
+        "memo": "x&y",
+        "fee": {"gas": "200000", "amount": []},
+        "chain_id": "cosmoshub-4",
+        "account_number": "7"
+    }"#;
+
+    fn field(number: u8, value: &[u8]) -> Vec<u8> {
+        let mut field = vec![number << 3 | 2, value.len() as u8];
+        field.extend_from_slice(value);
+        field
+    }
+
+    fn direct_sign_doc(chain_id: &str) -> Vec<u8> {
+        let any = [
+            field(1, b"/cosmos.bank.v1beta1.MsgSend"),
+            field(2, b"\x0a\x01a"),
+        ]
+        .concat();
+        let body = [field(1, &any), field(2, b"hi"), vec![3 << 3, 9]].concat();
+        [
+            field(1, &body),
+            field(2, b"\x12\x00"),
+            field(3, chain_id.as_bytes()),
+            vec![4 << 3, 0xac, 0x02],
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn test_amino_json_canonical_form() {
+        let sign_doc = SignDoc::amino_json(AMINO.as_bytes()).unwrap();
+        assert_eq!(
+            std::str::from_utf8(sign_doc.bytes()).unwrap(),
+            r#"{"account_number":"7","chain_id":"cosmoshub-4","fee":{"amount":[],"gas":"200000"},"memo":"x\u0026y","msgs":[{"type":"cosmos-sdk/MsgSend","value":{"amount":[{"amount":"1","denom":"uatom"}],"from_address":"a\u003cb","to_address":"b"}}],"sequence":"1"}"#
+        );
+        let info = sign_doc.info();
+        assert_eq!(info.chain_id, "cosmoshub-4");
+        assert_eq!((info.account_number, info.sequence), (7, Some(1)));
+        assert_eq!(info.memo, "x&y");
+        assert_eq!(info.messages, vec!["cosmos-sdk/MsgSend"]);
+
+        let float = AMINO.replace(r#""gas": "200000""#, r#""gas": 0.5"#);
+        assert!(matches!(
+            SignDoc::amino_json(float.as_bytes()),
+            Err(Error::InvalidSignDoc(_))
+        ));
+        let no_sequence = AMINO.replace(r#""sequence": "1","#, "");
+        assert!(SignDoc::amino_json(no_sequence.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_direct() {
+        let bytes = direct_sign_doc("osmosis-1");
+        let sign_doc = SignDoc::direct(bytes.clone()).unwrap();
+        assert_eq!(sign_doc.bytes(), bytes);
+        let info = sign_doc.info();
+        assert_eq!(info.chain_id, "osmosis-1");
+        assert_eq!(info.account_number, 300);
+        assert_eq!(info.sequence, None);
+        assert_eq!(info.memo, "hi");
+        assert_eq!(info.messages, vec!["/cosmos.bank.v1beta1.MsgSend"]);
+
+        let repeated = [bytes.clone(), field(3, b"other")].concat();
+        assert!(SignDoc::direct(repeated).is_err());
+        let unknown = [bytes.clone(), field(5, b"")].concat();
+        assert!(SignDoc::direct(unknown).is_err());
+        assert!(SignDoc::direct(bytes[..bytes.len() - 1].to_vec()).is_err());
+        assert!(SignDoc::direct(direct_sign_doc("")).is_err());
+    }
+
+    #[test]
+    fn test_address() {
+        // The generator point, whose key hash is the one of the BIP-173 P2WPKH example
+        let public_key: PublicKey =
+            "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798"
+                .parse()
+                .unwrap();
+        let address = Cosmos::new("cosmos", vec![]).unwrap().address(&public_key);
+        assert!(address.starts_with("cosmos1w508d6qejxtdg4y5r3zarvary0c5xw7k"));
+        let (hrp, data) = bech32::decode(&address).unwrap();
+        assert_eq!(hrp.as_str(), "cosmos");
+        assert_eq!(
+            hex::encode(data),
+            "751e76e8199196d454941c45d1b3a323f1433bd6"
+        );
+        assert!(Cosmos::new("not a prefix", vec![]).is_err());
+    }
+
+    #[test]
+    fn test_chain_allowlist() {
+        let cosmos = Cosmos::new(
+            "cosmos",
+            vec![Box::new(ChainAllowlist::new(&["cosmoshub-4".into()]))],
+        )
+        .unwrap();
+        let user_id = Uuid::new_v4();
+        let allowed = SignDoc::amino_json(AMINO.as_bytes()).unwrap();
+        assert!(cosmos.check(&user_id, &allowed).is_ok());
+        let other = SignDoc::direct(direct_sign_doc("osmosis-1")).unwrap();
+        assert!(matches!(
+            cosmos.check(&user_id, &other),
+            Err(Error::PolicyViolation(_))
+        ));
+    }
+}
@@ -0,0 +1,163 @@
+//! Verifiable credentials with Data Integrity proofs (eddsa-jcs-2022).
+//!
+//! Users are identified by the `did:key` of their verifying key (see
+//! [`signingcommon::did_key`]). A credential is secured by adding a proof whose signature covers
+//! the SHA-256 hashes of the JCS (RFC 8785) canonical forms of the proof configuration and the
+//! credential. The server canonicalizes, so clients can send credentials in any key order.
+
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use signingcommon::{DATA_INTEGRITY_CRYPTOSUITE, DataIntegrityProof, VerificationMethod};
+
+use crate::error::Error;
+
+/// The proof configuration of a proof by `verifying_key` made at `created` (unix time): the proof
+/// without its value. Credentials that already have a proof are refused.
+pub fn proof_options(
+    credential: &Map<String, Value>,
+    verifying_key: &[u8; 32],
+    created: u64,
+) -> Result<DataIntegrityProof, Error> {
+    if credential.contains_key("proof") {
+        return Err(Error::InvalidCredential("already has a proof".into()));
+    }
+    Ok(DataIntegrityProof {
+        context: credential.get("@context").cloned(),
+        proof_type: "DataIntegrityProof".to_string(),
+        cryptosuite: DATA_INTEGRITY_CRYPTOSUITE.to_string(),
+        created: date_time(created),
+        verification_method: VerificationMethod::new(verifying_key).id,
+        proof_purpose: "assertionMethod".to_string(),
+        proof_value: None,
+    })
+}
+
+/// The data signed for `proof` of `credential`: the SHA-256 of the canonical proof configuration
+/// followed by the SHA-256 of the canonical credential
+pub fn hash_data(credential: &Map<String, Value>, proof: &DataIntegrityProof) -> [u8; 64] {
+    let proof = DataIntegrityProof {
+        proof_value: None,
+        ..proof.clone()
+    };
+    let mut data = [0; 64];
+    data[..32].copy_from_slice(&Sha256::digest(canonicalize(&proof)));
+    data[32..].copy_from_slice(&Sha256::digest(canonicalize(credential)));
+    data
+}
+
+/// A signature as a proof value: base58btc multibase encoded
+pub fn proof_value(signature: &[u8; 64]) -> String {
+    format!("z{}", bs58::encode(signature).into_string())
+}
+
+fn canonicalize<T: serde::Serialize>(value: &T) -> Vec<u8> {
+    serde_jcs::to_vec(value).expect("JSON values canonicalize")
+}
+
+// A unix time as an XML Schema dateTime in UTC, e.g. 2023-02-24T23:36:38Z
+fn date_time(unix: u64) -> String {
+    let (days, seconds) = (unix / 86400, unix % 86400);
+    // The proleptic Gregorian date of days since the epoch (Howard Hinnant's civil_from_days)
+    let days = days + 719468;
+    let (era, day_of_era) = (days / 146097, days % 146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let (year, month) = if month < 10 {
+        (era * 400 + year_of_era, month + 3)
+    } else {
+        (era * 400 + year_of_era + 1, month - 9)
+    };
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::{Signer, SigningKey, Verifier};
+
+    #[test]
+    fn test_date_time() {
+        assert_eq!(date_time(0), "1970-01-01T00:00:00Z");
+        assert_eq!(date_time(1677281798), "2023-02-24T23:36:38Z");
+        assert_eq!(date_time(951825600), "2000-02-29T12:00:00Z");
+        assert_eq!(date_time(4102444799), "2099-12-31T23:59:59Z");
+    }
+
+    #[test]
+    fn test_proof() {
+        // VC Data Integrity EdDSA Cryptosuites, eddsa-jcs-2022 test vector
+        let signing_key = SigningKey::from_bytes(
+            &hex::decode("c96ef9ea10c5e414c471723aff9de72c35fa5b70fae97e8832ecac7d2e2b8ed6")
+                .unwrap()
+                .try_into()
+                .unwrap(),
+        );
+        let verifying_key = signing_key.verifying_key();
+        let credential = serde_json::json!({
+            "@context": [
+                "https://www.w3.org/ns/credentials/v2",
+                "https://www.w3.org/ns/credentials/examples/v2"
+            ],
+            "id": "urn:uuid:58172aac-d8ba-11ed-83dd-0b3aef56cc33",
+            "type": ["VerifiableCredential", "AlumniCredential"],
+            "name": "Alumni Credential",
+            "description": "A minimum viable example of an Alumni Credential.",
+            "issuer": "https://vc.example/issuers/5678",
+            "validFrom": "2023-01-01T00:00:00Z",
+            "credentialSubject": {
+                "id": "did:example:abcdefgh",
+                "alumniOf": "The School of Examples"
+            }
+        });
+        let credential = credential.as_object().unwrap();
+
+        let mut proof = proof_options(credential, verifying_key.as_bytes(), 1677281798).unwrap();
+        assert_eq!(proof.created, "2023-02-24T23:36:38Z");
+        assert_eq!(
+            proof.verification_method,
+            "did:key:z6MkrJVnaZkeFzdQyMZu1cgjg7k1pZZ6pvBQ7XJPt4swbTQ2\
+             #z6MkrJVnaZkeFzdQyMZu1cgjg7k1pZZ6pvBQ7XJPt4swbTQ2"
+        );
+        assert_eq!(proof.context.as_ref(), credential.get("@context"));
+
+        let data = hash_data(credential, &proof);
+        assert_eq!(hex::encode(data), HASH_DATA);
+        let signature = signing_key.sign(&data);
+        proof.proof_value = Some(proof_value(&signature.to_bytes()));
+        assert_eq!(proof.proof_value.as_deref(), Some(PROOF_VALUE));
+        // The value is not part of what is signed.
+        assert_eq!(hash_data(credential, &proof), data);
+        assert!(verifying_key.verify(&data, &signature).is_ok());
+
+        // Key order does not matter, but the content does.
+        let reordered: Map<String, Value> = credential
+            .iter()
+            .rev()
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        assert_eq!(hash_data(&reordered, &proof), data);
+        let mut changed = credential.clone();
+        changed.insert("name".into(), "Forged Credential".into());
+        assert_ne!(hash_data(&changed, &proof), data);
+
+        // Credentials are signed once.
+        changed.insert("proof".into(), serde_json::to_value(&proof).unwrap());
+        assert!(matches!(
+            proof_options(&changed, verifying_key.as_bytes(), 1677281798),
+            Err(Error::InvalidCredential(_))
+        ));
+    }
+
+    const HASH_DATA: &str = "66ab154f5c2890a140cb8388a22a160454f80575f6eae09e5a097cabe539a1db\
+                             59b7cb6251b8991add1ce0bc83107e3db9dbbab5bd2c28f687db1a03abc92f19";
+    const PROOF_VALUE: &str = "z2HnFSSPPBzR36zdDgK8PbEHeXbR56YF24jwMpt3R1eHXQzJDMWS93FCzpvJpwTWd3GA\
+                               VFuUfjoJdcnTMuVor51aX";
+}
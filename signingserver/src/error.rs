@@ -0,0 +1,147 @@
+use axum::http::StatusCode;
+use signingcommon::UserStatus;
+use thiserror::Error;
+
+/// Errors returned when operating on the server state
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Invalid user id: {0}")]
+    InvalidUserId(#[from] uuid::Error),
+    #[error("No such user")]
+    NoSuchUser,
+    #[error("Server is at capacity. Sorry.")]
+    AtCapacity,
+    #[error("Seed does not match the user's key")]
+    WrongSeed,
+    #[error("TTL must be at least one second")]
+    InvalidTtl,
+    #[error("Key has been revoked")]
+    Revoked,
+    #[error("Revocation list is full")]
+    RevocationListFull,
+    #[error("User is already registered with different settings")]
+    AlreadyRegistered,
+    #[error("Idempotency key was already used for a different request")]
+    IdempotencyKeyReused,
+    #[error("Invalid idempotency key: {0}")]
+    InvalidIdempotencyKey(String),
+    #[error("Invalid signing context: {0}")]
+    InvalidContext(String),
+    #[error("Key is not allowed to sign {0}")]
+    ContextNotAllowed(String),
+    #[error("Messages may not start with the context envelope prefix")]
+    ReservedPrefix,
+    #[error("Invalid request envelope: {0}")]
+    InvalidEnvelope(String),
+    #[error("Request has already been seen")]
+    Replayed,
+    #[error("Request envelope is not authenticated by the user's request key")]
+    UnauthenticatedEnvelope,
+    #[error("Too many requests of this user in flight, try again later")]
+    ReplayCacheFull,
+    #[error("User is frozen")]
+    Frozen,
+    #[error("User is pending approval")]
+    PendingApproval,
+    #[error("User is {0}")]
+    WrongStatus(UserStatus),
+    #[error("Invalid approver set: {0}")]
+    InvalidApproval(String),
+    #[error("Signatures by this user require approval")]
+    ApprovalRequired,
+    #[error("No such pending request")]
+    NoSuchRequest,
+    #[error("Not an approver of this request")]
+    NotAnApprover,
+    #[error("Too many pending requests, try again later")]
+    TooManyPending,
+    #[error("Invalid Solana message: {0}")]
+    InvalidSolanaMessage(String),
+    #[error("Invalid hash: {0}")]
+    InvalidHash(String),
+    #[error("Invalid sign doc: {0}")]
+    InvalidSignDoc(String),
+    #[error("Invalid claims: {0}")]
+    InvalidClaims(String),
+    #[error("Invalid Nostr event: {0}")]
+    InvalidNostrEvent(String),
+    #[error("Invalid credential: {0}")]
+    InvalidCredential(String),
+    #[error("Invalid message: {0}")]
+    InvalidMessage(String),
+    #[error("Message is longer than {0} bytes")]
+    MessageTooLarge(u64),
+    #[error("Message did not arrive within {0} seconds")]
+    StreamTimeout(u64),
+    #[error("Invalid trusted comment: {0}")]
+    InvalidTrustedComment(String),
+    #[error("Invalid PSBT: {0}")]
+    InvalidPsbt(String),
+    #[error("Key is not a signer of the transaction")]
+    NotASigner,
+    #[error("Refused by policy {0}")]
+    PolicyViolation(String),
+    #[error("Solana transaction messages can only be signed with /sign/solana")]
+    SolanaMessage,
+    #[error("Server is in read-only mode")]
+    ReadOnly,
+    #[error("Invalid nonce: {0}")]
+    InvalidNonce(String),
+    #[error("Attestation failed: {0}")]
+    Attestation(String),
+    #[error("Failed to store the identity generation: {0}")]
+    IdentityGeneration(String),
+    #[error(transparent)]
+    Signature(#[from] ed25519_dalek::SignatureError),
+}
+
+impl Error {
+    /// HTTP status to report this error with
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            Error::InvalidUserId(_) | Error::NoSuchUser | Error::NoSuchRequest => {
+                StatusCode::NOT_FOUND
+            }
+            Error::AtCapacity
+            | Error::RevocationListFull
+            | Error::Attestation(_)
+            | Error::IdentityGeneration(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Error::InvalidContext(_)
+            | Error::ReservedPrefix
+            | Error::InvalidEnvelope(_)
+            | Error::InvalidIdempotencyKey(_)
+            | Error::InvalidTtl
+            | Error::InvalidNonce(_)
+            | Error::InvalidApproval(_)
+            | Error::InvalidSolanaMessage(_)
+            | Error::InvalidPsbt(_)
+            | Error::InvalidSignDoc(_)
+            | Error::InvalidHash(_)
+            | Error::InvalidClaims(_)
+            | Error::InvalidNostrEvent(_)
+            | Error::InvalidCredential(_)
+            | Error::InvalidMessage(_)
+            | Error::InvalidTrustedComment(_)
+            | Error::Signature(_) => StatusCode::BAD_REQUEST,
+            Error::ContextNotAllowed(_)
+            | Error::WrongSeed
+            | Error::Revoked
+            | Error::PendingApproval
+            | Error::ApprovalRequired
+            | Error::NotAnApprover
+            | Error::NotASigner
+            | Error::PolicyViolation(_)
+            | Error::SolanaMessage
+            | Error::UnauthenticatedEnvelope => StatusCode::FORBIDDEN,
+            Error::Replayed | Error::AlreadyRegistered | Error::WrongStatus(_) => {
+                StatusCode::CONFLICT
+            }
+            Error::IdempotencyKeyReused => StatusCode::UNPROCESSABLE_ENTITY,
+            Error::ReplayCacheFull => StatusCode::TOO_MANY_REQUESTS,
+            Error::MessageTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            Error::StreamTimeout(_) => StatusCode::REQUEST_TIMEOUT,
+            Error::ReadOnly | Error::TooManyPending => StatusCode::SERVICE_UNAVAILABLE,
+            Error::Frozen => StatusCode::LOCKED,
+        }
+    }
+}
@@ -1,183 +1,2343 @@
-use axum::{Json, extract::State, http::StatusCode, response::IntoResponse};
+use axum::{
+    Json,
+    body::Body,
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use base64::prelude::{BASE64_STANDARD, Engine};
+use bitcoin::Psbt;
+use ed25519_dalek::Signature;
+use futures_util::StreamExt;
+use sha2::{Digest, Sha512};
 use std::sync::Arc;
-use tokio::sync::RwLock;
+use std::time::Duration;
 use tracing::{debug, error, info};
 
+use crate::approvals::PendingSignatures;
+use crate::attestation::Attestation;
+use crate::cosmos::{self, Cosmos};
+use crate::error::Error;
+use crate::idempotency::IdempotencyCache;
+use crate::nostr;
+use crate::psbt;
+use crate::replay::ReplayGuard;
+use crate::signer::{PoolError, SigningPool};
+use crate::solana::{self, Solana};
 use crate::state::AppState;
 use signingcommon::{
-    ErrorResponse, ForgetRequest, ForgetResponse, RegisterRequest, RegisterResponse, SignRequest,
-    SignResponse,
+    ApproveRequest, AttestationParams, CosmosSignMode, CosmosSignRequest, CosmosSignResponse,
+    CredentialSignRequest, DEFAULT_STREAM_CONTEXT, DidDocument, ErrorResponse, ForgetRequest,
+    ForgetResponse, IDEMPOTENCY_KEY_HEADER, IdentityResponse, Jwk, JwkSet, JwtSignRequest,
+    JwtSignResponse, KeyInfo, KeysResponse, NostrSignRequest, PendingQuery, PendingResponse,
+    PsbtSignRequest, PsbtSignResponse, RegisterRequest, RegisterResponse, RenewRequest,
+    RenewResponse, RevokeRequest, RotateRequest, RotateResponse, SignFormat, SignRequest,
+    SignResponse, SolanaSignRequest, SolanaSignResponse, SshSignRequest, SshSignResponse,
+    StreamSignParams, cose_key_set, cwt_claims, did_key, minisig, minisign_key_id,
+    minisign_public_key, minisign_public_key_file, request_mac_message, signify_public_key_file,
+    signify_signature, ssh_public_key, unix_time,
 };
 
 /// Register a new user and generate a signing key
+///
+/// Requests carrying an `Idempotency-Key` header can be retried safely: a repeated key returns the
+/// original outcome rather than registering another user.
 pub async fn register(
-    State(state): State<Arc<RwLock<AppState>>>,
+    State(state): State<Arc<AppState>>,
+    State(idempotency): State<Arc<IdempotencyCache>>,
+    State(cosmos): State<Arc<Cosmos>>,
+    headers: HeaderMap,
     Json(req): Json<RegisterRequest>,
 ) -> impl IntoResponse {
     debug!("Register request for user: {:?}", req.seed);
 
-    let mut state = state.write().await;
-    if let Ok((user_id, verifying_key)) = state.register_user(&req.seed) {
-        (
-            StatusCode::CREATED,
-            Json(RegisterResponse {
-                user_id: user_id.to_string(),
-                verifying_key: hex::encode(verifying_key.as_bytes()),
-            }),
-        )
-            .into_response()
-    } else {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Json(ErrorResponse {
-                error: "Server is at capacity".into(),
-            }),
-        )
-            .into_response()
+    let result = match headers.get(IDEMPOTENCY_KEY_HEADER).map(|key| key.to_str()) {
+        Some(Ok(key)) => {
+            let verifying_key = state.derive_verifying_key(&req.seed);
+            idempotency.register(key, &req, &verifying_key, || state.register_user(&req))
+        }
+        Some(Err(_)) => Err(Error::InvalidIdempotencyKey("not visible ASCII".into())),
+        None => state.register_user(&req),
+    };
+    match result {
+        Ok(registration) => {
+            let user_id = registration.user_id.to_string();
+            let secp256k1_public_key = state.secp256k1_public_key(&user_id).ok();
+            let cosmos_address = secp256k1_public_key
+                .as_ref()
+                .map(|public_key| cosmos.address(public_key));
+            let nostr_public_key = secp256k1_public_key
+                .map(|public_key| nostr::npub(&public_key.x_only_public_key().0));
+            (
+                if registration.created {
+                    StatusCode::CREATED
+                } else {
+                    StatusCode::OK
+                },
+                Json(RegisterResponse {
+                    user_id,
+                    verifying_key: hex::encode(registration.verifying_key.as_bytes()),
+                    expires_at: registration.expires_at,
+                    status: registration.status,
+                    ssh_public_key: Some(ssh_public_key(registration.verifying_key.as_bytes())),
+                    cosmos_address,
+                    nostr_public_key,
+                    did: Some(did_key(registration.verifying_key.as_bytes())),
+                    minisign_public_key: Some(minisign_public_key(
+                        registration.verifying_key.as_bytes(),
+                    )),
+                }),
+            )
+                .into_response()
+        }
+        Err(e) => {
+            error!("Registration failed: {}", e);
+            (
+                e.status_code(),
+                Json(ErrorResponse {
+                    error: e.to_string(),
+                }),
+            )
+                .into_response()
+        }
     }
 }
 
 /// Sign a message for a user
+///
+/// The signature is computed on the signing pool, not on the async executor. For users with an
+/// approver set nothing is signed yet: the request is recorded and returned with `202 Accepted`.
 pub async fn sign(
-    State(state): State<Arc<RwLock<AppState>>>,
+    State(state): State<Arc<AppState>>,
+    State(signer): State<Arc<SigningPool>>,
+    State(replay): State<Arc<ReplayGuard>>,
+    State(pending): State<Arc<PendingSignatures>>,
     Json(req): Json<SignRequest>,
 ) -> impl IntoResponse {
     info!("Sign request for user: {}", req.user_id);
 
-    let state = state.read().await;
-    match state.sign_message(&req.user_id, &req.message) {
-        Ok(signature) => {
-            info!("Message signed successfully for user: {}", req.user_id);
+    if let Err(e) = replay.check(&state, &req.user_id, req.envelope.as_ref(), || {
+        request_mac_message("/sign", &req)
+    }) {
+        error!("Rejected sign request: {}", e);
+        return (
+            e.status_code(),
+            Json(ErrorResponse {
+                error: format!("Signing failed: {}", e),
+            }),
+        )
+            .into_response();
+    }
+
+    // Approvers approve the message, not a COSE structure around it, so only raw signatures can
+    // wait for approval. COSE requests of users with approvers fail to sign below.
+    match state.approval(&req.user_id, req.context.as_deref()) {
+        Ok(None) => {}
+        Ok(Some(_)) if !req.format.is_raw() => {}
+        Ok(Some(approval)) => {
+            return match pending.create(&req.user_id, req.message, req.context, approval) {
+                Ok(request) => {
+                    info!(
+                        "Signature for user {} awaits approval as request {}",
+                        req.user_id, request.request_id
+                    );
+                    (StatusCode::ACCEPTED, Json(request)).into_response()
+                }
+                Err(e) => error_response(e),
+            };
+        }
+        Err(e) => return error_response(e),
+    }
+
+    let user_id = req.user_id.clone();
+    let payload = match req.format {
+        SignFormat::Raw => {
+            let result = signer
+                .run(move || state.sign_message(&req.user_id, &req.message, req.context.as_deref()))
+                .await;
+            return signature_response(&user_id, result);
+        }
+        SignFormat::Minisign | SignFormat::Signify => {
+            return sign_detached(state, signer, req).await;
+        }
+        SignFormat::CoseSign1 => req.message.into_bytes(),
+        SignFormat::Cwt => match serde_json::from_str(&req.message)
+            .map_err(|e| e.to_string())
+            .and_then(|claims| cwt_claims(&claims).map_err(|e| e.to_string()))
+        {
+            Ok(payload) => payload,
+            Err(e) => return error_response(Error::InvalidClaims(e)),
+        },
+    };
+
+    let cwt = req.format == SignFormat::Cwt;
+    let result = signer
+        .run(move || state.sign_cose(&req.user_id, payload, cwt, req.context.as_deref()))
+        .await;
+    match result {
+        Ok(Ok((signature, cose))) => {
+            info!("COSE message signed for user: {}", user_id);
             (
                 StatusCode::OK,
                 Json(SignResponse {
                     signature: hex::encode(signature.to_bytes()),
+                    cose: Some(BASE64_STANDARD.encode(cose.to_vec())),
+                    signature_file: None,
                 }),
             )
                 .into_response()
         }
-        Err(e) => {
-            error!("Signing failed: {}", e);
+        Ok(Err(e)) => signature_response(&user_id, Ok(Err(e))),
+        Err(e) => signature_response(&user_id, Err(e)),
+    }
+}
+
+// Sign a minisign or signify signature file for `req`
+async fn sign_detached(
+    state: Arc<AppState>,
+    signer: Arc<SigningPool>,
+    req: SignRequest,
+) -> Response {
+    if req.context.is_some() {
+        return error_response(Error::InvalidContext(
+            "minisign and signify signatures have no context".into(),
+        ));
+    }
+
+    let user_id = req.user_id.clone();
+    let result = if req.format == SignFormat::Minisign {
+        let hash: [u8; 64] = match hex::decode(&req.message)
+            .map_err(|e| e.to_string())
+            .and_then(|hash| {
+                hash.try_into()
+                    .map_err(|_| "not a BLAKE2b-512 hash".to_string())
+            }) {
+            Ok(hash) => hash,
+            Err(e) => return error_response(Error::InvalidHash(e)),
+        };
+        let trusted_comment = req
+            .trusted_comment
+            .unwrap_or_else(|| format!("timestamp:{}", unix_time()));
+        if trusted_comment.contains(['\r', '\n']) {
+            return error_response(Error::InvalidTrustedComment("must be a single line".into()));
+        }
+        signer
+            .run(move || {
+                let (signature, global_signature, verifying_key) =
+                    state.sign_minisign(&req.user_id, &hash, &trusted_comment)?;
+                let signature_file = minisig(
+                    &minisign_key_id(verifying_key.as_bytes()),
+                    &signature.to_bytes(),
+                    &trusted_comment,
+                    &global_signature.to_bytes(),
+                );
+                Ok((signature, signature_file))
+            })
+            .await
+    } else {
+        if req.trusted_comment.is_some() {
+            return error_response(Error::InvalidTrustedComment(
+                "signify signatures have none".into(),
+            ));
+        }
+        let file = match BASE64_STANDARD.decode(&req.message) {
+            Ok(file) => file,
+            Err(e) => return error_response(Error::InvalidMessage(e.to_string())),
+        };
+        signer
+            .run(move || {
+                let (signature, verifying_key) = state.sign_signify(&req.user_id, &file)?;
+                let signature_file = signify_signature(
+                    &minisign_key_id(verifying_key.as_bytes()),
+                    &signature.to_bytes(),
+                );
+                Ok((signature, signature_file))
+            })
+            .await
+    };
+    match result {
+        Ok(Ok((signature, signature_file))) => {
+            info!("{:?} signature made for user: {}", req.format, user_id);
             (
-                StatusCode::NOT_FOUND,
-                Json(ErrorResponse {
-                    error: format!("Signing failed: {}", e),
+                StatusCode::OK,
+                Json(SignResponse {
+                    signature: hex::encode(signature.to_bytes()),
+                    cose: None,
+                    signature_file: Some(signature_file),
                 }),
             )
                 .into_response()
         }
+        Ok(Err(e)) => signature_response(&user_id, Ok(Err(e))),
+        Err(e) => signature_response(&user_id, Err(e)),
     }
 }
 
-/// Forget a user
-pub async fn forget(
-    State(state): State<Arc<RwLock<AppState>>>,
-    Json(req): Json<ForgetRequest>,
+/// Sign a Solana transaction message for a user
+///
+/// The message is parsed and checked by the Solana policies first. The response shows what was
+/// signed, so that callers need not trust their own serialization.
+pub async fn sign_solana(
+    State(state): State<Arc<AppState>>,
+    State(signer): State<Arc<SigningPool>>,
+    State(replay): State<Arc<ReplayGuard>>,
+    State(solana): State<Arc<Solana>>,
+    Json(req): Json<SolanaSignRequest>,
 ) -> impl IntoResponse {
-    let mut state = state.write().await;
-    state.forget(&req.user_id);
-    (
-        StatusCode::OK,
-        Json(ForgetResponse {
-            message: "User successfully forgotten".to_string(),
-        }),
-    )
-        .into_response()
+    info!("Solana sign request for user: {}", req.user_id);
+
+    if let Err(e) = replay.check(&state, &req.user_id, req.envelope.as_ref(), || {
+        request_mac_message("/sign/solana", &req)
+    }) {
+        return error_response(e);
+    }
+
+    let message = match BASE64_STANDARD
+        .decode(&req.message)
+        .map_err(|e| Error::InvalidSolanaMessage(e.to_string()))
+        .and_then(solana::Message::parse)
+    {
+        Ok(message) => message,
+        Err(e) => return error_response(e),
+    };
+    let check = uuid::Uuid::parse_str(&req.user_id)
+        .map_err(Error::from)
+        .and_then(|user_id| solana.check(&user_id, &message));
+    if let Err(e) = check {
+        return error_response(e);
+    }
+
+    let info = message.info();
+    let user_id = req.user_id.clone();
+    let result = signer
+        .run(move || state.sign_solana(&req.user_id, &message))
+        .await;
+    match result {
+        Ok(Ok((signature, verifying_key))) => {
+            info!("Solana message signed for user: {}", user_id);
+            (
+                StatusCode::OK,
+                Json(SolanaSignResponse {
+                    signature: bs58::encode(signature.to_bytes()).into_string(),
+                    signer: bs58::encode(verifying_key.as_bytes()).into_string(),
+                    message: info,
+                }),
+            )
+                .into_response()
+        }
+        Ok(Err(e)) => signature_response(&user_id, Ok(Err(e))),
+        Err(e) => signature_response(&user_id, Err(e)),
+    }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// Make an SSH signature (SSHSIG), as `ssh-keygen -Y sign` does, for git commits, SSH challenges
+/// and files
+///
+/// The client hashes the message, so it never has to be sent.
+pub async fn sign_ssh(
+    State(state): State<Arc<AppState>>,
+    State(signer): State<Arc<SigningPool>>,
+    State(replay): State<Arc<ReplayGuard>>,
+    Json(req): Json<SshSignRequest>,
+) -> impl IntoResponse {
+    info!(
+        "SSH sign request for user: {} in namespace {:?}",
+        req.user_id, req.namespace
+    );
 
-    #[tokio::test]
-    async fn test_register() {
-        let app_state = Arc::new(RwLock::new(AppState::new()));
-        let req = RegisterRequest {
-            seed: vec![1, 2, 3, 4, 5],
-        };
+    if let Err(e) = replay.check(&state, &req.user_id, req.envelope.as_ref(), || {
+        request_mac_message("/sign/ssh", &req)
+    }) {
+        return error_response(e);
+    }
 
-        let response = register(State(app_state), Json(req)).await.into_response();
+    let hash: [u8; 64] = match hex::decode(&req.hash)
+        .map_err(|e| e.to_string())
+        .and_then(|hash| {
+            hash.try_into()
+                .map_err(|_| "not a SHA-512 hash".to_string())
+        }) {
+        Ok(hash) => hash,
+        Err(e) => return error_response(Error::InvalidHash(e)),
+    };
 
-        assert_eq!(response.status(), StatusCode::CREATED);
+    let user_id = req.user_id.clone();
+    let result = signer
+        .run(move || state.sign_ssh(&req.user_id, &req.namespace, &hash))
+        .await;
+    match result {
+        Ok(Ok(signature)) => {
+            info!("SSH signature made for user: {}", user_id);
+            (
+                StatusCode::OK,
+                Json(SshSignResponse {
+                    signature: signature.armor(),
+                }),
+            )
+                .into_response()
+        }
+        Ok(Err(e)) => signature_response(&user_id, Ok(Err(e))),
+        Err(e) => signature_response(&user_id, Err(e)),
     }
+}
 
-    #[tokio::test]
-    async fn test_forget() {
-        let app_state = Arc::new(RwLock::new(AppState::new()));
+/// Issue a JWT signed with the user's key (`alg: EdDSA`)
+///
+/// The `kid` of the token is the JWK thumbprint of the key, which identifies it in the user's
+/// `jwks.json`.
+pub async fn sign_jwt(
+    State(state): State<Arc<AppState>>,
+    State(signer): State<Arc<SigningPool>>,
+    State(replay): State<Arc<ReplayGuard>>,
+    Json(req): Json<JwtSignRequest>,
+) -> impl IntoResponse {
+    info!("JWT sign request for user: {}", req.user_id);
 
-        // Register
-        let user_id = {
-            let mut state = app_state.write().await;
-            let (user_id, _) = state.register_user(&[1, 2, 3, 4, 5]).unwrap();
-            user_id
-        };
+    if let Err(e) = replay.check(&state, &req.user_id, req.envelope.as_ref(), || {
+        request_mac_message("/sign/jwt", &req)
+    }) {
+        return error_response(e);
+    }
 
-        // Sign something, check success
-        let sign_req = SignRequest {
-            user_id: user_id.to_string(),
-            message: "test message".to_string(),
-        };
+    let user_id = req.user_id.clone();
+    let result = signer
+        .run(move || state.sign_jwt(&req.user_id, &req.claims))
+        .await;
+    match result {
+        Ok(Ok((token, kid))) => {
+            info!("JWT issued for user: {}", user_id);
+            (StatusCode::OK, Json(JwtSignResponse { token, kid })).into_response()
+        }
+        Ok(Err(e)) => signature_response(&user_id, Ok(Err(e))),
+        Err(e) => signature_response(&user_id, Err(e)),
+    }
+}
 
-        let sign_response = sign(State(app_state.clone()), Json(sign_req))
-            .await
-            .into_response();
+/// Sign a Nostr event (NIP-01) with the user's secp256k1 key
+///
+/// The server computes the event's id and returns the event with its id, public key and BIP-340
+/// signature filled in, ready to publish.
+pub async fn sign_nostr(
+    State(state): State<Arc<AppState>>,
+    State(signer): State<Arc<SigningPool>>,
+    State(replay): State<Arc<ReplayGuard>>,
+    Json(req): Json<NostrSignRequest>,
+) -> impl IntoResponse {
+    info!(
+        "Nostr sign request for user: {} of kind {}",
+        req.user_id, req.event.kind
+    );
 
-        assert_eq!(sign_response.status(), StatusCode::OK);
+    if let Err(e) = replay.check(&state, &req.user_id, req.envelope.as_ref(), || {
+        request_mac_message("/sign/nostr", &req)
+    }) {
+        return error_response(e);
+    }
 
-        // Forget
-        let forget_req = ForgetRequest {
-            user_id: user_id.to_string(),
-        };
+    let user_id = req.user_id.clone();
+    let result = signer
+        .run(move || state.sign_nostr(&req.user_id, req.event))
+        .await;
+    match result {
+        Ok(Ok(event)) => {
+            info!("Nostr event {} signed for user: {}", event.id, user_id);
+            (StatusCode::OK, Json(event)).into_response()
+        }
+        Ok(Err(e)) => signature_response(&user_id, Ok(Err(e))),
+        Err(e) => signature_response(&user_id, Err(e)),
+    }
+}
 
-        let forget_response = forget(State(app_state.clone()), Json(forget_req))
-            .await
-            .into_response();
+/// Secure a verifiable credential with a Data Integrity proof (eddsa-jcs-2022) by the user's key
+///
+/// Returns the credential with the proof added. The proof's verification method is in the user's
+/// DID document.
+pub async fn sign_credential(
+    State(state): State<Arc<AppState>>,
+    State(signer): State<Arc<SigningPool>>,
+    State(replay): State<Arc<ReplayGuard>>,
+    Json(req): Json<CredentialSignRequest>,
+) -> impl IntoResponse {
+    info!("Credential sign request for user: {}", req.user_id);
 
-        assert_eq!(forget_response.status(), StatusCode::OK);
+    if let Err(e) = replay.check(&state, &req.user_id, req.envelope.as_ref(), || {
+        request_mac_message("/sign/credential", &req)
+    }) {
+        return error_response(e);
+    }
 
-        // Assert "not found"
-        let sign_req_after = SignRequest {
-            user_id: user_id.to_string(),
-            message: "test message after forget".to_string(),
-        };
+    let user_id = req.user_id.clone();
+    let result = signer
+        .run(move || state.sign_credential(&req.user_id, req.credential))
+        .await;
+    match result {
+        Ok(Ok(credential)) => {
+            info!("Credential signed for user: {}", user_id);
+            (StatusCode::OK, Json(credential)).into_response()
+        }
+        Ok(Err(e)) => signature_response(&user_id, Ok(Err(e))),
+        Err(e) => signature_response(&user_id, Err(e)),
+    }
+}
 
-        let sign_response_after = sign(State(app_state), Json(sign_req_after))
-            .await
-            .into_response();
+/// Sign the inputs of a Bitcoin PSBT that spend the user's keys
+///
+/// Returns the PSBT with the user's partial signatures added, for the client to pass on to other
+/// signers or to finalize.
+pub async fn sign_psbt(
+    State(state): State<Arc<AppState>>,
+    State(signer): State<Arc<SigningPool>>,
+    State(replay): State<Arc<ReplayGuard>>,
+    Json(req): Json<PsbtSignRequest>,
+) -> impl IntoResponse {
+    info!("PSBT sign request for user: {}", req.user_id);
 
-        assert_eq!(sign_response_after.status(), StatusCode::NOT_FOUND);
+    if let Err(e) = replay.check(&state, &req.user_id, req.envelope.as_ref(), || {
+        request_mac_message("/sign/psbt", &req)
+    }) {
+        return error_response(e);
     }
 
-    #[tokio::test]
-    async fn test_sign_success() {
-        let app_state = Arc::new(RwLock::new(AppState::new()));
-        let user_id = {
-            let mut state = app_state.write().await;
-            let (user_id, _) = state.register_user(&[1, 2, 3, 4, 5]).unwrap();
-            user_id
-        };
+    let mut psbt = match req.psbt.parse::<Psbt>() {
+        Ok(psbt) => psbt,
+        Err(e) => return error_response(Error::InvalidPsbt(e.to_string())),
+    };
 
-        let sign_req = SignRequest {
-            user_id: user_id.to_string(),
-            message: "test message".to_string(),
-        };
+    let user_id = req.user_id.clone();
+    let result = signer
+        .run(move || {
+            state
+                .sign_psbt(&req.user_id, &mut psbt)
+                .map(|(signed_inputs, keys)| (psbt, signed_inputs, keys))
+        })
+        .await;
+    match result {
+        Ok(Ok((psbt, signed_inputs, keys))) => {
+            info!(
+                "Signed inputs {:?} of PSBT for user: {}",
+                signed_inputs, user_id
+            );
+            (
+                StatusCode::OK,
+                Json(PsbtSignResponse {
+                    psbt: psbt.to_string(),
+                    signed_inputs,
+                    keys,
+                }),
+            )
+                .into_response()
+        }
+        Ok(Err(e)) => signature_response(&user_id, Ok(Err(e))),
+        Err(e) => signature_response(&user_id, Err(e)),
+    }
+}
 
-        let response = sign(State(app_state), Json(sign_req)).await.into_response();
+/// Sign a Cosmos SDK transaction, in Amino JSON or direct sign mode, with the user's secp256k1 key
+pub async fn sign_cosmos(
+    State(state): State<Arc<AppState>>,
+    State(signer): State<Arc<SigningPool>>,
+    State(replay): State<Arc<ReplayGuard>>,
+    State(cosmos): State<Arc<Cosmos>>,
+    Json(req): Json<CosmosSignRequest>,
+) -> impl IntoResponse {
+    info!("Cosmos sign request for user: {}", req.user_id);
 
-        assert_eq!(response.status(), StatusCode::OK);
+    if let Err(e) = replay.check(&state, &req.user_id, req.envelope.as_ref(), || {
+        request_mac_message("/sign/cosmos", &req)
+    }) {
+        return error_response(e);
     }
 
-    #[tokio::test]
-    async fn test_sign_fail() {
-        let app_state = Arc::new(RwLock::new(AppState::new()));
+    let sign_doc = match req.mode {
+        CosmosSignMode::AminoJson => Ok(req.sign_doc.into_bytes()),
+        CosmosSignMode::Direct => BASE64_STANDARD
+            .decode(&req.sign_doc)
+            .map_err(|e| Error::InvalidSignDoc(e.to_string())),
+    }
+    .and_then(|sign_doc| cosmos::SignDoc::parse(req.mode, &sign_doc));
+    let sign_doc = match sign_doc {
+        Ok(sign_doc) => sign_doc,
+        Err(e) => return error_response(e),
+    };
+    let check = uuid::Uuid::parse_str(&req.user_id)
+        .map_err(Error::from)
+        .and_then(|user_id| cosmos.check(&user_id, &sign_doc));
+    if let Err(e) = check {
+        return error_response(e);
+    }
 
-        let sign_req = SignRequest {
-            user_id: "non-existent-user".to_string(),
-            message: "test message".to_string(),
+    let info = sign_doc.info().clone();
+    let user_id = req.user_id.clone();
+    let result = signer
+        .run(move || state.sign_cosmos(&req.user_id, &sign_doc))
+        .await;
+    match result {
+        Ok(Ok((signature, public_key))) => {
+            info!(
+                "Cosmos transaction signed for user: {} on chain {}",
+                user_id, info.chain_id
+            );
+            (
+                StatusCode::OK,
+                Json(CosmosSignResponse {
+                    signature: BASE64_STANDARD.encode(signature.serialize_compact()),
+                    public_key: BASE64_STANDARD.encode(public_key.serialize()),
+                    address: cosmos.address(&public_key),
+                    sign_doc: info,
+                }),
+            )
+                .into_response()
+        }
+        Ok(Err(e)) => signature_response(&user_id, Ok(Err(e))),
+        Err(e) => signature_response(&user_id, Err(e)),
+    }
+}
+
+/// The Bitcoin keys of a user, to build PSBTs with
+pub async fn bitcoin_keys(
+    State(state): State<Arc<AppState>>,
+    Path(user_id): Path<String>,
+) -> impl IntoResponse {
+    match state.secp256k1_public_key(&user_id) {
+        Ok(public_key) => (StatusCode::OK, Json(psbt::keys(&public_key))).into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+/// Approve a pending signature
+///
+/// The approval must be signed by the approver's registered key. The approval that meets the
+/// quorum makes the signature, which is then part of the returned request.
+pub async fn approve(
+    State(state): State<Arc<AppState>>,
+    State(signer): State<Arc<SigningPool>>,
+    State(pending): State<Arc<PendingSignatures>>,
+    Path(request_id): Path<String>,
+    Json(req): Json<ApproveRequest>,
+) -> impl IntoResponse {
+    info!(
+        "Approval of request {} by user {}",
+        request_id, req.approver_id
+    );
+
+    let approved = match pending.approve(&state, &request_id, &req) {
+        Ok(approved) => approved,
+        Err(e) => return error_response(e),
+    };
+    if !approved.ready {
+        return (StatusCode::OK, Json(approved.request)).into_response();
+    }
+
+    let request = approved.request;
+    let user_id = request.user_id.clone();
+    let result = signer
+        .run(move || {
+            state.sign_approved(
+                &request.user_id,
+                &request.message,
+                request.context.as_deref(),
+            )
+        })
+        .await;
+    match result {
+        Ok(Ok(signature)) => {
+            info!("Approved message signed for user: {}", user_id);
+            match pending.release(&request_id, signature) {
+                Ok(request) => (StatusCode::OK, Json(request)).into_response(),
+                Err(e) => error_response(e),
+            }
+        }
+        result => signature_response(&user_id, result),
+    }
+}
+
+/// A pending signature, including the signature once approved
+pub async fn pending_request(
+    State(state): State<Arc<AppState>>,
+    State(replay): State<Arc<ReplayGuard>>,
+    State(pending): State<Arc<PendingSignatures>>,
+    Path(request_id): Path<String>,
+    Query(query): Query<PendingQuery>,
+) -> impl IntoResponse {
+    let path = format!("/pending/{request_id}");
+    if let Err(e) = replay.check(&state, &query.user_id, Some(&query.envelope()), || {
+        query.mac_message(&path)
+    }) {
+        return error_response(e);
+    }
+    match pending.get(&request_id, &query.user_id) {
+        Ok(request) => (StatusCode::OK, Json(request)).into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+/// Pending signatures for a user, or awaiting their approval. Only the user can list them.
+pub async fn pending(
+    State(state): State<Arc<AppState>>,
+    State(replay): State<Arc<ReplayGuard>>,
+    State(pending): State<Arc<PendingSignatures>>,
+    Path(user_id): Path<String>,
+    Query(query): Query<PendingQuery>,
+) -> impl IntoResponse {
+    if query.user_id != user_id {
+        return error_response(Error::UnauthenticatedEnvelope);
+    }
+    let path = format!("/users/{user_id}/pending");
+    if let Err(e) = replay.check(&state, &user_id, Some(&query.envelope()), || {
+        query.mac_message(&path)
+    }) {
+        return error_response(e);
+    }
+    match pending.for_user(&user_id) {
+        Ok(requests) => (StatusCode::OK, Json(PendingResponse { requests })).into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+pub(crate) fn error_response(e: Error) -> Response {
+    error!("Request failed: {}", e);
+    (
+        e.status_code(),
+        Json(ErrorResponse {
+            error: e.to_string(),
+        }),
+    )
+        .into_response()
+}
+
+/// Limits on the messages streamed to `/sign/stream`
+#[derive(Debug, Clone, Copy)]
+pub struct StreamLimits {
+    /// Longest message accepted, in bytes
+    pub max_bytes: u64,
+    /// How long the whole message may take to arrive
+    pub timeout: Duration,
+}
+
+impl Default for StreamLimits {
+    fn default() -> Self {
+        StreamLimits {
+            max_bytes: 4 << 30,
+            timeout: Duration::from_secs(600),
+        }
+    }
+}
+
+/// Sign a message streamed as the request body for a user
+///
+/// The body is hashed incrementally with SHA-512 as it arrives and the digest signed with
+/// Ed25519ph, so the message is never held in memory as a whole. Everything that does not depend
+/// on the message is checked before it is read, so requests bound to fail cannot hold a connection
+/// open by streaming.
+pub async fn sign_stream(
+    State(state): State<Arc<AppState>>,
+    State(signer): State<Arc<SigningPool>>,
+    State(replay): State<Arc<ReplayGuard>>,
+    State(limits): State<StreamLimits>,
+    Query(params): Query<StreamSignParams>,
+    body: Body,
+) -> impl IntoResponse {
+    info!("Streaming sign request for user: {}", params.user_id);
+
+    let context = params
+        .context
+        .clone()
+        .unwrap_or_else(|| DEFAULT_STREAM_CONTEXT.to_string());
+    let envelope = params.envelope();
+    let checked = replay.check_unread(envelope.as_ref()).and_then(|()| {
+        match state.approval(&params.user_id, Some(&context))? {
+            Some(_) => Err(Error::ApprovalRequired),
+            None => Ok(()),
+        }
+    });
+    if let Err(e) = checked {
+        return error_response(e);
+    }
+
+    let prehashed =
+        match tokio::time::timeout(limits.timeout, hash_body(body, limits.max_bytes)).await {
+            Ok(Ok(prehashed)) => prehashed,
+            Ok(Err(response)) => return response,
+            Err(_) => return error_response(Error::StreamTimeout(limits.timeout.as_secs())),
         };
 
-        let response = sign(State(app_state), Json(sign_req)).await.into_response();
+    // The envelope's MAC covers the message too, so it can only be checked once the message is
+    // read.
+    let digest = prehashed.clone().finalize();
+    if let Err(e) = replay.check(&state, &params.user_id, envelope.as_ref(), || {
+        params.mac_message(&digest)
+    }) {
+        return error_response(e);
+    }
+
+    let user_id = params.user_id.clone();
+    let result = signer
+        .run(move || state.sign_prehashed(&params.user_id, prehashed, &context))
+        .await;
+    signature_response(&user_id, result)
+}
 
-        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+// Hash a streamed message with SHA-512, refusing messages longer than `max_bytes`.
+async fn hash_body(body: Body, max_bytes: u64) -> Result<Sha512, Response> {
+    let mut prehashed = Sha512::new();
+    let mut len = 0u64;
+    let mut stream = body.into_data_stream();
+    while let Some(chunk) = stream.next().await {
+        match chunk {
+            Ok(chunk) => {
+                len += chunk.len() as u64;
+                if len > max_bytes {
+                    return Err(error_response(Error::MessageTooLarge(max_bytes)));
+                }
+                prehashed.update(&chunk);
+            }
+            Err(e) => {
+                error!("Failed to read message stream: {}", e);
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    Json(ErrorResponse {
+                        error: format!("Failed to read message: {}", e),
+                    }),
+                )
+                    .into_response());
+            }
+        }
+    }
+    Ok(prehashed)
+}
+
+// Turn the outcome of a signing job into a response.
+fn signature_response(
+    user_id: &str,
+    result: Result<Result<Signature, Error>, PoolError>,
+) -> Response {
+    match result {
+        Ok(Ok(signature)) => {
+            info!("Message signed successfully for user: {}", user_id);
+            (
+                StatusCode::OK,
+                Json(SignResponse {
+                    signature: hex::encode(signature.to_bytes()),
+                    cose: None,
+                    signature_file: None,
+                }),
+            )
+                .into_response()
+        }
+        Ok(Err(e)) => {
+            error!("Signing failed: {}", e);
+            (
+                e.status_code(),
+                Json(ErrorResponse {
+                    error: format!("Signing failed: {}", e),
+                }),
+            )
+                .into_response()
+        }
+        Err(e) => {
+            error!("Signing failed: {}", e);
+            let status = match e {
+                PoolError::Busy | PoolError::DeadlineExceeded => StatusCode::SERVICE_UNAVAILABLE,
+                PoolError::Closed => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            (
+                status,
+                Json(ErrorResponse {
+                    error: format!("Signing failed: {}", e),
+                }),
+            )
+                .into_response()
+        }
+    }
+}
+
+/// Extend the lifetime of a user
+///
+/// The request must carry the user's seed, proving ownership of the key.
+pub async fn renew(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<RenewRequest>,
+) -> impl IntoResponse {
+    info!("Renew request for user: {}", req.user_id);
+
+    match state.renew(&req.user_id, &req.seed, req.ttl_secs) {
+        Ok(expires_at) => (
+            StatusCode::OK,
+            Json(RenewResponse {
+                user_id: req.user_id,
+                expires_at,
+            }),
+        )
+            .into_response(),
+        Err(e) => {
+            error!("Renewal failed: {}", e);
+            (
+                e.status_code(),
+                Json(ErrorResponse {
+                    error: e.to_string(),
+                }),
+            )
+                .into_response()
+        }
+    }
+}
+
+/// Rotate a user's key to its next generation
+///
+/// The request must carry the user's seed. The response includes a rotation certificate signed
+/// by the previous key.
+pub async fn rotate(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<RotateRequest>,
+) -> impl IntoResponse {
+    info!("Rotate request for user: {}", req.user_id);
+
+    match state.rotate(&req.user_id, &req.seed) {
+        Ok(rotation) => (
+            StatusCode::OK,
+            Json(RotateResponse {
+                user_id: req.user_id,
+                generation: rotation.certificate.generation,
+                verifying_key: rotation.certificate.verifying_key.clone(),
+                previous_valid_until: rotation.previous_valid_until,
+                certificate: rotation.certificate,
+            }),
+        )
+            .into_response(),
+        Err(e) => {
+            error!("Rotation failed: {}", e);
+            (
+                e.status_code(),
+                Json(ErrorResponse {
+                    error: e.to_string(),
+                }),
+            )
+                .into_response()
+        }
+    }
+}
+
+/// List the verifying keys of a user, including a recently rotated away key
+pub async fn keys(
+    State(state): State<Arc<AppState>>,
+    Path(user_id): Path<String>,
+) -> impl IntoResponse {
+    match state.verifying_keys(&user_id) {
+        Ok(keys) => (StatusCode::OK, Json(KeysResponse { user_id, keys })).into_response(),
+        Err(e) => (
+            e.status_code(),
+            Json(ErrorResponse {
+                error: e.to_string(),
+            }),
+        )
+            .into_response(),
+    }
+}
+
+/// The verifying keys of a user as a JWK set, for services that accept the user's JWTs
+///
+/// Like `/users/{id}/keys`, the set has the previous key during its grace period after a
+/// rotation, so tokens issued just before it still verify.
+pub async fn jwks(
+    State(state): State<Arc<AppState>>,
+    Path(user_id): Path<String>,
+) -> impl IntoResponse {
+    match state.verifying_keys(&user_id) {
+        Ok(keys) => {
+            let keys = key_bytes(&keys).iter().map(Jwk::new).collect();
+            (StatusCode::OK, Json(JwkSet { keys })).into_response()
+        }
+        Err(e) => error_response(e),
+    }
+}
+
+/// The DID document of the `did:key` of a user's current verifying key
+///
+/// A `did:key` document is derived from the key alone, so any resolver gives the same document.
+/// After a rotation the user has a new DID.
+pub async fn did_document(
+    State(state): State<Arc<AppState>>,
+    Path(user_id): Path<String>,
+) -> impl IntoResponse {
+    match state.verifying_keys(&user_id) {
+        // The current key comes first.
+        Ok(keys) => (
+            StatusCode::OK,
+            [(header::CONTENT_TYPE, "application/did+json")],
+            Json(DidDocument::new(&key_bytes(&keys)[0])),
+        )
+            .into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+/// A user's current verifying key as a minisign public key file, for `minisign -V -p`
+pub async fn minisign_key(
+    State(state): State<Arc<AppState>>,
+    Path(user_id): Path<String>,
+) -> impl IntoResponse {
+    public_key_file(&state, &user_id, minisign_public_key_file)
+}
+
+/// A user's current verifying key as a signify public key file, for `signify -V -p`
+pub async fn signify_key(
+    State(state): State<Arc<AppState>>,
+    Path(user_id): Path<String>,
+) -> impl IntoResponse {
+    public_key_file(&state, &user_id, signify_public_key_file)
+}
+
+// The public key file of a user's current key, in the format made by `file`
+fn public_key_file(state: &AppState, user_id: &str, file: fn(&[u8; 32]) -> String) -> Response {
+    match state.verifying_keys(user_id) {
+        // The current key comes first.
+        Ok(keys) => (
+            StatusCode::OK,
+            [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+            file(&key_bytes(&keys)[0]),
+        )
+            .into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+/// The verifying keys of a user as a COSE_KeySet (CBOR), for constrained verifiers of the user's
+/// COSE signatures and CWTs
+///
+/// Like `jwks.json`, the set has the previous key during its grace period after a rotation.
+pub async fn cose_keys(
+    State(state): State<Arc<AppState>>,
+    Path(user_id): Path<String>,
+) -> impl IntoResponse {
+    match state.verifying_keys(&user_id) {
+        Ok(keys) => (
+            StatusCode::OK,
+            [(header::CONTENT_TYPE, "application/cose-key-set")],
+            cose_key_set(&key_bytes(&keys)),
+        )
+            .into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+fn key_bytes(keys: &[KeyInfo]) -> Vec<[u8; 32]> {
+    keys.iter()
+        .map(|key| {
+            let mut verifying_key = [0; 32];
+            hex::decode_to_slice(&key.verifying_key, &mut verifying_key)
+                .expect("verifying keys are hex encoded");
+            verifying_key
+        })
+        .collect()
+}
+
+/// Revoke all keys of a user whose seed was compromised
+///
+/// The request must carry the user's seed. The response is a revocation statement signed by the
+/// server key.
+pub async fn revoke(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<RevokeRequest>,
+) -> impl IntoResponse {
+    info!("Revoke request for user: {}", req.user_id);
+
+    match state.revoke(&req.user_id, &req.seed) {
+        Ok(statement) => (StatusCode::OK, Json(statement)).into_response(),
+        Err(e) => {
+            error!("Revocation failed: {}", e);
+            (
+                e.status_code(),
+                Json(ErrorResponse {
+                    error: e.to_string(),
+                }),
+            )
+                .into_response()
+        }
+    }
+}
+
+/// The list of all revoked keys, signed by the server key
+pub async fn revocations(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    Json(state.revocation_list())
+}
+
+/// The server's verifying key
+pub async fn identity(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    Json(IdentityResponse {
+        verifying_key: hex::encode(state.identity().as_bytes()),
+    })
+}
+
+/// Attest the server binary, TLS certificate and identity key
+///
+/// The client picks the nonce, which makes the quote fresh.
+pub async fn attestation(
+    State(state): State<Arc<AppState>>,
+    State(attestation): State<Arc<Attestation>>,
+    Query(params): Query<AttestationParams>,
+) -> impl IntoResponse {
+    match attestation.attest(&params.nonce, &state.identity()) {
+        Ok(response) => (StatusCode::OK, Json(response)).into_response(),
+        Err(e) => {
+            error!("Attestation failed: {}", e);
+            (
+                e.status_code(),
+                Json(ErrorResponse {
+                    error: e.to_string(),
+                }),
+            )
+                .into_response()
+        }
+    }
+}
+
+/// Forget a user
+pub async fn forget(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<ForgetRequest>,
+) -> impl IntoResponse {
+    match state.forget(&req.user_id) {
+        Ok(()) => (
+            StatusCode::OK,
+            Json(ForgetResponse {
+                message: "User successfully forgotten".to_string(),
+            }),
+        )
+            .into_response(),
+        Err(e) => {
+            error!("Forget failed: {}", e);
+            (
+                e.status_code(),
+                Json(ErrorResponse {
+                    error: e.to_string(),
+                }),
+            )
+                .into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::signer::PoolConfig;
+
+    fn signer() -> Arc<SigningPool> {
+        Arc::new(SigningPool::new(PoolConfig::default()))
+    }
+
+    fn replay() -> Arc<ReplayGuard> {
+        Arc::new(ReplayGuard::new(false))
+    }
+
+    fn pending() -> Arc<PendingSignatures> {
+        Arc::new(PendingSignatures::new(std::time::Duration::from_secs(60)))
+    }
+
+    fn cosmos() -> Arc<Cosmos> {
+        Arc::new(Cosmos::new("cosmos", vec![]).unwrap())
+    }
+
+    fn idempotency() -> Arc<IdempotencyCache> {
+        Arc::new(IdempotencyCache::new(std::time::Duration::from_secs(60)))
+    }
+
+    // Call `sign` and return the status and the signature, if any
+    async fn sign_as(
+        app_state: &Arc<AppState>,
+        req: SignRequest,
+    ) -> (StatusCode, Option<SignResponse>) {
+        let response = sign(
+            State(app_state.clone()),
+            State(signer()),
+            State(replay()),
+            State(pending()),
+            Json(req),
+        )
+        .await
+        .into_response();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&body).ok())
+    }
+
+    fn register_user(state: &AppState, seed: &[u8]) -> (uuid::Uuid, ed25519_dalek::VerifyingKey) {
+        let registration = state
+            .register_user(&RegisterRequest {
+                seed: seed.to_vec(),
+                ..Default::default()
+            })
+            .unwrap();
+        (registration.user_id, registration.verifying_key)
+    }
+
+    #[tokio::test]
+    async fn test_register() {
+        let app_state = Arc::new(AppState::new());
+        let req = RegisterRequest {
+            seed: vec![1, 2, 3, 4, 5],
+            ..Default::default()
+        };
+
+        let response = register(
+            State(app_state),
+            State(idempotency()),
+            State(cosmos()),
+            HeaderMap::new(),
+            Json(req),
+        )
+        .await
+        .into_response();
+
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let response: RegisterResponse = serde_json::from_slice(&body).unwrap();
+        assert!(response.cosmos_address.unwrap().starts_with("cosmos1"));
+        assert!(response.nostr_public_key.unwrap().starts_with("npub1"));
+        assert!(response.did.unwrap().starts_with("did:key:z6Mk"));
+    }
+
+    #[tokio::test]
+    async fn test_register_idempotent() {
+        let app_state = Arc::new(AppState::new());
+        let idempotency = idempotency();
+        let req = RegisterRequest {
+            seed: vec![1, 2, 3, 4, 5],
+            ..Default::default()
+        };
+        let mut headers = HeaderMap::new();
+        headers.insert(IDEMPOTENCY_KEY_HEADER, "retry-me".parse().unwrap());
+
+        for _ in 0..3 {
+            let response = register(
+                State(app_state.clone()),
+                State(idempotency.clone()),
+                State(cosmos()),
+                headers.clone(),
+                Json(req.clone()),
+            )
+            .await
+            .into_response();
+            assert_eq!(response.status(), StatusCode::CREATED);
+        }
+        assert_eq!(app_state.user_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_renew() {
+        let app_state = Arc::new(AppState::new());
+        let (user_id, _) = register_user(&app_state, b"seed");
+
+        let renew_req = |seed: &[u8]| RenewRequest {
+            user_id: user_id.to_string(),
+            seed: seed.to_vec(),
+            ttl_secs: 60,
+        };
+        let response = renew(State(app_state.clone()), Json(renew_req(b"seed")))
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = renew(State(app_state), Json(renew_req(b"wrong seed")))
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_rotate() {
+        let app_state = Arc::new(AppState::new());
+        let (user_id, _) = register_user(&app_state, b"seed");
+
+        let response = rotate(
+            State(app_state.clone()),
+            Json(RotateRequest {
+                user_id: user_id.to_string(),
+                seed: b"seed".to_vec(),
+            }),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = keys(State(app_state), Path(user_id.to_string()))
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_revoke() {
+        let app_state = Arc::new(AppState::new());
+        let (user_id, _) = register_user(&app_state, b"seed");
+
+        let revoke_req = || RevokeRequest {
+            user_id: user_id.to_string(),
+            seed: b"seed".to_vec(),
+        };
+        let response = revoke(State(app_state.clone()), Json(revoke_req()))
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // The user is gone now.
+        let response = revoke(State(app_state), Json(revoke_req()))
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_forget() {
+        let app_state = Arc::new(AppState::new());
+
+        // Register
+        let (user_id, _) = register_user(&app_state, &[1, 2, 3, 4, 5]);
+
+        // Sign something, check success
+        let sign_req = SignRequest {
+            user_id: user_id.to_string(),
+            message: "test message".to_string(),
+            context: None,
+            envelope: None,
+            format: SignFormat::Raw,
+            trusted_comment: None,
+        };
+
+        let sign_response = sign(
+            State(app_state.clone()),
+            State(signer()),
+            State(replay()),
+            State(pending()),
+            Json(sign_req),
+        )
+        .await
+        .into_response();
+
+        assert_eq!(sign_response.status(), StatusCode::OK);
+
+        // Forget
+        let forget_req = ForgetRequest {
+            user_id: user_id.to_string(),
+        };
+
+        let forget_response = forget(State(app_state.clone()), Json(forget_req))
+            .await
+            .into_response();
+
+        assert_eq!(forget_response.status(), StatusCode::OK);
+
+        // Assert "not found"
+        let sign_req_after = SignRequest {
+            user_id: user_id.to_string(),
+            message: "test message after forget".to_string(),
+            context: None,
+            envelope: None,
+            format: SignFormat::Raw,
+            trusted_comment: None,
+        };
+
+        let sign_response_after = sign(
+            State(app_state),
+            State(signer()),
+            State(replay()),
+            State(pending()),
+            Json(sign_req_after),
+        )
+        .await
+        .into_response();
+
+        assert_eq!(sign_response_after.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_sign_success() {
+        let app_state = Arc::new(AppState::new());
+        let (user_id, _) = register_user(&app_state, &[1, 2, 3, 4, 5]);
+
+        let sign_req = SignRequest {
+            user_id: user_id.to_string(),
+            message: "test message".to_string(),
+            context: None,
+            envelope: None,
+            format: SignFormat::Raw,
+            trusted_comment: None,
+        };
+
+        let response = sign(
+            State(app_state),
+            State(signer()),
+            State(replay()),
+            State(pending()),
+            Json(sign_req),
+        )
+        .await
+        .into_response();
+
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_sign_fail() {
+        let app_state = Arc::new(AppState::new());
+
+        let sign_req = SignRequest {
+            user_id: "non-existent-user".to_string(),
+            message: "test message".to_string(),
+            context: None,
+            envelope: None,
+            format: SignFormat::Raw,
+            trusted_comment: None,
+        };
+
+        let response = sign(
+            State(app_state),
+            State(signer()),
+            State(replay()),
+            State(pending()),
+            Json(sign_req),
+        )
+        .await
+        .into_response();
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_sign_context_not_allowed() {
+        let app_state = Arc::new(AppState::new());
+        let user_id = app_state
+            .register_user(&RegisterRequest {
+                seed: vec![1, 2, 3, 4, 5],
+                allowed_contexts: vec!["app".to_string()],
+                ..Default::default()
+            })
+            .unwrap()
+            .user_id;
+
+        let sign_req = SignRequest {
+            user_id: user_id.to_string(),
+            message: "test message".to_string(),
+            context: Some("other-app".to_string()),
+            envelope: None,
+            format: SignFormat::Raw,
+            trusted_comment: None,
+        };
+        let response = sign(
+            State(app_state),
+            State(signer()),
+            State(replay()),
+            State(pending()),
+            Json(sign_req),
+        )
+        .await
+        .into_response();
+
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_sign_replayed() {
+        let app_state = Arc::new(AppState::new());
+        let replay = replay();
+        let (user_id, _) = register_user(&app_state, &[1, 2, 3, 4, 5]);
+
+        let mut sign_req = SignRequest {
+            user_id: user_id.to_string(),
+            message: "test message".to_string(),
+            context: None,
+            envelope: Some(signingcommon::RequestEnvelope {
+                nonce: hex::encode([1u8; 16]),
+                expires_at: signingcommon::unix_time() + 60,
+                mac: String::new(),
+            }),
+            format: SignFormat::Raw,
+            trusted_comment: None,
+        };
+        let mac = signingcommon::request_mac(
+            &signingcommon::request_key(&[1, 2, 3, 4, 5]),
+            &request_mac_message("/sign", &sign_req),
+        );
+        sign_req.envelope.as_mut().unwrap().mac = hex::encode(mac);
+        let response = sign(
+            State(app_state.clone()),
+            State(signer()),
+            State(replay.clone()),
+            State(pending()),
+            Json(sign_req.clone()),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = sign(
+            State(app_state),
+            State(signer()),
+            State(replay),
+            State(pending()),
+            Json(sign_req),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+    }
+
+    #[tokio::test]
+    async fn test_sign_stream() {
+        let app_state = Arc::new(AppState::new());
+        let (user_id, verifying_key) = register_user(&app_state, &[1, 2, 3, 4, 5]);
+        let message = vec![7u8; 100_000];
+
+        let params = StreamSignParams {
+            user_id: user_id.to_string(),
+            context: None,
+            ..Default::default()
+        };
+        let response = sign_stream(
+            State(app_state),
+            State(signer()),
+            State(replay()),
+            State(StreamLimits::default()),
+            Query(params),
+            Body::from(message.clone()),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let resp: SignResponse = serde_json::from_slice(&body).unwrap();
+        let signature = Signature::from_slice(&hex::decode(resp.signature).unwrap()).unwrap();
+        let prehashed = Sha512::new().chain_update(&message);
+        assert!(
+            verifying_key
+                .verify_prehashed(
+                    prehashed,
+                    Some(DEFAULT_STREAM_CONTEXT.as_bytes()),
+                    &signature
+                )
+                .is_ok()
+        );
+    }
+
+    // A body that never yields, so a handler that reads it never finishes.
+    fn endless_body() -> Body {
+        Body::from_stream(futures_util::stream::pending::<
+            Result<Vec<u8>, std::io::Error>,
+        >())
+    }
+
+    #[tokio::test]
+    async fn test_sign_stream_checks_before_reading() {
+        let app_state = Arc::new(AppState::new());
+        let (user_id, _) = register_user(&app_state, b"stream");
+
+        let unknown = StreamSignParams {
+            user_id: uuid::Uuid::new_v4().to_string(),
+            ..Default::default()
+        };
+        let unsealed = StreamSignParams {
+            user_id: user_id.to_string(),
+            ..Default::default()
+        };
+        for (replay, params, status) in [
+            (replay(), unknown, StatusCode::NOT_FOUND),
+            (
+                Arc::new(ReplayGuard::new(true)),
+                unsealed,
+                StatusCode::BAD_REQUEST,
+            ),
+        ] {
+            let response = tokio::time::timeout(
+                Duration::from_secs(5),
+                sign_stream(
+                    State(app_state.clone()),
+                    State(signer()),
+                    State(replay),
+                    State(StreamLimits::default()),
+                    Query(params),
+                    endless_body(),
+                ),
+            )
+            .await
+            .expect("the body should not be read")
+            .into_response();
+            assert_eq!(response.status(), status);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sign_stream_limits() {
+        let app_state = Arc::new(AppState::new());
+        let (user_id, _) = register_user(&app_state, b"stream");
+        let params = StreamSignParams {
+            user_id: user_id.to_string(),
+            ..Default::default()
+        };
+        let limits = StreamLimits {
+            max_bytes: 1000,
+            timeout: Duration::from_millis(50),
+        };
+
+        let response = sign_stream(
+            State(app_state.clone()),
+            State(signer()),
+            State(replay()),
+            State(limits),
+            Query(params.clone()),
+            Body::from(vec![0u8; 1001]),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let response = sign_stream(
+            State(app_state),
+            State(signer()),
+            State(replay()),
+            State(limits),
+            Query(params),
+            endless_body(),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::REQUEST_TIMEOUT);
+    }
+
+    // A legacy message with `payer` as fee payer, invoking the system program on `payer`.
+    fn solana_message(payer: &[u8; 32]) -> String {
+        let mut message = vec![1, 0, 1, 2];
+        message.extend_from_slice(payer);
+        message.extend_from_slice(&[0; 32]);
+        message.extend_from_slice(&[9; 32]);
+        message.extend_from_slice(&[1, 1, 1, 0, 0]);
+        BASE64_STANDARD.encode(message)
+    }
+
+    #[tokio::test]
+    async fn test_sign_solana() {
+        let app_state = Arc::new(AppState::new());
+        let (user_id, verifying_key) = register_user(&app_state, b"solana");
+        let solana = Arc::new(Solana::new(vec![]));
+
+        let req = SolanaSignRequest {
+            user_id: user_id.to_string(),
+            message: solana_message(verifying_key.as_bytes()),
+            envelope: None,
+        };
+        let response = sign_solana(
+            State(app_state.clone()),
+            State(signer()),
+            State(replay()),
+            State(solana.clone()),
+            Json(req.clone()),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let response: SolanaSignResponse = serde_json::from_slice(&body).unwrap();
+        let signature =
+            Signature::from_slice(&bs58::decode(&response.signature).into_vec().unwrap()).unwrap();
+        let message = BASE64_STANDARD.decode(&req.message).unwrap();
+        assert!(verifying_key.verify_strict(&message, &signature).is_ok());
+        assert_eq!(response.message.accounts.len(), 2);
+
+        // The user's key must be a required signer.
+        let response = sign_solana(
+            State(app_state),
+            State(signer()),
+            State(replay()),
+            State(solana),
+            Json(SolanaSignRequest {
+                message: solana_message(&[1; 32]),
+                ..req
+            }),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    // A PSBT spending a P2WPKH output of `public_key`.
+    fn p2wpkh_psbt(public_key: &str) -> Psbt {
+        use bitcoin::{Amount, CompressedPublicKey, ScriptBuf, Transaction, TxIn, TxOut};
+        let public_key: CompressedPublicKey = public_key.parse().unwrap();
+        let output = TxOut {
+            value: Amount::from_sat(1000),
+            script_pubkey: ScriptBuf::new_p2wpkh(&public_key.wpubkey_hash()),
+        };
+        let tx = Transaction {
+            version: bitcoin::transaction::Version::TWO,
+            lock_time: bitcoin::absolute::LockTime::ZERO,
+            input: vec![TxIn::default()],
+            output: vec![output.clone()],
+        };
+        let mut psbt = Psbt::from_unsigned_tx(tx).unwrap();
+        psbt.inputs[0].witness_utxo = Some(output);
+        psbt
+    }
+
+    #[tokio::test]
+    async fn test_sign_psbt() {
+        let app_state = Arc::new(AppState::new());
+        let (user_id, _) = register_user(&app_state, b"bitcoin");
+        let keys = psbt::keys(
+            &app_state
+                .secp256k1_public_key(&user_id.to_string())
+                .unwrap(),
+        );
+
+        let req = PsbtSignRequest {
+            user_id: user_id.to_string(),
+            psbt: p2wpkh_psbt(&keys.public_key).to_string(),
+            envelope: None,
+        };
+        let response = sign_psbt(
+            State(app_state.clone()),
+            State(signer()),
+            State(replay()),
+            Json(req.clone()),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let response: PsbtSignResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(response.signed_inputs, vec![0]);
+        assert_eq!(response.keys, keys);
+        let psbt: Psbt = response.psbt.parse().unwrap();
+        assert_eq!(psbt.inputs[0].partial_sigs.len(), 1);
+
+        // Another user's key signs none of the inputs.
+        let (other, _) = register_user(&app_state, b"other");
+        let response = sign_psbt(
+            State(app_state.clone()),
+            State(signer()),
+            State(replay()),
+            Json(PsbtSignRequest {
+                user_id: other.to_string(),
+                ..req.clone()
+            }),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let response = sign_psbt(
+            State(app_state),
+            State(signer()),
+            State(replay()),
+            Json(PsbtSignRequest {
+                psbt: "cHNidP8=".into(),
+                ..req
+            }),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_sign_cosmos() {
+        use bitcoin::secp256k1::{Message, Secp256k1, ecdsa};
+        use sha2::Sha256;
+
+        let app_state = Arc::new(AppState::new());
+        let (user_id, _) = register_user(&app_state, b"cosmos");
+        let req = CosmosSignRequest {
+            user_id: user_id.to_string(),
+            mode: CosmosSignMode::AminoJson,
+            sign_doc: r#"{"chain_id": "cosmoshub-4", "account_number": "1", "sequence": "0",
+                "fee": {"amount": [], "gas": "0"}, "memo": "", "msgs": []}"#
+                .into(),
+            envelope: None,
+        };
+        let response = sign_cosmos(
+            State(app_state.clone()),
+            State(signer()),
+            State(replay()),
+            State(cosmos()),
+            Json(req.clone()),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let response: CosmosSignResponse = serde_json::from_slice(&body).unwrap();
+        assert!(response.address.starts_with("cosmos1"));
+        assert_eq!(response.sign_doc.chain_id, "cosmoshub-4");
+
+        let public_key = app_state
+            .secp256k1_public_key(&user_id.to_string())
+            .unwrap();
+        assert_eq!(
+            BASE64_STANDARD.decode(&response.public_key).unwrap(),
+            public_key.serialize()
+        );
+        let signature =
+            ecdsa::Signature::from_compact(&BASE64_STANDARD.decode(&response.signature).unwrap())
+                .unwrap();
+        let canonical = br#"{"account_number":"1","chain_id":"cosmoshub-4","fee":{"amount":[],"gas":"0"},"memo":"","msgs":[],"sequence":"0"}"#;
+        let digest = Message::from_digest(Sha256::digest(canonical).into());
+        assert!(
+            Secp256k1::verification_only()
+                .verify_ecdsa(&digest, &signature, &public_key)
+                .is_ok()
+        );
+
+        let response = sign_cosmos(
+            State(app_state),
+            State(signer()),
+            State(replay()),
+            State(cosmos()),
+            Json(CosmosSignRequest {
+                mode: CosmosSignMode::Direct,
+                ..req
+            }),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_sign_ssh() {
+        use signingcommon::SshSignature;
+
+        let app_state = Arc::new(AppState::new());
+        let (user_id, verifying_key) = register_user(&app_state, b"ssh");
+        let hash = Sha512::digest(b"tree 4b825dc6\n");
+        let req = SshSignRequest {
+            user_id: user_id.to_string(),
+            namespace: "git".into(),
+            hash: hex::encode(hash),
+            envelope: None,
+        };
+        let response = sign_ssh(
+            State(app_state.clone()),
+            State(signer()),
+            State(replay()),
+            Json(req.clone()),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let response: SshSignResponse = serde_json::from_slice(&body).unwrap();
+        let signature = SshSignature::parse(&response.signature).unwrap();
+        assert_eq!(signature.verifying_key, verifying_key.to_bytes());
+        assert_eq!(signature.namespace, "git");
+        let data = SshSignature::signed_data("git", "sha512", &hash);
+        assert!(
+            verifying_key
+                .verify_strict(&data, &Signature::from_bytes(&signature.signature))
+                .is_ok()
+        );
+
+        // Namespaces are contexts: a user restricted to "git" cannot sign files.
+        let restricted = app_state
+            .register_user(&RegisterRequest {
+                seed: b"git only".to_vec(),
+                allowed_contexts: vec!["git".into()],
+                ..Default::default()
+            })
+            .unwrap()
+            .user_id;
+        let response = sign_ssh(
+            State(app_state.clone()),
+            State(signer()),
+            State(replay()),
+            Json(SshSignRequest {
+                user_id: restricted.to_string(),
+                namespace: "file".into(),
+                ..req.clone()
+            }),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let response = sign_ssh(
+            State(app_state),
+            State(signer()),
+            State(replay()),
+            Json(SshSignRequest {
+                hash: "abcd".into(),
+                ..req
+            }),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_sign_jwt() {
+        use base64::prelude::BASE64_URL_SAFE_NO_PAD;
+
+        let app_state = Arc::new(AppState::new());
+        let (user_id, verifying_key) = register_user(&app_state, b"jwt");
+        let claims = serde_json::json!({"iss": "signingserver", "sub": "alice", "exp": 2000000000});
+        let req = JwtSignRequest {
+            user_id: user_id.to_string(),
+            claims: claims.as_object().unwrap().clone(),
+            envelope: None,
+        };
+        let response = sign_jwt(
+            State(app_state.clone()),
+            State(signer()),
+            State(replay()),
+            Json(req),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let issued: JwtSignResponse = serde_json::from_slice(&body).unwrap();
+
+        let (input, signature) = issued.token.rsplit_once('.').unwrap();
+        let signature = BASE64_URL_SAFE_NO_PAD.decode(signature).unwrap();
+        assert!(
+            verifying_key
+                .verify_strict(
+                    input.as_bytes(),
+                    &Signature::from_slice(&signature).unwrap()
+                )
+                .is_ok()
+        );
+        let (header, payload) = input.split_once('.').unwrap();
+        let header: serde_json::Value =
+            serde_json::from_slice(&BASE64_URL_SAFE_NO_PAD.decode(header).unwrap()).unwrap();
+        assert_eq!(header["alg"], "EdDSA");
+        assert_eq!(header["kid"], issued.kid.as_str());
+        let payload: serde_json::Value =
+            serde_json::from_slice(&BASE64_URL_SAFE_NO_PAD.decode(payload).unwrap()).unwrap();
+        assert_eq!(payload, claims);
+
+        // The kid identifies the key in the user's JWK set, which keeps the previous key after a
+        // rotation.
+        app_state.rotate(&user_id.to_string(), b"jwt").unwrap();
+        let response = jwks(State(app_state.clone()), Path(user_id.to_string()))
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let set: JwkSet = serde_json::from_slice(&body).unwrap();
+        assert_eq!(set.keys.len(), 2);
+        assert_eq!(set.keys[1], Jwk::new(verifying_key.as_bytes()));
+        assert_eq!(set.keys[1].kid, issued.kid);
+
+        let response = jwks(State(app_state), Path(uuid::Uuid::new_v4().to_string()))
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_sign_cose() {
+        use signingcommon::{CoseSign1, cose_key_thumbprint, parse_cose_keys, parse_cwt_claims};
+
+        let app_state = Arc::new(AppState::new());
+        let (user_id, verifying_key) = register_user(&app_state, b"sensor");
+        let req = SignRequest {
+            user_id: user_id.to_string(),
+            message: "21.5C".to_string(),
+            context: Some("telemetry".to_string()),
+            envelope: None,
+            format: SignFormat::CoseSign1,
+            trusted_comment: None,
+        };
+        let (status, response) = sign_as(&app_state, req.clone()).await;
+        assert_eq!(status, StatusCode::OK);
+        let response = response.unwrap();
+        let cose =
+            CoseSign1::parse(&BASE64_STANDARD.decode(response.cose.unwrap()).unwrap()).unwrap();
+        assert!(!cose.cwt);
+        assert_eq!(cose.payload(), b"21.5C");
+        assert_eq!(cose.key_id(), cose_key_thumbprint(verifying_key.as_bytes()));
+        let signature = Signature::from_bytes(&cose.signature().unwrap());
+        assert_eq!(response.signature, hex::encode(signature.to_bytes()));
+        // The context is the external AAD.
+        assert!(
+            verifying_key
+                .verify_strict(&cose.to_be_signed(b"telemetry"), &signature)
+                .is_ok()
+        );
+
+        let (status, response) = sign_as(
+            &app_state,
+            SignRequest {
+                message: r#"{"iss":"sensor","exp":2000000000}"#.to_string(),
+                context: None,
+                format: SignFormat::Cwt,
+                trusted_comment: None,
+                ..req.clone()
+            },
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let cwt = CoseSign1::parse(
+            &BASE64_STANDARD
+                .decode(response.unwrap().cose.unwrap())
+                .unwrap(),
+        )
+        .unwrap();
+        assert!(cwt.cwt);
+        let claims = parse_cwt_claims(cwt.payload()).unwrap();
+        assert_eq!(claims["iss"], "sensor");
+        assert!(
+            verifying_key
+                .verify_strict(
+                    &cwt.to_be_signed(b""),
+                    &Signature::from_bytes(&cwt.signature().unwrap())
+                )
+                .is_ok()
+        );
+
+        for message in ["[1, 2]", r#"{"exp":"soon"}"#] {
+            let (status, _) = sign_as(
+                &app_state,
+                SignRequest {
+                    message: message.to_string(),
+                    format: SignFormat::Cwt,
+                    trusted_comment: None,
+                    ..req.clone()
+                },
+            )
+            .await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+        }
+
+        // Approvers approve messages, not COSE structures.
+        let (approver, _) = register_user(&app_state, b"approver");
+        let treasury = app_state
+            .register_user(&signingcommon::RegisterRequest {
+                seed: b"treasury".to_vec(),
+                approval: Some(signingcommon::ApprovalPolicy {
+                    threshold: 1,
+                    approvers: vec![approver.to_string()],
+                }),
+                ..Default::default()
+            })
+            .unwrap()
+            .user_id;
+        let (status, _) = sign_as(
+            &app_state,
+            SignRequest {
+                user_id: treasury.to_string(),
+                ..req.clone()
+            },
+        )
+        .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        let response = cose_keys(State(app_state.clone()), Path(user_id.to_string()))
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "application/cose-key-set"
+        );
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(
+            parse_cose_keys(&body).unwrap(),
+            vec![verifying_key.to_bytes()]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sign_nostr() {
+        use bitcoin::secp256k1::{Message, Secp256k1, XOnlyPublicKey, schnorr};
+        use signingcommon::{NostrEvent, UnsignedNostrEvent, nostr_event_id};
+        use std::str::FromStr;
+
+        let app_state = Arc::new(AppState::new());
+        let (user_id, _) = register_user(&app_state, b"nostr");
+        let req = NostrSignRequest {
+            user_id: user_id.to_string(),
+            event: UnsignedNostrEvent {
+                pubkey: None,
+                created_at: 1700000000,
+                kind: 1,
+                tags: vec![],
+                content: "gm".into(),
+            },
+            envelope: None,
+        };
+        let response = sign_nostr(
+            State(app_state.clone()),
+            State(signer()),
+            State(replay()),
+            Json(req.clone()),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let event: NostrEvent = serde_json::from_slice(&body).unwrap();
+
+        let public_key = app_state
+            .secp256k1_public_key(&user_id.to_string())
+            .unwrap()
+            .x_only_public_key()
+            .0;
+        assert_eq!(XOnlyPublicKey::from_str(&event.pubkey).unwrap(), public_key);
+        let id = nostr_event_id(&event.pubkey, 1700000000, 1, &[], "gm");
+        assert_eq!(event.id, hex::encode(id));
+        assert!(
+            Secp256k1::verification_only()
+                .verify_schnorr(
+                    &schnorr::Signature::from_str(&event.sig).unwrap(),
+                    &Message::from_digest(id),
+                    &public_key
+                )
+                .is_ok()
+        );
+
+        let mut other = req.clone();
+        other.event.pubkey = Some(hex::encode([2; 32]));
+        let response = sign_nostr(
+            State(app_state),
+            State(signer()),
+            State(replay()),
+            Json(other),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_sign_credential() {
+        use crate::did;
+        use ed25519_dalek::Verifier;
+        use signingcommon::DataIntegrityProof;
+
+        let app_state = Arc::new(AppState::new());
+        let (user_id, verifying_key) = register_user(&app_state, b"credential");
+        let did = did_key(verifying_key.as_bytes());
+
+        let credential = serde_json::json!({
+            "@context": ["https://www.w3.org/ns/credentials/v2"],
+            "type": ["VerifiableCredential"],
+            "issuer": did,
+            "credentialSubject": {"id": "did:example:abcdefgh", "name": "Alice"}
+        });
+        let req = CredentialSignRequest {
+            user_id: user_id.to_string(),
+            credential: credential.as_object().unwrap().clone(),
+            envelope: None,
+        };
+        let response = sign_credential(
+            State(app_state.clone()),
+            State(signer()),
+            State(replay()),
+            Json(req),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let mut signed: serde_json::Map<String, serde_json::Value> =
+            serde_json::from_slice(&body).unwrap();
+
+        // The proof verifies with the key of the user's DID document.
+        let proof: DataIntegrityProof =
+            serde_json::from_value(signed.remove("proof").unwrap()).unwrap();
+        assert_eq!(signed, *credential.as_object().unwrap());
+        assert_eq!(proof.cryptosuite, "eddsa-jcs-2022");
+        let response = did_document(State(app_state.clone()), Path(user_id.to_string()))
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "application/did+json"
+        );
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let document: DidDocument = serde_json::from_slice(&body).unwrap();
+        assert_eq!(document.id, did);
+        assert_eq!(
+            document.assertion_method,
+            vec![proof.verification_method.clone()]
+        );
+
+        let value = proof.proof_value.clone().unwrap();
+        let signature: [u8; 64] = bs58::decode(value.strip_prefix('z').unwrap())
+            .into_vec()
+            .unwrap()
+            .try_into()
+            .unwrap();
+        assert!(
+            verifying_key
+                .verify(
+                    &did::hash_data(&signed, &proof),
+                    &Signature::from_bytes(&signature)
+                )
+                .is_ok()
+        );
+
+        // Signed credentials are refused.
+        signed.insert("proof".into(), serde_json::to_value(&proof).unwrap());
+        let req = CredentialSignRequest {
+            user_id: user_id.to_string(),
+            credential: signed,
+            envelope: None,
+        };
+        let response = sign_credential(
+            State(app_state.clone()),
+            State(signer()),
+            State(replay()),
+            Json(req),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let response = did_document(State(app_state), Path(uuid::Uuid::new_v4().to_string()))
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_sign_minisign() {
+        use ed25519_dalek::Verifier;
+
+        let app_state = Arc::new(AppState::new());
+        let (user_id, verifying_key) = register_user(&app_state, b"release");
+        let key_id = minisign_key_id(verifying_key.as_bytes());
+        let hash = [5; 64];
+        let req = SignRequest {
+            user_id: user_id.to_string(),
+            message: hex::encode(hash),
+            context: None,
+            envelope: None,
+            format: SignFormat::Minisign,
+            trusted_comment: Some("timestamp:1700000000\tfile:release.tar.gz\thashed".into()),
+        };
+        let (status, response) = sign_as(&app_state, req.clone()).await;
+        assert_eq!(status, StatusCode::OK);
+        let response = response.unwrap();
+        let file = response.signature_file.unwrap();
+        let lines: Vec<_> = file.lines().collect();
+        let signature = BASE64_STANDARD.decode(lines[1]).unwrap();
+        assert_eq!(signature[..10], [&b"ED"[..], &key_id].concat());
+        let signature = Signature::from_slice(&signature[10..]).unwrap();
+        assert_eq!(response.signature, hex::encode(signature.to_bytes()));
+        assert!(verifying_key.verify(&hash, &signature).is_ok());
+        let trusted_comment = lines[2].strip_prefix("trusted comment: ").unwrap();
+        assert_eq!(trusted_comment, req.trusted_comment.as_deref().unwrap());
+        let global_signature =
+            Signature::from_slice(&BASE64_STANDARD.decode(lines[3]).unwrap()).unwrap();
+        let global_data =
+            signingcommon::minisign_global_data(&signature.to_bytes(), trusted_comment);
+        assert!(
+            verifying_key
+                .verify(&global_data, &global_signature)
+                .is_ok()
+        );
+
+        // The trusted comment defaults to the time of signing, and must be a single line.
+        let (_, response) = sign_as(
+            &app_state,
+            SignRequest {
+                trusted_comment: None,
+                ..req.clone()
+            },
+        )
+        .await;
+        assert!(
+            response
+                .unwrap()
+                .signature_file
+                .unwrap()
+                .lines()
+                .nth(2)
+                .unwrap()
+                .starts_with("trusted comment: timestamp:")
+        );
+        for req in [
+            SignRequest {
+                trusted_comment: Some("one\ntrusted comment: two".into()),
+                ..req.clone()
+            },
+            SignRequest {
+                message: hex::encode([5; 32]),
+                ..req.clone()
+            },
+            SignRequest {
+                context: Some("release".into()),
+                ..req.clone()
+            },
+        ] {
+            assert_eq!(sign_as(&app_state, req).await.0, StatusCode::BAD_REQUEST);
+        }
+
+        // signify signs the file itself.
+        let req = SignRequest {
+            message: BASE64_STANDARD.encode(b"release contents"),
+            format: SignFormat::Signify,
+            trusted_comment: None,
+            ..req
+        };
+        let (status, response) = sign_as(&app_state, req).await;
+        assert_eq!(status, StatusCode::OK);
+        let file = response.unwrap().signature_file.unwrap();
+        let signature = BASE64_STANDARD
+            .decode(file.lines().nth(1).unwrap())
+            .unwrap();
+        assert_eq!(signature[..10], [&b"Ed"[..], &key_id].concat());
+        let signature = Signature::from_slice(&signature[10..]).unwrap();
+        assert!(
+            verifying_key
+                .verify(b"release contents", &signature)
+                .is_ok()
+        );
+
+        // The key files hold the same key.
+        let response = minisign_key(State(app_state.clone()), Path(user_id.to_string()))
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(body, minisign_public_key_file(verifying_key.as_bytes()));
+        let response = signify_key(State(app_state), Path(user_id.to_string()))
+            .await
+            .into_response();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(body, signify_public_key_file(verifying_key.as_bytes()));
     }
 }
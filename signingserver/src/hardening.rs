@@ -0,0 +1,113 @@
+//! OS level protections for the memory holding secret material.
+//!
+//! On Linux the key storage is locked into RAM (`mlock`) so it is never written to swap, the
+//! process is marked as non-dumpable (`PR_SET_DUMPABLE=0`), core dumps are disabled
+//! (`RLIMIT_CORE=0`) and the pages holding the storage are excluded from any dump that happens
+//! anyway (`MADV_DONTDUMP`). On other platforms none of this is available and all protections are
+//! reported as inactive.
+
+use tracing::{info, warn};
+
+/// Which protections were successfully enabled.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Protections {
+    pub memory_locked: bool,
+    pub not_dumpable: bool,
+    pub core_dumps_disabled: bool,
+    pub excluded_from_dumps: bool,
+}
+
+impl Protections {
+    /// `true` if every protection is active.
+    pub fn all_active(&self) -> bool {
+        self.memory_locked
+            && self.not_dumpable
+            && self.core_dumps_disabled
+            && self.excluded_from_dumps
+    }
+
+    /// Log the status of each protection.
+    pub fn report(&self) {
+        for (name, active) in [
+            ("mlock on key storage", self.memory_locked),
+            ("PR_SET_DUMPABLE=0", self.not_dumpable),
+            ("RLIMIT_CORE=0", self.core_dumps_disabled),
+            ("MADV_DONTDUMP on key storage", self.excluded_from_dumps),
+        ] {
+            if active {
+                info!("Memory protection active: {}", name);
+            } else {
+                warn!("Memory protection NOT active: {}", name);
+            }
+        }
+    }
+}
+
+/// Apply all available protections to the process and to the given `(address, length)` memory
+/// regions.
+#[cfg(target_os = "linux")]
+pub fn protect(regions: impl IntoIterator<Item = (*const u8, usize)>) -> Protections {
+    // SAFETY: all calls below take plain integer arguments or a pointer to a properly initialized
+    // `rlimit`. `mlock` and `madvise` only change page attributes of memory we own and do not
+    // touch its contents.
+    unsafe {
+        let not_dumpable = libc::prctl(libc::PR_SET_DUMPABLE, 0, 0, 0, 0) == 0;
+        let no_core = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        let core_dumps_disabled = libc::setrlimit(libc::RLIMIT_CORE, &no_core) == 0;
+
+        let page_size = libc::sysconf(libc::_SC_PAGESIZE) as usize;
+        let mut memory_locked = true;
+        let mut excluded_from_dumps = true;
+        for (addr, len) in regions {
+            memory_locked &= libc::mlock(addr.cast(), len) == 0;
+
+            // `madvise` wants a page aligned range, so widen the region to the pages it spans.
+            // This also excludes whatever happens to share those pages, which is fine.
+            let start = addr as usize & !(page_size - 1);
+            let end = (addr as usize + len).next_multiple_of(page_size);
+            excluded_from_dumps &=
+                libc::madvise(start as *mut libc::c_void, end - start, libc::MADV_DONTDUMP) == 0;
+        }
+
+        Protections {
+            memory_locked,
+            not_dumpable,
+            core_dumps_disabled,
+            excluded_from_dumps,
+        }
+    }
+}
+
+/// Apply all available protections to the process and to the given `(address, length)` memory
+/// regions.
+#[cfg(not(target_os = "linux"))]
+pub fn protect(_regions: impl IntoIterator<Item = (*const u8, usize)>) -> Protections {
+    Protections::default()
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_protect_disables_core_dumps() {
+        let secret = [0u8; 64];
+        let protections = protect([(secret.as_ptr(), secret.len())]);
+
+        // `mlock` is subject to RLIMIT_MEMLOCK and may legitimately fail in a sandbox, but the
+        // process level settings must always succeed.
+        assert!(protections.not_dumpable);
+        assert!(protections.core_dumps_disabled);
+        assert!(protections.excluded_from_dumps);
+
+        let mut limit = libc::rlimit {
+            rlim_cur: 1,
+            rlim_max: 1,
+        };
+        assert_eq!(unsafe { libc::getrlimit(libc::RLIMIT_CORE, &mut limit) }, 0);
+        assert_eq!(limit.rlim_cur, 0);
+    }
+}
@@ -0,0 +1,190 @@
+//! Idempotent registrations.
+//!
+//! A client that times out waiting for `/register` cannot know whether its user was created. By
+//! sending an `Idempotency-Key` header it can safely retry: within the idempotency window a
+//! repeated key returns the outcome of the first request instead of registering (and using up
+//! the capacity for) another user. Outcomes are remembered in a fixed-size map; when it is full
+//! the oldest entry is evicted.
+
+use ed25519_dalek::VerifyingKey;
+use heapless::index_map::FnvIndexMap;
+use sha2::{Digest, Sha256};
+use signingcommon::{RegisterRequest, unix_time};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::error::Error;
+use crate::state::Registration;
+
+/// Maximum number of idempotency keys remembered at any time. Must be a power of two.
+pub const MAX_IDEMPOTENCY_KEYS: usize = 1_024;
+/// Maximum length of an idempotency key, in bytes
+pub const MAX_IDEMPOTENCY_KEY_LEN: usize = 255;
+
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    // Identifies the request the key was first used with.
+    fingerprint: [u8; 32],
+    registration: Registration,
+    seen_at: u64,
+}
+
+/// Outcomes of recent registrations, by idempotency key
+#[derive(Debug)]
+pub struct IdempotencyCache {
+    window: u64,
+    // Keyed by the SHA-256 of the idempotency key, to keep entries fixed-size.
+    entries: Mutex<Box<FnvIndexMap<[u8; 32], Entry, MAX_IDEMPOTENCY_KEYS>>>,
+}
+
+impl IdempotencyCache {
+    /// Create a cache remembering registrations for `window`
+    pub fn new(window: Duration) -> Self {
+        IdempotencyCache {
+            window: window.as_secs(),
+            entries: Mutex::new(Box::new(FnvIndexMap::new())),
+        }
+    }
+
+    /// Perform `register` for `req`, unless a request with the same idempotency `key` was seen
+    /// within the window, in which case the original outcome is returned. `verifying_key` is the
+    /// key `req` registers and is used, rather than the seed, to recognize a repeated request.
+    pub fn register(
+        &self,
+        key: &str,
+        req: &RegisterRequest,
+        verifying_key: &VerifyingKey,
+        register: impl FnOnce() -> Result<Registration, Error>,
+    ) -> Result<Registration, Error> {
+        if key.is_empty() || key.len() > MAX_IDEMPOTENCY_KEY_LEN {
+            return Err(Error::InvalidIdempotencyKey(format!(
+                "must be between 1 and {MAX_IDEMPOTENCY_KEY_LEN} bytes"
+            )));
+        }
+        let key: [u8; 32] = Sha256::digest(key.as_bytes()).into();
+        let fingerprint = fingerprint(req, verifying_key);
+        let now = unix_time();
+
+        // Hold the lock throughout so that concurrent retries cannot both register.
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(entry) = entries.get(&key)
+            && entry.seen_at + self.window > now
+        {
+            if entry.fingerprint != fingerprint {
+                return Err(Error::IdempotencyKeyReused);
+            }
+            return Ok(entry.registration);
+        }
+
+        let registration = register()?;
+
+        entries.retain(|_, entry| entry.seen_at + self.window > now);
+        if entries.is_full() {
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.seen_at)
+                .map(|(key, _)| *key)
+                .expect("cache is full, hence not empty");
+            entries.remove(&oldest);
+        }
+        entries
+            .insert(
+                key,
+                Entry {
+                    fingerprint,
+                    registration,
+                    seen_at: now,
+                },
+            )
+            .expect("there is room for at least one entry");
+
+        Ok(registration)
+    }
+}
+
+// Hash of all registration parameters, with the seed replaced by the verifying key it derives so
+// that nothing secret is kept around.
+fn fingerprint(req: &RegisterRequest, verifying_key: &VerifyingKey) -> [u8; 32] {
+    let req = RegisterRequest {
+        seed: verifying_key.to_bytes().to_vec(),
+        ..req.clone()
+    };
+    let json = serde_json::to_vec(&req).expect("requests can be serialized");
+    Sha256::digest(json).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::AppState;
+
+    #[test]
+    fn test_retry_returns_original_registration() {
+        let state = AppState::new();
+        let cache = IdempotencyCache::new(Duration::from_secs(60));
+        let req = RegisterRequest {
+            seed: b"seed".to_vec(),
+            ..Default::default()
+        };
+        let verifying_key = state.derive_verifying_key(&req.seed);
+
+        let first = cache
+            .register("key-1", &req, &verifying_key, || state.register_user(&req))
+            .unwrap();
+        let retry = cache
+            .register("key-1", &req, &verifying_key, || state.register_user(&req))
+            .unwrap();
+        assert_eq!(first.user_id, retry.user_id);
+        assert_eq!(state.user_count(), 1);
+
+        let other = cache
+            .register("key-2", &req, &verifying_key, || state.register_user(&req))
+            .unwrap();
+        assert_ne!(first.user_id, other.user_id);
+        assert_eq!(state.user_count(), 2);
+    }
+
+    #[test]
+    fn test_key_reused_for_different_request() {
+        let state = AppState::new();
+        let cache = IdempotencyCache::new(Duration::from_secs(60));
+        let req = RegisterRequest {
+            seed: b"seed".to_vec(),
+            ..Default::default()
+        };
+        let other = RegisterRequest {
+            seed: b"other seed".to_vec(),
+            ..Default::default()
+        };
+
+        let verifying_key = state.derive_verifying_key(&req.seed);
+        cache
+            .register("key", &req, &verifying_key, || state.register_user(&req))
+            .unwrap();
+        let verifying_key = state.derive_verifying_key(&other.seed);
+        assert!(matches!(
+            cache.register("key", &other, &verifying_key, || state
+                .register_user(&other)),
+            Err(Error::IdempotencyKeyReused)
+        ));
+    }
+
+    #[test]
+    fn test_expired_key_registers_again() {
+        let state = AppState::new();
+        let cache = IdempotencyCache::new(Duration::ZERO);
+        let req = RegisterRequest {
+            seed: b"seed".to_vec(),
+            ..Default::default()
+        };
+        let verifying_key = state.derive_verifying_key(&req.seed);
+
+        let first = cache
+            .register("key", &req, &verifying_key, || state.register_user(&req))
+            .unwrap();
+        let second = cache
+            .register("key", &req, &verifying_key, || state.register_user(&req))
+            .unwrap();
+        assert_ne!(first.user_id, second.user_id);
+    }
+}
@@ -0,0 +1,36 @@
+use axum::extract::FromRef;
+use std::sync::Arc;
+
+pub mod admin;
+pub mod approvals;
+pub mod attestation;
+pub mod cosmos;
+pub mod did;
+pub mod error;
+pub mod handlers;
+pub mod hardening;
+pub mod idempotency;
+pub mod nostr;
+pub mod psbt;
+pub mod replay;
+pub mod response_signing;
+pub mod revocation;
+pub mod secret;
+pub mod signer;
+pub mod solana;
+pub mod state;
+
+/// Everything the request handlers need; individual handlers extract the parts they use.
+#[derive(Clone, FromRef)]
+pub struct ServerState {
+    pub state: Arc<state::AppState>,
+    pub identity_generation: Arc<secret::IdentityGeneration>,
+    pub signer: Arc<signer::SigningPool>,
+    pub replay: Arc<replay::ReplayGuard>,
+    pub stream_limits: handlers::StreamLimits,
+    pub idempotency: Arc<idempotency::IdempotencyCache>,
+    pub attestation: Arc<attestation::Attestation>,
+    pub pending: Arc<approvals::PendingSignatures>,
+    pub solana: Arc<solana::Solana>,
+    pub cosmos: Arc<cosmos::Cosmos>,
+}
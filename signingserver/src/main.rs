@@ -1,19 +1,119 @@
 use axum::{
-    Router,
+    Router, middleware,
     routing::{delete, get, post},
 };
-use axum_server::tls_rustls::RustlsConfig;
-use std::sync::Arc;
-use tokio::sync::RwLock;
-use tracing::info;
+use axum_server::{Handle, tls_rustls::RustlsConfig};
+use clap::Parser;
+use std::{path::PathBuf, sync::Arc, time::Duration};
+use tracing::{error, info, warn};
+use zeroize::Zeroize;
 
-mod handlers;
-mod state;
+use signingserver::{
+    ServerState,
+    admin::{self, AdminAuth},
+    approvals::PendingSignatures,
+    attestation::{Attestation, MockAttestation, read_certificate_der},
+    cosmos::{ChainAllowlist, Cosmos, CosmosPolicy},
+    handlers::{self, StreamLimits},
+    hardening,
+    idempotency::IdempotencyCache,
+    replay::ReplayGuard,
+    response_signing,
+    secret::{self, IdentityGeneration},
+    signer::{PoolConfig, SigningPool},
+    solana::{ProgramAllowlist, Solana, SolanaPolicy},
+    state::AppState,
+};
+
+/// TLS certificate and private key of the server
+const CERT_PATH: &str = "signingserver/certs/cert.pem";
+const KEY_PATH: &str = "signingserver/certs/key.pem";
+
+/// How long in-flight requests are given to complete once a shutdown signal is received.
+const SHUTDOWN_DEADLINE: Duration = Duration::from_secs(10);
+
+#[derive(Parser, Debug)]
+#[command(name = "signingserver")]
+#[command(about = "Remote signing service")]
+struct Args {
+    /// Refuse to start unless all memory protections for secret material are active
+    #[arg(long)]
+    require_hardening: bool,
+
+    /// Number of dedicated signing threads [default: number of CPUs]
+    #[arg(long)]
+    signing_threads: Option<usize>,
+
+    /// Maximum number of queued signing requests; beyond this requests are rejected with 503
+    #[arg(long, default_value_t = 256)]
+    signing_queue_depth: usize,
+
+    /// Maximum time in milliseconds a signing request may wait for and spend on a signing thread
+    #[arg(long, default_value_t = 5_000)]
+    signing_deadline_ms: u64,
+
+    /// Pin each signing thread to its own CPU core
+    #[arg(long)]
+    pin_signing_threads: bool,
+
+    /// Accept sign requests without a replay protection envelope. Anyone who knows a user's ID can
+    /// then sign for them, and captured requests can be replayed
+    #[arg(long)]
+    allow_requests_without_envelope: bool,
+
+    /// Longest message, in bytes, accepted by /sign/stream
+    #[arg(long, default_value_t = StreamLimits::default().max_bytes)]
+    max_stream_bytes: u64,
+
+    /// How long, in seconds, a message streamed to /sign/stream may take to arrive
+    #[arg(long, default_value_t = StreamLimits::default().timeout.as_secs())]
+    stream_timeout_secs: u64,
+
+    /// How long, in seconds, a registration's `Idempotency-Key` is remembered
+    #[arg(long, default_value_t = 600)]
+    idempotency_window_secs: u64,
 
-use state::AppState;
+    /// File holding the bearer token for the admin API. Without it the admin API is disabled
+    #[arg(long)]
+    admin_token_file: Option<PathBuf>,
+
+    /// Require operators to approve new users, through the admin API, before they can sign
+    #[arg(long)]
+    require_approval: bool,
+
+    /// File holding the server secret, from which the server identity key and deterministic user
+    /// IDs are derived. A random secret is generated into it on first start. Defaults to
+    /// `$XDG_STATE_HOME/signingserver/server.secret` (`~/.local/state/signingserver/server.secret`)
+    #[arg(long)]
+    secret_file: Option<PathBuf>,
+
+    /// How long, in seconds, a signature waits for approvals before it is dropped
+    #[arg(long, default_value_t = 3600)]
+    approval_ttl_secs: u64,
+
+    /// Only sign Solana transactions whose instructions all invoke one of these programs (base58
+    /// program IDs, may be repeated). Without it any program is allowed
+    #[arg(long = "solana-allowed-program")]
+    solana_allowed_programs: Vec<String>,
+
+    /// Bech32 prefix of the Cosmos addresses returned on registration and signing
+    #[arg(long, default_value = "cosmos")]
+    cosmos_prefix: String,
+
+    /// Only sign Cosmos transactions for these chain IDs (may be repeated). Without it any chain
+    /// is allowed
+    #[arg(long = "cosmos-allowed-chain")]
+    cosmos_allowed_chains: Vec<String>,
+
+    /// How often, in seconds, expired users are forgotten
+    #[arg(long, default_value_t = 60)]
+    reap_interval_secs: u64,
+}
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+
     // Initialize tracing with colored output
     tracing_subscriber::fmt()
         .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
@@ -22,36 +122,220 @@ async fn main() -> anyhow::Result<()> {
 
     info!("Starting signing server...");
 
-    let app_state = Arc::new(RwLock::new(AppState::new()));
+    let secret_file = match &args.secret_file {
+        Some(path) => path.clone(),
+        None => secret::default_path()?,
+    };
+    let mut server_secret = secret::load_or_create(&secret_file)?;
+    let identity_generation = IdentityGeneration::load(&secret_file)?;
+    let app_state = Arc::new(AppState::with_server_secret(
+        &server_secret,
+        identity_generation.get(),
+    ));
+    server_secret.zeroize();
+    if args.require_approval {
+        if args.admin_token_file.is_none() {
+            anyhow::bail!("--require-approval needs the admin API (--admin-token-file)");
+        }
+        app_state.set_approval_required(true);
+    }
+
+    // The key storage is allocated once for the lifetime of the process, so it is safe to lock it
+    // in memory once here.
+    let protections = hardening::protect(app_state.secret_regions());
+    protections.report();
+    if args.require_hardening && !protections.all_active() {
+        error!("Refusing to start: not all memory protections are active (--require-hardening)");
+        anyhow::bail!("memory hardening unavailable");
+    }
+
+    info!(
+        "Server identity key: {}",
+        hex::encode(app_state.identity().as_bytes())
+    );
+
+    // There is no TEE support yet, so attestation is simulated.
+    let attestation = Attestation::new(
+        Box::new(MockAttestation::new()?),
+        &read_certificate_der(CERT_PATH)?,
+    );
+    warn!(
+        "Attestation is simulated ({} provider), measurement: {}",
+        attestation.provider(),
+        hex::encode(attestation.measurement())
+    );
+
+    let mut solana_policies: Vec<Box<dyn SolanaPolicy>> = Vec::new();
+    if !args.solana_allowed_programs.is_empty() {
+        solana_policies.push(Box::new(ProgramAllowlist::new(
+            &args.solana_allowed_programs,
+        )?));
+    }
+    let solana = Solana::new(solana_policies);
+
+    let mut cosmos_policies: Vec<Box<dyn CosmosPolicy>> = Vec::new();
+    if !args.cosmos_allowed_chains.is_empty() {
+        cosmos_policies.push(Box::new(ChainAllowlist::new(&args.cosmos_allowed_chains)));
+    }
+    let cosmos = Cosmos::new(&args.cosmos_prefix, cosmos_policies)?;
+
+    let default_pool = PoolConfig::default();
+    let signer = Arc::new(SigningPool::new(PoolConfig {
+        threads: args.signing_threads.unwrap_or(default_pool.threads),
+        queue_depth: args.signing_queue_depth,
+        deadline: Duration::from_millis(args.signing_deadline_ms),
+        pin_cores: args.pin_signing_threads,
+    }));
+
+    let mut app = Router::new();
+    if let Some(path) = &args.admin_token_file {
+        let token = std::fs::read_to_string(path)?;
+        let token = token.trim();
+        if token.is_empty() {
+            anyhow::bail!("admin token file {} is empty", path.display());
+        }
+        let admin = Router::new()
+            .route("/users", get(admin::users))
+            .route("/users/:user_id", delete(admin::forget))
+            .route("/users/:user_id/freeze", post(admin::freeze))
+            .route("/users/:user_id/unfreeze", post(admin::unfreeze))
+            .route("/users/:user_id/approve", post(admin::approve))
+            .route("/stats", get(admin::stats))
+            .route("/read-only", post(admin::read_only))
+            .route("/rotate-identity", post(admin::rotate_identity))
+            .route_layer(middleware::from_fn_with_state(
+                Arc::new(AdminAuth::new(token)),
+                admin::require_admin,
+            ));
+        app = app.nest("/admin", admin);
+        info!("Admin API enabled");
+    }
 
     // Build router with all endpoints
-    let app = Router::new()
+    let app = app
         .route("/health", get(health_check))
         .route("/register", post(handlers::register))
         .route("/sign", post(handlers::sign))
+        .route("/sign/stream", post(handlers::sign_stream))
+        .route("/sign/solana", post(handlers::sign_solana))
+        .route("/sign/ssh", post(handlers::sign_ssh))
+        .route("/sign/jwt", post(handlers::sign_jwt))
+        .route("/sign/nostr", post(handlers::sign_nostr))
+        .route("/sign/credential", post(handlers::sign_credential))
+        .route("/sign/psbt", post(handlers::sign_psbt))
+        .route("/sign/cosmos", post(handlers::sign_cosmos))
+        .route("/renew", post(handlers::renew))
+        .route("/rotate", post(handlers::rotate))
+        .route("/users/:user_id/keys", get(handlers::keys))
+        .route("/users/:user_id/bitcoin", get(handlers::bitcoin_keys))
+        .route("/users/:user_id/jwks.json", get(handlers::jwks))
+        .route("/users/:user_id/cose_key", get(handlers::cose_keys))
+        .route("/users/:user_id/did.json", get(handlers::did_document))
+        .route("/users/:user_id/minisign.pub", get(handlers::minisign_key))
+        .route("/users/:user_id/signify.pub", get(handlers::signify_key))
+        .route("/revoke", post(handlers::revoke))
+        .route("/revocations", get(handlers::revocations))
+        .route("/identity", get(handlers::identity))
+        .route("/attestation", get(handlers::attestation))
+        .route("/approve/:request_id", post(handlers::approve))
+        .route("/pending/:request_id", get(handlers::pending_request))
+        .route("/users/:user_id/pending", get(handlers::pending))
         .route("/forget", delete(handlers::forget))
-        .with_state(app_state);
+        .layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            response_signing::sign_responses,
+        ))
+        .with_state(ServerState {
+            state: app_state.clone(),
+            signer,
+            identity_generation: Arc::new(identity_generation),
+            replay: Arc::new(ReplayGuard::new(!args.allow_requests_without_envelope)),
+            stream_limits: StreamLimits {
+                max_bytes: args.max_stream_bytes,
+                timeout: Duration::from_secs(args.stream_timeout_secs),
+            },
+            idempotency: Arc::new(IdempotencyCache::new(Duration::from_secs(
+                args.idempotency_window_secs,
+            ))),
+            attestation: Arc::new(attestation),
+            pending: Arc::new(PendingSignatures::new(Duration::from_secs(
+                args.approval_ttl_secs,
+            ))),
+            solana: Arc::new(solana),
+            cosmos: Arc::new(cosmos),
+        });
 
     // Load TLS configuration
-    let config = RustlsConfig::from_pem_file(
-        "signingserver/certs/cert.pem",
-        "signingserver/certs/key.pem",
-    )
-    .await?;
+    let config = RustlsConfig::from_pem_file(CERT_PATH, KEY_PATH).await?;
 
     let addr = "127.0.0.1:3443";
 
     info!("Server listening on https://{}", addr);
     info!("Note: Using self-signed certificate.");
 
+    let reaper = tokio::spawn(reap_expired_users(
+        app_state.clone(),
+        Duration::from_secs(args.reap_interval_secs.max(1)),
+    ));
+
+    let handle = Handle::new();
+    tokio::spawn(shutdown_signal(handle.clone()));
+
     axum_server::bind_rustls(addr.parse()?, config)
+        .handle(handle)
         .serve(app.into_make_service())
         .await?;
 
+    // All connections are closed at this point: wipe the keys and the master secret.
+    reaper.abort();
+    app_state.zeroize();
+    info!("Secret material zeroized");
+
     info!("Server shut down gracefully");
     Ok(())
 }
 
+/// Periodically forget users whose TTL has run out, zeroizing their keys.
+async fn reap_expired_users(state: Arc<AppState>, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        let reaped = state.reap_expired(signingcommon::unix_time());
+        if reaped > 0 {
+            info!("Forgot {} expired users", reaped);
+        }
+    }
+}
+
+/// Wait for SIGINT or SIGTERM, then stop accepting new connections and give in-flight requests
+/// [`SHUTDOWN_DEADLINE`] to complete.
+async fn shutdown_signal(handle: Handle) {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to install SIGINT handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+
+    info!("Shutdown signal received, draining in-flight requests...");
+    handle.graceful_shutdown(Some(SHUTDOWN_DEADLINE));
+}
+
 /// Health check endpoint
 async fn health_check() -> &'static str {
     "OK"
@@ -0,0 +1,108 @@
+//! Nostr event signing (NIP-01).
+//!
+//! Nostr identities are secp256k1 keys, and events are signed with BIP-340 Schnorr signatures
+//! over their id. Users sign with the same secp256k1 key as for Bitcoin and Cosmos (see
+//! [`AppState::sign_nostr`](crate::state::AppState::sign_nostr)), whose x-only form is their
+//! Nostr public key.
+
+use bech32::{Bech32, Hrp};
+use bitcoin::secp256k1::{Keypair, Message, Secp256k1, SecretKey, XOnlyPublicKey};
+use signingcommon::{NostrEvent, UnsignedNostrEvent, nostr_event_id};
+
+use crate::error::Error;
+
+const NPUB: Hrp = Hrp::parse_unchecked("npub");
+
+/// The bech32 `npub` encoding of a Nostr public key (NIP-19)
+pub fn npub(public_key: &XOnlyPublicKey) -> String {
+    bech32::encode::<Bech32>(NPUB, &public_key.serialize()).expect("32 bytes fit in an npub")
+}
+
+/// Sign `event` with `secret_key`. The event's public key, if it has one, must be the key's.
+pub fn sign(event: UnsignedNostrEvent, secret_key: &SecretKey) -> Result<NostrEvent, Error> {
+    let secp = Secp256k1::new();
+    let keypair = Keypair::from_secret_key(&secp, secret_key);
+    let pubkey = keypair.x_only_public_key().0.to_string();
+    if event.pubkey.as_ref().is_some_and(|key| *key != pubkey) {
+        return Err(Error::InvalidNostrEvent(
+            "pubkey is not the user's key".into(),
+        ));
+    }
+
+    let id = nostr_event_id(
+        &pubkey,
+        event.created_at,
+        event.kind,
+        &event.tags,
+        &event.content,
+    );
+    let sig = secp.sign_schnorr(&Message::from_digest(id), &keypair);
+    Ok(NostrEvent {
+        id: hex::encode(id),
+        pubkey,
+        created_at: event.created_at,
+        kind: event.kind,
+        tags: event.tags,
+        content: event.content,
+        sig: sig.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::secp256k1::schnorr;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_npub() {
+        // NIP-19
+        let public_key = XOnlyPublicKey::from_str(
+            "3bf0c63fcb93463407af97a5e5ee64fa883d107ef9e558472c4eb9aaaefa459d",
+        )
+        .unwrap();
+        assert_eq!(
+            npub(&public_key),
+            "npub180cvv07tjdrrgpa0j7j7tmnyl2yr6yr7l8j4s3evf6u64th6gkwsyjh6w6"
+        );
+    }
+
+    #[test]
+    fn test_sign() {
+        let secp = Secp256k1::new();
+        let secret_key = SecretKey::from_slice(&[3; 32]).unwrap();
+        let public_key = secret_key.x_only_public_key(&secp).0;
+        let unsigned = UnsignedNostrEvent {
+            pubkey: None,
+            created_at: 1700000000,
+            kind: 1,
+            tags: vec![vec!["t".into(), "nostr".into()]],
+            content: "hello".into(),
+        };
+
+        let event = sign(unsigned.clone(), &secret_key).unwrap();
+        assert_eq!(event.pubkey, public_key.to_string());
+        let id = nostr_event_id(&event.pubkey, 1700000000, 1, &event.tags, "hello");
+        assert_eq!(event.id, hex::encode(id));
+        let sig = schnorr::Signature::from_str(&event.sig).unwrap();
+        assert!(
+            secp.verify_schnorr(&sig, &Message::from_digest(id), &public_key)
+                .is_ok()
+        );
+
+        // Events of the user's own key are signed, others refused.
+        let own = UnsignedNostrEvent {
+            pubkey: Some(public_key.to_string()),
+            ..unsigned.clone()
+        };
+        assert_eq!(sign(own, &secret_key).unwrap().id, event.id);
+        let other = UnsignedNostrEvent {
+            pubkey: Some("3bf0c63fcb93463407af97a5e5ee64fa883d107ef9e558472c4eb9aaaefa459d".into()),
+            ..unsigned
+        };
+        assert!(matches!(
+            sign(other, &secret_key),
+            Err(Error::InvalidNostrEvent(_))
+        ));
+    }
+}
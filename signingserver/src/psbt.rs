@@ -0,0 +1,313 @@
+//! Signing of Bitcoin PSBTs (BIP-174).
+//!
+//! Besides their Ed25519 key, users have a secp256k1 key derived from it (see
+//! [`AppState::sign_psbt`](crate::state::AppState::sign_psbt)). An input belongs to the user when
+//! the script it spends pays to their key: P2PK, P2PKH, P2WPKH and P2SH-P2WPKH outputs of their
+//! compressed public key, P2SH and P2WSH scripts that mention it (such as multisig), and Taproot
+//! outputs whose internal key is their x-only key, which are signed on the key path. Other inputs
+//! are left alone, so that a PSBT can go from signer to signer.
+//!
+//! The outputs an input spends come from its `witness_utxo`, or its `non_witness_utxo`, which must
+//! be the transaction the input spends.
+
+use bitcoin::hashes::Hash;
+use bitcoin::key::TapTweak;
+use bitcoin::psbt::Input;
+use bitcoin::script::Instruction;
+use bitcoin::secp256k1::{self, Keypair, Message, Secp256k1, SecretKey};
+use bitcoin::sighash::{Prevouts, SighashCache};
+use bitcoin::{
+    CompressedPublicKey, Psbt, PublicKey, Script, ScriptBuf, TapSighashType, TxOut, ecdsa, taproot,
+};
+use signingcommon::BitcoinKeys;
+
+use crate::error::Error;
+
+/// The keys of the secp256k1 `public_key` as Bitcoin scripts use them
+pub fn keys(public_key: &secp256k1::PublicKey) -> BitcoinKeys {
+    BitcoinKeys {
+        public_key: CompressedPublicKey(*public_key).to_string(),
+        x_only_public_key: public_key.x_only_public_key().0.to_string(),
+    }
+}
+
+/// Add signatures by `secret_key` to the inputs of `psbt` that belong to it, and return their
+/// indexes. Finalized inputs are skipped.
+pub fn sign(psbt: &mut Psbt, secret_key: &SecretKey) -> Result<Vec<usize>, Error> {
+    let secp = Secp256k1::new();
+    let keypair = Keypair::from_secret_key(&secp, secret_key);
+    let public_key = PublicKey::new(keypair.public_key());
+    let (internal_key, _) = keypair.x_only_public_key();
+
+    let tx = psbt.unsigned_tx.clone();
+    let mut cache = SighashCache::new(&tx);
+    let mut signed = Vec::new();
+    for index in 0..psbt.inputs.len() {
+        let input = &psbt.inputs[index];
+        if input.final_script_sig.is_some() || input.final_script_witness.is_some() {
+            continue;
+        }
+        let Some(utxo) = spent_output(psbt, index)? else {
+            continue;
+        };
+
+        if utxo.script_pubkey.is_p2tr() {
+            let output_script = ScriptBuf::new_p2tr(&secp, internal_key, input.tap_merkle_root);
+            if utxo.script_pubkey != output_script {
+                continue;
+            }
+            let sighash_type = input
+                .sighash_type
+                .map(|sighash_type| sighash_type.taproot_hash_ty())
+                .unwrap_or(Ok(TapSighashType::Default))
+                .map_err(|e| Error::InvalidPsbt(e.to_string()))?;
+            let sighash = if sighash_type as u8 & 0x80 != 0 {
+                cache.taproot_key_spend_signature_hash(
+                    index,
+                    &Prevouts::One(index, utxo),
+                    sighash_type,
+                )
+            } else {
+                let utxos = (0..psbt.inputs.len())
+                    .map(|index| {
+                        spent_output(psbt, index)?.ok_or_else(|| {
+                            Error::InvalidPsbt(format!("input {index} is missing its UTXO"))
+                        })
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                cache.taproot_key_spend_signature_hash(index, &Prevouts::All(&utxos), sighash_type)
+            }
+            .map_err(|e| Error::InvalidPsbt(e.to_string()))?;
+
+            let tweaked = keypair.tap_tweak(&secp, input.tap_merkle_root).to_keypair();
+            let signature = secp.sign_schnorr(&Message::from(sighash), &tweaked);
+            let input = &mut psbt.inputs[index];
+            input.tap_key_sig = Some(taproot::Signature {
+                signature,
+                sighash_type,
+            });
+            input.tap_internal_key = Some(internal_key);
+        } else {
+            if !script_code(input, &utxo.script_pubkey)
+                .is_some_and(|script| mentions(script, &public_key))
+            {
+                continue;
+            }
+            let (sighash, sighash_type) = psbt
+                .sighash_ecdsa(index, &mut cache)
+                .map_err(|e| Error::InvalidPsbt(format!("input {index}: {e}")))?;
+            let signature = secp.sign_ecdsa(&sighash, secret_key);
+            psbt.inputs[index].partial_sigs.insert(
+                public_key,
+                ecdsa::Signature {
+                    signature,
+                    sighash_type,
+                },
+            );
+        }
+        signed.push(index);
+    }
+    Ok(signed)
+}
+
+// The output input `index` spends, if the PSBT has it. A full previous transaction must match the
+// outpoint, as legacy signatures do not commit to the amount spent.
+fn spent_output(psbt: &Psbt, index: usize) -> Result<Option<TxOut>, Error> {
+    let input = &psbt.inputs[index];
+    if let Some(utxo) = &input.witness_utxo {
+        return Ok(Some(utxo.clone()));
+    }
+    let Some(previous_tx) = &input.non_witness_utxo else {
+        return Ok(None);
+    };
+    let outpoint = psbt.unsigned_tx.input[index].previous_output;
+    if previous_tx.compute_txid() != outpoint.txid {
+        return Err(Error::InvalidPsbt(format!(
+            "input {index} spends {}, not the given transaction",
+            outpoint.txid
+        )));
+    }
+    previous_tx
+        .output
+        .get(outpoint.vout as usize)
+        .cloned()
+        .map(Some)
+        .ok_or_else(|| Error::InvalidPsbt(format!("input {index} spends a missing output")))
+}
+
+// The script whose conditions an input spending `script_pubkey` has to meet: the redeem or
+// witness script for script hash outputs, otherwise the output script itself.
+fn script_code<'a>(input: &'a Input, script_pubkey: &'a Script) -> Option<&'a Script> {
+    let script = if script_pubkey.is_p2sh() {
+        input.redeem_script.as_deref()?
+    } else {
+        script_pubkey
+    };
+    if script.is_p2wsh() {
+        input.witness_script.as_deref()
+    } else {
+        Some(script)
+    }
+}
+
+// Whether `script` pushes `public_key` or its hash.
+fn mentions(script: &Script, public_key: &PublicKey) -> bool {
+    let key = public_key.to_bytes();
+    let hash = public_key.pubkey_hash();
+    script.instructions().any(|instruction| {
+        matches!(instruction, Ok(Instruction::PushBytes(bytes))
+            if bytes.as_bytes() == key || bytes.as_bytes() == hash.as_byte_array())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::opcodes::all::OP_CHECKMULTISIG;
+    use bitcoin::script::Builder;
+    use bitcoin::transaction::Version;
+    use bitcoin::{
+        Amount, OutPoint, Transaction, TxIn, Txid, absolute::LockTime, secp256k1::PublicKey as Key,
+    };
+
+    fn transaction(inputs: Vec<OutPoint>, outputs: Vec<TxOut>) -> Transaction {
+        Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: inputs
+                .into_iter()
+                .map(|previous_output| TxIn {
+                    previous_output,
+                    ..Default::default()
+                })
+                .collect(),
+            output: outputs,
+        }
+    }
+
+    fn output(script_pubkey: ScriptBuf) -> TxOut {
+        TxOut {
+            value: Amount::from_sat(50_000),
+            script_pubkey,
+        }
+    }
+
+    fn outpoint(n: u8) -> OutPoint {
+        OutPoint {
+            txid: Txid::from_byte_array([n; 32]),
+            vout: 0,
+        }
+    }
+
+    #[test]
+    fn test_sign_inputs_of_key() {
+        let secp = Secp256k1::new();
+        let secret_key = SecretKey::from_slice(&[7; 32]).unwrap();
+        let public_key = PublicKey::new(Key::from_secret_key(&secp, &secret_key));
+        let compressed = CompressedPublicKey(public_key.inner);
+        let (internal_key, _) = public_key.inner.x_only_public_key();
+        let other = CompressedPublicKey(Key::from_secret_key(
+            &secp,
+            &SecretKey::from_slice(&[8; 32]).unwrap(),
+        ));
+
+        let previous_tx = transaction(
+            vec![outpoint(9)],
+            vec![
+                output(ScriptBuf::new()),
+                output(ScriptBuf::new_p2pkh(&public_key.pubkey_hash())),
+            ],
+        );
+        let multisig = Builder::new()
+            .push_int(2)
+            .push_key(&public_key)
+            .push_key(&other.into())
+            .push_int(2)
+            .push_opcode(OP_CHECKMULTISIG)
+            .into_script();
+        let tx = transaction(
+            vec![
+                outpoint(1),
+                outpoint(2),
+                OutPoint {
+                    txid: previous_tx.compute_txid(),
+                    vout: 1,
+                },
+                outpoint(3),
+                outpoint(4),
+            ],
+            vec![output(ScriptBuf::new_p2wpkh(&other.wpubkey_hash()))],
+        );
+        let mut psbt = Psbt::from_unsigned_tx(tx).unwrap();
+        psbt.inputs[0].witness_utxo =
+            Some(output(ScriptBuf::new_p2wpkh(&compressed.wpubkey_hash())));
+        psbt.inputs[1].witness_utxo = Some(output(ScriptBuf::new_p2tr(&secp, internal_key, None)));
+        psbt.inputs[2].non_witness_utxo = Some(previous_tx);
+        psbt.inputs[3].witness_utxo = Some(output(ScriptBuf::new_p2wpkh(&other.wpubkey_hash())));
+        psbt.inputs[4].witness_utxo = Some(output(ScriptBuf::new_p2wsh(&multisig.wscript_hash())));
+        psbt.inputs[4].witness_script = Some(multisig);
+
+        // Sign what the client sends: the PSBT as it comes out of its base64 encoding.
+        let mut psbt: Psbt = psbt.to_string().parse().unwrap();
+        assert_eq!(sign(&mut psbt, &secret_key).unwrap(), vec![0, 1, 2, 4]);
+        assert!(psbt.inputs[3].partial_sigs.is_empty());
+
+        let mut cache = SighashCache::new(psbt.unsigned_tx.clone());
+        for index in [0, 2, 4] {
+            let (sighash, _) = psbt.sighash_ecdsa(index, &mut cache).unwrap();
+            let signature = psbt.inputs[index].partial_sigs[&public_key];
+            assert!(
+                secp.verify_ecdsa(&sighash, &signature.signature, &public_key.inner)
+                    .is_ok()
+            );
+        }
+
+        let utxos: Vec<_> = psbt
+            .inputs
+            .iter()
+            .enumerate()
+            .map(|(index, input)| {
+                input.witness_utxo.clone().unwrap_or_else(|| {
+                    input.non_witness_utxo.as_ref().unwrap().output
+                        [psbt.unsigned_tx.input[index].previous_output.vout as usize]
+                        .clone()
+                })
+            })
+            .collect();
+        let sighash = cache
+            .taproot_key_spend_signature_hash(1, &Prevouts::All(&utxos), TapSighashType::Default)
+            .unwrap();
+        let (output_key, _) = internal_key.tap_tweak(&secp, None);
+        let signature = psbt.inputs[1].tap_key_sig.unwrap();
+        assert!(
+            secp.verify_schnorr(
+                &signature.signature,
+                &Message::from(sighash),
+                &output_key.to_x_only_public_key()
+            )
+            .is_ok()
+        );
+        assert_eq!(psbt.inputs[1].tap_internal_key, Some(internal_key));
+    }
+
+    #[test]
+    fn test_previous_transaction_must_match() {
+        let secp = Secp256k1::new();
+        let secret_key = SecretKey::from_slice(&[7; 32]).unwrap();
+        let public_key = PublicKey::new(Key::from_secret_key(&secp, &secret_key));
+        let previous_tx = transaction(
+            vec![outpoint(9)],
+            vec![output(ScriptBuf::new_p2pkh(&public_key.pubkey_hash()))],
+        );
+        let mut psbt = Psbt::from_unsigned_tx(transaction(
+            vec![outpoint(1)],
+            vec![output(ScriptBuf::new())],
+        ))
+        .unwrap();
+        psbt.inputs[0].non_witness_utxo = Some(previous_tx);
+        assert!(matches!(
+            sign(&mut psbt, &secret_key),
+            Err(Error::InvalidPsbt(_))
+        ));
+    }
+}
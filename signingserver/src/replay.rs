@@ -0,0 +1,315 @@
+//! Replay protection for sign requests.
+//!
+//! Clients wrap sign requests in a [`RequestEnvelope`] carrying a random nonce, an expiry time
+//! and a MAC of the whole request under a key derived from the user's seed. Nonces are remembered
+//! until their request expires, so a captured request can neither be replayed while it is valid
+//! nor after it has expired, and without the seed it cannot be given a fresh nonce or its envelope
+//! moved to another request. Expiry times are capped at [`MAX_VALIDITY`] in the future, which
+//! bounds how long a nonce must be remembered and thus lets the nonces live in a fixed-size map.
+//!
+//! Each user has their own map of [`MAX_NONCES_PER_USER`] nonces, kept with their key, so a user
+//! who sends requests faster than they expire only locks themselves out.
+
+use heapless::index_map::FnvIndexMap;
+use signingcommon::{RequestEnvelope, unix_time};
+
+use crate::error::Error;
+use crate::state::AppState;
+
+/// Maximum number of nonces remembered per user at any time. Must be a power of two.
+pub const MAX_NONCES_PER_USER: usize = 64;
+/// How far in the future, in seconds, a request may expire
+pub const MAX_VALIDITY: u64 = 300;
+/// Length of a nonce in bytes
+pub const NONCE_LEN: usize = 16;
+
+/// Recently seen nonces of a user
+pub type UserNonces = NonceCache<MAX_NONCES_PER_USER>;
+
+/// Recently seen nonces and the time their requests expire
+#[derive(Debug, Default)]
+pub struct NonceCache<const N: usize> {
+    nonces: FnvIndexMap<[u8; NONCE_LEN], u64, N>,
+}
+
+impl<const N: usize> NonceCache<N> {
+    pub fn new() -> Self {
+        NonceCache {
+            nonces: FnvIndexMap::new(),
+        }
+    }
+
+    /// Record `nonce` as used until `expires_at`, unless it was used before or the request is
+    /// stale.
+    pub fn check(
+        &mut self,
+        nonce: [u8; NONCE_LEN],
+        expires_at: u64,
+        now: u64,
+    ) -> Result<(), Error> {
+        check_expiry(expires_at, now)?;
+        if self.nonces.contains_key(&nonce) {
+            return Err(Error::Replayed);
+        }
+        if self.nonces.is_full() {
+            self.nonces.retain(|_, expires_at| *expires_at > now);
+        }
+        self.nonces
+            .insert(nonce, expires_at)
+            .map_err(|_| Error::ReplayCacheFull)?;
+        Ok(())
+    }
+}
+
+// Check that a request expiring at `expires_at` has not expired and is not valid for too long.
+fn check_expiry(expires_at: u64, now: u64) -> Result<(), Error> {
+    if expires_at <= now {
+        return Err(Error::InvalidEnvelope("request has expired".into()));
+    }
+    if expires_at > now + MAX_VALIDITY {
+        return Err(Error::InvalidEnvelope(format!(
+            "requests may be valid for at most {MAX_VALIDITY} seconds"
+        )));
+    }
+    Ok(())
+}
+
+// Decode the hex encoded nonce of an envelope.
+fn parse_nonce(nonce: &str) -> Result<[u8; NONCE_LEN], Error> {
+    hex::decode(nonce)
+        .ok()
+        .and_then(|nonce| nonce.try_into().ok())
+        .ok_or_else(|| {
+            Error::InvalidEnvelope(format!("nonce must be {NONCE_LEN} hex encoded bytes"))
+        })
+}
+
+/// Checks sign requests for replays
+#[derive(Debug)]
+pub struct ReplayGuard {
+    require_envelope: bool,
+}
+
+impl ReplayGuard {
+    /// Create a guard; if `require_envelope` is set requests without an envelope are rejected.
+    pub fn new(require_envelope: bool) -> Self {
+        ReplayGuard { require_envelope }
+    }
+
+    /// Check the envelope of a user's request, if any, and consume its nonce from the user's
+    /// [`UserNonces`]. The envelope's MAC must be made with the user's request key over `message`,
+    /// which is only computed when there is an envelope.
+    pub fn check(
+        &self,
+        state: &AppState,
+        user_id: &str,
+        envelope: Option<&RequestEnvelope>,
+        message: impl FnOnce() -> Vec<u8>,
+    ) -> Result<(), Error> {
+        let Some(envelope) = envelope else {
+            return self.check_missing();
+        };
+        let nonce = parse_nonce(&envelope.nonce)?;
+        state.check_envelope(
+            user_id,
+            &message(),
+            &envelope.mac,
+            nonce,
+            envelope.expires_at,
+        )
+    }
+
+    /// Check what can be checked of a request's envelope before the request is read: that it is
+    /// there if required, well formed and current. The request must still go through
+    /// [`check`](Self::check) once read.
+    pub fn check_unread(&self, envelope: Option<&RequestEnvelope>) -> Result<(), Error> {
+        let Some(envelope) = envelope else {
+            return self.check_missing();
+        };
+        parse_nonce(&envelope.nonce)?;
+        check_expiry(envelope.expires_at, unix_time())
+    }
+
+    // Accept a request without an envelope only if envelopes are optional.
+    fn check_missing(&self) -> Result<(), Error> {
+        if self.require_envelope {
+            Err(Error::InvalidEnvelope(
+                "request envelope is required".into(),
+            ))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use signingcommon::{
+        RegisterRequest, SignFormat, SignRequest, request_key, request_mac, request_mac_message,
+        unix_time,
+    };
+
+    #[test]
+    fn test_replayed_nonce_is_rejected() {
+        let mut cache = NonceCache::<4>::new();
+        assert!(cache.check([1; NONCE_LEN], 110, 100).is_ok());
+        assert!(matches!(
+            cache.check([1; NONCE_LEN], 110, 101),
+            Err(Error::Replayed)
+        ));
+        assert!(cache.check([2; NONCE_LEN], 110, 101).is_ok());
+    }
+
+    #[test]
+    fn test_stale_and_long_lived_requests_are_rejected() {
+        let mut cache = NonceCache::<4>::new();
+        assert!(matches!(
+            cache.check([1; NONCE_LEN], 100, 100),
+            Err(Error::InvalidEnvelope(_))
+        ));
+        assert!(matches!(
+            cache.check([1; NONCE_LEN], 101 + MAX_VALIDITY, 100),
+            Err(Error::InvalidEnvelope(_))
+        ));
+        assert!(cache.check([1; NONCE_LEN], 100 + MAX_VALIDITY, 100).is_ok());
+    }
+
+    #[test]
+    fn test_full_cache_evicts_expired_nonces() {
+        let mut cache = NonceCache::<2>::new();
+        assert!(cache.check([1; NONCE_LEN], 105, 100).is_ok());
+        assert!(cache.check([2; NONCE_LEN], 200, 100).is_ok());
+        // Full, and nothing has expired yet.
+        assert!(matches!(
+            cache.check([3; NONCE_LEN], 200, 101),
+            Err(Error::ReplayCacheFull)
+        ));
+        // The first nonce has expired and makes room.
+        assert!(cache.check([3; NONCE_LEN], 200, 106).is_ok());
+        assert!(matches!(
+            cache.check([2; NONCE_LEN], 200, 106),
+            Err(Error::Replayed)
+        ));
+    }
+
+    // A sign request for `user_id` in an envelope with `nonce`, authenticated with the request
+    // key of `seed`.
+    fn sealed(user_id: &str, message: &str, nonce: u8, seed: &[u8]) -> SignRequest {
+        let mut request = SignRequest {
+            user_id: user_id.to_string(),
+            message: message.to_string(),
+            context: None,
+            envelope: Some(RequestEnvelope {
+                nonce: hex::encode([nonce; NONCE_LEN]),
+                expires_at: unix_time() + 60,
+                mac: String::new(),
+            }),
+            format: SignFormat::Raw,
+            trusted_comment: None,
+        };
+        let mac = request_mac(&request_key(seed), &request_mac_message("/sign", &request));
+        request.envelope.as_mut().unwrap().mac = hex::encode(mac);
+        request
+    }
+
+    fn check(guard: &ReplayGuard, state: &AppState, request: &SignRequest) -> Result<(), Error> {
+        guard.check(state, &request.user_id, request.envelope.as_ref(), || {
+            request_mac_message("/sign", request)
+        })
+    }
+
+    #[test]
+    fn test_guard() {
+        let state = AppState::new();
+        let user_id = state
+            .register_user(&RegisterRequest {
+                seed: b"seed".to_vec(),
+                ..Default::default()
+            })
+            .unwrap()
+            .user_id
+            .to_string();
+
+        let guard = ReplayGuard::new(false);
+        assert!(
+            guard
+                .check(&state, &user_id, None, || unreachable!())
+                .is_ok()
+        );
+        let request = sealed(&user_id, "hello", 7, b"seed");
+        assert!(check(&guard, &state, &request).is_ok());
+        assert!(matches!(
+            check(&guard, &state, &request),
+            Err(Error::Replayed)
+        ));
+
+        // Without the seed, a captured request cannot be given a fresh nonce, nor can its envelope
+        // be moved to another request.
+        let mut fresh_nonce = request.clone();
+        fresh_nonce.envelope.as_mut().unwrap().nonce = hex::encode([8; NONCE_LEN]);
+        assert!(matches!(
+            check(&guard, &state, &fresh_nonce),
+            Err(Error::UnauthenticatedEnvelope)
+        ));
+        let mut moved = sealed(&user_id, "goodbye", 9, b"seed");
+        moved.envelope = sealed(&user_id, "hello", 9, b"seed").envelope;
+        assert!(matches!(
+            check(&guard, &state, &moved),
+            Err(Error::UnauthenticatedEnvelope)
+        ));
+        assert!(matches!(
+            check(
+                &guard,
+                &state,
+                &sealed(&user_id, "hello", 10, b"other seed")
+            ),
+            Err(Error::UnauthenticatedEnvelope)
+        ));
+        // Rejected envelopes do not use up their nonce.
+        assert!(check(&guard, &state, &sealed(&user_id, "goodbye", 9, b"seed")).is_ok());
+
+        let mut bad_nonce = sealed(&user_id, "hello", 11, b"seed");
+        bad_nonce.envelope.as_mut().unwrap().nonce = "abcd".into();
+        assert!(matches!(
+            check(&guard, &state, &bad_nonce),
+            Err(Error::InvalidEnvelope(_))
+        ));
+
+        let guard = ReplayGuard::new(true);
+        assert!(matches!(
+            guard.check(&state, &user_id, None, || unreachable!()),
+            Err(Error::InvalidEnvelope(_))
+        ));
+    }
+
+    #[test]
+    fn test_users_have_separate_nonces() {
+        let state = AppState::new();
+        let register = |seed: &[u8]| {
+            state
+                .register_user(&RegisterRequest {
+                    seed: seed.to_vec(),
+                    ..Default::default()
+                })
+                .unwrap()
+                .user_id
+                .to_string()
+        };
+        let (greedy, other) = (register(b"greedy"), register(b"other"));
+        let guard = ReplayGuard::new(true);
+
+        for nonce in 0..MAX_NONCES_PER_USER {
+            let request = sealed(&greedy, "hello", nonce as u8, b"greedy");
+            assert!(check(&guard, &state, &request).is_ok());
+        }
+        let request = sealed(&greedy, "hello", MAX_NONCES_PER_USER as u8, b"greedy");
+        assert!(matches!(
+            check(&guard, &state, &request),
+            Err(Error::ReplayCacheFull)
+        ));
+        // Filling their nonces does not lock out anyone else.
+        let request = sealed(&other, "hello", 0, b"other");
+        assert!(check(&guard, &state, &request).is_ok());
+    }
+}
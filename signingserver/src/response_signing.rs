@@ -0,0 +1,150 @@
+//! Signed responses.
+//!
+//! TLS only tells a client it is talking to whoever holds the certificate. To let clients
+//! recognize the real server, every response body is signed with the server's identity key and
+//! the detached signature sent in the [`RESPONSE_SIGNATURE_HEADER`] header. Clients that pin the
+//! identity key can then tell, for example, that a `RegisterResponse` binding a user ID to a
+//! verifying key really came from this server.
+//!
+//! A client can send a nonce of its choosing in the [`REQUEST_NONCE_HEADER`] header, which is
+//! signed along with the response. Checking it tells the client the response answers this very
+//! request rather than being an earlier response to the same endpoint played back.
+
+use axum::{
+    body::{Body, to_bytes},
+    extract::{Request, State},
+    http::{HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use signingcommon::{
+    MAX_REQUEST_NONCE_LEN, REQUEST_NONCE_HEADER, RESPONSE_SIGNATURE_HEADER,
+    response_signature_message,
+};
+use std::sync::Arc;
+use tracing::error;
+
+use crate::error::Error;
+use crate::handlers::error_response;
+use crate::state::AppState;
+
+/// Largest response body that is signed. No endpoint produces anything close to this.
+const MAX_SIGNED_RESPONSE_LEN: usize = 4 * 1024 * 1024;
+
+/// Middleware adding a detached signature by the server's identity key to every response
+pub async fn sign_responses(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let path = request.uri().path().to_string();
+    let (nonce, response) = match request_nonce(&request) {
+        Ok(nonce) => (nonce, next.run(request).await),
+        Err(e) => (String::new(), error_response(e)),
+    };
+
+    let (mut parts, body) = response.into_parts();
+    let body = match to_bytes(body, MAX_SIGNED_RESPONSE_LEN).await {
+        Ok(body) => body,
+        Err(e) => {
+            error!("Failed to buffer response for signing: {}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    let signature = state.sign_as_server(&response_signature_message(
+        &path,
+        &nonce,
+        parts.status.as_u16(),
+        &body,
+    ));
+    parts.headers.insert(
+        RESPONSE_SIGNATURE_HEADER,
+        HeaderValue::from_str(&hex::encode(signature.to_bytes()))
+            .expect("hex is a valid header value"),
+    );
+    Response::from_parts(parts, Body::from(body))
+}
+
+// The nonce the client sent with a request, or an empty one if it sent none.
+fn request_nonce(request: &Request) -> Result<String, Error> {
+    let Some(nonce) = request.headers().get(REQUEST_NONCE_HEADER) else {
+        return Ok(String::new());
+    };
+    let nonce = nonce.as_bytes();
+    if nonce.len() > MAX_REQUEST_NONCE_LEN || !nonce.iter().all(u8::is_ascii_graphic) {
+        return Err(Error::InvalidNonce(format!(
+            "{REQUEST_NONCE_HEADER} must be at most {MAX_REQUEST_NONCE_LEN} printable ASCII characters"
+        )));
+    }
+    Ok(String::from_utf8(nonce.to_vec()).expect("ASCII is valid UTF-8"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{Router, middleware, routing::get};
+    use ed25519_dalek::Signature;
+    use tower::ServiceExt;
+
+    fn app(state: &Arc<AppState>) -> Router {
+        Router::new()
+            .route("/hello", get(|| async { (StatusCode::CREATED, "hello") }))
+            .layer(middleware::from_fn_with_state(
+                state.clone(),
+                sign_responses,
+            ))
+    }
+
+    #[tokio::test]
+    async fn test_responses_are_signed() {
+        let state = Arc::new(AppState::new());
+
+        let response = app(&state)
+            .oneshot(
+                Request::get("/hello")
+                    .header(REQUEST_NONCE_HEADER, "n1")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let signature = response.headers()[RESPONSE_SIGNATURE_HEADER]
+            .to_str()
+            .unwrap();
+        let signature = Signature::from_slice(&hex::decode(signature).unwrap()).unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], b"hello");
+
+        let message = response_signature_message("/hello", "n1", 201, &body);
+        assert!(state.identity().verify_strict(&message, &signature).is_ok());
+        for other in [
+            response_signature_message("/other", "n1", 201, &body),
+            response_signature_message("/hello", "n2", 201, &body),
+            response_signature_message("/hello", "", 201, &body),
+        ] {
+            assert!(state.identity().verify_strict(&other, &signature).is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_invalid_request_nonces_are_rejected() {
+        let state = Arc::new(AppState::new());
+        for nonce in [
+            "with space".to_string(),
+            "n".repeat(MAX_REQUEST_NONCE_LEN + 1),
+        ] {
+            let response = app(&state)
+                .oneshot(
+                    Request::get("/hello")
+                        .header(REQUEST_NONCE_HEADER, nonce)
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+            assert!(response.headers().contains_key(RESPONSE_SIGNATURE_HEADER));
+        }
+    }
+}
@@ -0,0 +1,95 @@
+//! Revoked keys.
+//!
+//! When a seed is compromised its owner revokes the user: the keys are forgotten and recorded
+//! here, so that relying parties can learn about the revocation from the signed list served at
+//! `/revocations`, and so that the seed cannot be registered again. Revocations are permanent for
+//! the lifetime of the process and live in a fixed-size list.
+
+use ed25519_dalek::{PUBLIC_KEY_LENGTH, VerifyingKey};
+use std::sync::Mutex;
+use uuid::Uuid;
+
+use crate::error::Error;
+
+/// Maximum number of revoked keys
+pub const MAX_REVOCATIONS: usize = 4_096;
+
+/// A revoked key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Revoked {
+    pub user_id: Uuid,
+    pub verifying_key: [u8; PUBLIC_KEY_LENGTH],
+    pub revoked_at: u64,
+}
+
+/// All keys revoked so far, in order of revocation
+#[derive(Debug)]
+pub struct Revocations {
+    entries: Mutex<Box<heapless::Vec<Revoked, MAX_REVOCATIONS>>>,
+}
+
+impl Revocations {
+    pub fn new() -> Self {
+        Revocations {
+            entries: Mutex::new(Box::new(heapless::Vec::new())),
+        }
+    }
+
+    /// Whether `verifying_key` has been revoked
+    pub fn is_revoked(&self, verifying_key: &VerifyingKey) -> bool {
+        self.entries
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|entry| entry.verifying_key == verifying_key.to_bytes())
+    }
+
+    /// Record all of `revoked`, or none of them if there is not enough room left.
+    pub fn add(&self, revoked: &[Revoked]) -> Result<(), Error> {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() + revoked.len() > entries.capacity() {
+            return Err(Error::RevocationListFull);
+        }
+        for entry in revoked {
+            if !entries
+                .iter()
+                .any(|e| e.verifying_key == entry.verifying_key)
+            {
+                entries.push(*entry).expect("room was checked above");
+            }
+        }
+        Ok(())
+    }
+
+    /// Copy of all revocations
+    pub fn snapshot(&self) -> Vec<Revoked> {
+        self.entries.lock().unwrap().to_vec()
+    }
+}
+
+impl Default for Revocations {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn revoked(key: u8) -> Revoked {
+        Revoked {
+            user_id: Uuid::nil(),
+            verifying_key: [key; PUBLIC_KEY_LENGTH],
+            revoked_at: 1,
+        }
+    }
+
+    #[test]
+    fn test_add_is_idempotent() {
+        let revocations = Revocations::new();
+        revocations.add(&[revoked(1), revoked(2)]).unwrap();
+        revocations.add(&[revoked(1)]).unwrap();
+        assert_eq!(revocations.snapshot(), vec![revoked(1), revoked(2)]);
+    }
+}
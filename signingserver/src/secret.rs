@@ -0,0 +1,207 @@
+//! The server secret: random bytes generated on first start and kept in a file only the server's
+//! user can read.
+//!
+//! Unlike the compiled-in master key, the secret is not known to whoever has the binary, so the
+//! server identity key and the deterministic user IDs derived from it cannot be computed outside
+//! the server. Operators can also provision the file themselves, as the hex encoding of
+//! [`SERVER_SECRET_LEN`] bytes.
+//!
+//! The generation of the server identity key derived from the secret is kept next to it, see
+//! [`IdentityGeneration`].
+
+use anyhow::Context;
+use rand::RngCore;
+use std::fs::{DirBuilder, File, OpenOptions};
+use std::io::{ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tracing::info;
+use zeroize::Zeroize;
+
+pub const SERVER_SECRET_LEN: usize = 32;
+
+/// Where the server secret is kept unless configured otherwise:
+/// `$XDG_STATE_HOME/signingserver/server.secret`, or `~/.local/state/signingserver/server.secret`
+/// if `XDG_STATE_HOME` is not set.
+pub fn default_path() -> anyhow::Result<PathBuf> {
+    let state_home = match std::env::var_os("XDG_STATE_HOME").filter(|dir| !dir.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => std::env::var_os("HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(|home| Path::new(&home).join(".local/state"))
+            .context("neither XDG_STATE_HOME nor HOME is set, pass --secret-file")?,
+    };
+    Ok(state_home.join("signingserver/server.secret"))
+}
+
+/// Read the server secret from `path`, or generate one and write it there if the file does not
+/// exist yet, creating its directory if needed. The file is created readable by its owner only,
+/// and files that other users can access are refused.
+pub fn load_or_create(path: &Path) -> anyhow::Result<[u8; SERVER_SECRET_LEN]> {
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        let mut builder = DirBuilder::new();
+        builder.recursive(true);
+        #[cfg(unix)]
+        std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+        builder
+            .create(dir)
+            .with_context(|| format!("creating directory {}", dir.display()))?;
+    }
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    match options.open(path) {
+        Ok(file) => {
+            let secret = create(file)
+                .with_context(|| format!("writing server secret {}", path.display()))?;
+            info!("Generated a new server secret in {}", path.display());
+            Ok(secret)
+        }
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => load(path),
+        Err(e) => Err(e).with_context(|| format!("creating server secret {}", path.display())),
+    }
+}
+
+fn create(mut file: File) -> anyhow::Result<[u8; SERVER_SECRET_LEN]> {
+    let mut secret = [0; SERVER_SECRET_LEN];
+    rand::rngs::OsRng.fill_bytes(&mut secret);
+    let mut encoded = hex::encode(secret);
+    let written = writeln!(file, "{encoded}").and_then(|_| file.sync_all());
+    encoded.zeroize();
+    written?;
+    Ok(secret)
+}
+
+fn load(path: &Path) -> anyhow::Result<[u8; SERVER_SECRET_LEN]> {
+    let mut file =
+        File::open(path).with_context(|| format!("opening server secret {}", path.display()))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = file.metadata()?.permissions().mode();
+        if mode & 0o077 != 0 {
+            anyhow::bail!(
+                "server secret {} is accessible by other users (mode {:o}), restrict it to 0600",
+                path.display(),
+                mode & 0o777
+            );
+        }
+    }
+    let mut encoded = String::new();
+    let read = file.read_to_string(&mut encoded);
+    let mut secret = [0; SERVER_SECRET_LEN];
+    let decoded = hex::decode_to_slice(encoded.trim(), &mut secret);
+    encoded.zeroize();
+    read.with_context(|| format!("reading server secret {}", path.display()))?;
+    decoded.map_err(|_| {
+        anyhow::anyhow!(
+            "server secret {} does not hold {SERVER_SECRET_LEN} hex encoded bytes",
+            path.display()
+        )
+    })?;
+    Ok(secret)
+}
+
+/// The generation of the server identity key, kept in a file next to the server secret (its path
+/// with `.generation` appended) so that a rotated identity key survives restarts. A missing file
+/// means generation 0.
+#[derive(Debug)]
+pub struct IdentityGeneration {
+    path: PathBuf,
+    generation: Mutex<u32>,
+}
+
+impl IdentityGeneration {
+    /// Read the generation stored next to the server secret at `secret_path`
+    pub fn load(secret_path: &Path) -> anyhow::Result<Self> {
+        let mut path = secret_path.as_os_str().to_owned();
+        path.push(".generation");
+        let path = PathBuf::from(path);
+        let generation = match std::fs::read_to_string(&path) {
+            Ok(generation) => generation.trim().parse().map_err(|_| {
+                anyhow::anyhow!(
+                    "identity generation {} does not hold a number",
+                    path.display()
+                )
+            })?,
+            Err(e) if e.kind() == ErrorKind::NotFound => 0,
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("reading identity generation {}", path.display()));
+            }
+        };
+        Ok(IdentityGeneration {
+            path,
+            generation: Mutex::new(generation),
+        })
+    }
+
+    /// The current generation
+    pub fn get(&self) -> u32 {
+        *self.generation.lock().unwrap()
+    }
+
+    /// Store the next generation, then call `rotate` to switch to it. Nothing is rotated if the
+    /// generation cannot be stored, so the server never uses a key it would lose on restart.
+    pub fn advance<T>(&self, rotate: impl FnOnce() -> T) -> std::io::Result<T> {
+        let mut generation = self.generation.lock().unwrap();
+        let next = *generation + 1;
+        // Written to a temporary file first, so that a crash cannot leave a truncated generation.
+        let mut tmp = self.path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let mut file = File::create(&tmp)?;
+        writeln!(file, "{next}")?;
+        file.sync_all()?;
+        std::fs::rename(&tmp, &self.path)?;
+        *generation = next;
+        Ok(rotate())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_or_create() {
+        let path = std::env::temp_dir().join(format!("server-secret-{}", uuid::Uuid::new_v4()));
+        let secret = load_or_create(&path).unwrap();
+        assert_ne!(secret, [0; SERVER_SECRET_LEN]);
+        // Later starts read the same secret back.
+        assert_eq!(load_or_create(&path).unwrap(), secret);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(
+                std::fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+                0o600
+            );
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+            assert!(load_or_create(&path).is_err());
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600)).unwrap();
+        }
+
+        std::fs::write(&path, "not hex\n").unwrap();
+        assert!(load_or_create(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_identity_generation() {
+        let path = std::env::temp_dir().join(format!("server-secret-{}", uuid::Uuid::new_v4()));
+        let generation = IdentityGeneration::load(&path).unwrap();
+        assert_eq!(generation.get(), 0);
+        assert_eq!(generation.advance(|| "rotated").unwrap(), "rotated");
+        generation.advance(|| ()).unwrap();
+        assert_eq!(generation.get(), 2);
+
+        // Later starts read the stored generation back.
+        assert_eq!(IdentityGeneration::load(&path).unwrap().get(), 2);
+
+        std::fs::write(&generation.path, "two\n").unwrap();
+        assert!(IdentityGeneration::load(&path).is_err());
+        std::fs::remove_file(&generation.path).unwrap();
+    }
+}
@@ -0,0 +1,189 @@
+//! A small pool of dedicated signing threads.
+//!
+//! Signing large messages is CPU bound and would block a tokio worker, so handlers hand the work
+//! to this pool and await the result. The queue in front of the pool is bounded: when it is full
+//! new work is rejected immediately rather than piling up, and work that has waited longer than
+//! its deadline is dropped without being executed.
+
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tracing::{debug, warn};
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Errors returned by [`SigningPool::run`]
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum PoolError {
+    #[error("signing queue is full, try again later")]
+    Busy,
+    #[error("signing deadline exceeded")]
+    DeadlineExceeded,
+    #[error("signing pool is shut down")]
+    Closed,
+}
+
+/// Signing pool configuration
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    /// Number of signing threads
+    pub threads: usize,
+    /// Maximum number of jobs waiting for a free thread
+    pub queue_depth: usize,
+    /// How long a job may take from submission to completion
+    pub deadline: Duration,
+    /// Pin each signing thread to its own CPU core
+    pub pin_cores: bool,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        PoolConfig {
+            threads: thread::available_parallelism().map_or(1, |n| n.get()),
+            queue_depth: 256,
+            deadline: Duration::from_secs(5),
+            pin_cores: false,
+        }
+    }
+}
+
+/// Bounded pool of signing threads
+#[derive(Debug)]
+pub struct SigningPool {
+    queue: SyncSender<Job>,
+    deadline: Duration,
+}
+
+impl SigningPool {
+    pub fn new(config: PoolConfig) -> Self {
+        let (queue, jobs) = mpsc::sync_channel::<Job>(config.queue_depth);
+        let jobs = Arc::new(Mutex::new(jobs));
+        let cores = if config.pin_cores {
+            core_affinity::get_core_ids().unwrap_or_default()
+        } else {
+            Vec::new()
+        };
+        if config.pin_cores && cores.is_empty() {
+            warn!("Unable to determine CPU cores, signing threads will not be pinned");
+        }
+
+        for i in 0..config.threads.max(1) {
+            let jobs = jobs.clone();
+            let core = (!cores.is_empty()).then(|| cores[i % cores.len()]);
+            thread::Builder::new()
+                .name(format!("signer-{i}"))
+                .spawn(move || {
+                    if let Some(core) = core
+                        && !core_affinity::set_for_current(core)
+                    {
+                        warn!("Failed to pin signing thread {} to core {:?}", i, core);
+                    }
+                    worker(&jobs);
+                })
+                .expect("failed to spawn signing thread");
+        }
+
+        SigningPool {
+            queue,
+            deadline: config.deadline,
+        }
+    }
+
+    /// Run `f` on a signing thread and wait for its result.
+    pub async fn run<F, T>(&self, f: F) -> Result<T, PoolError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let deadline = Instant::now() + self.deadline;
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let job: Job = Box::new(move || {
+            // Nobody is waiting for stale results, don't waste time on them.
+            if Instant::now() < deadline {
+                let _ = tx.send(f());
+            } else {
+                debug!("Dropping signing job past its deadline");
+            }
+        });
+
+        self.queue.try_send(job).map_err(|e| match e {
+            TrySendError::Full(_) => PoolError::Busy,
+            TrySendError::Disconnected(_) => PoolError::Closed,
+        })?;
+
+        match tokio::time::timeout_at(deadline.into(), rx).await {
+            Ok(Ok(result)) => Ok(result),
+            // The job was dropped unexecuted.
+            Ok(Err(_)) | Err(_) => Err(PoolError::DeadlineExceeded),
+        }
+    }
+}
+
+fn worker(jobs: &Mutex<Receiver<Job>>) {
+    loop {
+        // Only hold the lock while waiting for a job, not while running it.
+        let job = jobs.lock().unwrap().recv();
+        match job {
+            Ok(job) => job(),
+            // The pool was dropped.
+            Err(_) => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Barrier;
+
+    fn pool(queue_depth: usize, deadline: Duration) -> SigningPool {
+        SigningPool::new(PoolConfig {
+            threads: 1,
+            queue_depth,
+            deadline,
+            pin_cores: false,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_run_returns_result() {
+        let pool = pool(1, Duration::from_secs(5));
+        assert_eq!(pool.run(|| 40 + 2).await, Ok(42));
+    }
+
+    #[tokio::test]
+    async fn test_full_queue_is_rejected() {
+        let pool = Arc::new(pool(1, Duration::from_secs(5)));
+        // Occupy the only thread until the end of the test...
+        let barrier = Arc::new(Barrier::new(2));
+        let (started_tx, started_rx) = tokio::sync::oneshot::channel();
+        let blocked = tokio::spawn({
+            let pool = pool.clone();
+            let barrier = barrier.clone();
+            async move {
+                pool.run(move || {
+                    started_tx.send(()).unwrap();
+                    barrier.wait();
+                })
+                .await
+            }
+        });
+        started_rx.await.unwrap();
+        // ...and fill the queue.
+        assert!(pool.queue.try_send(Box::new(|| {})).is_ok());
+
+        assert_eq!(pool.run(|| ()).await, Err(PoolError::Busy));
+
+        barrier.wait();
+        assert!(blocked.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_deadline_exceeded() {
+        let pool = pool(1, Duration::from_millis(50));
+        let result = pool.run(|| thread::sleep(Duration::from_millis(200))).await;
+        assert_eq!(result, Err(PoolError::DeadlineExceeded));
+    }
+}
@@ -0,0 +1,560 @@
+//! Solana transaction signing.
+//!
+//! Solana signs transactions with Ed25519 over the serialized transaction message, so user keys
+//! can sign them as they are. Rather than signing opaque bytes, `/sign/solana` parses the message
+//! (legacy or v0), checks the user's key is one of its required signers, lets the configured
+//! [`SolanaPolicy`] hooks inspect it and returns what it understood along with the signature.
+//!
+//! The parser follows the wire format of the Solana SDK and its sanity checks, but resolves
+//! nothing that needs chain state, such as the accounts of address lookup tables.
+
+use signingcommon::{
+    SolanaAccount, SolanaAddressTableLookup, SolanaInstruction, SolanaMessageInfo,
+};
+use uuid::Uuid;
+
+use crate::error::Error;
+
+/// A Solana public key
+pub type Pubkey = [u8; 32];
+
+/// Versioned messages have the top bit of their first byte set
+const VERSION_PREFIX: u8 = 0x80;
+/// A transaction can reference at most this many accounts
+const MAX_ACCOUNTS: usize = 256;
+
+/// Message format version
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Version {
+    Legacy,
+    V0,
+}
+
+/// Counts of signing and read-only accounts, which determine each account's role
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub num_required_signatures: u8,
+    pub num_readonly_signed: u8,
+    pub num_readonly_unsigned: u8,
+}
+
+/// An instruction, referring to accounts by index
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Instruction {
+    pub program_id_index: u8,
+    pub accounts: Vec<u8>,
+    pub data: Vec<u8>,
+}
+
+/// Accounts a v0 message loads from an address lookup table
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddressTableLookup {
+    pub account_key: Pubkey,
+    pub writable_indexes: Vec<u8>,
+    pub readonly_indexes: Vec<u8>,
+}
+
+/// A parsed transaction message, along with the bytes it was parsed from
+#[derive(Debug, Clone)]
+pub struct Message {
+    bytes: Vec<u8>,
+    pub version: Version,
+    pub header: Header,
+    pub account_keys: Vec<Pubkey>,
+    pub recent_blockhash: [u8; 32],
+    pub instructions: Vec<Instruction>,
+    pub address_table_lookups: Vec<AddressTableLookup>,
+}
+
+impl Message {
+    /// Parse and sanity check a serialized message
+    pub fn parse(bytes: Vec<u8>) -> Result<Self, Error> {
+        let message = Message::decode(&bytes)?;
+        Ok(Message { bytes, ..message })
+    }
+
+    /// Whether `bytes` are a valid message that `key` must sign. Parses the borrowed bytes, so
+    /// checking arbitrary messages does not copy them.
+    pub fn is_signed_by(bytes: &[u8], key: &Pubkey) -> bool {
+        Message::decode(bytes).is_ok_and(|message| message.required_signers().contains(key))
+    }
+
+    // Parse and sanity check a serialized message, leaving `bytes` empty.
+    fn decode(bytes: &[u8]) -> Result<Self, Error> {
+        let mut reader = Reader { bytes };
+        let version = match reader.peek()? {
+            b if b & VERSION_PREFIX == 0 => Version::Legacy,
+            b if b == VERSION_PREFIX => {
+                reader.u8()?;
+                Version::V0
+            }
+            b => {
+                return Err(invalid(format!(
+                    "unsupported version {}",
+                    b & !VERSION_PREFIX
+                )));
+            }
+        };
+        let header = Header {
+            num_required_signatures: reader.u8()?,
+            num_readonly_signed: reader.u8()?,
+            num_readonly_unsigned: reader.u8()?,
+        };
+        let account_keys = reader.vec(Reader::pubkey)?;
+        let recent_blockhash = reader.pubkey()?;
+        let instructions = reader.vec(|reader| {
+            Ok(Instruction {
+                program_id_index: reader.u8()?,
+                accounts: reader.vec(Reader::u8)?,
+                data: reader.vec(Reader::u8)?,
+            })
+        })?;
+        let address_table_lookups = match version {
+            Version::Legacy => Vec::new(),
+            Version::V0 => reader.vec(|reader| {
+                Ok(AddressTableLookup {
+                    account_key: reader.pubkey()?,
+                    writable_indexes: reader.vec(Reader::u8)?,
+                    readonly_indexes: reader.vec(Reader::u8)?,
+                })
+            })?,
+        };
+        if !reader.bytes.is_empty() {
+            return Err(invalid("trailing bytes after the message"));
+        }
+        let message = Message {
+            bytes: Vec::new(),
+            version,
+            header,
+            account_keys,
+            recent_blockhash,
+            instructions,
+            address_table_lookups,
+        };
+        message.sanitize()?;
+        Ok(message)
+    }
+
+    // The checks the Solana runtime performs before looking at a message.
+    fn sanitize(&self) -> Result<(), Error> {
+        let header = &self.header;
+        let static_keys = self.account_keys.len();
+        if header.num_required_signatures == 0 {
+            return Err(invalid("a message needs a fee payer"));
+        }
+        if usize::from(header.num_required_signatures) > static_keys {
+            return Err(invalid("more required signatures than accounts"));
+        }
+        if header.num_readonly_signed >= header.num_required_signatures {
+            return Err(invalid("the fee payer must be writable"));
+        }
+        if usize::from(header.num_readonly_unsigned)
+            > static_keys - usize::from(header.num_required_signatures)
+        {
+            return Err(invalid(
+                "more read-only unsigned accounts than unsigned accounts",
+            ));
+        }
+        if self.account_count() > MAX_ACCOUNTS {
+            return Err(invalid(format!("more than {MAX_ACCOUNTS} accounts")));
+        }
+        for (i, key) in self.account_keys.iter().enumerate() {
+            if self.account_keys[..i].contains(key) {
+                return Err(invalid("duplicate account keys"));
+            }
+        }
+        for lookup in &self.address_table_lookups {
+            if lookup.writable_indexes.is_empty() && lookup.readonly_indexes.is_empty() {
+                return Err(invalid("address table lookup without accounts"));
+            }
+        }
+        for instruction in &self.instructions {
+            // Programs cannot be loaded from lookup tables, nor pay fees.
+            let program = usize::from(instruction.program_id_index);
+            if program == 0 || program >= static_keys {
+                return Err(invalid("invalid program id index"));
+            }
+            if instruction
+                .accounts
+                .iter()
+                .any(|&account| usize::from(account) >= self.account_count())
+            {
+                return Err(invalid("invalid account index"));
+            }
+        }
+        Ok(())
+    }
+
+    /// The serialized message, which is what gets signed
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// The keys that must sign the message, fee payer first
+    pub fn required_signers(&self) -> &[Pubkey] {
+        &self.account_keys[..usize::from(self.header.num_required_signatures)]
+    }
+
+    /// The program an instruction invokes
+    pub fn program_id(&self, instruction: &Instruction) -> &Pubkey {
+        &self.account_keys[usize::from(instruction.program_id_index)]
+    }
+
+    // Number of static accounts plus accounts loaded from lookup tables.
+    fn account_count(&self) -> usize {
+        self.account_keys.len()
+            + self
+                .address_table_lookups
+                .iter()
+                .map(|lookup| lookup.writable_indexes.len() + lookup.readonly_indexes.len())
+                .sum::<usize>()
+    }
+
+    // Whether the static account at `index` is writable.
+    fn is_writable(&self, index: usize) -> bool {
+        let header = &self.header;
+        let signers = usize::from(header.num_required_signatures);
+        if index < signers {
+            index < signers - usize::from(header.num_readonly_signed)
+        } else {
+            index < self.account_keys.len() - usize::from(header.num_readonly_unsigned)
+        }
+    }
+
+    // Accounts as referred to by instructions: static keys, then the writable accounts of all
+    // lookups, then their read-only accounts.
+    fn account_name(&self, index: usize) -> String {
+        if let Some(key) = self.account_keys.get(index) {
+            return bs58::encode(key).into_string();
+        }
+        let mut loaded = self
+            .address_table_lookups
+            .iter()
+            .flat_map(|lookup| lookup.writable_indexes.iter().map(move |i| (lookup, i)))
+            .chain(
+                self.address_table_lookups
+                    .iter()
+                    .flat_map(|lookup| lookup.readonly_indexes.iter().map(move |i| (lookup, i))),
+            );
+        let (lookup, table_index) = loaded
+            .nth(index - self.account_keys.len())
+            .expect("account indexes are checked when parsing");
+        format!(
+            "{}[{}]",
+            bs58::encode(lookup.account_key).into_string(),
+            table_index
+        )
+    }
+
+    /// What the message contains, for display
+    pub fn info(&self) -> SolanaMessageInfo {
+        SolanaMessageInfo {
+            version: match self.version {
+                Version::Legacy => "legacy".into(),
+                Version::V0 => "0".into(),
+            },
+            recent_blockhash: bs58::encode(self.recent_blockhash).into_string(),
+            accounts: self
+                .account_keys
+                .iter()
+                .enumerate()
+                .map(|(i, key)| SolanaAccount {
+                    pubkey: bs58::encode(key).into_string(),
+                    signer: i < usize::from(self.header.num_required_signatures),
+                    writable: self.is_writable(i),
+                })
+                .collect(),
+            instructions: self
+                .instructions
+                .iter()
+                .map(|instruction| SolanaInstruction {
+                    program_id: bs58::encode(self.program_id(instruction)).into_string(),
+                    accounts: instruction
+                        .accounts
+                        .iter()
+                        .map(|&account| self.account_name(usize::from(account)))
+                        .collect(),
+                    data: bs58::encode(&instruction.data).into_string(),
+                })
+                .collect(),
+            address_table_lookups: self
+                .address_table_lookups
+                .iter()
+                .map(|lookup| SolanaAddressTableLookup {
+                    account_key: bs58::encode(lookup.account_key).into_string(),
+                    writable_indexes: lookup.writable_indexes.clone(),
+                    readonly_indexes: lookup.readonly_indexes.clone(),
+                })
+                .collect(),
+        }
+    }
+}
+
+fn invalid(reason: impl Into<String>) -> Error {
+    Error::InvalidSolanaMessage(reason.into())
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl Reader<'_> {
+    fn peek(&self) -> Result<u8, Error> {
+        self.bytes
+            .first()
+            .copied()
+            .ok_or_else(|| invalid("message is truncated"))
+    }
+
+    fn u8(&mut self) -> Result<u8, Error> {
+        let byte = self.peek()?;
+        self.bytes = &self.bytes[1..];
+        Ok(byte)
+    }
+
+    fn pubkey(&mut self) -> Result<Pubkey, Error> {
+        let (key, rest) = self
+            .bytes
+            .split_first_chunk()
+            .ok_or_else(|| invalid("message is truncated"))?;
+        self.bytes = rest;
+        Ok(*key)
+    }
+
+    // A compact-u16 length: up to three bytes of seven bits each, least significant first, in
+    // its shortest encoding.
+    fn len(&mut self) -> Result<usize, Error> {
+        let mut len = 0usize;
+        for i in 0..3 {
+            let byte = self.u8()?;
+            len |= usize::from(byte & 0x7f) << (7 * i);
+            if byte & 0x80 == 0 {
+                if i > 0 && byte == 0 {
+                    return Err(invalid("length is not in its shortest encoding"));
+                }
+                if len > usize::from(u16::MAX) {
+                    break;
+                }
+                return Ok(len);
+            }
+        }
+        Err(invalid("length does not fit in 16 bits"))
+    }
+
+    fn vec<T>(&mut self, item: impl Fn(&mut Self) -> Result<T, Error>) -> Result<Vec<T>, Error> {
+        let len = self.len()?;
+        // Every item takes at least a byte, which bounds the allocation by the message size.
+        if len > self.bytes.len() {
+            return Err(invalid("message is truncated"));
+        }
+        (0..len).map(|_| item(self)).collect()
+    }
+}
+
+/// A check on Solana messages before they are signed
+pub trait SolanaPolicy: Send + Sync {
+    /// Name of the policy, reported when it refuses a message
+    fn name(&self) -> &'static str;
+
+    /// Return the reason to refuse signing `message` for `user_id`, if any
+    fn check(&self, user_id: &Uuid, message: &Message) -> Result<(), String>;
+}
+
+/// Only sign messages whose instructions all invoke one of the listed programs
+#[derive(Debug)]
+pub struct ProgramAllowlist {
+    programs: Vec<Pubkey>,
+}
+
+impl ProgramAllowlist {
+    /// Allow the programs with the given base58 encoded IDs
+    pub fn new(programs: &[String]) -> anyhow::Result<Self> {
+        let programs = programs
+            .iter()
+            .map(|program| {
+                let mut key = Pubkey::default();
+                match bs58::decode(program).onto(&mut key) {
+                    Ok(32) => Ok(key),
+                    _ => Err(anyhow::anyhow!("invalid program id {program}")),
+                }
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(ProgramAllowlist { programs })
+    }
+}
+
+impl SolanaPolicy for ProgramAllowlist {
+    fn name(&self) -> &'static str {
+        "program allowlist"
+    }
+
+    fn check(&self, _user_id: &Uuid, message: &Message) -> Result<(), String> {
+        for instruction in &message.instructions {
+            let program = message.program_id(instruction);
+            if !self.programs.contains(program) {
+                return Err(format!(
+                    "program {} is not allowed",
+                    bs58::encode(program).into_string()
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The policies Solana messages must pass
+pub struct Solana {
+    policies: Vec<Box<dyn SolanaPolicy>>,
+}
+
+impl std::fmt::Debug for Solana {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Solana")
+            .field(
+                "policies",
+                &self.policies.iter().map(|p| p.name()).collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+impl Solana {
+    pub fn new(policies: Vec<Box<dyn SolanaPolicy>>) -> Self {
+        Solana { policies }
+    }
+
+    /// Run all policies on `message`
+    pub fn check(&self, user_id: &Uuid, message: &Message) -> Result<(), Error> {
+        for policy in &self.policies {
+            policy
+                .check(user_id, message)
+                .map_err(|reason| Error::PolicyViolation(format!("{}: {reason}", policy.name())))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SYSTEM_PROGRAM: Pubkey = [0; 32];
+
+    // A legacy message transferring lamports from `from` to `to` with the system program.
+    fn transfer(from: Pubkey, to: Pubkey) -> Vec<u8> {
+        let mut message = vec![1, 0, 1, 3];
+        message.extend_from_slice(&from);
+        message.extend_from_slice(&to);
+        message.extend_from_slice(&SYSTEM_PROGRAM);
+        message.extend_from_slice(&[9; 32]);
+        // One instruction: program 2, accounts [0, 1], 12 bytes of data.
+        message.extend_from_slice(&[1, 2, 2, 0, 1, 12, 2, 0, 0, 0]);
+        message.extend_from_slice(&1_000u64.to_le_bytes());
+        message
+    }
+
+    #[test]
+    fn test_parse_legacy() {
+        let message = Message::parse(transfer([1; 32], [2; 32])).unwrap();
+        assert_eq!(message.version, Version::Legacy);
+        assert_eq!(message.required_signers(), &[[1; 32]]);
+        assert_eq!(
+            message.program_id(&message.instructions[0]),
+            &SYSTEM_PROGRAM
+        );
+
+        let info = message.info();
+        assert_eq!(info.version, "legacy");
+        assert!(info.accounts[0].signer && info.accounts[0].writable);
+        assert!(!info.accounts[1].signer && info.accounts[1].writable);
+        assert!(!info.accounts[2].signer && !info.accounts[2].writable);
+        assert_eq!(
+            info.instructions[0].program_id,
+            "11111111111111111111111111111111"
+        );
+        assert_eq!(info.instructions[0].accounts.len(), 2);
+    }
+
+    #[test]
+    fn test_parse_v0() {
+        let mut bytes = vec![VERSION_PREFIX];
+        bytes.extend_from_slice(&transfer([1; 32], [2; 32]));
+        // One lookup loading a writable account, referenced by the instruction as account 3.
+        bytes.push(1);
+        bytes.extend_from_slice(&[7; 32]);
+        bytes.extend_from_slice(&[1, 5, 0]);
+        let account_index = 1 + 4 + 3 * 32 + 32 + 3;
+        bytes[account_index] = 3;
+
+        let message = Message::parse(bytes).unwrap();
+        assert_eq!(message.version, Version::V0);
+        let info = message.info();
+        assert_eq!(info.version, "0");
+        assert_eq!(
+            info.instructions[0].accounts[0],
+            format!("{}[5]", bs58::encode([7; 32]).into_string())
+        );
+    }
+
+    #[test]
+    fn test_reject_malformed() {
+        let mut trailing = transfer([1; 32], [2; 32]);
+        trailing.push(0);
+        let mut truncated = transfer([1; 32], [2; 32]);
+        truncated.pop();
+        let duplicate = transfer([1; 32], [1; 32]);
+        let mut fee_payer_program = transfer([1; 32], [2; 32]);
+        fee_payer_program[4 + 4 * 32 + 1] = 0;
+        let mut unsupported_version = vec![VERSION_PREFIX | 1];
+        unsupported_version.extend_from_slice(&transfer([1; 32], [2; 32]));
+
+        for bytes in [
+            trailing,
+            truncated,
+            duplicate,
+            fee_payer_program,
+            unsupported_version,
+            vec![],
+        ] {
+            assert!(matches!(
+                Message::parse(bytes),
+                Err(Error::InvalidSolanaMessage(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_compact_u16() {
+        let mut reader = Reader {
+            bytes: &[0x80, 0x01, 0xff, 0xff, 0x03],
+        };
+        assert_eq!(reader.len().unwrap(), 128);
+        assert_eq!(reader.len().unwrap(), 0xffff);
+        // Not the shortest encoding of 0.
+        let mut reader = Reader {
+            bytes: &[0x80, 0x00],
+        };
+        assert!(reader.len().is_err());
+        let mut reader = Reader {
+            bytes: &[0xff, 0xff, 0x04],
+        };
+        assert!(reader.len().is_err());
+    }
+
+    #[test]
+    fn test_program_allowlist() {
+        let message = Message::parse(transfer([1; 32], [2; 32])).unwrap();
+        let system = Solana::new(vec![Box::new(
+            ProgramAllowlist::new(&["11111111111111111111111111111111".into()]).unwrap(),
+        )]);
+        assert!(system.check(&Uuid::nil(), &message).is_ok());
+
+        let other = Solana::new(vec![Box::new(
+            ProgramAllowlist::new(&[bs58::encode([3; 32]).into_string()]).unwrap(),
+        )]);
+        assert!(matches!(
+            other.check(&Uuid::nil(), &message),
+            Err(Error::PolicyViolation(_))
+        ));
+    }
+}
@@ -1,69 +1,1704 @@
-use anyhow::anyhow;
+use base64::prelude::{BASE64_URL_SAFE_NO_PAD, Engine};
+use bitcoin::{Psbt, secp256k1};
 use ed25519_dalek::{SECRET_KEY_LENGTH, Signature, Signer, SigningKey, VerifyingKey};
 use heapless::index_map::FnvIndexMap;
 use hkdf::Hkdf;
-use sha2::Sha256;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256, Sha512};
+use signingcommon::{
+    APPROVAL_STATEMENT_PREFIX, AdminUser, ApprovalPolicy, BitcoinKeys, CONTEXT_ENVELOPE_PREFIX,
+    CoseSign1, IdentityRotation, JwsHeader, KeyInfo, NostrEvent, ROTATION_STATEMENT_PREFIX,
+    RegisterRequest, Revocation, RevocationList, RevocationStatement, RotationCertificate,
+    SSHSIG_HASH_ALGORITHM, SshSignature, UnsignedNostrEvent, UserStatus, context_envelope,
+    jws_signing_input, minisign_global_data, request_key, request_mac, unix_time,
+};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Mutex, RwLock};
+use subtle::ConstantTimeEq;
 use uuid::Uuid;
+use zeroize::{Zeroize, Zeroizing};
 
-const MAX_KEYS: usize = 1_024;
+use crate::error::Error;
+use crate::replay::{NONCE_LEN, UserNonces};
+use crate::revocation::{Revocations, Revoked};
+use crate::secret::SERVER_SECRET_LEN;
+use crate::{cosmos, did, nostr, psbt, solana};
+
+/// Number of independently locked shards the key storage is split into.
+pub const SHARDS: usize = 16;
+/// Capacity of each shard. `heapless` requires this to be a power of two.
+pub const MAX_KEYS_PER_SHARD: usize = 64;
+pub const MAX_KEYS: usize = SHARDS * MAX_KEYS_PER_SHARD;
+
+/// How many fresh UUIDs `register_user` tries before concluding the server is full. UUIDs are
+/// spread evenly over the shards, so this only matters when most shards are at capacity.
+const REGISTER_ATTEMPTS: usize = 4 * SHARDS;
+
+/// Maximum number of contexts a key can be restricted to
+pub const MAX_CONTEXTS: usize = 4;
+/// Maximum length of a signing context, in bytes
+pub const MAX_CONTEXT_LEN: usize = 64;
+
+type Context = heapless::String<MAX_CONTEXT_LEN>;
+
+/// Maximum number of approvers in an approver set
+pub const MAX_APPROVERS: usize = 8;
 
 // The master key is used to salt user key derivation. This should be carefully guarded.
-const MASTER_KEY: &[u8] = b"s!kr!ts!kr!ts!kr!ts!kr!ts!kr!ts!kr!ts!kr!ts!kr!t";
+const MASTER_KEY: &[u8; 48] = b"s!kr!ts!kr!ts!kr!ts!kr!ts!kr!ts!kr!ts!kr!ts!kr!t";
+
+/// How long, in seconds, a retired key remains listed for verification after a rotation
+pub const ROTATION_GRACE_SECS: u64 = 24 * 60 * 60;
+
+// TODO: Should probably instantiate this with SIP rather than FNV. `heapless` does not provide
+// a builtin alias something like this should work:
+// 	`pub type FnvIndexMap<K, V, const N: usize> = IndexMap<K, V, BuildHasherDefault<SipHasher>, N>;`
+type Shard = FnvIndexMap<Uuid, User, MAX_KEYS_PER_SHARD>;
+
+/// Outcome of a registration
+#[derive(Debug, Clone, Copy)]
+pub struct Registration {
+    pub user_id: Uuid,
+    pub verifying_key: VerifyingKey,
+    /// `false` if a deterministic registration found the user already registered
+    pub created: bool,
+    /// Unix timestamp after which the user is forgotten, if any
+    pub expires_at: Option<u64>,
+    pub status: UserStatus,
+}
+
+/// Outcome of a key rotation
+#[derive(Debug, Clone)]
+pub struct Rotation {
+    pub certificate: RotationCertificate,
+    /// Unix timestamp until which the previous key remains listed for verification
+    pub previous_valid_until: u64,
+}
+
+/// The approvers whose approvals every signature by a user requires
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Approval {
+    pub threshold: usize,
+    pub approvers: heapless::Vec<Uuid, MAX_APPROVERS>,
+}
+
+/// A key that was rotated away from. It no longer signs, but is still listed for verification.
+#[derive(Debug, Clone, Copy)]
+struct RetiredKey {
+    generation: u32,
+    verifying_key: VerifyingKey,
+    valid_until: u64,
+}
+
+/// A registered user
+#[derive(Debug)]
+struct User {
+    signing_key: SigningKey,
+    // Key authenticating the user's request envelopes, see [`signingcommon::request_key`].
+    request_key: Zeroizing<[u8; 32]>,
+    // Nonces of the user's recent request envelopes.
+    nonces: Mutex<UserNonces>,
+    // Generation of `signing_key`, incremented on every rotation.
+    generation: u32,
+    // The key before the last rotation, if any.
+    retired: Option<RetiredKey>,
+    // Contexts the key may sign under. Empty means unrestricted.
+    allowed_contexts: heapless::Vec<Context, MAX_CONTEXTS>,
+    created_at: u64,
+    expires_at: Option<u64>,
+    status: UserStatus,
+    // Approvers required for every signature, if any.
+    approval: Option<Approval>,
+}
+
+impl User {
+    // Check that the user may sign.
+    fn check_active(&self) -> Result<(), Error> {
+        match self.status {
+            UserStatus::Active => Ok(()),
+            UserStatus::Frozen => Err(Error::Frozen),
+            UserStatus::PendingApproval => Err(Error::PendingApproval),
+        }
+    }
+
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+
+    // Check that the user's key may sign under `context` (`None` meaning no context).
+    fn check_context(&self, context: Option<&str>) -> Result<(), Error> {
+        match context {
+            _ if self.allowed_contexts.is_empty() => Ok(()),
+            Some(context) if self.allowed_contexts.iter().any(|c| c == context) => Ok(()),
+            Some(context) => Err(Error::ContextNotAllowed(format!(
+                "under context {context:?}"
+            ))),
+            None => Err(Error::ContextNotAllowed("without a context".into())),
+        }
+    }
+}
+
+// Check a context is well formed and convert it to its fixed size representation.
+fn parse_context(context: &str) -> Result<Context, Error> {
+    if context.is_empty() {
+        return Err(Error::InvalidContext("context is empty".into()));
+    }
+    // Contexts end up in NUL separated statements and in logs.
+    if context.chars().any(char::is_control) {
+        return Err(Error::InvalidContext("contains control characters".into()));
+    }
+    Context::try_from(context)
+        .map_err(|_| Error::InvalidContext(format!("longer than {MAX_CONTEXT_LEN} bytes")))
+}
+
+// Check a message cannot pass for a context envelope, a rotation statement or an approval.
+fn check_reserved(message: &[u8]) -> Result<(), Error> {
+    if message.starts_with(CONTEXT_ENVELOPE_PREFIX)
+        || message.starts_with(ROTATION_STATEMENT_PREFIX)
+        || message.starts_with(APPROVAL_STATEMENT_PREFIX)
+    {
+        return Err(Error::ReservedPrefix);
+    }
+    Ok(())
+}
 
 /// Application state managing keys
+///
+/// Keys are spread over [`SHARDS`] fixed-capacity maps, each behind its own lock, so that
+/// registering or forgetting a user only blocks signing for users in the same shard. All storage
+/// is allocated up front, one heap allocation per shard.
 #[derive(Debug)]
 pub struct AppState {
-    // TODO: Should probably instantiate this with SIP rather than FNV. `heapless` does not provide
-    // a builtin alias something like this should work:
-    // 	`pub type FnvIndexMap<K, V, const N: usize> = IndexMap<K, V, BuildHasherDefault<SipHasher>, N>;`
-    keys: FnvIndexMap<Uuid, SigningKey, MAX_KEYS>,
+    shards: [Box<RwLock<Shard>>; SHARDS],
+    // Copy of the master key, so that it can be wiped on shutdown. The compiled-in constant is of
+    // course still part of the binary.
+    master_key: RwLock<[u8; MASTER_KEY.len()]>,
+    // The server secret, see [`crate::secret`]. The server identity key and deterministic user
+    // IDs are derived from it.
+    server_secret: RwLock<[u8; SERVER_SECRET_LEN]>,
+    revocations: Revocations,
+    // Generation of the server identity key, incremented when operators rotate it.
+    identity_generation: AtomicU32,
+    // When set, users cannot be registered, renewed, rotated or forgotten.
+    read_only: AtomicBool,
+    // When set, new users are pending until operators approve them.
+    approval_required: AtomicBool,
 }
 
 impl AppState {
+    /// State with a random server secret, so with an identity key that does not survive restarts
     pub fn new() -> Self {
+        let mut server_secret = [0; SERVER_SECRET_LEN];
+        rand::RngCore::fill_bytes(&mut rand::rngs::OsRng, &mut server_secret);
+        let state = Self::with_server_secret(&server_secret, 0);
+        server_secret.zeroize();
+        state
+    }
+
+    /// State with the given server secret, whose server identity key is of the given generation
+    /// (see [`crate::secret::IdentityGeneration`]).
+    pub fn with_server_secret(
+        server_secret: &[u8; SERVER_SECRET_LEN],
+        identity_generation: u32,
+    ) -> Self {
         AppState {
-            keys: FnvIndexMap::new(),
+            shards: std::array::from_fn(|_| Box::new(RwLock::new(FnvIndexMap::new()))),
+            master_key: RwLock::new(*MASTER_KEY),
+            server_secret: RwLock::new(*server_secret),
+            revocations: Revocations::new(),
+            identity_generation: AtomicU32::new(identity_generation),
+            read_only: AtomicBool::new(false),
+            approval_required: AtomicBool::new(false),
         }
     }
 
-    /// Register a new user with a deterministically derived signing key
-    pub fn register_user(&mut self, seed: &[u8]) -> anyhow::Result<(Uuid, VerifyingKey)> {
-        // Derive a signing key from seed + master key using HKDF.
-        let hkdf = Hkdf::<Sha256>::new(Some(MASTER_KEY), seed);
+    // The shard a user lives in. UUIDs are random or hash derived, so their low bits are uniformly
+    // distributed.
+    fn shard(&self, user_id: &Uuid) -> &RwLock<Shard> {
+        &self.shards[user_id.as_u128() as usize % SHARDS]
+    }
+
+    // Derive a signing key of the given generation from seed + master key using HKDF. Generation 0
+    // uses the original, unversioned label so that keys registered before rotation existed are
+    // unchanged.
+    fn derive_signing_key(&self, seed: &[u8], generation: u32) -> SigningKey {
+        let hkdf = Hkdf::<Sha256>::new(Some(&*self.master_key.read().unwrap()), seed);
+        let info = match generation {
+            0 => "signing_key".to_string(),
+            generation => format!("signing_key/v{generation}"),
+        };
         let mut signing_key_bytes = [0u8; SECRET_KEY_LENGTH];
-        hkdf.expand(b"signing_key", &mut signing_key_bytes)
+        hkdf.expand(info.as_bytes(), &mut signing_key_bytes)
             .expect("okm has valid and hardcoded length");
         let signing_key = SigningKey::from_bytes(&signing_key_bytes);
+        signing_key_bytes.zeroize();
+        signing_key
+    }
+
+    // The secp256k1 key of a user, derived from their signing key and the master key, so that it
+    // changes when the signing key is rotated.
+    fn secp256k1_key(&self, signing_key: &SigningKey) -> secp256k1::SecretKey {
+        let hkdf = Hkdf::<Sha256>::new(
+            Some(&*self.master_key.read().unwrap()),
+            signing_key.as_bytes(),
+        );
+        let mut secret_key_bytes = [0u8; 32];
+        hkdf.expand(b"secp256k1_key", &mut secret_key_bytes)
+            .expect("okm has valid and hardcoded length");
+        // Fails only if the bytes are zero or not below the curve order, which is negligibly
+        // unlikely.
+        let secret_key = secp256k1::SecretKey::from_slice(&secret_key_bytes)
+            .expect("derived secp256k1 key is valid");
+        secret_key_bytes.zeroize();
+        secret_key
+    }
+
+    // The UUID of a deterministically registered user: the HMAC-SHA256 of its verifying key under
+    // the server secret, truncated into a UUID v8, so that it cannot be computed from the key.
+    fn deterministic_user_id(&self, verifying_key: &VerifyingKey) -> Uuid {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&*self.server_secret.read().unwrap())
+            .expect("HMAC takes keys of any length");
+        mac.update(verifying_key.as_bytes());
+        let digest = mac.finalize().into_bytes();
+        Uuid::new_v8(digest[..16].try_into().expect("HMAC-SHA256 is 32 bytes"))
+    }
+
+    // The server's own key, used to sign responses and revocations. It is derived from the server
+    // secret so that it survives restarts, and is not kept in memory between uses.
+    fn identity_key(&self) -> SigningKey {
+        self.derive_identity_key(self.identity_generation.load(Ordering::SeqCst))
+    }
+
+    fn derive_identity_key(&self, generation: u32) -> SigningKey {
+        let hkdf = Hkdf::<Sha256>::new(None, &*self.server_secret.read().unwrap());
+        let info = match generation {
+            0 => "server_identity_key".to_string(),
+            generation => format!("server_identity_key/v{generation}"),
+        };
+        let mut signing_key_bytes = [0u8; SECRET_KEY_LENGTH];
+        hkdf.expand(info.as_bytes(), &mut signing_key_bytes)
+            .expect("okm has valid and hardcoded length");
+        let signing_key = SigningKey::from_bytes(&signing_key_bytes);
+        signing_key_bytes.zeroize();
+        signing_key
+    }
+
+    /// The server's verifying key, which signs responses and revocations
+    pub fn identity(&self) -> VerifyingKey {
+        self.identity_key().verifying_key()
+    }
+
+    /// Sign `message` with the server's identity key
+    pub fn sign_as_server(&self, message: &[u8]) -> Signature {
+        self.identity_key().sign(message)
+    }
+
+    /// Switch to the next generation of the server identity key, derived from the server secret
+    /// like the first. The previous key signs a statement binding the new one.
+    pub fn rotate_identity(&self) -> IdentityRotation {
+        let previous = self.identity_generation.fetch_add(1, Ordering::SeqCst);
+        let previous_key = self.derive_identity_key(previous);
+        let mut rotation = IdentityRotation {
+            previous_generation: previous,
+            previous_verifying_key: hex::encode(previous_key.verifying_key().as_bytes()),
+            generation: previous + 1,
+            verifying_key: hex::encode(
+                self.derive_identity_key(previous + 1)
+                    .verifying_key()
+                    .as_bytes(),
+            ),
+            signature: String::new(),
+        };
+        rotation.signature = hex::encode(previous_key.sign(&rotation.statement()).to_bytes());
+        rotation
+    }
+
+    /// Whether the state is read-only
+    pub fn is_read_only(&self) -> bool {
+        self.read_only.load(Ordering::SeqCst)
+    }
+
+    /// Make the state read-only, or writable again. Signing is unaffected.
+    pub fn set_read_only(&self, read_only: bool) {
+        self.read_only.store(read_only, Ordering::SeqCst);
+    }
+
+    /// Require new users to be approved by an operator before they can sign
+    pub fn set_approval_required(&self, approval_required: bool) {
+        self.approval_required
+            .store(approval_required, Ordering::SeqCst);
+    }
+
+    fn check_writable(&self) -> Result<(), Error> {
+        if self.is_read_only() {
+            return Err(Error::ReadOnly);
+        }
+        Ok(())
+    }
+
+    /// The verifying key a registration with `seed` results in
+    pub fn derive_verifying_key(&self, seed: &[u8]) -> VerifyingKey {
+        self.derive_signing_key(seed, 0).verifying_key()
+    }
+
+    // Check that `seed` is the seed `user` was registered with.
+    fn check_seed(&self, user: &User, seed: &[u8]) -> Result<(), Error> {
+        let verifying_key = self
+            .derive_signing_key(seed, user.generation)
+            .verifying_key();
+        if user.signing_key.verifying_key() != verifying_key {
+            return Err(Error::WrongSeed);
+        }
+        Ok(())
+    }
+
+    // Check an approver set is well formed and convert it to its fixed size representation.
+    // Approvers must be registered and may not require approvals themselves, as they sign their
+    // approvals with their own keys.
+    fn parse_approval(&self, policy: &ApprovalPolicy) -> Result<Approval, Error> {
+        if policy.approvers.len() > MAX_APPROVERS {
+            return Err(Error::InvalidApproval(format!(
+                "at most {MAX_APPROVERS} approvers are allowed"
+            )));
+        }
+        if policy.threshold == 0 || policy.threshold > policy.approvers.len() {
+            return Err(Error::InvalidApproval(
+                "threshold must be between 1 and the number of approvers".into(),
+            ));
+        }
+        let mut approvers = heapless::Vec::new();
+        for approver in &policy.approvers {
+            let approver = Uuid::parse_str(approver)
+                .map_err(|_| Error::InvalidApproval(format!("invalid approver {approver}")))?;
+            if approvers.contains(&approver) {
+                return Err(Error::InvalidApproval(format!(
+                    "approver {approver} is listed twice"
+                )));
+            }
+            let shard = self.shard(&approver).read().unwrap();
+            match shard.get(&approver) {
+                Some(user) if !user.is_expired(unix_time()) && user.approval.is_none() => {}
+                Some(_) | None => {
+                    return Err(Error::InvalidApproval(format!(
+                        "approver {approver} is not a registered user without approvers"
+                    )));
+                }
+            }
+            approvers.push(approver).expect("length was checked above");
+        }
+        Ok(Approval {
+            threshold: policy.threshold,
+            approvers,
+        })
+    }
+
+    /// Register a new user with a deterministically derived signing key, optionally restricted to
+    /// signing under the given contexts.
+    ///
+    /// With `deterministic_id` set the UUID is derived from the verifying key and the server
+    /// secret, and registering an already registered seed again returns the existing user.
+    pub fn register_user(&self, req: &RegisterRequest) -> Result<Registration, Error> {
+        self.check_writable()?;
+        if req.allowed_contexts.len() > MAX_CONTEXTS {
+            return Err(Error::InvalidContext(format!(
+                "at most {MAX_CONTEXTS} contexts can be allowed"
+            )));
+        }
+        let allowed_contexts = req
+            .allowed_contexts
+            .iter()
+            .map(|c| parse_context(c))
+            .collect::<Result<_, _>>()?;
+        let approval = req
+            .approval
+            .as_ref()
+            .map(|policy| self.parse_approval(policy))
+            .transpose()?;
+
+        let now = unix_time();
+        let expires_at = match req.ttl_secs {
+            Some(0) => return Err(Error::InvalidTtl),
+            Some(ttl) => Some(now.saturating_add(ttl)),
+            None => None,
+        };
+
+        let signing_key = self.derive_signing_key(&req.seed, 0);
+        let verifying_key = signing_key.verifying_key();
+        if self.revocations.is_revoked(&verifying_key) {
+            return Err(Error::Revoked);
+        }
+        let user = User {
+            signing_key,
+            request_key: Zeroizing::new(request_key(&req.seed)),
+            nonces: Mutex::default(),
+            generation: 0,
+            retired: None,
+            allowed_contexts,
+            created_at: now,
+            expires_at,
+            status: if self.approval_required.load(Ordering::SeqCst) {
+                UserStatus::PendingApproval
+            } else {
+                UserStatus::Active
+            },
+            approval,
+        };
+        let status = user.status;
+
+        if req.deterministic_id {
+            let user_id = self.deterministic_user_id(&verifying_key);
+            let mut shard = self.shard(&user_id).write().unwrap();
+            match shard.get(&user_id) {
+                Some(existing) if existing.is_expired(now) => {
+                    shard.remove(&user_id);
+                }
+                Some(existing)
+                    if existing.allowed_contexts != user.allowed_contexts
+                        || existing.approval != user.approval =>
+                {
+                    return Err(Error::AlreadyRegistered);
+                }
+                Some(existing) => {
+                    return Ok(Registration {
+                        user_id,
+                        // The user may have rotated since registering.
+                        verifying_key: existing.signing_key.verifying_key(),
+                        created: false,
+                        expires_at: existing.expires_at,
+                        status: existing.status,
+                    });
+                }
+                None => {}
+            }
+            shard.insert(user_id, user).map_err(|_| Error::AtCapacity)?;
+            return Ok(Registration {
+                user_id,
+                verifying_key,
+                created: true,
+                expires_at,
+                status,
+            });
+        }
+
+        // If the shard for a UUID is full, pick another UUID.
+        for _ in 0..REGISTER_ATTEMPTS {
+            let user_id = Uuid::new_v4();
+            let mut shard = self.shard(&user_id).write().unwrap();
+            if !shard.is_full() {
+                shard.insert(user_id, user).map_err(|_| Error::AtCapacity)?;
+                return Ok(Registration {
+                    user_id,
+                    verifying_key,
+                    created: true,
+                    expires_at,
+                    status,
+                });
+            }
+        }
+        Err(Error::AtCapacity)
+    }
+
+    // Get a copy of a user's signing key, provided it may sign under `context`. Unless `approved`,
+    // users with approvers may not sign.
+    fn signing_key(
+        &self,
+        user_id: &str,
+        context: Option<&str>,
+        approved: bool,
+    ) -> Result<SigningKey, Error> {
+        let user_id = Uuid::parse_str(user_id)?;
+        let shard = self.shard(&user_id).read().unwrap();
+        let user = shard
+            .get(&user_id)
+            .filter(|user| !user.is_expired(unix_time()))
+            .ok_or(Error::NoSuchUser)?;
+        user.check_active()?;
+        user.check_context(context)?;
+        if user.approval.is_some() && !approved {
+            return Err(Error::ApprovalRequired);
+        }
+        Ok(user.signing_key.clone())
+    }
+
+    /// The approvers a signature by a user under `context` requires, if any. Fails if the user
+    /// could not sign at all.
+    pub fn approval(
+        &self,
+        user_id: &str,
+        context: Option<&str>,
+    ) -> Result<Option<Approval>, Error> {
+        if let Some(context) = context {
+            parse_context(context)?;
+        }
+        let user_id = Uuid::parse_str(user_id)?;
+        let shard = self.shard(&user_id).read().unwrap();
+        let user = shard
+            .get(&user_id)
+            .filter(|user| !user.is_expired(unix_time()))
+            .ok_or(Error::NoSuchUser)?;
+        user.check_active()?;
+        user.check_context(context)?;
+        Ok(user.approval.clone())
+    }
+
+    /// Sign the `statement` of an approval with an approver's key. Only the owner of the seed may
+    /// do this.
+    pub fn sign_approval(
+        &self,
+        approver: &Uuid,
+        seed: &[u8],
+        statement: &[u8],
+    ) -> Result<Signature, Error> {
+        let shard = self.shard(approver).read().unwrap();
+        let user = shard
+            .get(approver)
+            .filter(|user| !user.is_expired(unix_time()))
+            .ok_or(Error::NoSuchUser)?;
+        self.check_seed(user, seed)?;
+        user.check_active()?;
+        Ok(user.signing_key.sign(statement))
+    }
+
+    /// Sign a message for a user, wrapped in a domain separation envelope if a context is given
+    pub fn sign_message(
+        &self,
+        user_id: &str,
+        message: &str,
+        context: Option<&str>,
+    ) -> Result<Signature, Error> {
+        self.sign(user_id, message, context, false)
+    }
+
+    /// Sign a Solana transaction message for a user, whose key must be one of its required
+    /// signers. Returns the signature and the key that made it.
+    pub fn sign_solana(
+        &self,
+        user_id: &str,
+        message: &solana::Message,
+    ) -> Result<(Signature, VerifyingKey), Error> {
+        let signing_key = self.signing_key(user_id, None, false)?;
         let verifying_key = signing_key.verifying_key();
-        let user_id = Uuid::new_v4();
+        if !message
+            .required_signers()
+            .contains(verifying_key.as_bytes())
+        {
+            return Err(Error::NotASigner);
+        }
+        check_reserved(message.bytes())?;
+        Ok((signing_key.sign(message.bytes()), verifying_key))
+    }
 
-        self.keys
-            .insert(user_id.clone(), signing_key)
-            .map_err(|_| anyhow!("Server is at capacity. Sorry."))?;
-        Ok((user_id, verifying_key))
+    /// Sign the inputs of a PSBT that spend a user's secp256k1 key, see [`psbt::sign`]. Returns the
+    /// indexes of the signed inputs and the user's keys.
+    pub fn sign_psbt(
+        &self,
+        user_id: &str,
+        psbt: &mut Psbt,
+    ) -> Result<(Vec<usize>, BitcoinKeys), Error> {
+        let secret_key = self.secp256k1_key(&self.signing_key(user_id, None, false)?);
+        let signed = psbt::sign(psbt, &secret_key)?;
+        if signed.is_empty() {
+            return Err(Error::NotASigner);
+        }
+        let public_key = secret_key.public_key(&secp256k1::Secp256k1::signing_only());
+        Ok((signed, psbt::keys(&public_key)))
     }
 
-    // Get a user by UUID
-    fn user(&self, user_id: &str) -> anyhow::Result<SigningKey> {
+    /// The secp256k1 public key of a user, which signs for Bitcoin and Cosmos
+    pub fn secp256k1_public_key(&self, user_id: &str) -> Result<secp256k1::PublicKey, Error> {
         let user_id = Uuid::parse_str(user_id)?;
-        self.keys
+        let signing_key = self
+            .shard(&user_id)
+            .read()
+            .unwrap()
             .get(&user_id)
-            .cloned()
-            .ok_or_else(|| anyhow::anyhow!("No such user"))
+            .filter(|user| !user.is_expired(unix_time()))
+            .map(|user| user.signing_key.clone())
+            .ok_or(Error::NoSuchUser)?;
+        let secret_key = self.secp256k1_key(&signing_key);
+        Ok(secret_key.public_key(&secp256k1::Secp256k1::signing_only()))
+    }
+
+    /// Sign a Cosmos SDK sign doc for a user. Returns the signature and the key that made it.
+    pub fn sign_cosmos(
+        &self,
+        user_id: &str,
+        sign_doc: &cosmos::SignDoc,
+    ) -> Result<(secp256k1::ecdsa::Signature, secp256k1::PublicKey), Error> {
+        let secret_key = self.secp256k1_key(&self.signing_key(user_id, None, false)?);
+        let secp = secp256k1::Secp256k1::signing_only();
+        let digest = Sha256::digest(sign_doc.bytes());
+        let signature =
+            secp.sign_ecdsa(&secp256k1::Message::from_digest(digest.into()), &secret_key);
+        Ok((signature, secret_key.public_key(&secp)))
+    }
+
+    /// Sign a Nostr event for a user with their secp256k1 key, see [`nostr::sign`]
+    pub fn sign_nostr(
+        &self,
+        user_id: &str,
+        event: UnsignedNostrEvent,
+    ) -> Result<NostrEvent, Error> {
+        let secret_key = self.secp256k1_key(&self.signing_key(user_id, None, false)?);
+        nostr::sign(event, &secret_key)
+    }
+
+    /// Make an SSH signature for a user over the SHA-512 `hash` of a message. The namespace must be
+    /// one of the user's contexts if they are restricted to some.
+    pub fn sign_ssh(
+        &self,
+        user_id: &str,
+        namespace: &str,
+        hash: &[u8; 64],
+    ) -> Result<SshSignature, Error> {
+        parse_context(namespace)?;
+        let signing_key = self.signing_key(user_id, Some(namespace), false)?;
+        let data = SshSignature::signed_data(namespace, SSHSIG_HASH_ALGORITHM, hash);
+        Ok(SshSignature {
+            verifying_key: signing_key.verifying_key().to_bytes(),
+            namespace: namespace.to_string(),
+            hash_algorithm: SSHSIG_HASH_ALGORITHM.to_string(),
+            signature: self.sign_bytes(&signing_key, &data)?.to_bytes(),
+        })
+    }
+
+    /// Sign `payload` for a user as a COSE_Sign1, or a CWT if `cwt`. The context, if any, is the
+    /// external AAD of the signature. Returns the signature and the signed structure.
+    pub fn sign_cose(
+        &self,
+        user_id: &str,
+        payload: Vec<u8>,
+        cwt: bool,
+        context: Option<&str>,
+    ) -> Result<(Signature, CoseSign1), Error> {
+        if let Some(context) = context {
+            parse_context(context)?;
+        }
+        let signing_key = self.signing_key(user_id, context, false)?;
+        let mut cose = CoseSign1::new(signing_key.verifying_key().as_bytes(), payload, cwt);
+        let external_aad = context.unwrap_or_default().as_bytes();
+        let signature = self.sign_bytes(&signing_key, &cose.to_be_signed(external_aad))?;
+        cose.set_signature(&signature.to_bytes());
+        Ok((signature, cose))
+    }
+
+    /// Make a minisign signature for a user over the BLAKE2b-512 `hash` of a file. Returns the
+    /// signature, the global signature of it and `trusted_comment`, and the key that made them.
+    pub fn sign_minisign(
+        &self,
+        user_id: &str,
+        hash: &[u8; 64],
+        trusted_comment: &str,
+    ) -> Result<(Signature, Signature, VerifyingKey), Error> {
+        let signing_key = self.signing_key(user_id, None, false)?;
+        let signature = self.sign_bytes(&signing_key, hash)?;
+        let global_data = minisign_global_data(&signature.to_bytes(), trusted_comment);
+        let global_signature = self.sign_bytes(&signing_key, &global_data)?;
+        Ok((signature, global_signature, signing_key.verifying_key()))
+    }
+
+    /// Make a signify signature for a user over a file. Returns the signature and the key that
+    /// made it.
+    pub fn sign_signify(
+        &self,
+        user_id: &str,
+        file: &[u8],
+    ) -> Result<(Signature, VerifyingKey), Error> {
+        let signing_key = self.signing_key(user_id, None, false)?;
+        Ok((
+            self.sign_bytes(&signing_key, file)?,
+            signing_key.verifying_key(),
+        ))
+    }
+
+    /// Issue a JWT with `claims` for a user, signed with EdDSA. Returns the token in the compact
+    /// serialization and the `kid` of its header.
+    pub fn sign_jwt(
+        &self,
+        user_id: &str,
+        claims: &serde_json::Map<String, serde_json::Value>,
+    ) -> Result<(String, String), Error> {
+        let signing_key = self.signing_key(user_id, None, false)?;
+        let header = JwsHeader::new(signing_key.verifying_key().as_bytes());
+        let input = jws_signing_input(&header, claims);
+        let signature = self.sign_bytes(&signing_key, input.as_bytes())?;
+        let token = format!(
+            "{input}.{}",
+            BASE64_URL_SAFE_NO_PAD.encode(signature.to_bytes())
+        );
+        Ok((token, header.kid))
     }
 
-    /// Sign a message for a user
-    pub fn sign_message(&self, user_id: &str, message: &str) -> anyhow::Result<Signature> {
-        let signing_key = self.user(user_id)?;
+    /// Add a Data Integrity proof (eddsa-jcs-2022) by a user's key to a credential, see
+    /// [`did::proof_options`]
+    pub fn sign_credential(
+        &self,
+        user_id: &str,
+        mut credential: serde_json::Map<String, serde_json::Value>,
+    ) -> Result<serde_json::Map<String, serde_json::Value>, Error> {
+        let signing_key = self.signing_key(user_id, None, false)?;
+        let mut proof = did::proof_options(
+            &credential,
+            signing_key.verifying_key().as_bytes(),
+            unix_time(),
+        )?;
+        let data = did::hash_data(&credential, &proof);
+        let signature = self.sign_bytes(&signing_key, &data)?;
+        proof.proof_value = Some(did::proof_value(&signature.to_bytes()));
+        credential.insert(
+            "proof".to_string(),
+            serde_json::to_value(proof).expect("proofs serialize"),
+        );
+        Ok(credential)
+    }
 
-        let signature = signing_key.sign(message.as_bytes());
+    /// Sign a message for a user whose approvers approved it
+    pub fn sign_approved(
+        &self,
+        user_id: &str,
+        message: &str,
+        context: Option<&str>,
+    ) -> Result<Signature, Error> {
+        self.sign(user_id, message, context, true)
+    }
+
+    fn sign(
+        &self,
+        user_id: &str,
+        message: &str,
+        context: Option<&str>,
+        approved: bool,
+    ) -> Result<Signature, Error> {
+        if let Some(context) = context {
+            parse_context(context)?;
+        }
+        let signing_key = self.signing_key(user_id, context, approved)?;
+
+        match context {
+            Some(context) => Ok(signing_key.sign(&context_envelope(context, message.as_bytes()))),
+            None => self.sign_bytes(&signing_key, message.as_bytes()),
+        }
+    }
+
+    // Sign `message` as is, unless it could pass for a context envelope, a rotation statement or
+    // an approval, or is a Solana transaction the key signs: those only pass through
+    // `sign_solana`, after the Solana policies.
+    fn sign_bytes(&self, signing_key: &SigningKey, message: &[u8]) -> Result<Signature, Error> {
+        check_reserved(message)?;
+        if solana::Message::is_signed_by(message, signing_key.verifying_key().as_bytes()) {
+            return Err(Error::SolanaMessage);
+        }
+        Ok(signing_key.sign(message))
+    }
+
+    /// Sign a SHA-512 prehashed message for a user using Ed25519ph
+    pub fn sign_prehashed(
+        &self,
+        user_id: &str,
+        prehashed: Sha512,
+        context: &str,
+    ) -> Result<Signature, Error> {
+        parse_context(context)?;
+        let signing_key = self.signing_key(user_id, Some(context), false)?;
+
+        let signature = signing_key.sign_prehashed(prehashed, Some(context.as_bytes()))?;
 
         Ok(signature)
     }
 
     /// Delete a user (forget)
-    pub fn forget(&mut self, user_id: &str) {
+    pub fn forget(&self, user_id: &str) -> Result<(), Error> {
+        self.check_writable()?;
         let user_id = Uuid::parse_str(user_id).unwrap_or_else(|_| Uuid::new_v4());
-        self.keys.remove(&user_id);
+        self.shard(&user_id).write().unwrap().remove(&user_id);
+        Ok(())
+    }
+
+    /// Delete a user on behalf of an operator, even in read-only mode
+    pub fn force_forget(&self, user_id: &str) -> Result<(), Error> {
+        let user_id = Uuid::parse_str(user_id)?;
+        self.shard(&user_id)
+            .write()
+            .unwrap()
+            .remove(&user_id)
+            .map(drop)
+            .ok_or(Error::NoSuchUser)
+    }
+
+    /// Move a user from status `from` to `to`, e.g. to freeze an active user or approve a pending
+    /// one. Users keep their key and slot whatever their status.
+    pub fn set_status(&self, user_id: &str, from: UserStatus, to: UserStatus) -> Result<(), Error> {
+        let user_id = Uuid::parse_str(user_id)?;
+        let mut shard = self.shard(&user_id).write().unwrap();
+        let user = shard.get_mut(&user_id).ok_or(Error::NoSuchUser)?;
+        if user.status != from {
+            return Err(Error::WrongStatus(user.status));
+        }
+        user.status = to;
+        Ok(())
+    }
+
+    /// All users, for operators. Contains no key material.
+    pub fn users(&self) -> Vec<AdminUser> {
+        let mut users = Vec::with_capacity(MAX_KEYS);
+        for shard in &self.shards {
+            users.extend(
+                shard
+                    .read()
+                    .unwrap()
+                    .iter()
+                    .map(|(user_id, user)| AdminUser {
+                        user_id: user_id.to_string(),
+                        key_types: vec!["ed25519".to_string(), "secp256k1".to_string()],
+                        created_at: user.created_at,
+                        status: user.status,
+                    }),
+            );
+        }
+        users.sort_by_key(|user| user.created_at);
+        users
+    }
+
+    /// Number of users in each shard
+    pub fn shard_usage(&self) -> Vec<usize> {
+        self.shards
+            .iter()
+            .map(|s| s.read().unwrap().len())
+            .collect()
+    }
+
+    /// Number of revoked keys
+    pub fn revoked_count(&self) -> usize {
+        self.revocations.snapshot().len()
+    }
+
+    /// Check a user's request envelope: `mac` (hex encoded) must be the MAC of `message` under the
+    /// user's request key, see [`signingcommon::request_mac`], and `nonce` must not have been seen
+    /// from the user before. The nonce is then remembered until `expires_at`.
+    pub fn check_envelope(
+        &self,
+        user_id: &str,
+        message: &[u8],
+        mac: &str,
+        nonce: [u8; NONCE_LEN],
+        expires_at: u64,
+    ) -> Result<(), Error> {
+        let user_id = Uuid::parse_str(user_id)?;
+        let now = unix_time();
+        let shard = self.shard(&user_id).read().unwrap();
+        let user = shard
+            .get(&user_id)
+            .filter(|user| !user.is_expired(now))
+            .ok_or(Error::NoSuchUser)?;
+        let expected = request_mac(&user.request_key, message);
+        let mac = hex::decode(mac).unwrap_or_default();
+        // Authenticate first, so that only the user can use up their nonces.
+        if !bool::from(expected[..].ct_eq(&mac)) {
+            return Err(Error::UnauthenticatedEnvelope);
+        }
+        user.nonces.lock().unwrap().check(nonce, expires_at, now)
+    }
+
+    /// Extend a user's lifetime to `ttl_secs` from now. Only the owner of the seed may do this.
+    pub fn renew(&self, user_id: &str, seed: &[u8], ttl_secs: u64) -> Result<u64, Error> {
+        self.check_writable()?;
+        if ttl_secs == 0 {
+            return Err(Error::InvalidTtl);
+        }
+        let user_id = Uuid::parse_str(user_id)?;
+        let now = unix_time();
+
+        let mut shard = self.shard(&user_id).write().unwrap();
+        let user = shard
+            .get_mut(&user_id)
+            .filter(|user| !user.is_expired(now))
+            .ok_or(Error::NoSuchUser)?;
+        self.check_seed(user, seed)?;
+        let expires_at = now.saturating_add(ttl_secs);
+        user.expires_at = Some(expires_at);
+        Ok(expires_at)
+    }
+
+    /// Rotate a user's key to the next generation, keeping the UUID. Only the owner of the seed may
+    /// do this.
+    ///
+    /// From now on only the new key signs. The old key signs a [`RotationCertificate`] binding the
+    /// new verifying key, and stays listed for verification for [`ROTATION_GRACE_SECS`] or until
+    /// the next rotation, whichever comes first.
+    pub fn rotate(&self, user_id: &str, seed: &[u8]) -> Result<Rotation, Error> {
+        self.check_writable()?;
+        let uuid = Uuid::parse_str(user_id)?;
+        let now = unix_time();
+
+        let mut shard = self.shard(&uuid).write().unwrap();
+        let user = shard
+            .get_mut(&uuid)
+            .filter(|user| !user.is_expired(now))
+            .ok_or(Error::NoSuchUser)?;
+        self.check_seed(user, seed)?;
+        user.check_active()?;
+
+        let generation = user.generation + 1;
+        let signing_key = self.derive_signing_key(seed, generation);
+        let previous_verifying_key = user.signing_key.verifying_key();
+        let mut certificate = RotationCertificate {
+            user_id: uuid.to_string(),
+            previous_generation: user.generation,
+            previous_verifying_key: hex::encode(previous_verifying_key.as_bytes()),
+            generation,
+            verifying_key: hex::encode(signing_key.verifying_key().as_bytes()),
+            issued_at: now,
+            signature: String::new(),
+        };
+        let signature = user.signing_key.sign(&certificate.statement());
+        certificate.signature = hex::encode(signature.to_bytes());
+
+        let previous_valid_until = now.saturating_add(ROTATION_GRACE_SECS);
+        user.retired = Some(RetiredKey {
+            generation: user.generation,
+            verifying_key: previous_verifying_key,
+            valid_until: previous_valid_until,
+        });
+        // The old key is zeroized when dropped.
+        user.signing_key = signing_key;
+        user.generation = generation;
+
+        Ok(Rotation {
+            certificate,
+            previous_valid_until,
+        })
+    }
+
+    /// The verifying keys of a user: the current key, followed by the previous key if it is
+    /// still within its grace period.
+    pub fn verifying_keys(&self, user_id: &str) -> Result<Vec<KeyInfo>, Error> {
+        let user_id = Uuid::parse_str(user_id)?;
+        let now = unix_time();
+        let shard = self.shard(&user_id).read().unwrap();
+        let user = shard
+            .get(&user_id)
+            .filter(|user| !user.is_expired(now))
+            .ok_or(Error::NoSuchUser)?;
+
+        let current = KeyInfo {
+            generation: user.generation,
+            verifying_key: hex::encode(user.signing_key.verifying_key().as_bytes()),
+            valid_until: None,
+        };
+        let retired = user
+            .retired
+            .filter(|retired| retired.valid_until > now)
+            .map(|retired| KeyInfo {
+                generation: retired.generation,
+                verifying_key: hex::encode(retired.verifying_key.as_bytes()),
+                valid_until: Some(retired.valid_until),
+            });
+        Ok(std::iter::once(current).chain(retired).collect())
+    }
+
+    /// Revoke all keys of a user, because its seed was compromised. Only the owner of the seed may
+    /// do this.
+    ///
+    /// The user is forgotten, and the keys of all its generations, from the current one back to
+    /// the first (which registering the seed again would derive), are added to the revocation
+    /// list. Returns a statement of the revocation signed by the server.
+    pub fn revoke(&self, user_id: &str, seed: &[u8]) -> Result<RevocationStatement, Error> {
+        let uuid = Uuid::parse_str(user_id)?;
+        let now = unix_time();
+
+        let mut shard = self.shard(&uuid).write().unwrap();
+        let user = shard
+            .get(&uuid)
+            .filter(|user| !user.is_expired(now))
+            .ok_or(Error::NoSuchUser)?;
+        self.check_seed(user, seed)?;
+
+        let verifying_keys: Vec<_> = (0..=user.generation)
+            .rev()
+            .map(|generation| self.derive_signing_key(seed, generation).verifying_key())
+            .collect();
+        let revoked: Vec<_> = verifying_keys
+            .iter()
+            .map(|verifying_key| Revoked {
+                user_id: uuid,
+                verifying_key: verifying_key.to_bytes(),
+                revoked_at: now,
+            })
+            .collect();
+        self.revocations.add(&revoked)?;
+        // Dropping the user zeroizes its key.
+        shard.remove(&uuid);
+
+        let mut statement = RevocationStatement {
+            user_id: uuid.to_string(),
+            verifying_keys: verifying_keys
+                .iter()
+                .map(|verifying_key| hex::encode(verifying_key.as_bytes()))
+                .collect(),
+            revoked_at: now,
+            signature: String::new(),
+        };
+        statement.signature = hex::encode(self.sign_as_server(&statement.statement()).to_bytes());
+        Ok(statement)
+    }
+
+    /// The current list of revoked keys, signed by the server
+    pub fn revocation_list(&self) -> RevocationList {
+        let mut list = RevocationList {
+            issued_at: unix_time(),
+            revocations: self
+                .revocations
+                .snapshot()
+                .into_iter()
+                .map(|revoked| Revocation {
+                    user_id: revoked.user_id.to_string(),
+                    verifying_key: hex::encode(revoked.verifying_key),
+                    revoked_at: revoked.revoked_at,
+                })
+                .collect(),
+            signature: String::new(),
+        };
+        list.signature = hex::encode(self.sign_as_server(&list.statement()).to_bytes());
+        list
+    }
+
+    /// Forget all users that expired at or before `now`, returning how many there were
+    pub fn reap_expired(&self, now: u64) -> usize {
+        let mut reaped = 0;
+        for shard in &self.shards {
+            let mut shard = shard.write().unwrap();
+            let before = shard.len();
+            // Dropping the users zeroizes their keys.
+            shard.retain(|_, user| !user.is_expired(now));
+            reaped += before - shard.len();
+        }
+        reaped
+    }
+
+    /// The memory regions holding secret material, as `(address, length)` pairs. They are
+    /// allocated once and never move for the lifetime of the state.
+    pub fn secret_regions(&self) -> impl Iterator<Item = (*const u8, usize)> + '_ {
+        let shards = self.shards.iter().map(|shard| {
+            (
+                (&**shard as *const RwLock<Shard>).cast(),
+                size_of::<RwLock<Shard>>(),
+            )
+        });
+        let master_key = (
+            (&self.master_key as *const RwLock<[u8; MASTER_KEY.len()]>).cast(),
+            size_of_val(&self.master_key),
+        );
+        let server_secret = (
+            (&self.server_secret as *const RwLock<[u8; SERVER_SECRET_LEN]>).cast(),
+            size_of_val(&self.server_secret),
+        );
+        shards.chain([master_key, server_secret])
+    }
+
+    /// Number of registered users
+    pub fn user_count(&self) -> usize {
+        self.shards.iter().map(|s| s.read().unwrap().len()).sum()
+    }
+
+    /// Wipe all secret material held by the service: every `SigningKey` is zeroized when dropped
+    /// from its shard, and the master key and server secret are overwritten in place.
+    pub fn zeroize(&self) {
+        for shard in &self.shards {
+            shard.write().unwrap().clear();
+        }
+        self.master_key.write().unwrap().zeroize();
+        self.server_secret.write().unwrap().zeroize();
+    }
+}
+
+impl Default for AppState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn register(state: &AppState, seed: &[u8]) -> (Uuid, VerifyingKey) {
+        let registration = state
+            .register_user(&RegisterRequest {
+                seed: seed.to_vec(),
+                ..Default::default()
+            })
+            .unwrap();
+        (registration.user_id, registration.verifying_key)
+    }
+
+    #[test]
+    fn test_zeroize_clears_state() {
+        let state = AppState::new();
+        let (user_id, _) = register(&state, &[1, 2, 3, 4, 5]);
+        assert!(
+            state
+                .sign_message(&user_id.to_string(), "hello", None)
+                .is_ok()
+        );
+
+        state.zeroize();
+
+        assert_eq!(state.user_count(), 0);
+        assert!(state.master_key.read().unwrap().iter().all(|b| *b == 0));
+        assert!(state.server_secret.read().unwrap().iter().all(|b| *b == 0));
+        assert!(
+            state
+                .sign_message(&user_id.to_string(), "hello", None)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_register_until_full() {
+        let state = AppState::new();
+        let mut registered = 0;
+        while state
+            .register_user(&RegisterRequest {
+                seed: vec![registered as u8],
+                ..Default::default()
+            })
+            .is_ok()
+        {
+            registered += 1;
+            assert!(registered <= MAX_KEYS);
+        }
+        // Random UUIDs may fill up a shard early, but most of the capacity must be usable.
+        assert!(registered > MAX_KEYS * 3 / 4, "only {registered} users fit");
+        assert_eq!(state.user_count(), registered);
+    }
+
+    #[test]
+    fn test_sign_prehashed() {
+        use sha2::Digest;
+
+        let state = AppState::new();
+        let (user_id, verifying_key) = register(&state, b"seed");
+        let mut prehashed = Sha512::new();
+        prehashed.update(b"hello ");
+        prehashed.update(b"world");
+
+        let signature = state
+            .sign_prehashed(&user_id.to_string(), prehashed.clone(), "ctx")
+            .unwrap();
+
+        assert!(
+            verifying_key
+                .verify_prehashed(prehashed.clone(), Some(b"ctx"), &signature)
+                .is_ok()
+        );
+        assert!(
+            verifying_key
+                .verify_prehashed(prehashed, Some(b"other"), &signature)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_sign_with_context() {
+        let state = AppState::new();
+        let (user_id, verifying_key) = register(&state, b"seed");
+        let user_id = user_id.to_string();
+
+        let signature = state.sign_message(&user_id, "hello", Some("app")).unwrap();
+        assert!(
+            verifying_key
+                .verify_strict(&context_envelope("app", b"hello"), &signature)
+                .is_ok()
+        );
+        assert!(verifying_key.verify_strict(b"hello", &signature).is_err());
+
+        // A plain message must not be mistaken for an envelope.
+        let forged = String::from_utf8(context_envelope("app", b"hello")).unwrap();
+        assert!(matches!(
+            state.sign_message(&user_id, &forged, None),
+            Err(Error::ReservedPrefix)
+        ));
+        assert!(matches!(
+            state.sign_message(&user_id, "hello", Some(&"x".repeat(MAX_CONTEXT_LEN + 1))),
+            Err(Error::InvalidContext(_))
+        ));
+        for context in ["app\0evil", "app\nevil", "app\u{7f}"] {
+            assert!(matches!(
+                state.sign_message(&user_id, "hello", Some(context)),
+                Err(Error::InvalidContext(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_allowed_contexts() {
+        let state = AppState::new();
+        let allowed = ["app".to_string(), "other-app".to_string()];
+        let user_id = state
+            .register_user(&RegisterRequest {
+                seed: b"seed".to_vec(),
+                allowed_contexts: allowed.to_vec(),
+                ..Default::default()
+            })
+            .unwrap()
+            .user_id;
+        let user_id = user_id.to_string();
+
+        assert!(state.sign_message(&user_id, "hello", Some("app")).is_ok());
+        assert!(
+            state
+                .sign_message(&user_id, "hello", Some("other-app"))
+                .is_ok()
+        );
+        assert!(matches!(
+            state.sign_message(&user_id, "hello", Some("evil-app")),
+            Err(Error::ContextNotAllowed(_))
+        ));
+        assert!(matches!(
+            state.sign_message(&user_id, "hello", None),
+            Err(Error::ContextNotAllowed(_))
+        ));
+        assert!(matches!(
+            state.sign_prehashed(&user_id, Sha512::default(), "evil-app"),
+            Err(Error::ContextNotAllowed(_))
+        ));
+
+        let too_many = vec!["app".to_string(); MAX_CONTEXTS + 1];
+        assert!(matches!(
+            state.register_user(&RegisterRequest {
+                seed: b"seed".to_vec(),
+                allowed_contexts: too_many,
+                ..Default::default()
+            }),
+            Err(Error::InvalidContext(_))
+        ));
+    }
+
+    #[test]
+    fn test_deterministic_id() {
+        let state = AppState::new();
+        let req = RegisterRequest {
+            seed: b"seed".to_vec(),
+            deterministic_id: true,
+            ..Default::default()
+        };
+
+        let first = state.register_user(&req).unwrap();
+        assert!(first.created);
+        let second = state.register_user(&req).unwrap();
+        assert!(!second.created);
+        assert_eq!(first.user_id, second.user_id);
+        assert_eq!(state.user_count(), 1);
+
+        // Same seed, different settings
+        let conflicting = RegisterRequest {
+            allowed_contexts: vec!["app".into()],
+            ..req.clone()
+        };
+        assert!(matches!(
+            state.register_user(&conflicting),
+            Err(Error::AlreadyRegistered)
+        ));
+
+        // Random ids are unaffected
+        let (random_id, _) = register(&state, b"seed");
+        assert_ne!(random_id, first.user_id);
+
+        // Ids are keyed by the server secret: they survive restarts, but other servers, or anyone
+        // knowing only the verifying key, get other ids.
+        assert_eq!(first.user_id.get_version_num(), 8);
+        let server_secret = *state.server_secret.read().unwrap();
+        let restarted = AppState::with_server_secret(&server_secret, 0);
+        assert_eq!(
+            restarted.register_user(&req).unwrap().user_id,
+            first.user_id
+        );
+        let other = AppState::new();
+        assert_ne!(other.register_user(&req).unwrap().user_id, first.user_id);
+    }
+
+    #[test]
+    fn test_ttl_and_reaping() {
+        let state = AppState::new();
+        let req = RegisterRequest {
+            seed: b"seed".to_vec(),
+            ttl_secs: Some(100),
+            ..Default::default()
+        };
+        let registration = state.register_user(&req).unwrap();
+        let expires_at = registration.expires_at.unwrap();
+        let (permanent, _) = register(&state, b"other seed");
+        assert_eq!(state.user_count(), 2);
+
+        assert_eq!(state.reap_expired(expires_at - 1), 0);
+        assert_eq!(state.reap_expired(expires_at), 1);
+        assert_eq!(state.user_count(), 1);
+        assert!(
+            state
+                .sign_message(&permanent.to_string(), "hello", None)
+                .is_ok()
+        );
+
+        let invalid = RegisterRequest {
+            ttl_secs: Some(0),
+            ..req
+        };
+        assert!(matches!(
+            state.register_user(&invalid),
+            Err(Error::InvalidTtl)
+        ));
+    }
+
+    #[test]
+    fn test_renew() {
+        let state = AppState::new();
+        let req = RegisterRequest {
+            seed: b"seed".to_vec(),
+            ttl_secs: Some(10),
+            ..Default::default()
+        };
+        let registration = state.register_user(&req).unwrap();
+        let user_id = registration.user_id.to_string();
+
+        let expires_at = state.renew(&user_id, b"seed", 1_000).unwrap();
+        assert!(expires_at > registration.expires_at.unwrap());
+        assert_eq!(state.reap_expired(registration.expires_at.unwrap()), 0);
+
+        assert!(matches!(
+            state.renew(&user_id, b"not the seed", 1_000),
+            Err(Error::WrongSeed)
+        ));
+        assert!(matches!(
+            state.renew(&Uuid::new_v4().to_string(), b"seed", 1_000),
+            Err(Error::NoSuchUser)
+        ));
+    }
+
+    #[test]
+    fn test_rotate() {
+        let state = AppState::new();
+        let (user_id, old_key) = register(&state, b"seed");
+        let user_id = user_id.to_string();
+
+        assert!(matches!(
+            state.rotate(&user_id, b"not the seed"),
+            Err(Error::WrongSeed)
+        ));
+        let rotation = state.rotate(&user_id, b"seed").unwrap();
+        let certificate = &rotation.certificate;
+        assert_eq!(certificate.previous_generation, 0);
+        assert_eq!(certificate.generation, 1);
+        assert_eq!(
+            certificate.previous_verifying_key,
+            hex::encode(old_key.as_bytes())
+        );
+        let signature =
+            Signature::from_slice(&hex::decode(&certificate.signature).unwrap()).unwrap();
+        assert!(
+            old_key
+                .verify_strict(&certificate.statement(), &signature)
+                .is_ok()
+        );
+
+        // Only the new key signs.
+        let new_key = VerifyingKey::from_bytes(
+            &hex::decode(&certificate.verifying_key)
+                .unwrap()
+                .try_into()
+                .unwrap(),
+        )
+        .unwrap();
+        let signature = state.sign_message(&user_id, "hello", None).unwrap();
+        assert!(new_key.verify_strict(b"hello", &signature).is_ok());
+        assert!(old_key.verify_strict(b"hello", &signature).is_err());
+
+        let keys = state.verifying_keys(&user_id).unwrap();
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys[0].verifying_key, certificate.verifying_key);
+        assert_eq!(keys[0].valid_until, None);
+        assert_eq!(keys[1].verifying_key, certificate.previous_verifying_key);
+        assert_eq!(keys[1].valid_until, Some(rotation.previous_valid_until));
+
+        // The seed now authenticates against the new generation.
+        assert!(state.renew(&user_id, b"seed", 60).is_ok());
+        let rotation = state.rotate(&user_id, b"seed").unwrap();
+        assert_eq!(rotation.certificate.generation, 2);
+    }
+
+    #[test]
+    fn test_rotation_statement_cannot_be_signed() {
+        let state = AppState::new();
+        let (user_id, _) = register(&state, b"seed");
+        assert!(matches!(
+            state.sign_message(&user_id.to_string(), "wallet-poc/rotate\0forged", None),
+            Err(Error::ReservedPrefix)
+        ));
+    }
+
+    #[test]
+    fn test_revoke() {
+        let state = AppState::new();
+        let (user_id, first_key) = register(&state, b"seed");
+        let user_id = user_id.to_string();
+        let rotation = state.rotate(&user_id, b"seed").unwrap();
+
+        assert!(matches!(
+            state.revoke(&user_id, b"not the seed"),
+            Err(Error::WrongSeed)
+        ));
+        let statement = state.revoke(&user_id, b"seed").unwrap();
+        assert_eq!(
+            statement.verifying_keys,
+            vec![
+                rotation.certificate.verifying_key.clone(),
+                hex::encode(first_key.as_bytes())
+            ]
+        );
+        let signature = Signature::from_slice(&hex::decode(&statement.signature).unwrap()).unwrap();
+        assert!(
+            state
+                .identity()
+                .verify_strict(&statement.statement(), &signature)
+                .is_ok()
+        );
+
+        // The user is gone and the seed cannot be registered again.
+        assert!(matches!(
+            state.sign_message(&user_id, "hello", None),
+            Err(Error::NoSuchUser)
+        ));
+        assert!(matches!(
+            state.register_user(&RegisterRequest {
+                seed: b"seed".to_vec(),
+                ..Default::default()
+            }),
+            Err(Error::Revoked)
+        ));
+
+        let list = state.revocation_list();
+        assert_eq!(list.revocations.len(), 2);
+        assert!(list.find(&rotation.certificate.verifying_key).is_some());
+        let signature = Signature::from_slice(&hex::decode(&list.signature).unwrap()).unwrap();
+        assert!(
+            state
+                .identity()
+                .verify_strict(&list.statement(), &signature)
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn test_revoke_after_rotations() {
+        let state = AppState::new();
+        let (user_id, first_key) = register(&state, b"seed");
+        let user_id = user_id.to_string();
+        let mut keys = vec![hex::encode(first_key.as_bytes())];
+        for _ in 0..3 {
+            let rotation = state.rotate(&user_id, b"seed").unwrap();
+            keys.push(rotation.certificate.verifying_key);
+        }
+
+        // Every generation is revoked, including those no longer listed for verification.
+        let statement = state.revoke(&user_id, b"seed").unwrap();
+        keys.reverse();
+        assert_eq!(statement.verifying_keys, keys);
+        let list = state.revocation_list();
+        assert_eq!(list.revocations.len(), 4);
+        for key in &keys {
+            assert!(list.find(key).is_some());
+        }
+    }
+
+    #[test]
+    fn test_frozen_user_cannot_sign() {
+        let state = AppState::new();
+        let (user_id, _) = register(&state, b"seed");
+        let user_id = user_id.to_string();
+
+        state
+            .set_status(&user_id, UserStatus::Active, UserStatus::Frozen)
+            .unwrap();
+        assert!(matches!(
+            state.sign_message(&user_id, "hello", None),
+            Err(Error::Frozen)
+        ));
+        assert!(matches!(
+            state.rotate(&user_id, b"seed"),
+            Err(Error::Frozen)
+        ));
+        assert_eq!(state.user_count(), 1);
+
+        assert!(matches!(
+            state.set_status(&user_id, UserStatus::PendingApproval, UserStatus::Active),
+            Err(Error::WrongStatus(UserStatus::Frozen))
+        ));
+        state
+            .set_status(&user_id, UserStatus::Frozen, UserStatus::Active)
+            .unwrap();
+        assert!(state.sign_message(&user_id, "hello", None).is_ok());
+    }
+
+    #[test]
+    fn test_approver_set_validation() {
+        let state = AppState::new();
+        let (approver, _) = register(&state, b"approver");
+        let with_approval = |threshold, approvers: Vec<String>| RegisterRequest {
+            seed: b"treasury".to_vec(),
+            approval: Some(ApprovalPolicy {
+                threshold,
+                approvers,
+            }),
+            ..Default::default()
+        };
+
+        for invalid in [
+            with_approval(0, vec![approver.to_string()]),
+            with_approval(2, vec![approver.to_string()]),
+            with_approval(2, vec![approver.to_string(), approver.to_string()]),
+            with_approval(1, vec![Uuid::new_v4().to_string()]),
+        ] {
+            assert!(matches!(
+                state.register_user(&invalid),
+                Err(Error::InvalidApproval(_))
+            ));
+        }
+
+        let treasury = state
+            .register_user(&with_approval(1, vec![approver.to_string()]))
+            .unwrap();
+        // Users with approvers cannot approve, as they cannot sign their approvals.
+        assert!(matches!(
+            state.register_user(&RegisterRequest {
+                seed: b"other".to_vec(),
+                approval: Some(ApprovalPolicy {
+                    threshold: 1,
+                    approvers: vec![treasury.user_id.to_string()],
+                }),
+                ..Default::default()
+            }),
+            Err(Error::InvalidApproval(_))
+        ));
+    }
+
+    #[test]
+    fn test_registration_approval() {
+        let state = AppState::new();
+        state.set_approval_required(true);
+        let (user_id, _) = register(&state, b"seed");
+        let user_id = user_id.to_string();
+        assert_eq!(state.users()[0].status, UserStatus::PendingApproval);
+        assert_eq!(state.users()[0].key_types, ["ed25519", "secp256k1"]);
+        assert!(matches!(
+            state.sign_message(&user_id, "hello", None),
+            Err(Error::PendingApproval)
+        ));
+
+        state
+            .set_status(&user_id, UserStatus::PendingApproval, UserStatus::Active)
+            .unwrap();
+        assert!(state.sign_message(&user_id, "hello", None).is_ok());
+    }
+
+    #[test]
+    fn test_read_only() {
+        let state = AppState::new();
+        let (user_id, _) = register(&state, b"seed");
+        let user_id = user_id.to_string();
+
+        state.set_read_only(true);
+        let req = RegisterRequest {
+            seed: b"other seed".to_vec(),
+            ..Default::default()
+        };
+        assert!(matches!(state.register_user(&req), Err(Error::ReadOnly)));
+        assert!(matches!(state.forget(&user_id), Err(Error::ReadOnly)));
+        assert!(state.sign_message(&user_id, "hello", None).is_ok());
+        // Operators can still remove users.
+        assert!(state.force_forget(&user_id).is_ok());
+        assert!(matches!(
+            state.force_forget(&user_id),
+            Err(Error::NoSuchUser)
+        ));
+
+        state.set_read_only(false);
+        assert!(state.register_user(&req).is_ok());
+    }
+
+    #[test]
+    fn test_rotate_identity() {
+        let state = AppState::new();
+        let previous = state.identity();
+        let rotation = state.rotate_identity();
+        assert_eq!(rotation.generation, 1);
+        assert_eq!(
+            rotation.previous_verifying_key,
+            hex::encode(previous.as_bytes())
+        );
+        assert_eq!(
+            rotation.verifying_key,
+            hex::encode(state.identity().as_bytes())
+        );
+        let signature = Signature::from_slice(&hex::decode(&rotation.signature).unwrap()).unwrap();
+        assert!(
+            previous
+                .verify_strict(&rotation.statement(), &signature)
+                .is_ok()
+        );
+
+        // A restarted server picks up the rotated key again.
+        let server_secret = *state.server_secret.read().unwrap();
+        assert_eq!(
+            AppState::with_server_secret(&server_secret, 1).identity(),
+            state.identity()
+        );
+        // No generation is derived from anything public: other secrets give other keys.
+        assert_ne!(AppState::new().identity(), previous);
+        assert_ne!(
+            AppState::with_server_secret(&[0; SERVER_SECRET_LEN], 1).identity(),
+            state.identity()
+        );
+    }
+
+    #[test]
+    fn test_forget_frees_slot() {
+        let state = AppState::new();
+        let (user_id, _) = register(&state, b"seed");
+        assert_eq!(state.user_count(), 1);
+        state.forget(&user_id.to_string()).unwrap();
+        assert_eq!(state.user_count(), 0);
+    }
+
+    // A legacy message with `payer` as fee payer, invoking the system program on `payer`.
+    fn solana_message(payer: &[u8; 32]) -> Vec<u8> {
+        let mut message = vec![1, 0, 1, 2];
+        message.extend_from_slice(payer);
+        message.extend_from_slice(&[0; 32]);
+        message.extend_from_slice(&[9; 32]);
+        message.extend_from_slice(&[1, 1, 1, 0, 0]);
+        message
+    }
+
+    #[test]
+    fn test_solana_messages_only_sign_through_sign_solana() {
+        let state = AppState::new();
+        let (user_id, verifying_key) = register(&state, b"solana");
+        let signing_key = state
+            .signing_key(&user_id.to_string(), None, false)
+            .unwrap();
+
+        let message = solana_message(verifying_key.as_bytes());
+        assert!(matches!(
+            state.sign_bytes(&signing_key, &message),
+            Err(Error::SolanaMessage)
+        ));
+        let message = solana::Message::parse(message).unwrap();
+        assert!(state.sign_solana(&user_id.to_string(), &message).is_ok());
+
+        // Messages the user's key does not sign are just bytes.
+        assert!(
+            state
+                .sign_bytes(&signing_key, &solana_message(&[1; 32]))
+                .is_ok()
+        );
     }
 }